@@ -0,0 +1,37 @@
+;; Throws a wasm exception across a WASI call, catches it, and then throws
+;; another one which escapes _start:
+;;
+;;   $ cargo run examples/exception.wasm
+;;   hello from try
+;;   caught
+;;   Error: Uncaught wasm exception with tag error and payload [42]
+(module
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (tag $error (export "error") (param i32))
+
+  (data (i32.const 16) "hello from try\n")
+  (data (i32.const 32) "caught\n")
+
+  (func $print (param $ptr i32) (param $len i32)
+    ;; iovec { buf, buf_len } at address 0, nwritten at address 8
+    (i32.store (i32.const 0) (local.get $ptr))
+    (i32.store (i32.const 4) (local.get $len))
+    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))))
+
+  (func $write_and_throw
+    (call $print (i32.const 16) (i32.const 15))
+    (throw $error (i32.const 1)))
+
+  (func (export "_start")
+    (block $caught (result i32)
+      (try_table (catch $error $caught)
+        (call $write_and_throw))
+      (unreachable))
+    (drop)
+    (call $print (i32.const 32) (i32.const 7))
+
+    ;; this exception is not caught
+    (throw $error (i32.const 42))))
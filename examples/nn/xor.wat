@@ -0,0 +1,67 @@
+;; Computes XOR of 1 and 0 with the model xor.lv8nn via wasi-nn,
+;; and exits with the result:
+;;
+;;   $ cd examples/nn
+;;   $ cargo run xor.wasm; echo $?
+;;   1
+(module
+  (import "wasi_snapshot_preview1" "path_open"
+    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_read"
+    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "proc_exit"
+    (func $proc_exit (param i32)))
+  (import "wasi_ephemeral_nn" "load"
+    (func $load (param i32 i32 i32 i32 i32) (result i32)))
+  (import "wasi_ephemeral_nn" "init_execution_context"
+    (func $init_execution_context (param i32 i32) (result i32)))
+  (import "wasi_ephemeral_nn" "set_input"
+    (func $set_input (param i32 i32 i32) (result i32)))
+  (import "wasi_ephemeral_nn" "compute"
+    (func $compute (param i32) (result i32)))
+  (import "wasi_ephemeral_nn" "get_output"
+    (func $get_output (param i32 i32 i32 i32 i32) (result i32)))
+
+  (memory (export "memory") 1)
+
+  ;; path of the model
+  (data (i32.const 16) "xor.lv8nn")
+  ;; iovec { buf, buf_len } to read the model into address 1024
+  (data (i32.const 40) "\00\04\00\00\00\04\00\00")
+  ;; tensor dimensions [1, 2] and data [1.0, 0.0]
+  (data (i32.const 80) "\01\00\00\00\02\00\00\00")
+  (data (i32.const 96) "\00\00\80\3f\00\00\00\00")
+  ;; tensor { dimensions: (80, 2), type: f32, data: (96, 8) }
+  (data (i32.const 112) "\50\00\00\00\02\00\00\00\01\00\00\00\60\00\00\00\08\00\00\00")
+
+  ;; exits with 100 + errno if the call failed
+  (func $check (param $errno i32)
+    (if (local.get $errno)
+      (then
+        (call $proc_exit (i32.add (i32.const 100) (local.get $errno)))
+        (unreachable))))
+
+  (func (export "_start")
+    ;; read the model (fd 3 is the preopened current directory)
+    (call $check (call $path_open
+      (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 9) (i32.const 0)
+      (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 32)))
+    (call $check (call $fd_read
+      (i32.load (i32.const 32)) (i32.const 40) (i32.const 1) (i32.const 48)))
+
+    ;; graph_builder_array [(1024, nread)]
+    (i32.store (i32.const 64) (i32.const 1024))
+    (i32.store (i32.const 68) (i32.load (i32.const 48)))
+
+    ;; load with autodetect encoding on CPU
+    (call $check (call $load
+      (i32.const 64) (i32.const 1) (i32.const 6) (i32.const 0) (i32.const 140)))
+    (call $check (call $init_execution_context
+      (i32.load (i32.const 140)) (i32.const 144)))
+    (call $check (call $set_input
+      (i32.load (i32.const 144)) (i32.const 0) (i32.const 112)))
+    (call $check (call $compute (i32.load (i32.const 144))))
+    (call $check (call $get_output
+      (i32.load (i32.const 144)) (i32.const 0) (i32.const 148) (i32.const 4) (i32.const 152)))
+
+    (call $proc_exit (i32.trunc_f32_s (f32.nearest (f32.load (i32.const 148)))))))
@@ -1,24 +1,218 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 use crate::runtime::{self};
 
+// options of the runtime are global, so that `lv8 repl` and `lv8 run-js` take them after the
+// subcommand as well
 #[derive(Parser)]
 #[clap(
     name = "lv8",
     version = env!("CARGO_PKG_VERSION"),
-    about = "lv8 is a WebAssembly runtime"
+    about = "lv8 is a WebAssembly runtime",
+    subcommand_negates_reqs = true
 )]
 pub struct Cli {
-    pub wasmfile_path: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(required = true)]
+    pub wasmfile_path: Option<PathBuf>,
+
+    /// Name of the exported (or imported) memory used by WASI functions
+    #[arg(long, global = true, value_name = "EXPORT_NAME")]
+    pub wasi_memory: Option<String>,
+
+    /// Run WASI functions asynchronously via JS Promise Integration
+    #[arg(long, global = true)]
+    pub jspi: bool,
+
+    /// Provide the `env` functions imported by modules built with Emscripten in standalone mode
+    #[arg(long, global = true)]
+    pub emscripten: bool,
+
+    /// Provide the `env` functions imported by modules built with AssemblyScript (abort, trace and seed)
+    #[arg(long, global = true)]
+    pub assemblyscript: bool,
+
+    /// Listen on the TCP address and pass the socket to the wasm module as a preopened fd
+    #[arg(long, global = true, value_name = "ADDR:PORT")]
+    pub tcplisten: Vec<String>,
+
+    /// Evaluate the JS file before instantiating the module, where `imports` is the import object
+    #[arg(long, global = true, value_name = "SCRIPT")]
+    pub preload: Vec<PathBuf>,
+
+    /// Pass the fd (e.g. a pipe or a socket) opened by the parent process to the wasm module, as the guest fd if given
+    #[arg(long, global = true, value_name = "HOSTFD[:GUESTFD]")]
+    pub inherit_fd: Vec<String>,
+
+    /// Mount an in-memory directory at the guest path, optionally seeded with a copy of the host directory
+    #[arg(long, global = true, value_name = "[HOST_DIR:]GUEST_DIR")]
+    pub mount_mem: Vec<String>,
+
+    /// Write the contents of in-memory directories to the host directory after the run
+    #[arg(long, global = true, value_name = "DIR")]
+    pub mem_export: Option<PathBuf>,
+
+    /// Mount the contents of a tar or zip archive at the guest path as a read-only directory
+    #[arg(long, global = true, value_name = "ARCHIVE:GUEST_DIR")]
+    pub mount_archive: Vec<String>,
+
+    /// Mount a copy-on-write view of the host directory at the guest path, and print the changes after the run
+    #[arg(long, global = true, value_name = "HOST_DIR:GUEST_DIR")]
+    pub overlay: Vec<String>,
+
+    /// Write files added or modified in overlays to the host directory after the run
+    #[arg(long, global = true, value_name = "DIR")]
+    pub overlay_export: Option<PathBuf>,
+
+    /// Read stdin of the wasm module from the file
+    #[arg(long, global = true, value_name = "FILE")]
+    pub stdin: Option<PathBuf>,
+
+    /// Write stdout of the wasm module to the file
+    #[arg(long, global = true, value_name = "FILE")]
+    pub stdout: Option<PathBuf>,
+
+    /// Write stderr of the wasm module to the file
+    #[arg(long, global = true, value_name = "FILE")]
+    pub stderr: Option<PathBuf>,
+
+    /// Use a fake clock and a fixed random seed (0 unless --random-seed is given) for reproducible runs
+    #[arg(long, global = true)]
+    pub deterministic: bool,
+
+    /// Replace the clocks with a fake clock which starts at 2000-01-01 and advances only when read or slept on
+    #[arg(long, global = true)]
+    pub fake_clock: bool,
+
+    /// Seed of the random number generator used by random_get
+    #[arg(long, global = true, value_name = "N")]
+    pub random_seed: Option<u64>,
+
+    /// Log every WASI call with its arguments, errno and duration
+    #[arg(long, global = true)]
+    pub trace_wasi: bool,
+
+    /// Write the log of WASI calls to the file instead of stderr (implies --trace-wasi)
+    #[arg(long, global = true, value_name = "FILE")]
+    pub trace_file: Option<PathBuf>,
+
+    /// Write the log of WASI calls as JSON lines
+    #[arg(long, global = true)]
+    pub trace_json: bool,
+
+    /// Allow only these WASI functions, separated by commas (`sock_*` matches every function starting with `sock_`)
+    #[arg(long, global = true, value_name = "FUNCS", value_delimiter = ',')]
+    pub allow_wasi: Vec<String>,
+
+    /// Deny these WASI functions, separated by commas (`sock_*` matches every function starting with `sock_`)
+    #[arg(long, global = true, value_name = "FUNCS", value_delimiter = ',')]
+    pub deny_wasi: Vec<String>,
+
+    /// Read rules of allowed and denied WASI functions from the file
+    #[arg(long, global = true, value_name = "FILE")]
+    pub wasi_policy: Option<PathBuf>,
+
+    /// What happens when a denied WASI function is called [default: enosys]
+    #[arg(long, global = true, value_enum)]
+    pub deny_action: Option<DenyAction>,
 
     /// Arguments after -- are passed to wasm module
     #[arg(trailing_var_arg = true)]
     wasm_args: Vec<String>,
 }
 
+#[derive(Subcommand)]
+pub enum Command {
+    /// Print the imports, exports, limits and custom sections of a wasm module
+    Inspect {
+        wasmfile_path: PathBuf,
+
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Instantiate a wasm module without running it, and evaluate JS against the instance interactively
+    Repl { wasmfile_path: PathBuf },
+    /// Run a JS program with a WASI helper compatible with the `wasi` module of Node
+    RunJs {
+        script_path: PathBuf,
+        /// Arguments after -- are passed to the script and the wasm module
+        #[arg(last = true)]
+        script_args: Vec<String>,
+    },
+    /// Check that wasm modules compile, and print the errors of invalid ones
+    Validate {
+        #[arg(required = true)]
+        wasmfile_paths: Vec<PathBuf>,
+    },
+}
+
+/// Action taken when the wasm module calls a denied WASI function
+#[derive(Clone, Copy, ValueEnum)]
+pub enum DenyAction {
+    /// Return ENOSYS without calling the function
+    Enosys,
+    /// Return EPERM without calling the function
+    Eperm,
+    /// Trap the wasm module
+    Trap,
+    /// Print a warning and call the function anyway
+    Log,
+}
+
 pub fn run() -> Result<i32> {
     let args = Cli::parse();
-    runtime::run(&args)
+    match &args.command {
+        Some(Command::Inspect {
+            wasmfile_path,
+            json,
+        }) => runtime::inspect(&args, wasmfile_path, *json),
+        Some(Command::Repl { wasmfile_path }) => runtime::repl(&args, wasmfile_path),
+        Some(Command::RunJs { script_path, .. }) => runtime::run_js(&args, script_path),
+        Some(Command::Validate { wasmfile_paths }) => runtime::validate(wasmfile_paths),
+        None => runtime::run(&args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_options_before_and_after_subcommands() {
+        let args = Cli::parse_from(["lv8", "--trace-wasi", "repl", "--emscripten", "a.wasm"]);
+        assert!(args.trace_wasi);
+        assert!(args.emscripten);
+        assert!(matches!(args.command, Some(Command::Repl { .. })));
+
+        let args = Cli::parse_from([
+            "lv8",
+            "run-js",
+            "--deny-wasi",
+            "sock_*",
+            "app.js",
+            "--",
+            "arg1",
+        ]);
+        assert_eq!(args.deny_wasi, ["sock_*"]);
+        let Some(Command::RunJs { script_args, .. }) = args.command else {
+            panic!("not run-js");
+        };
+        assert_eq!(script_args, ["arg1"]);
+    }
+
+    #[test]
+    fn runs_modules_without_subcommands() {
+        let args = Cli::parse_from(["lv8", "--jspi", "a.wasm", "--", "repl", "--jspi"]);
+        assert!(args.jspi);
+        assert!(args.command.is_none());
+        assert_eq!(args.wasmfile_path, Some(PathBuf::from("a.wasm")));
+        assert_eq!(args.wasm_args, ["repl", "--jspi"]);
+
+        assert!(Cli::try_parse_from(["lv8", "--jspi"]).is_err());
+    }
 }
@@ -1,5 +1,4 @@
-mod driver;
-mod runtime;
+use lv8::driver;
 
 fn main() {
     match driver::run() {
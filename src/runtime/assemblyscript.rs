@@ -0,0 +1,148 @@
+// `env` functions imported by modules built with AssemblyScript, which are given by its loader in JS
+// Strings are passed as pointers to UTF-16 data, preceded by the length in bytes (rtSize).
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{get_or_create_import_module, set_function, wasi};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub(super) fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub(super) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Adds the functions to `imports.env`
+pub(super) fn create_assemblyscript_import<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    import_object: &v8::Local<'a, v8::Object>,
+) {
+    let import_env = get_or_create_import_module(scope, import_object, "env");
+    set_function(scope, import_env, "abort", abort);
+    set_function(scope, import_env, "trace", trace);
+    set_function(scope, import_env, "seed", seed);
+}
+
+/// Returns the string at the pointer, or None if it is null or out of bounds
+fn read_string(memory: &[u8], ptr: u32) -> Option<String> {
+    let ptr = ptr as usize;
+    let header = memory.get(ptr.checked_sub(4)?..ptr)?;
+    let byte_length = u32::from_le_bytes(header.try_into().unwrap()) as usize;
+    let data = memory.get(ptr..ptr.checked_add(byte_length)?)?;
+    let units = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect::<Vec<u16>>();
+    Some(String::from_utf16_lossy(&units))
+}
+
+/// Returns the string pointed by the argument, or `null` like the loader of AssemblyScript
+fn string_arg(
+    scope: &mut v8::HandleScope,
+    args: &v8::FunctionCallbackArguments,
+    index: i32,
+) -> String {
+    let ptr = args.get(index).uint32_value(scope).unwrap_or_default();
+    let string = match wasi::get_backing_store_from_scope(scope) {
+        Ok(backing_store) => read_string(wasi::memory_bytes(&backing_store), ptr),
+        Err(_) => None,
+    };
+    string.unwrap_or_else(|| "null".to_string())
+}
+
+/// abort(message, file_name, line, column), which traps with the message
+///
+/// The trap is a RuntimeError like those of wasm, which `Runtime::run` reports as the error.
+/// The message is formatted like the error thrown by the loader of AssemblyScript.
+fn abort(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments, _rv: v8::ReturnValue) {
+    let message = string_arg(scope, &args, 0);
+    let file_name = string_arg(scope, &args, 1);
+    let line = args.get(2).uint32_value(scope).unwrap_or_default();
+    let column = args.get(3).uint32_value(scope).unwrap_or_default();
+    let message = format!("abort: {} at {}:{}:{}", message, file_name, line, column);
+
+    let message = v8::String::new(scope, &message).unwrap();
+    let context = scope.get_current_context();
+    let global = context.global(scope);
+    let str_wasm = v8::String::new(scope, "WebAssembly").unwrap();
+    let global_wasm = global
+        .get(scope, str_wasm.into())
+        .unwrap()
+        .to_object(scope)
+        .unwrap();
+    let str_runtime_error = v8::String::new(scope, "RuntimeError").unwrap();
+    let runtime_error_ctor = global_wasm.get(scope, str_runtime_error.into()).unwrap();
+    let runtime_error_ctor = runtime_error_ctor.cast::<v8::Function>();
+    // new WebAssembly.RuntimeError(message)
+    if let Some(exception) = runtime_error_ctor.new_instance(scope, &[message.into()]) {
+        scope.throw_exception(exception.into());
+    }
+}
+
+/// trace(message, n, a0, a1, a2, a3, a4), which prints the message and the first n values to stderr
+fn trace(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments, _rv: v8::ReturnValue) {
+    let message = string_arg(scope, &args, 0);
+    let n = args.get(1).uint32_value(scope).unwrap_or_default().min(5) as i32;
+    let values = (0..n)
+        .map(|i| {
+            let value = args.get(2 + i).number_value(scope).unwrap_or_default();
+            v8::Number::new(scope, value).to_rust_string_lossy(scope)
+        })
+        .collect::<Vec<String>>();
+    if values.is_empty() {
+        eprintln!("trace: {}", message);
+    } else {
+        eprintln!("trace: {} {}", message, values.join(", "));
+    }
+}
+
+/// seed(), which returns the seed of `Math.random`
+///
+/// The seed is taken from the random source of WASI, so that it is fixed by --random-seed.
+fn seed(
+    scope: &mut v8::HandleScope,
+    _args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    // any f64 except NaN can be a seed, so use 53 bits which are exactly representable
+    let seed = (wasi::random_u64() >> 11) as f64;
+    rv.set(v8::Number::new(scope, seed).into());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a memory with the string at 8, preceded by its length in bytes
+    fn memory_with(string: &str) -> Vec<u8> {
+        let units: Vec<u16> = string.encode_utf16().collect();
+        let mut memory = vec![0; 4];
+        memory.extend_from_slice(&(units.len() as u32 * 2).to_le_bytes());
+        memory.extend(units.iter().flat_map(|unit| unit.to_le_bytes()));
+        memory
+    }
+
+    #[test]
+    fn reads_utf16_strings() {
+        let memory = memory_with("index out of range");
+        assert_eq!(read_string(&memory, 8).unwrap(), "index out of range");
+        let memory = memory_with("~lib/ü.ts 🦀");
+        assert_eq!(read_string(&memory, 8).unwrap(), "~lib/ü.ts 🦀");
+        let memory = memory_with("");
+        assert_eq!(read_string(&memory, 8).unwrap(), "");
+    }
+
+    #[test]
+    fn rejects_null_and_out_of_bounds_strings() {
+        let memory = memory_with("abc");
+        assert_eq!(read_string(&memory, 0), None);
+        assert_eq!(read_string(&memory, 2), None);
+        // the length goes beyond the memory
+        assert_eq!(read_string(&memory[..12], 8), None);
+        assert_eq!(read_string(&memory, 100), None);
+        assert_eq!(read_string(&memory, u32::MAX), None);
+    }
+}
@@ -0,0 +1,1067 @@
+// Canonical ABI of the component model, which passes component values to and from core wasm
+//
+// Values are flattened into core values when there are few of them, and are stored in the linear
+// memory otherwise. Strings and lists given to the component are allocated by its realloc
+// function. The rules follow CanonicalABI.md of the component model.
+
+use anyhow::{anyhow, Result};
+use wasmparser::component_types::{ComponentDefinedType, ComponentFuncType, ComponentValType};
+use wasmparser::types::Types;
+use wasmparser::PrimitiveValType;
+
+/// Maximum number of flat parameters, beyond which the parameters are passed in the memory
+const MAX_FLAT_PARAMS: usize = 16;
+/// Maximum number of flat results, beyond which the results are returned in the memory
+const MAX_FLAT_RESULTS: usize = 1;
+
+/// Type of a component value
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Type {
+    Bool,
+    S8,
+    U8,
+    S16,
+    U16,
+    S32,
+    U32,
+    S64,
+    U64,
+    F32,
+    F64,
+    Char,
+    String,
+    List(Box<Type>),
+    /// Record or tuple, whose fields are laid out in order
+    Record(Vec<Type>),
+    /// Variant, enum, option or result, with the names and payloads of the cases
+    Variant(Vec<(String, Option<Type>)>),
+    Flags(Vec<String>),
+    /// Handle of a resource, which is either owned or borrowed
+    Handle,
+}
+
+/// Component value
+///
+/// Cases of variants and flags are given by name, and options and results have the cases
+/// `none`/`some` and `ok`/`error`.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Val {
+    Bool(bool),
+    S8(i8),
+    U8(u8),
+    S16(i16),
+    U16(u16),
+    S32(i32),
+    U32(u32),
+    S64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Char(char),
+    String(String),
+    List(Vec<Val>),
+    /// `list<u8>`, which is kept as bytes
+    Bytes(Vec<u8>),
+    Record(Vec<Val>),
+    Variant(String, Option<Box<Val>>),
+    Flags(Vec<String>),
+    Handle(u32),
+}
+
+impl Val {
+    pub fn variant(case: &str, payload: Option<Val>) -> Val {
+        Val::Variant(case.to_string(), payload.map(Box::new))
+    }
+
+    pub fn ok(payload: Option<Val>) -> Val {
+        Val::variant("ok", payload)
+    }
+
+    pub fn error(payload: Option<Val>) -> Val {
+        Val::variant("error", payload)
+    }
+
+    pub fn none() -> Val {
+        Val::variant("none", None)
+    }
+
+    pub fn some(payload: Val) -> Val {
+        Val::variant("some", Some(payload))
+    }
+}
+
+/// Core value type of flattened values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum FlatType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+/// Core value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Flat {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl Flat {
+    fn zero(ty: FlatType) -> Flat {
+        match ty {
+            FlatType::I32 => Flat::I32(0),
+            FlatType::I64 => Flat::I64(0),
+            FlatType::F32 => Flat::F32(0.0),
+            FlatType::F64 => Flat::F64(0.0),
+        }
+    }
+
+    fn i32(self) -> Result<i32> {
+        match self {
+            Flat::I32(value) => Ok(value),
+            _ => Err(anyhow!("expected an i32 but got {:?}", self)),
+        }
+    }
+
+    fn i64(self) -> Result<i64> {
+        match self {
+            Flat::I64(value) => Ok(value),
+            _ => Err(anyhow!("expected an i64 but got {:?}", self)),
+        }
+    }
+
+    /// Converts the value of the payload of a variant case from the joined type of the cases
+    fn narrow(self, ty: FlatType) -> Result<Flat> {
+        Ok(match (self, ty) {
+            (Flat::I32(value), FlatType::F32) => Flat::F32(f32::from_bits(value as u32)),
+            (Flat::I64(value), FlatType::I32) => Flat::I32(value as i32),
+            (Flat::I64(value), FlatType::F32) => Flat::F32(f32::from_bits(value as u32)),
+            (Flat::I64(value), FlatType::F64) => Flat::F64(f64::from_bits(value as u64)),
+            (Flat::I32(_), FlatType::I32)
+            | (Flat::I64(_), FlatType::I64)
+            | (Flat::F32(_), FlatType::F32)
+            | (Flat::F64(_), FlatType::F64) => self,
+            _ => return Err(anyhow!("cannot convert {:?} to {:?}", self, ty)),
+        })
+    }
+
+    /// Converts the value of the payload of a variant case to the joined type of the cases
+    fn widen(self, ty: FlatType) -> Flat {
+        match (self, ty) {
+            (Flat::F32(value), FlatType::I32) => Flat::I32(value.to_bits() as i32),
+            (Flat::I32(value), FlatType::I64) => Flat::I64(value as u32 as i64),
+            (Flat::F32(value), FlatType::I64) => Flat::I64(value.to_bits() as i64),
+            (Flat::F64(value), FlatType::I64) => Flat::I64(value.to_bits() as i64),
+            _ => self,
+        }
+    }
+}
+
+/// Linear memory of the component, which is given by the `memory` and `realloc` options
+pub(super) trait Memory {
+    fn bytes(&mut self) -> &mut [u8];
+    /// Allocates memory by the realloc function, and returns the address
+    fn alloc(&mut self, align: u32, size: u32) -> Result<u32>;
+}
+
+/// Type of a function given to or by the component
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct FuncType {
+    pub params: Vec<Type>,
+    pub results: Vec<Type>,
+}
+
+impl FuncType {
+    pub fn from_component(types: &Types, ty: &ComponentFuncType) -> Self {
+        FuncType {
+            params: ty
+                .params
+                .iter()
+                .map(|(_, ty)| Type::from_component(types, ty))
+                .collect(),
+            results: ty
+                .results
+                .iter()
+                .map(|(_, ty)| Type::from_component(types, ty))
+                .collect(),
+        }
+    }
+
+    fn flat_params(&self) -> Vec<FlatType> {
+        flatten_all(&self.params)
+    }
+
+    fn flat_results(&self) -> Vec<FlatType> {
+        flatten_all(&self.results)
+    }
+
+    /// Parameters of the core function made by `canon lower`, which end with the pointer to the
+    /// results if they are returned in the memory
+    pub fn lowered_params(&self) -> Vec<FlatType> {
+        let mut params = self.flat_params();
+        if params.len() > MAX_FLAT_PARAMS {
+            params = vec![FlatType::I32];
+        }
+        if self.flat_results().len() > MAX_FLAT_RESULTS {
+            params.push(FlatType::I32);
+        }
+        params
+    }
+
+    /// Results of the core function given to `canon lift`, which returns the pointer to the results
+    /// if they are stored in the memory
+    pub fn lifted_results(&self) -> Vec<FlatType> {
+        let results = self.flat_results();
+        if results.len() > MAX_FLAT_RESULTS {
+            vec![FlatType::I32]
+        } else {
+            results
+        }
+    }
+
+    /// Lifts the arguments given to a function made by `canon lower`
+    pub fn lift_args(&self, memory: &mut dyn Memory, args: &[Flat]) -> Result<Vec<Val>> {
+        if self.flat_params().len() > MAX_FLAT_PARAMS {
+            let ptr = first(args)?.i32()? as u32;
+            let params = Type::Record(self.params.clone());
+            let Val::Record(values) = params.load(memory, ptr)? else {
+                unreachable!()
+            };
+            return Ok(values);
+        }
+        let mut args = args.iter().copied();
+        self.params
+            .iter()
+            .map(|ty| ty.lift_flat(memory, &mut args))
+            .collect()
+    }
+
+    /// Lowers the results of a function made by `canon lower` into the values returned to the
+    /// caller, or stores them at the pointer given as the last argument
+    pub fn lower_results(
+        &self,
+        memory: &mut dyn Memory,
+        args: &[Flat],
+        results: &[Val],
+    ) -> Result<Vec<Flat>> {
+        if results.len() != self.results.len() {
+            return Err(anyhow!(
+                "expected {} results but got {}",
+                self.results.len(),
+                results.len()
+            ));
+        }
+        if self.flat_results().len() > MAX_FLAT_RESULTS {
+            let ptr = args
+                .last()
+                .copied()
+                .map(Flat::i32)
+                .transpose()?
+                .unwrap_or(0) as u32;
+            let results_type = Type::Record(self.results.clone());
+            results_type.store(memory, &Val::Record(results.to_vec()), ptr)?;
+            return Ok(vec![]);
+        }
+        let mut flat = vec![];
+        for (ty, value) in self.results.iter().zip(results) {
+            ty.lower_flat(memory, value, &mut flat)?;
+        }
+        Ok(flat)
+    }
+
+    /// Lifts the results returned by a core function made by `canon lift`
+    pub fn lift_results(&self, memory: &mut dyn Memory, results: &[Flat]) -> Result<Vec<Val>> {
+        if self.flat_results().len() > MAX_FLAT_RESULTS {
+            let ptr = first(results)?.i32()? as u32;
+            let results_type = Type::Record(self.results.clone());
+            let Val::Record(values) = results_type.load(memory, ptr)? else {
+                unreachable!()
+            };
+            return Ok(values);
+        }
+        let mut results = results.iter().copied();
+        self.results
+            .iter()
+            .map(|ty| ty.lift_flat(memory, &mut results))
+            .collect()
+    }
+}
+
+fn first(values: &[Flat]) -> Result<Flat> {
+    values
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("missing pointer"))
+}
+
+fn flatten_all(types: &[Type]) -> Vec<FlatType> {
+    let mut flat = vec![];
+    for ty in types {
+        ty.flatten(&mut flat);
+    }
+    flat
+}
+
+/// Returns the type of a slot shared by the payloads of variant cases
+fn join(a: FlatType, b: FlatType) -> FlatType {
+    match (a, b) {
+        _ if a == b => a,
+        (FlatType::I32, FlatType::F32) | (FlatType::F32, FlatType::I32) => FlatType::I32,
+        _ => FlatType::I64,
+    }
+}
+
+fn align_to(offset: u32, align: u32) -> u32 {
+    offset.div_ceil(align) * align
+}
+
+impl Type {
+    pub fn from_component(types: &Types, ty: &ComponentValType) -> Type {
+        let id = match ty {
+            ComponentValType::Primitive(ty) => return Type::from_primitive(*ty),
+            ComponentValType::Type(id) => *id,
+        };
+        let convert = |ty: &ComponentValType| Type::from_component(types, ty);
+        match &types[id] {
+            ComponentDefinedType::Primitive(ty) => Type::from_primitive(*ty),
+            ComponentDefinedType::Record(record) => {
+                Type::Record(record.fields.values().map(convert).collect())
+            }
+            ComponentDefinedType::Variant(variant) => Type::Variant(
+                variant
+                    .cases
+                    .iter()
+                    .map(|(name, case)| (name.to_string(), case.ty.as_ref().map(convert)))
+                    .collect(),
+            ),
+            ComponentDefinedType::List(ty) => Type::List(Box::new(convert(ty))),
+            ComponentDefinedType::Tuple(tuple) => {
+                Type::Record(tuple.types.iter().map(convert).collect())
+            }
+            ComponentDefinedType::Flags(names) => {
+                Type::Flags(names.iter().map(|name| name.to_string()).collect())
+            }
+            ComponentDefinedType::Enum(names) => {
+                Type::Variant(names.iter().map(|name| (name.to_string(), None)).collect())
+            }
+            ComponentDefinedType::Option(ty) => Type::Variant(vec![
+                ("none".to_string(), None),
+                ("some".to_string(), Some(convert(ty))),
+            ]),
+            ComponentDefinedType::Result { ok, err } => Type::Variant(vec![
+                ("ok".to_string(), ok.as_ref().map(convert)),
+                ("error".to_string(), err.as_ref().map(convert)),
+            ]),
+            ComponentDefinedType::Own(_) | ComponentDefinedType::Borrow(_) => Type::Handle,
+        }
+    }
+
+    fn from_primitive(ty: PrimitiveValType) -> Type {
+        match ty {
+            PrimitiveValType::Bool => Type::Bool,
+            PrimitiveValType::S8 => Type::S8,
+            PrimitiveValType::U8 => Type::U8,
+            PrimitiveValType::S16 => Type::S16,
+            PrimitiveValType::U16 => Type::U16,
+            PrimitiveValType::S32 => Type::S32,
+            PrimitiveValType::U32 => Type::U32,
+            PrimitiveValType::S64 => Type::S64,
+            PrimitiveValType::U64 => Type::U64,
+            PrimitiveValType::F32 => Type::F32,
+            PrimitiveValType::F64 => Type::F64,
+            PrimitiveValType::Char => Type::Char,
+            PrimitiveValType::String => Type::String,
+        }
+    }
+
+    fn flatten(&self, flat: &mut Vec<FlatType>) {
+        match self {
+            Type::Bool
+            | Type::S8
+            | Type::U8
+            | Type::S16
+            | Type::U16
+            | Type::S32
+            | Type::U32
+            | Type::Char
+            | Type::Handle => flat.push(FlatType::I32),
+            Type::S64 | Type::U64 => flat.push(FlatType::I64),
+            Type::F32 => flat.push(FlatType::F32),
+            Type::F64 => flat.push(FlatType::F64),
+            Type::String | Type::List(_) => flat.extend([FlatType::I32, FlatType::I32]),
+            Type::Record(fields) => {
+                for field in fields {
+                    field.flatten(flat);
+                }
+            }
+            Type::Variant(cases) => {
+                flat.push(FlatType::I32);
+                flat.extend(variant_payload(cases));
+            }
+            Type::Flags(names) => {
+                flat.extend(std::iter::repeat_n(FlatType::I32, names.len().div_ceil(32)))
+            }
+        }
+    }
+
+    fn flat_types(&self) -> Vec<FlatType> {
+        let mut flat = vec![];
+        self.flatten(&mut flat);
+        flat
+    }
+
+    pub fn size(&self) -> u32 {
+        match self {
+            Type::Bool | Type::S8 | Type::U8 => 1,
+            Type::S16 | Type::U16 => 2,
+            Type::S32 | Type::U32 | Type::F32 | Type::Char | Type::Handle => 4,
+            Type::S64 | Type::U64 | Type::F64 => 8,
+            Type::String | Type::List(_) => 8,
+            Type::Record(fields) => {
+                let mut size = 0;
+                for field in fields {
+                    size = align_to(size, field.align()) + field.size();
+                }
+                align_to(size, self.align())
+            }
+            Type::Variant(cases) => {
+                let size = align_to(discriminant_size(cases.len()), max_case_align(cases))
+                    + cases
+                        .iter()
+                        .filter_map(|(_, ty)| ty.as_ref().map(Type::size))
+                        .max()
+                        .unwrap_or(0);
+                align_to(size, self.align())
+            }
+            Type::Flags(names) => match names.len() {
+                0 => 0,
+                1..=8 => 1,
+                9..=16 => 2,
+                n => 4 * n.div_ceil(32) as u32,
+            },
+        }
+    }
+
+    pub fn align(&self) -> u32 {
+        match self {
+            Type::Record(fields) => fields.iter().map(Type::align).max().unwrap_or(1),
+            Type::Variant(cases) => discriminant_size(cases.len()).max(max_case_align(cases)),
+            Type::Flags(_) => self.size().clamp(1, 4),
+            Type::String | Type::List(_) => 4,
+            _ => self.size(),
+        }
+    }
+
+    /// Lifts a value from flat values given by core wasm
+    fn lift_flat(
+        &self,
+        memory: &mut dyn Memory,
+        flat: &mut dyn Iterator<Item = Flat>,
+    ) -> Result<Val> {
+        let mut next = || flat.next().ok_or_else(|| anyhow!("missing core value"));
+        Ok(match self {
+            Type::Bool => Val::Bool(next()?.i32()? != 0),
+            Type::S8 => Val::S8(next()?.i32()? as i8),
+            Type::U8 => Val::U8(next()?.i32()? as u8),
+            Type::S16 => Val::S16(next()?.i32()? as i16),
+            Type::U16 => Val::U16(next()?.i32()? as u16),
+            Type::S32 => Val::S32(next()?.i32()?),
+            Type::U32 => Val::U32(next()?.i32()? as u32),
+            Type::S64 => Val::S64(next()?.i64()?),
+            Type::U64 => Val::U64(next()?.i64()? as u64),
+            Type::F32 => match next()? {
+                Flat::F32(value) => Val::F32(value),
+                value => return Err(anyhow!("expected an f32 but got {:?}", value)),
+            },
+            Type::F64 => match next()? {
+                Flat::F64(value) => Val::F64(value),
+                value => return Err(anyhow!("expected an f64 but got {:?}", value)),
+            },
+            Type::Char => Val::Char(to_char(next()?.i32()? as u32)?),
+            Type::Handle => Val::Handle(next()?.i32()? as u32),
+            Type::String | Type::List(_) => {
+                let ptr = next()?.i32()? as u32;
+                let len = next()?.i32()? as u32;
+                self.load_contents(memory, ptr, len)?
+            }
+            Type::Record(fields) => Val::Record(
+                fields
+                    .iter()
+                    .map(|field| field.lift_flat(memory, flat))
+                    .collect::<Result<_>>()?,
+            ),
+            Type::Variant(cases) => {
+                let index = next()?.i32()? as u32;
+                // every slot of the payload is given, even if the case uses fewer
+                let payload = variant_payload(cases)
+                    .into_iter()
+                    .map(|_| next())
+                    .collect::<Result<Vec<_>>>()?;
+                let (name, ty) = case(cases, index)?;
+                let payload = match ty {
+                    Some(ty) => {
+                        let values = ty
+                            .flat_types()
+                            .into_iter()
+                            .zip(payload)
+                            .map(|(ty, value)| value.narrow(ty))
+                            .collect::<Result<Vec<_>>>()?;
+                        Some(Box::new(ty.lift_flat(memory, &mut values.into_iter())?))
+                    }
+                    None => None,
+                };
+                Val::Variant(name.clone(), payload)
+            }
+            Type::Flags(names) => {
+                let words = (0..names.len().div_ceil(32))
+                    .map(|_| Ok(next()?.i32()? as u32))
+                    .collect::<Result<Vec<_>>>()?;
+                Val::Flags(flag_names(names, &words))
+            }
+        })
+    }
+
+    /// Lowers a value into flat values given to core wasm
+    fn lower_flat(&self, memory: &mut dyn Memory, value: &Val, flat: &mut Vec<Flat>) -> Result<()> {
+        match (self, value) {
+            (Type::Bool, Val::Bool(value)) => flat.push(Flat::I32(*value as i32)),
+            (Type::S8, Val::S8(value)) => flat.push(Flat::I32(*value as i32)),
+            (Type::U8, Val::U8(value)) => flat.push(Flat::I32(*value as i32)),
+            (Type::S16, Val::S16(value)) => flat.push(Flat::I32(*value as i32)),
+            (Type::U16, Val::U16(value)) => flat.push(Flat::I32(*value as i32)),
+            (Type::S32, Val::S32(value)) => flat.push(Flat::I32(*value)),
+            (Type::U32, Val::U32(value)) => flat.push(Flat::I32(*value as i32)),
+            (Type::S64, Val::S64(value)) => flat.push(Flat::I64(*value)),
+            (Type::U64, Val::U64(value)) => flat.push(Flat::I64(*value as i64)),
+            (Type::F32, Val::F32(value)) => flat.push(Flat::F32(*value)),
+            (Type::F64, Val::F64(value)) => flat.push(Flat::F64(*value)),
+            (Type::Char, Val::Char(value)) => flat.push(Flat::I32(*value as i32)),
+            (Type::Handle, Val::Handle(value)) => flat.push(Flat::I32(*value as i32)),
+            (Type::String | Type::List(_), _) => {
+                let (ptr, len) = self.store_contents(memory, value)?;
+                flat.extend([Flat::I32(ptr as i32), Flat::I32(len as i32)]);
+            }
+            (Type::Record(fields), Val::Record(values)) if fields.len() == values.len() => {
+                for (field, value) in fields.iter().zip(values) {
+                    field.lower_flat(memory, value, flat)?;
+                }
+            }
+            (Type::Variant(cases), Val::Variant(name, payload)) => {
+                let (index, ty) = case_by_name(cases, name)?;
+                flat.push(Flat::I32(index as i32));
+                let mut values = vec![];
+                if let (Some(ty), Some(payload)) = (ty, payload) {
+                    ty.lower_flat(memory, payload, &mut values)?;
+                } else if ty.is_some() || payload.is_some() {
+                    return Err(anyhow!("invalid payload of the case {}", name));
+                }
+                for (i, slot) in variant_payload(cases).into_iter().enumerate() {
+                    flat.push(match values.get(i) {
+                        Some(value) => value.widen(slot),
+                        None => Flat::zero(slot),
+                    });
+                }
+            }
+            (Type::Flags(names), Val::Flags(flags)) => {
+                for word in flag_words(names, flags)? {
+                    flat.push(Flat::I32(word as i32));
+                }
+            }
+            _ => return Err(mismatch(self, value)),
+        }
+        Ok(())
+    }
+
+    /// Loads a value from the memory
+    fn load(&self, memory: &mut dyn Memory, ptr: u32) -> Result<Val> {
+        if !ptr.is_multiple_of(self.align()) {
+            return Err(anyhow!("unaligned pointer {:#x}", ptr));
+        }
+        Ok(match self {
+            Type::Bool => Val::Bool(read::<1>(memory, ptr)?[0] != 0),
+            Type::S8 => Val::S8(read::<1>(memory, ptr)?[0] as i8),
+            Type::U8 => Val::U8(read::<1>(memory, ptr)?[0]),
+            Type::S16 => Val::S16(i16::from_le_bytes(read(memory, ptr)?)),
+            Type::U16 => Val::U16(u16::from_le_bytes(read(memory, ptr)?)),
+            Type::S32 => Val::S32(i32::from_le_bytes(read(memory, ptr)?)),
+            Type::U32 => Val::U32(u32::from_le_bytes(read(memory, ptr)?)),
+            Type::S64 => Val::S64(i64::from_le_bytes(read(memory, ptr)?)),
+            Type::U64 => Val::U64(u64::from_le_bytes(read(memory, ptr)?)),
+            Type::F32 => Val::F32(f32::from_le_bytes(read(memory, ptr)?)),
+            Type::F64 => Val::F64(f64::from_le_bytes(read(memory, ptr)?)),
+            Type::Char => Val::Char(to_char(u32::from_le_bytes(read(memory, ptr)?))?),
+            Type::Handle => Val::Handle(u32::from_le_bytes(read(memory, ptr)?)),
+            Type::String | Type::List(_) => {
+                let contents_ptr = u32::from_le_bytes(read(memory, ptr)?);
+                let len = u32::from_le_bytes(read(memory, ptr + 4)?);
+                self.load_contents(memory, contents_ptr, len)?
+            }
+            Type::Record(fields) => {
+                let mut offset = 0;
+                let mut values = vec![];
+                for field in fields {
+                    offset = align_to(offset, field.align());
+                    values.push(field.load(memory, ptr + offset)?);
+                    offset += field.size();
+                }
+                Val::Record(values)
+            }
+            Type::Variant(cases) => {
+                let index = match discriminant_size(cases.len()) {
+                    1 => read::<1>(memory, ptr)?[0] as u32,
+                    2 => u16::from_le_bytes(read(memory, ptr)?) as u32,
+                    _ => u32::from_le_bytes(read(memory, ptr)?),
+                };
+                let (name, ty) = case(cases, index)?;
+                let offset = align_to(discriminant_size(cases.len()), max_case_align(cases));
+                let payload = match ty {
+                    Some(ty) => Some(Box::new(ty.load(memory, ptr + offset)?)),
+                    None => None,
+                };
+                Val::Variant(name.clone(), payload)
+            }
+            Type::Flags(names) => {
+                let words = match self.size() {
+                    0 => vec![],
+                    1 => vec![read::<1>(memory, ptr)?[0] as u32],
+                    2 => vec![u16::from_le_bytes(read(memory, ptr)?) as u32],
+                    size => (0..size / 4)
+                        .map(|i| Ok(u32::from_le_bytes(read(memory, ptr + i * 4)?)))
+                        .collect::<Result<_>>()?,
+                };
+                Val::Flags(flag_names(names, &words))
+            }
+        })
+    }
+
+    /// Stores a value into the memory
+    fn store(&self, memory: &mut dyn Memory, value: &Val, ptr: u32) -> Result<()> {
+        if !ptr.is_multiple_of(self.align()) {
+            return Err(anyhow!("unaligned pointer {:#x}", ptr));
+        }
+        match (self, value) {
+            (Type::Bool, Val::Bool(value)) => write(memory, ptr, &[*value as u8]),
+            (Type::S8, Val::S8(value)) => write(memory, ptr, &value.to_le_bytes()),
+            (Type::U8, Val::U8(value)) => write(memory, ptr, &value.to_le_bytes()),
+            (Type::S16, Val::S16(value)) => write(memory, ptr, &value.to_le_bytes()),
+            (Type::U16, Val::U16(value)) => write(memory, ptr, &value.to_le_bytes()),
+            (Type::S32, Val::S32(value)) => write(memory, ptr, &value.to_le_bytes()),
+            (Type::U32, Val::U32(value)) => write(memory, ptr, &value.to_le_bytes()),
+            (Type::S64, Val::S64(value)) => write(memory, ptr, &value.to_le_bytes()),
+            (Type::U64, Val::U64(value)) => write(memory, ptr, &value.to_le_bytes()),
+            (Type::F32, Val::F32(value)) => write(memory, ptr, &value.to_le_bytes()),
+            (Type::F64, Val::F64(value)) => write(memory, ptr, &value.to_le_bytes()),
+            (Type::Char, Val::Char(value)) => write(memory, ptr, &(*value as u32).to_le_bytes()),
+            (Type::Handle, Val::Handle(value)) => write(memory, ptr, &value.to_le_bytes()),
+            (Type::String | Type::List(_), _) => {
+                let (contents_ptr, len) = self.store_contents(memory, value)?;
+                write(memory, ptr, &contents_ptr.to_le_bytes())?;
+                write(memory, ptr + 4, &len.to_le_bytes())
+            }
+            (Type::Record(fields), Val::Record(values)) if fields.len() == values.len() => {
+                let mut offset = 0;
+                for (field, value) in fields.iter().zip(values) {
+                    offset = align_to(offset, field.align());
+                    field.store(memory, value, ptr + offset)?;
+                    offset += field.size();
+                }
+                Ok(())
+            }
+            (Type::Variant(cases), Val::Variant(name, payload)) => {
+                let (index, ty) = case_by_name(cases, name)?;
+                match discriminant_size(cases.len()) {
+                    1 => write(memory, ptr, &[index as u8])?,
+                    2 => write(memory, ptr, &(index as u16).to_le_bytes())?,
+                    _ => write(memory, ptr, &index.to_le_bytes())?,
+                }
+                let offset = align_to(discriminant_size(cases.len()), max_case_align(cases));
+                match (ty, payload) {
+                    (Some(ty), Some(payload)) => ty.store(memory, payload, ptr + offset),
+                    (None, None) => Ok(()),
+                    _ => Err(anyhow!("invalid payload of the case {}", name)),
+                }
+            }
+            (Type::Flags(names), Val::Flags(flags)) => {
+                let words = flag_words(names, flags)?;
+                match self.size() {
+                    0 => Ok(()),
+                    1 => write(memory, ptr, &[words[0] as u8]),
+                    2 => write(memory, ptr, &(words[0] as u16).to_le_bytes()),
+                    _ => {
+                        for (i, word) in words.iter().enumerate() {
+                            write(memory, ptr + i as u32 * 4, &word.to_le_bytes())?;
+                        }
+                        Ok(())
+                    }
+                }
+            }
+            _ => Err(mismatch(self, value)),
+        }
+    }
+
+    /// Loads the contents of a string (UTF-8) or a list
+    fn load_contents(&self, memory: &mut dyn Memory, ptr: u32, len: u32) -> Result<Val> {
+        match self {
+            Type::String => {
+                let bytes = slice(memory, ptr, len)?.to_vec();
+                let string =
+                    String::from_utf8(bytes).map_err(|_| anyhow!("invalid UTF-8 string"))?;
+                Ok(Val::String(string))
+            }
+            Type::List(ty) if **ty == Type::U8 => Ok(Val::Bytes(slice(memory, ptr, len)?.to_vec())),
+            Type::List(ty) => {
+                let size = ty.size();
+                // the whole list must be in bounds, even if its elements are not read
+                slice(
+                    memory,
+                    ptr,
+                    len.checked_mul(size).ok_or_else(out_of_bounds)?,
+                )?;
+                if !ptr.is_multiple_of(ty.align()) {
+                    return Err(anyhow!("unaligned pointer {:#x}", ptr));
+                }
+                let values = (0..len)
+                    .map(|i| ty.load(memory, ptr + i * size))
+                    .collect::<Result<_>>()?;
+                Ok(Val::List(values))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Stores the contents of a string or a list into memory allocated by realloc, and returns the
+    /// address and the length
+    fn store_contents(&self, memory: &mut dyn Memory, value: &Val) -> Result<(u32, u32)> {
+        match (self, value) {
+            (Type::String, Val::String(string)) => {
+                let len = u32::try_from(string.len()).map_err(|_| out_of_bounds())?;
+                let ptr = memory.alloc(1, len)?;
+                write(memory, ptr, string.as_bytes())?;
+                Ok((ptr, len))
+            }
+            (Type::List(ty), Val::Bytes(bytes)) if **ty == Type::U8 => {
+                let len = u32::try_from(bytes.len()).map_err(|_| out_of_bounds())?;
+                let ptr = memory.alloc(1, len)?;
+                write(memory, ptr, bytes)?;
+                Ok((ptr, len))
+            }
+            (Type::List(ty), Val::List(values)) => {
+                let len = u32::try_from(values.len()).map_err(|_| out_of_bounds())?;
+                let size = len.checked_mul(ty.size()).ok_or_else(out_of_bounds)?;
+                // the list is allocated before the strings and lists of its elements
+                let ptr = memory.alloc(ty.align(), size)?;
+                for (i, value) in values.iter().enumerate() {
+                    ty.store(memory, value, ptr + i as u32 * ty.size())?;
+                }
+                Ok((ptr, len))
+            }
+            _ => Err(mismatch(self, value)),
+        }
+    }
+}
+
+/// Returns the types of the slots of the payloads of the cases, where each slot has the type
+/// which can hold the values of all cases
+fn variant_payload(cases: &[(String, Option<Type>)]) -> Vec<FlatType> {
+    let mut flat: Vec<FlatType> = vec![];
+    for ty in cases.iter().filter_map(|(_, ty)| ty.as_ref()) {
+        for (i, case_flat) in ty.flat_types().into_iter().enumerate() {
+            match flat.get_mut(i) {
+                Some(slot) => *slot = join(*slot, case_flat),
+                None => flat.push(case_flat),
+            }
+        }
+    }
+    flat
+}
+
+fn discriminant_size(cases: usize) -> u32 {
+    match cases {
+        0..=0x100 => 1,
+        0x101..=0x10000 => 2,
+        _ => 4,
+    }
+}
+
+fn max_case_align(cases: &[(String, Option<Type>)]) -> u32 {
+    cases
+        .iter()
+        .filter_map(|(_, ty)| ty.as_ref().map(Type::align))
+        .max()
+        .unwrap_or(1)
+}
+
+fn case(cases: &[(String, Option<Type>)], index: u32) -> Result<&(String, Option<Type>)> {
+    cases
+        .get(index as usize)
+        .ok_or_else(|| anyhow!("invalid discriminant {}", index))
+}
+
+fn case_by_name<'a>(
+    cases: &'a [(String, Option<Type>)],
+    name: &str,
+) -> Result<(u32, &'a Option<Type>)> {
+    cases
+        .iter()
+        .position(|(case, _)| case == name)
+        .map(|index| (index as u32, &cases[index].1))
+        .ok_or_else(|| anyhow!("unknown case {}", name))
+}
+
+fn flag_names(names: &[String], words: &[u32]) -> Vec<String> {
+    names
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| words[i / 32] & (1 << (i % 32)) != 0)
+        .map(|(_, name)| name.clone())
+        .collect()
+}
+
+fn flag_words(names: &[String], flags: &[String]) -> Result<Vec<u32>> {
+    let mut words = vec![0u32; names.len().div_ceil(32)];
+    for flag in flags {
+        let i = names
+            .iter()
+            .position(|name| name == flag)
+            .ok_or_else(|| anyhow!("unknown flag {}", flag))?;
+        words[i / 32] |= 1 << (i % 32);
+    }
+    Ok(words)
+}
+
+fn to_char(value: u32) -> Result<char> {
+    char::from_u32(value).ok_or_else(|| anyhow!("invalid char {:#x}", value))
+}
+
+fn mismatch(ty: &Type, value: &Val) -> anyhow::Error {
+    anyhow!("value {:?} does not match the type {:?}", value, ty)
+}
+
+fn out_of_bounds() -> anyhow::Error {
+    anyhow!("out of bounds memory access")
+}
+
+fn slice(memory: &mut dyn Memory, ptr: u32, len: u32) -> Result<&mut [u8]> {
+    let start = ptr as usize;
+    let end = start + len as usize;
+    memory.bytes().get_mut(start..end).ok_or_else(out_of_bounds)
+}
+
+fn read<const N: usize>(memory: &mut dyn Memory, ptr: u32) -> Result<[u8; N]> {
+    Ok(slice(memory, ptr, N as u32)?.try_into().unwrap())
+}
+
+fn write(memory: &mut dyn Memory, ptr: u32, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len()).map_err(|_| out_of_bounds())?;
+    slice(memory, ptr, len)?.copy_from_slice(bytes);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Memory whose realloc allocates from the end of the used bytes
+    pub struct TestMemory {
+        pub bytes: Vec<u8>,
+        pub allocations: Vec<(u32, u32)>,
+    }
+
+    impl TestMemory {
+        pub fn new() -> Self {
+            TestMemory {
+                bytes: vec![0; 64],
+                allocations: vec![],
+            }
+        }
+    }
+
+    impl Memory for TestMemory {
+        fn bytes(&mut self) -> &mut [u8] {
+            &mut self.bytes
+        }
+
+        fn alloc(&mut self, align: u32, size: u32) -> Result<u32> {
+            let ptr = align_to(self.bytes.len() as u32, align);
+            self.bytes.resize((ptr + size) as usize, 0);
+            self.allocations.push((align, size));
+            Ok(ptr)
+        }
+    }
+
+    fn result(ok: Option<Type>, err: Option<Type>) -> Type {
+        Type::Variant(vec![("ok".to_string(), ok), ("error".to_string(), err)])
+    }
+
+    fn option(ty: Type) -> Type {
+        Type::Variant(vec![
+            ("none".to_string(), None),
+            ("some".to_string(), Some(ty)),
+        ])
+    }
+
+    #[test]
+    fn joins_the_payloads_of_variant_cases() {
+        let ty = Type::Variant(vec![
+            ("a".to_string(), Some(Type::F32)),
+            (
+                "b".to_string(),
+                Some(Type::Record(vec![Type::U32, Type::U64])),
+            ),
+            ("c".to_string(), None),
+        ]);
+        assert_eq!(
+            ty.flat_types(),
+            [FlatType::I32, FlatType::I32, FlatType::I64]
+        );
+
+        let mut memory = TestMemory::new();
+        let value = Val::variant("a", Some(Val::F32(1.5)));
+        let mut flat = vec![];
+        ty.lower_flat(&mut memory, &value, &mut flat).unwrap();
+        assert_eq!(
+            flat,
+            [
+                Flat::I32(0),
+                Flat::I32(1.5f32.to_bits() as i32),
+                Flat::I64(0)
+            ]
+        );
+        let lifted = ty.lift_flat(&mut memory, &mut flat.into_iter()).unwrap();
+        assert_eq!(lifted, value);
+
+        let value = Val::variant(
+            "b",
+            Some(Val::Record(vec![Val::U32(u32::MAX), Val::U64(7)])),
+        );
+        let mut flat = vec![];
+        ty.lower_flat(&mut memory, &value, &mut flat).unwrap();
+        assert_eq!(flat, [Flat::I32(1), Flat::I32(-1), Flat::I64(7)]);
+        let lifted = ty.lift_flat(&mut memory, &mut flat.into_iter()).unwrap();
+        assert_eq!(lifted, value);
+    }
+
+    #[test]
+    fn lays_out_records_and_variants() {
+        // descriptor-stat of wasi:filesystem/types
+        let datetime = Type::Record(vec![Type::U64, Type::U32]);
+        let stat = Type::Record(vec![
+            Type::Variant((0..8).map(|i| (i.to_string(), None)).collect()),
+            Type::U64,
+            Type::U64,
+            option(datetime.clone()),
+            option(datetime.clone()),
+            option(datetime),
+        ]);
+        assert_eq!((stat.size(), stat.align()), (96, 8));
+        assert_eq!(
+            (result(Some(stat.clone()), Some(Type::U8)).size(), 8),
+            (104, 8)
+        );
+        let flags = Type::Flags((0..6).map(|i| i.to_string()).collect());
+        assert_eq!((flags.size(), flags.align()), (1, 1));
+        let flags = Type::Flags((0..40).map(|i| i.to_string()).collect());
+        assert_eq!((flags.size(), flags.align()), (8, 4));
+        assert_eq!(flags.flat_types(), [FlatType::I32, FlatType::I32]);
+    }
+
+    #[test]
+    fn stores_results_at_the_pointer_and_allocates_lists_first() {
+        // get-environment: func() -> list<tuple<string, string>>
+        let ty = FuncType {
+            params: vec![],
+            results: vec![Type::List(Box::new(Type::Record(vec![
+                Type::String,
+                Type::String,
+            ])))],
+        };
+        assert_eq!(ty.lowered_params(), [FlatType::I32]);
+        assert_eq!(ty.lifted_results(), [FlatType::I32]);
+
+        let mut memory = TestMemory::new();
+        let environment = Val::List(vec![Val::Record(vec![
+            Val::String("HOME".to_string()),
+            Val::String("/".to_string()),
+        ])]);
+        let flat = ty
+            .lower_results(
+                &mut memory,
+                &[Flat::I32(8)],
+                std::slice::from_ref(&environment),
+            )
+            .unwrap();
+        assert!(flat.is_empty());
+        assert_eq!(memory.allocations, [(4, 16), (1, 4), (1, 1)]);
+        assert_eq!(
+            ty.lift_results(&mut memory, &[Flat::I32(8)]).unwrap(),
+            [environment]
+        );
+    }
+
+    #[test]
+    fn lifts_arguments_from_flat_values_and_the_memory() {
+        // write: func(contents: list<u8>) -> result<_, stream-error>, as a method
+        let stream_error = Type::Variant(vec![
+            ("last-operation-failed".to_string(), Some(Type::Handle)),
+            ("closed".to_string(), None),
+        ]);
+        let ty = FuncType {
+            params: vec![Type::Handle, Type::List(Box::new(Type::U8))],
+            results: vec![result(None, Some(stream_error))],
+        };
+        assert_eq!(
+            ty.lowered_params(),
+            [FlatType::I32, FlatType::I32, FlatType::I32, FlatType::I32]
+        );
+        let mut memory = TestMemory::new();
+        memory.bytes[16..21].copy_from_slice(b"hello");
+        let args = [Flat::I32(3), Flat::I32(16), Flat::I32(5), Flat::I32(32)];
+        assert_eq!(
+            ty.lift_args(&mut memory, &args).unwrap(),
+            [Val::Handle(3), Val::Bytes(b"hello".to_vec())]
+        );
+        ty.lower_results(
+            &mut memory,
+            &args,
+            &[Val::error(Some(Val::variant("closed", None)))],
+        )
+        .unwrap();
+        assert_eq!(memory.bytes[32..36], [1, 0, 0, 0]);
+        assert_eq!(memory.bytes[36], 1);
+
+        // more than 16 flat parameters are passed as a pointer to a tuple
+        let ty = FuncType {
+            params: vec![Type::U64; 17],
+            results: vec![],
+        };
+        assert_eq!(ty.lowered_params(), [FlatType::I32]);
+        let mut memory = TestMemory::new();
+        memory.bytes.resize(8 + 17 * 8, 0);
+        memory.bytes[8 + 16 * 8] = 42;
+        let args = ty.lift_args(&mut memory, &[Flat::I32(8)]).unwrap();
+        assert_eq!(args.len(), 17);
+        assert_eq!(args[16], Val::U64(42));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut memory = TestMemory::new();
+        let ty = Type::Variant(vec![("a".to_string(), None)]);
+        assert!(ty
+            .lift_flat(&mut memory, &mut [Flat::I32(1)].into_iter())
+            .is_err());
+        assert!(Type::Char
+            .lift_flat(&mut memory, &mut [Flat::I32(0xd800)].into_iter())
+            .is_err());
+        memory.bytes[0] = 0xff;
+        assert!(Type::String
+            .lift_flat(&mut memory, &mut [Flat::I32(0), Flat::I32(1)].into_iter())
+            .is_err());
+        assert!(Type::String
+            .lift_flat(&mut memory, &mut [Flat::I32(60), Flat::I32(8)].into_iter())
+            .is_err());
+        assert!(Type::U32.load(&mut memory, 2).is_err());
+    }
+}
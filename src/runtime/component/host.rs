@@ -0,0 +1,1633 @@
+// Interfaces of the wasi:cli/command world, implemented by the preview1 functions of the WASI context
+//
+// Arguments and results of the preview1 functions are laid out in a scratch memory, so the context
+// (its files, clocks and random source) is the same as that of core modules, and the policy and
+// tracing apply to each preview1 function called by the interfaces.
+
+use std::collections::VecDeque;
+use tokio::runtime::Runtime as TokioRuntime;
+use wasi_common::snapshots::preview_1::wasi_snapshot_preview1 as preview1;
+use wasi_common::WasiCtx;
+use wiggle::GuestMemory;
+
+use super::abi::Val;
+use crate::runtime::{policy, trace, wasi};
+
+/// Maximum number of bytes read by a call, beyond which the call reads less bytes than requested
+const MAX_READ_SIZE: u64 = 1024 * 1024;
+/// Number of bytes which can be written by a call, returned by `check-write`
+const WRITE_BUDGET: u64 = 1024 * 1024;
+/// Size of the buffer of `fd_readdir`, which is enlarged for long names
+const READDIR_BUFFER_SIZE: u32 = 4096;
+/// Size of the buffer of `path_readlink`
+const READLINK_BUFFER_SIZE: u32 = 4096;
+
+const ERRNO_BADF: i32 = 8;
+const CLOCK_REALTIME: i32 = 0;
+const CLOCK_MONOTONIC: i32 = 1;
+const RIGHTS_FD_READ: u64 = 1 << 1;
+const RIGHTS_FD_WRITE: u64 = 1 << 6;
+const RIGHTS_PATH_CREATE_FILE: u64 = 1 << 10;
+const RIGHTS_ALL: u64 = (1 << 29) - 1;
+const FDFLAGS_DSYNC: i32 = 1 << 1;
+const FDFLAGS_RSYNC: i32 = 1 << 3;
+const FDFLAGS_SYNC: i32 = 1 << 4;
+const FILETYPE_DIRECTORY: u8 = 3;
+
+/// Cases of `error-code` of wasi:filesystem by the errno of preview1
+const ERROR_CODES: &[(i32, &str)] = &[
+    (2, "access"),
+    (6, "would-block"),
+    (7, "already"),
+    (8, "bad-descriptor"),
+    (10, "busy"),
+    (16, "deadlock"),
+    (19, "quota"),
+    (20, "exist"),
+    (22, "file-too-large"),
+    (25, "illegal-byte-sequence"),
+    (26, "in-progress"),
+    (27, "interrupted"),
+    (28, "invalid"),
+    (29, "io"),
+    (31, "is-directory"),
+    (32, "loop"),
+    (34, "too-many-links"),
+    (35, "message-size"),
+    (37, "name-too-long"),
+    (43, "no-device"),
+    (44, "no-entry"),
+    (46, "no-lock"),
+    (48, "insufficient-memory"),
+    (51, "insufficient-space"),
+    (52, "unsupported"),
+    (54, "not-directory"),
+    (55, "not-empty"),
+    (56, "not-recoverable"),
+    (58, "unsupported"),
+    (59, "no-tty"),
+    (60, "no-such-device"),
+    (61, "overflow"),
+    (63, "not-permitted"),
+    (64, "pipe"),
+    (69, "read-only"),
+    (70, "invalid-seek"),
+    (74, "text-file-busy"),
+    (75, "cross-device"),
+    (76, "not-permitted"),
+];
+
+/// Cases of `descriptor-type` by the filetype of preview1
+const DESCRIPTOR_TYPES: [&str; 8] = [
+    "unknown",
+    "block-device",
+    "character-device",
+    "directory",
+    "regular-file",
+    "socket",
+    "socket",
+    "symbolic-link",
+];
+
+/// Cases of `advice`, which are numbered like those of preview1
+const ADVICES: [&str; 6] = [
+    "normal",
+    "sequential",
+    "random",
+    "will-need",
+    "dont-need",
+    "no-reuse",
+];
+
+/// Reason why the component stops
+#[derive(Debug, PartialEq)]
+pub(super) enum HostError {
+    /// `exit` of wasi:cli/exit
+    Exit(i32),
+    Trap(String),
+}
+
+/// Failure of a host function, which is an error code returned to the component unless it is a
+/// `HostError`
+enum Failure {
+    Errno(i32),
+    Host(HostError),
+}
+
+impl From<HostError> for Failure {
+    fn from(error: HostError) -> Self {
+        Failure::Host(error)
+    }
+}
+
+fn trap(message: impl Into<String>) -> Failure {
+    Failure::Host(HostError::Trap(message.into()))
+}
+
+/// Position where a stream reads or writes
+#[derive(Clone, Copy, Debug)]
+enum Position {
+    /// Position of the file descriptor, which is used by stdio
+    Current,
+    /// Offset in the file, which is advanced by each read or write
+    At(u64),
+    /// End of the file
+    Append,
+}
+
+#[derive(Debug)]
+enum Resource {
+    InputStream {
+        fd: u32,
+        position: Position,
+    },
+    OutputStream {
+        fd: u32,
+        position: Position,
+    },
+    /// Descriptor, whose file descriptor is closed when it is dropped unless it is preopened
+    Descriptor {
+        fd: u32,
+        preopened: bool,
+    },
+    DirectoryEntryStream {
+        fd: u32,
+        cookie: u64,
+        entries: VecDeque<Val>,
+        done: bool,
+    },
+    /// Pollable which is ready at the instant of the monotonic clock, or always
+    Pollable {
+        deadline: Option<u64>,
+    },
+    /// Error of a stream, with the errno of preview1
+    Error {
+        errno: i32,
+    },
+}
+
+/// Resources of the component, whose handles are indexes from 1
+#[derive(Default)]
+pub(super) struct Host {
+    resources: Vec<Option<Resource>>,
+}
+
+/// Memory given to the preview1 functions, which holds their arguments and results
+struct Scratch(Vec<u8>);
+
+impl Scratch {
+    fn new() -> Self {
+        // address 0 is unused, so that no argument is a null pointer
+        Scratch(vec![0; 8])
+    }
+
+    /// Reserves zeroed bytes aligned to 8, and returns the address
+    fn alloc(&mut self, size: u64) -> i32 {
+        let ptr = self.0.len().next_multiple_of(8);
+        self.0.resize(ptr + size as usize, 0);
+        ptr as i32
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> i32 {
+        let ptr = self.alloc(bytes.len() as u64);
+        self.0[ptr as usize..][..bytes.len()].copy_from_slice(bytes);
+        ptr
+    }
+
+    /// Puts an iovec of the bytes at the address, and returns the address of the iovec
+    fn iovec(&mut self, ptr: i32, len: u64) -> i32 {
+        let iovec = self.alloc(8);
+        self.0[iovec as usize..][..4].copy_from_slice(&ptr.to_le_bytes());
+        self.0[iovec as usize + 4..][..4].copy_from_slice(&(len as u32).to_le_bytes());
+        iovec
+    }
+
+    fn bytes(&self, ptr: i32, len: u64) -> &[u8] {
+        &self.0[ptr as usize..][..len as usize]
+    }
+
+    fn u8(&self, ptr: i32) -> u8 {
+        self.0[ptr as usize]
+    }
+
+    fn u16(&self, ptr: i32) -> u16 {
+        u16::from_le_bytes(self.bytes(ptr, 2).try_into().unwrap())
+    }
+
+    fn u32(&self, ptr: i32) -> u32 {
+        u32::from_le_bytes(self.bytes(ptr, 4).try_into().unwrap())
+    }
+
+    fn u64(&self, ptr: i32) -> u64 {
+        u64::from_le_bytes(self.bytes(ptr, 8).try_into().unwrap())
+    }
+
+    /// Reads the NUL-terminated strings pointed by the array of pointers
+    fn strings(&self, ptrs: i32, count: u32) -> Vec<String> {
+        (0..count as i32)
+            .map(|i| {
+                let start = self.u32(ptrs + i * 4) as usize;
+                let len = self.0[start..].iter().position(|&b| b == 0).unwrap_or(0);
+                String::from_utf8_lossy(&self.0[start..start + len]).into_owned()
+            })
+            .collect()
+    }
+}
+
+/// Calls a preview1 function of the WASI context with the policy and tracing applied, like the
+/// functions imported by core modules
+fn call_preview1(
+    name: &'static str,
+    values: &[i64],
+    scratch: &mut Scratch,
+    call: impl FnOnce(&mut WasiCtx, &mut GuestMemory<'_>) -> anyhow::Result<i32>,
+) -> Result<(), Failure> {
+    let trace = trace::is_enabled().then(|| trace::Call::new(name, values, false, &scratch.0));
+    match policy::decide(name) {
+        policy::Decision::Allow => {}
+        policy::Decision::Return(errno) => {
+            if let Some(trace) = trace {
+                trace.finish(errno);
+            }
+            return Err(Failure::Errno(errno));
+        }
+        policy::Decision::Trap => {
+            let message = format!("WASI function {} is denied by the policy", name);
+            if let Some(trace) = trace {
+                trace.finish_without_return(&message);
+            }
+            return Err(trap(message));
+        }
+    }
+    let mut wasi_ctx = wasi::get_wasi_ctx_mut().lock().unwrap();
+    let result = call(&mut wasi_ctx, &mut GuestMemory::Unshared(&mut scratch.0));
+    drop(wasi_ctx);
+    match result {
+        Ok(errno) => {
+            if let Some(trace) = trace {
+                trace.finish(errno);
+            }
+            if errno == 0 {
+                Ok(())
+            } else {
+                Err(Failure::Errno(errno))
+            }
+        }
+        Err(e) => {
+            if let Some(trace) = trace {
+                trace.finish_without_return(&e.to_string());
+            }
+            Err(trap(e.to_string()))
+        }
+    }
+}
+
+/// Calls a preview1 function, whose arguments are i32 or i64 values (or pointers into the scratch
+/// memory)
+macro_rules! preview1 {
+    ($scratch:expr, $name:ident, $( $arg:expr ),*) => {{
+        call_preview1(stringify!($name), &[$( $arg as i64 ),*], $scratch, |ctx, memory| {
+            TokioRuntime::new()
+                .unwrap()
+                .block_on(preview1::$name(ctx, memory, $( $arg ),*))
+        })
+    }};
+}
+
+/// Arguments of a host function, which are taken in order
+struct Args(std::vec::IntoIter<Val>);
+
+impl Args {
+    fn next(&mut self) -> Result<Val, Failure> {
+        self.0.next().ok_or_else(|| trap("missing argument"))
+    }
+
+    fn handle(&mut self) -> Result<u32, Failure> {
+        match self.next()? {
+            Val::Handle(handle) => Ok(handle),
+            value => Err(trap(format!("expected a handle but got {:?}", value))),
+        }
+    }
+
+    fn u64(&mut self) -> Result<u64, Failure> {
+        match self.next()? {
+            Val::U64(value) => Ok(value),
+            value => Err(trap(format!("expected a u64 but got {:?}", value))),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Failure> {
+        match self.next()? {
+            Val::String(value) => Ok(value),
+            value => Err(trap(format!("expected a string but got {:?}", value))),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, Failure> {
+        match self.next()? {
+            Val::Bytes(value) => Ok(value),
+            value => Err(trap(format!("expected bytes but got {:?}", value))),
+        }
+    }
+
+    fn flags(&mut self) -> Result<Vec<String>, Failure> {
+        match self.next()? {
+            Val::Flags(value) => Ok(value),
+            value => Err(trap(format!("expected flags but got {:?}", value))),
+        }
+    }
+
+    fn variant(&mut self) -> Result<(String, Option<Val>), Failure> {
+        match self.next()? {
+            Val::Variant(case, payload) => Ok((case, payload.map(|payload| *payload))),
+            value => Err(trap(format!("expected a variant but got {:?}", value))),
+        }
+    }
+
+    fn handles(&mut self) -> Result<Vec<u32>, Failure> {
+        match self.next()? {
+            Val::List(values) => values
+                .into_iter()
+                .map(|value| match value {
+                    Val::Handle(handle) => Ok(handle),
+                    value => Err(trap(format!("expected a handle but got {:?}", value))),
+                })
+                .collect(),
+            value => Err(trap(format!("expected a list but got {:?}", value))),
+        }
+    }
+}
+
+/// Returns the `error-code` of wasi:filesystem of the errno
+fn error_code(errno: i32) -> Val {
+    let case = ERROR_CODES
+        .iter()
+        .find(|(code, _)| *code == errno)
+        .map_or("io", |(_, case)| case);
+    Val::variant(case, None)
+}
+
+/// Returns `result<T, error-code>` of wasi:filesystem
+fn fs_result(result: Result<Option<Val>, Failure>) -> Result<Vec<Val>, HostError> {
+    match result {
+        Ok(value) => Ok(vec![Val::ok(value)]),
+        Err(Failure::Errno(errno)) => Ok(vec![Val::error(Some(error_code(errno)))]),
+        Err(Failure::Host(error)) => Err(error),
+    }
+}
+
+/// Returns the value of a function which returns no error code
+fn value(result: Result<Option<Val>, Failure>) -> Result<Vec<Val>, HostError> {
+    match result {
+        Ok(value) => Ok(value.into_iter().collect()),
+        Err(Failure::Errno(errno)) => Err(HostError::Trap(format!(
+            "WASI function failed with errno {}",
+            errno
+        ))),
+        Err(Failure::Host(error)) => Err(error),
+    }
+}
+
+fn datetime(nanos: u64) -> Val {
+    Val::Record(vec![
+        Val::U64(nanos / 1_000_000_000),
+        Val::U32((nanos % 1_000_000_000) as u32),
+    ])
+}
+
+fn path_flags(flags: &[String]) -> i32 {
+    flags.iter().any(|flag| flag == "symlink-follow") as i32
+}
+
+/// Converts a `new-timestamp` into the time and the fstflags of the access or modification time
+fn new_timestamp(
+    (case, payload): (String, Option<Val>),
+    set: i32,
+    now: i32,
+) -> Result<(i64, i32), Failure> {
+    match (case.as_str(), payload) {
+        ("no-change", None) => Ok((0, 0)),
+        ("now", None) => Ok((0, now)),
+        ("timestamp", Some(Val::Record(fields))) => match fields.as_slice() {
+            [Val::U64(seconds), Val::U32(nanoseconds)] => Ok((
+                seconds
+                    .saturating_mul(1_000_000_000)
+                    .saturating_add(*nanoseconds as u64) as i64,
+                set,
+            )),
+            _ => Err(trap("invalid datetime")),
+        },
+        _ => Err(trap(format!("invalid new-timestamp {}", case))),
+    }
+}
+
+/// Converts a filestat of preview1 into `descriptor-stat`
+fn descriptor_stat(scratch: &Scratch, ptr: i32) -> Val {
+    let timestamp = |offset| match scratch.u64(ptr + offset) {
+        0 => Val::none(),
+        nanos => Val::some(datetime(nanos)),
+    };
+    Val::Record(vec![
+        descriptor_type(scratch.u8(ptr + 16)),
+        Val::U64(scratch.u64(ptr + 24)),
+        Val::U64(scratch.u64(ptr + 32)),
+        timestamp(40),
+        timestamp(48),
+        timestamp(56),
+    ])
+}
+
+fn descriptor_type(filetype: u8) -> Val {
+    let case = DESCRIPTOR_TYPES
+        .get(filetype as usize)
+        .copied()
+        .unwrap_or("unknown");
+    Val::variant(case, None)
+}
+
+/// Returns `metadata-hash-value` of a filestat, made of its inode and device
+fn metadata_hash(scratch: &Scratch, ptr: i32) -> Val {
+    Val::Record(vec![
+        Val::U64(scratch.u64(ptr + 8)),
+        Val::U64(scratch.u64(ptr)),
+    ])
+}
+
+impl Host {
+    /// Calls the function of the interface (without its version), which returns the results
+    pub fn call(
+        &mut self,
+        interface: &str,
+        name: &str,
+        args: Vec<Val>,
+    ) -> Result<Vec<Val>, HostError> {
+        let interface = interface.split('@').next().unwrap_or(interface);
+        let args = &mut Args(args.into_iter());
+        match (interface, name) {
+            ("wasi:cli/environment", "get-environment") => value(self.get_environment()),
+            ("wasi:cli/environment", "get-arguments") => value(self.get_arguments()),
+            ("wasi:cli/environment", "initial-cwd") => Ok(vec![Val::none()]),
+            ("wasi:cli/exit", "exit") => {
+                let (case, _) = args.variant().map_err(host_error)?;
+                Err(exit(if case == "ok" { 0 } else { 1 }))
+            }
+            ("wasi:cli/exit", "exit-with-code") => match args.next().map_err(host_error)? {
+                Val::U8(code) => Err(exit(code as i32)),
+                value => Err(HostError::Trap(format!("invalid exit code {:?}", value))),
+            },
+            ("wasi:cli/stdin", "get-stdin") => Ok(vec![self.stream(true, 0, Position::Current)]),
+            ("wasi:cli/stdout", "get-stdout") => Ok(vec![self.stream(false, 1, Position::Current)]),
+            ("wasi:cli/stderr", "get-stderr") => Ok(vec![self.stream(false, 2, Position::Current)]),
+            // stdio is never a terminal, since there are no terminal interfaces to use it
+            ("wasi:cli/terminal-stdin", "get-terminal-stdin")
+            | ("wasi:cli/terminal-stdout", "get-terminal-stdout")
+            | ("wasi:cli/terminal-stderr", "get-terminal-stderr") => Ok(vec![Val::none()]),
+            ("wasi:io/error", "[method]error.to-debug-string") => {
+                value(self.error_debug_string(args))
+            }
+            ("wasi:io/poll", "[method]pollable.ready") => value(self.pollable_ready(args)),
+            ("wasi:io/poll", "[method]pollable.block") => value(self.pollable_block(args)),
+            ("wasi:io/poll", "poll") => value(self.poll(args)),
+            ("wasi:io/streams", "[method]input-stream.read")
+            | ("wasi:io/streams", "[method]input-stream.blocking-read") => {
+                self.stream_result(|host| host.read(args, false))
+            }
+            ("wasi:io/streams", "[method]input-stream.skip")
+            | ("wasi:io/streams", "[method]input-stream.blocking-skip") => {
+                self.stream_result(|host| host.read(args, true))
+            }
+            ("wasi:io/streams", "[method]input-stream.subscribe")
+            | ("wasi:io/streams", "[method]output-stream.subscribe") => {
+                value(self.subscribe_stream(args))
+            }
+            ("wasi:io/streams", "[method]output-stream.check-write") => {
+                self.stream_result(|host| {
+                    host.output_stream(args.handle()?)?;
+                    Ok(Some(Val::U64(WRITE_BUDGET)))
+                })
+            }
+            ("wasi:io/streams", "[method]output-stream.write")
+            | ("wasi:io/streams", "[method]output-stream.blocking-write-and-flush") => self
+                .stream_result(|host| {
+                    let handle = args.handle()?;
+                    let contents = args.bytes()?;
+                    host.write(handle, &contents)
+                }),
+            ("wasi:io/streams", "[method]output-stream.write-zeroes")
+            | ("wasi:io/streams", "[method]output-stream.blocking-write-zeroes-and-flush") => self
+                .stream_result(|host| {
+                    let handle = args.handle()?;
+                    let len = args.u64()?.min(WRITE_BUDGET);
+                    host.write(handle, &vec![0; len as usize])
+                }),
+            // writes are not buffered
+            ("wasi:io/streams", "[method]output-stream.flush")
+            | ("wasi:io/streams", "[method]output-stream.blocking-flush") => {
+                self.stream_result(|host| {
+                    host.output_stream(args.handle()?)?;
+                    Ok(None)
+                })
+            }
+            ("wasi:clocks/monotonic-clock", "now") => value(
+                self.clock(CLOCK_MONOTONIC, false)
+                    .map(|now| Some(Val::U64(now))),
+            ),
+            ("wasi:clocks/monotonic-clock", "resolution") => value(
+                self.clock(CLOCK_MONOTONIC, true)
+                    .map(|res| Some(Val::U64(res))),
+            ),
+            ("wasi:clocks/monotonic-clock", "subscribe-instant") => {
+                value(args.u64().map(|instant| Some(self.pollable(Some(instant)))))
+            }
+            ("wasi:clocks/monotonic-clock", "subscribe-duration") => value((|| {
+                let duration = args.u64()?;
+                let now = self.clock(CLOCK_MONOTONIC, false)?;
+                Ok(Some(self.pollable(Some(now.saturating_add(duration)))))
+            })()),
+            ("wasi:clocks/wall-clock", "now") => value(
+                self.clock(CLOCK_REALTIME, false)
+                    .map(|now| Some(datetime(now))),
+            ),
+            ("wasi:clocks/wall-clock", "resolution") => value(
+                self.clock(CLOCK_REALTIME, true)
+                    .map(|res| Some(datetime(res))),
+            ),
+            ("wasi:random/random", "get-random-bytes")
+            | ("wasi:random/insecure", "get-insecure-random-bytes") => value((|| {
+                let len = args.u64()?.min(MAX_READ_SIZE);
+                Ok(Some(Val::Bytes(self.random(len)?)))
+            })()),
+            ("wasi:random/random", "get-random-u64")
+            | ("wasi:random/insecure", "get-insecure-random-u64") => value((|| {
+                let bytes = self.random(8)?;
+                Ok(Some(Val::U64(u64::from_le_bytes(
+                    bytes.try_into().unwrap(),
+                ))))
+            })()),
+            ("wasi:random/insecure-seed", "insecure-seed") => value((|| {
+                let bytes = self.random(16)?;
+                Ok(Some(Val::Record(vec![
+                    Val::U64(u64::from_le_bytes(bytes[..8].try_into().unwrap())),
+                    Val::U64(u64::from_le_bytes(bytes[8..].try_into().unwrap())),
+                ])))
+            })()),
+            ("wasi:filesystem/preopens", "get-directories") => value(self.get_directories()),
+            ("wasi:filesystem/types", "filesystem-error-code") => value((|| {
+                let handle = args.handle()?;
+                match self.resource(handle)? {
+                    Resource::Error { errno } => Ok(Some(Val::some(error_code(*errno)))),
+                    _ => Err(trap(format!("handle {} is not an error", handle))),
+                }
+            })()),
+            ("wasi:filesystem/types", "[method]directory-entry-stream.read-directory-entry") => {
+                fs_result(self.read_directory_entry(args))
+            }
+            ("wasi:filesystem/types", method) if method.starts_with("[method]descriptor.") => {
+                self.descriptor_method(&method["[method]descriptor.".len()..], args)
+            }
+            _ => Err(HostError::Trap(format!(
+                "{}#{} is not supported",
+                interface, name
+            ))),
+        }
+    }
+
+    /// Drops the resource of the handle, which is given by `resource.drop`
+    pub fn drop(&mut self, handle: u32) -> Result<(), HostError> {
+        let resource = self
+            .resources
+            .get_mut((handle as usize).wrapping_sub(1))
+            .and_then(Option::take)
+            .ok_or_else(|| HostError::Trap(format!("unknown handle {}", handle)))?;
+        if let Resource::Descriptor {
+            fd,
+            preopened: false,
+        } = resource
+        {
+            // the error of close cannot be returned to the component
+            let _ = preview1!(&mut Scratch::new(), fd_close, fd as i32);
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, resource: Resource) -> Val {
+        let index = match self.resources.iter().position(Option::is_none) {
+            Some(index) => {
+                self.resources[index] = Some(resource);
+                index
+            }
+            None => {
+                self.resources.push(Some(resource));
+                self.resources.len() - 1
+            }
+        };
+        Val::Handle(index as u32 + 1)
+    }
+
+    fn resource(&mut self, handle: u32) -> Result<&mut Resource, Failure> {
+        self.resources
+            .get_mut((handle as usize).wrapping_sub(1))
+            .and_then(Option::as_mut)
+            .ok_or_else(|| trap(format!("unknown handle {}", handle)))
+    }
+
+    fn descriptor(&mut self, handle: u32) -> Result<u32, Failure> {
+        match self.resource(handle)? {
+            Resource::Descriptor { fd, .. } => Ok(*fd),
+            _ => Err(trap(format!("handle {} is not a descriptor", handle))),
+        }
+    }
+
+    fn output_stream(&mut self, handle: u32) -> Result<(u32, Position), Failure> {
+        match self.resource(handle)? {
+            Resource::OutputStream { fd, position } => Ok((*fd, *position)),
+            _ => Err(trap(format!("handle {} is not an output stream", handle))),
+        }
+    }
+
+    fn stream(&mut self, input: bool, fd: u32, position: Position) -> Val {
+        self.push(if input {
+            Resource::InputStream { fd, position }
+        } else {
+            Resource::OutputStream { fd, position }
+        })
+    }
+
+    fn pollable(&mut self, deadline: Option<u64>) -> Val {
+        self.push(Resource::Pollable { deadline })
+    }
+
+    /// Returns `result<T, stream-error>` of wasi:io/streams
+    fn stream_result(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<Option<Val>, Failure>,
+    ) -> Result<Vec<Val>, HostError> {
+        match f(self) {
+            Ok(value) => Ok(vec![Val::ok(value)]),
+            // end of file
+            Err(Failure::Errno(0)) => Ok(vec![Val::error(Some(Val::variant("closed", None)))]),
+            Err(Failure::Errno(errno)) => {
+                let error = self.push(Resource::Error { errno });
+                Ok(vec![Val::error(Some(Val::variant(
+                    "last-operation-failed",
+                    Some(error),
+                )))])
+            }
+            Err(Failure::Host(error)) => Err(error),
+        }
+    }
+
+    fn get_environment(&mut self) -> Result<Option<Val>, Failure> {
+        let scratch = &mut Scratch::new();
+        let (count, size) = (scratch.alloc(4), scratch.alloc(4));
+        preview1!(scratch, environ_sizes_get, count, size)?;
+        let (count, size) = (scratch.u32(count), scratch.u32(size));
+        let (ptrs, buf) = (scratch.alloc(count as u64 * 4), scratch.alloc(size as u64));
+        preview1!(scratch, environ_get, ptrs, buf)?;
+        let environment = scratch
+            .strings(ptrs, count)
+            .into_iter()
+            .map(|var| {
+                let (key, value) = var.split_once('=').unwrap_or((&var, ""));
+                Val::Record(vec![
+                    Val::String(key.to_string()),
+                    Val::String(value.to_string()),
+                ])
+            })
+            .collect();
+        Ok(Some(Val::List(environment)))
+    }
+
+    fn get_arguments(&mut self) -> Result<Option<Val>, Failure> {
+        let scratch = &mut Scratch::new();
+        let (count, size) = (scratch.alloc(4), scratch.alloc(4));
+        preview1!(scratch, args_sizes_get, count, size)?;
+        let (count, size) = (scratch.u32(count), scratch.u32(size));
+        let (ptrs, buf) = (scratch.alloc(count as u64 * 4), scratch.alloc(size as u64));
+        preview1!(scratch, args_get, ptrs, buf)?;
+        let arguments = scratch.strings(ptrs, count).into_iter().map(Val::String);
+        Ok(Some(Val::List(arguments.collect())))
+    }
+
+    fn error_debug_string(&mut self, args: &mut Args) -> Result<Option<Val>, Failure> {
+        let handle = args.handle()?;
+        match self.resource(handle)? {
+            Resource::Error { errno } => {
+                let Val::Variant(case, _) = error_code(*errno) else {
+                    unreachable!()
+                };
+                Ok(Some(Val::String(format!("{} (errno {})", case, errno))))
+            }
+            _ => Err(trap(format!("handle {} is not an error", handle))),
+        }
+    }
+
+    fn deadline(&mut self, handle: u32) -> Result<Option<u64>, Failure> {
+        match self.resource(handle)? {
+            Resource::Pollable { deadline } => Ok(*deadline),
+            _ => Err(trap(format!("handle {} is not a pollable", handle))),
+        }
+    }
+
+    fn pollable_ready(&mut self, args: &mut Args) -> Result<Option<Val>, Failure> {
+        let ready = match self.deadline(args.handle()?)? {
+            Some(deadline) => self.clock(CLOCK_MONOTONIC, false)? >= deadline,
+            None => true,
+        };
+        Ok(Some(Val::Bool(ready)))
+    }
+
+    fn pollable_block(&mut self, args: &mut Args) -> Result<Option<Val>, Failure> {
+        if let Some(deadline) = self.deadline(args.handle()?)? {
+            self.sleep_until(deadline)?;
+        }
+        Ok(None)
+    }
+
+    /// Blocks until any of the pollables is ready, and returns the indexes of the ready ones
+    fn poll(&mut self, args: &mut Args) -> Result<Option<Val>, Failure> {
+        let deadlines = args
+            .handles()?
+            .into_iter()
+            .map(|handle| self.deadline(handle))
+            .collect::<Result<Vec<_>, _>>()?;
+        if deadlines.is_empty() {
+            return Err(trap("poll requires at least one pollable"));
+        }
+        let mut now = self.clock(CLOCK_MONOTONIC, false)?;
+        let is_ready =
+            |deadline: &Option<u64>, now| deadline.is_none_or(|deadline| now >= deadline);
+        if !deadlines.iter().any(|deadline| is_ready(deadline, now)) {
+            let earliest = deadlines.iter().flatten().min().copied().unwrap();
+            self.sleep_until(earliest)?;
+            now = self.clock(CLOCK_MONOTONIC, false)?;
+        }
+        let ready = deadlines
+            .iter()
+            .enumerate()
+            .filter(|(_, deadline)| is_ready(deadline, now))
+            .map(|(i, _)| Val::U32(i as u32));
+        Ok(Some(Val::List(ready.collect())))
+    }
+
+    /// Sleeps by `poll_oneoff` with a subscription of the monotonic clock
+    fn sleep_until(&mut self, deadline: u64) -> Result<(), Failure> {
+        let now = self.clock(CLOCK_MONOTONIC, false)?;
+        if now >= deadline {
+            return Ok(());
+        }
+        let scratch = &mut Scratch::new();
+        let subscription = scratch.alloc(48);
+        let offset = subscription as usize;
+        scratch.0[offset + 16..][..4].copy_from_slice(&(CLOCK_MONOTONIC as u32).to_le_bytes());
+        scratch.0[offset + 24..][..8].copy_from_slice(&(deadline - now).to_le_bytes());
+        let (event, count) = (scratch.alloc(32), scratch.alloc(4));
+        preview1!(scratch, poll_oneoff, subscription, event, 1i32, count)
+    }
+
+    fn subscribe_stream(&mut self, args: &mut Args) -> Result<Option<Val>, Failure> {
+        let handle = args.handle()?;
+        match self.resource(handle)? {
+            // reads and writes block until they are done, so streams are always ready
+            Resource::InputStream { .. } | Resource::OutputStream { .. } => {
+                Ok(Some(self.pollable(None)))
+            }
+            _ => Err(trap(format!("handle {} is not a stream", handle))),
+        }
+    }
+
+    /// Reads from an input stream, and returns the bytes or the number of skipped bytes
+    ///
+    /// The end of the file is `Errno(0)`, which is `closed` of `stream-error`.
+    fn read(&mut self, args: &mut Args, skip: bool) -> Result<Option<Val>, Failure> {
+        let handle = args.handle()?;
+        let len = args.u64()?.min(MAX_READ_SIZE);
+        let (fd, position) = match self.resource(handle)? {
+            Resource::InputStream { fd, position } => (*fd, *position),
+            _ => return Err(trap(format!("handle {} is not an input stream", handle))),
+        };
+        let scratch = &mut Scratch::new();
+        let buf = scratch.alloc(len);
+        let iovec = scratch.iovec(buf, len);
+        let nread = scratch.alloc(4);
+        match position {
+            Position::At(offset) => preview1!(
+                scratch,
+                fd_pread,
+                fd as i32,
+                iovec,
+                1i32,
+                offset as i64,
+                nread
+            )?,
+            Position::Current | Position::Append => {
+                preview1!(scratch, fd_read, fd as i32, iovec, 1i32, nread)?
+            }
+        }
+        let nread = scratch.u32(nread) as u64;
+        if nread == 0 && len > 0 {
+            return Err(Failure::Errno(0));
+        }
+        if let Resource::InputStream {
+            position: Position::At(offset),
+            ..
+        } = self.resource(handle)?
+        {
+            *offset += nread;
+        }
+        if skip {
+            Ok(Some(Val::U64(nread)))
+        } else {
+            Ok(Some(Val::Bytes(scratch.bytes(buf, nread).to_vec())))
+        }
+    }
+
+    /// Writes all the bytes to an output stream
+    fn write(&mut self, handle: u32, contents: &[u8]) -> Result<Option<Val>, Failure> {
+        let (fd, position) = self.output_stream(handle)?;
+        let mut offset = match position {
+            Position::At(offset) => Some(offset),
+            Position::Append => Some(self.stat(fd)?.1),
+            Position::Current => None,
+        };
+        let mut written = 0;
+        while written < contents.len() {
+            let scratch = &mut Scratch::new();
+            let buf = scratch.put(&contents[written..]);
+            let iovec = scratch.iovec(buf, (contents.len() - written) as u64);
+            let nwritten = scratch.alloc(4);
+            match offset {
+                Some(offset) => preview1!(
+                    scratch,
+                    fd_pwrite,
+                    fd as i32,
+                    iovec,
+                    1i32,
+                    offset as i64,
+                    nwritten
+                )?,
+                None => preview1!(scratch, fd_write, fd as i32, iovec, 1i32, nwritten)?,
+            }
+            let nwritten = scratch.u32(nwritten) as usize;
+            if nwritten == 0 {
+                return Err(Failure::Errno(0));
+            }
+            written += nwritten;
+            offset = offset.map(|offset| offset + nwritten as u64);
+        }
+        if let (Position::At(_), Some(end)) = (position, offset) {
+            if let Resource::OutputStream { position, .. } = self.resource(handle)? {
+                *position = Position::At(end);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the time or the resolution of the clock in nanoseconds
+    fn clock(&mut self, id: i32, resolution: bool) -> Result<u64, Failure> {
+        let scratch = &mut Scratch::new();
+        let time = scratch.alloc(8);
+        if resolution {
+            preview1!(scratch, clock_res_get, id, time)?;
+        } else {
+            preview1!(scratch, clock_time_get, id, 1i64, time)?;
+        }
+        Ok(scratch.u64(time))
+    }
+
+    fn random(&mut self, len: u64) -> Result<Vec<u8>, Failure> {
+        let scratch = &mut Scratch::new();
+        let buf = scratch.alloc(len);
+        preview1!(scratch, random_get, buf, len as i32)?;
+        Ok(scratch.bytes(buf, len).to_vec())
+    }
+
+    /// Returns the preopened directories, which are the file descriptors from 3 with prestats
+    fn get_directories(&mut self) -> Result<Option<Val>, Failure> {
+        let mut directories = vec![];
+        for fd in 3.. {
+            let scratch = &mut Scratch::new();
+            let prestat = scratch.alloc(8);
+            match preview1!(scratch, fd_prestat_get, fd, prestat) {
+                Ok(()) => {}
+                Err(Failure::Errno(ERRNO_BADF)) => break,
+                Err(e) => return Err(e),
+            }
+            let len = scratch.u32(prestat + 4) as u64;
+            let name = scratch.alloc(len);
+            preview1!(scratch, fd_prestat_dir_name, fd, name, len as i32)?;
+            let name = String::from_utf8_lossy(scratch.bytes(name, len)).into_owned();
+            let descriptor = self.push(Resource::Descriptor {
+                fd: fd as u32,
+                preopened: true,
+            });
+            directories.push(Val::Record(vec![descriptor, Val::String(name)]));
+        }
+        Ok(Some(Val::List(directories)))
+    }
+
+    /// Returns the filetype and the size of the file
+    fn stat(&mut self, fd: u32) -> Result<(u8, u64), Failure> {
+        let scratch = &mut Scratch::new();
+        let filestat = scratch.alloc(64);
+        preview1!(scratch, fd_filestat_get, fd as i32, filestat)?;
+        Ok((scratch.u8(filestat + 16), scratch.u64(filestat + 32)))
+    }
+
+    fn read_directory_entry(&mut self, args: &mut Args) -> Result<Option<Val>, Failure> {
+        let handle = args.handle()?;
+        let Resource::DirectoryEntryStream {
+            fd,
+            cookie,
+            entries,
+            done,
+        } = self.resource(handle)?
+        else {
+            return Err(trap(format!(
+                "handle {} is not a directory entry stream",
+                handle
+            )));
+        };
+        let (fd, mut cookie, done) = (*fd, *cookie, *done);
+        if let Some(entry) = entries.pop_front() {
+            return Ok(Some(Val::some(entry)));
+        }
+        if done {
+            return Ok(Some(Val::none()));
+        }
+
+        // entries are buffered, and the buffer is enlarged until it holds at least one entry
+        let mut buffer_size = READDIR_BUFFER_SIZE;
+        let mut new_entries = VecDeque::new();
+        let mut end = false;
+        while new_entries.is_empty() && !end {
+            let scratch = &mut Scratch::new();
+            let buf = scratch.alloc(buffer_size as u64);
+            let bufused = scratch.alloc(4);
+            preview1!(
+                scratch,
+                fd_readdir,
+                fd as i32,
+                buf,
+                buffer_size as i32,
+                cookie as i64,
+                bufused
+            )?;
+            let bufused = scratch.u32(bufused);
+            end = bufused < buffer_size;
+            let mut offset = 0;
+            while offset + 24 <= bufused {
+                let namlen = scratch.u32(buf + offset as i32 + 16);
+                if offset + 24 + namlen > bufused {
+                    break;
+                }
+                let name = scratch.bytes(buf + offset as i32 + 24, namlen as u64);
+                let name = String::from_utf8_lossy(name).into_owned();
+                cookie = scratch.u64(buf + offset as i32);
+                if name != "." && name != ".." {
+                    new_entries.push_back(Val::Record(vec![
+                        descriptor_type(scratch.u8(buf + offset as i32 + 20)),
+                        Val::String(name),
+                    ]));
+                }
+                offset += 24 + namlen;
+            }
+            if offset == 0 && !end {
+                buffer_size *= 2;
+            }
+        }
+        let entry = new_entries.pop_front();
+        if let Resource::DirectoryEntryStream {
+            cookie: stream_cookie,
+            entries,
+            done,
+            ..
+        } = self.resource(handle)?
+        {
+            *stream_cookie = cookie;
+            *entries = new_entries;
+            *done = end;
+        }
+        Ok(Some(match entry {
+            Some(entry) => Val::some(entry),
+            None => Val::none(),
+        }))
+    }
+
+    /// Calls a method of `descriptor` of wasi:filesystem/types
+    fn descriptor_method(&mut self, method: &str, args: &mut Args) -> Result<Vec<Val>, HostError> {
+        let fd = self
+            .descriptor(args.handle().map_err(host_error)?)
+            .map_err(host_error)?;
+        let result = match method {
+            "read-via-stream" => args
+                .u64()
+                .map(|offset| Some(self.stream(true, fd, Position::At(offset)))),
+            "write-via-stream" => args
+                .u64()
+                .map(|offset| Some(self.stream(false, fd, Position::At(offset)))),
+            "append-via-stream" => Ok(Some(self.stream(false, fd, Position::Append))),
+            "advise" => (|| {
+                let (offset, len) = (args.u64()?, args.u64()?);
+                let (advice, _) = args.variant()?;
+                let advice = ADVICES
+                    .iter()
+                    .position(|case| *case == advice)
+                    .ok_or_else(|| trap(format!("invalid advice {}", advice)))?;
+                let scratch = &mut Scratch::new();
+                preview1!(
+                    scratch,
+                    fd_advise,
+                    fd as i32,
+                    offset as i64,
+                    len as i64,
+                    advice as i32
+                )?;
+                Ok(None)
+            })(),
+            "sync-data" => preview1!(&mut Scratch::new(), fd_datasync, fd as i32).map(|()| None),
+            "sync" => preview1!(&mut Scratch::new(), fd_sync, fd as i32).map(|()| None),
+            "get-flags" => self.get_flags(fd),
+            "get-type" => self
+                .stat(fd)
+                .map(|(filetype, _)| Some(descriptor_type(filetype))),
+            "set-size" => args.u64().and_then(|size| {
+                let scratch = &mut Scratch::new();
+                preview1!(scratch, fd_filestat_set_size, fd as i32, size as i64).map(|()| None)
+            }),
+            "set-times" => (|| {
+                let (atim, atim_flags) = new_timestamp(args.variant()?, 1, 2)?;
+                let (mtim, mtim_flags) = new_timestamp(args.variant()?, 4, 8)?;
+                let scratch = &mut Scratch::new();
+                preview1!(
+                    scratch,
+                    fd_filestat_set_times,
+                    fd as i32,
+                    atim,
+                    mtim,
+                    atim_flags | mtim_flags
+                )?;
+                Ok(None)
+            })(),
+            "read" => (|| {
+                let (len, offset) = (args.u64()?.min(MAX_READ_SIZE), args.u64()?);
+                let scratch = &mut Scratch::new();
+                let buf = scratch.alloc(len);
+                let iovec = scratch.iovec(buf, len);
+                let nread = scratch.alloc(4);
+                preview1!(
+                    scratch,
+                    fd_pread,
+                    fd as i32,
+                    iovec,
+                    1i32,
+                    offset as i64,
+                    nread
+                )?;
+                let nread = scratch.u32(nread) as u64;
+                Ok(Some(Val::Record(vec![
+                    Val::Bytes(scratch.bytes(buf, nread).to_vec()),
+                    Val::Bool(nread == 0 && len > 0),
+                ])))
+            })(),
+            "write" => (|| {
+                let (contents, offset) = (args.bytes()?, args.u64()?);
+                let scratch = &mut Scratch::new();
+                let buf = scratch.put(&contents);
+                let iovec = scratch.iovec(buf, contents.len() as u64);
+                let nwritten = scratch.alloc(4);
+                preview1!(
+                    scratch,
+                    fd_pwrite,
+                    fd as i32,
+                    iovec,
+                    1i32,
+                    offset as i64,
+                    nwritten
+                )?;
+                Ok(Some(Val::U64(scratch.u32(nwritten) as u64)))
+            })(),
+            "read-directory" => Ok(Some(self.push(Resource::DirectoryEntryStream {
+                fd,
+                cookie: 0,
+                entries: VecDeque::new(),
+                done: false,
+            }))),
+            "create-directory-at" => args.string().and_then(|path| {
+                let scratch = &mut Scratch::new();
+                let (ptr, len) = (scratch.put(path.as_bytes()), path.len() as i32);
+                preview1!(scratch, path_create_directory, fd as i32, ptr, len).map(|()| None)
+            }),
+            "stat" => (|| {
+                let scratch = &mut Scratch::new();
+                let filestat = scratch.alloc(64);
+                preview1!(scratch, fd_filestat_get, fd as i32, filestat)?;
+                Ok(Some(descriptor_stat(scratch, filestat)))
+            })(),
+            "stat-at" => (|| {
+                let (flags, path) = (path_flags(&args.flags()?), args.string()?);
+                let scratch = &mut Scratch::new();
+                let (ptr, len) = (scratch.put(path.as_bytes()), path.len() as i32);
+                let filestat = scratch.alloc(64);
+                preview1!(
+                    scratch,
+                    path_filestat_get,
+                    fd as i32,
+                    flags,
+                    ptr,
+                    len,
+                    filestat
+                )?;
+                Ok(Some(descriptor_stat(scratch, filestat)))
+            })(),
+            "set-times-at" => (|| {
+                let (flags, path) = (path_flags(&args.flags()?), args.string()?);
+                let (atim, atim_flags) = new_timestamp(args.variant()?, 1, 2)?;
+                let (mtim, mtim_flags) = new_timestamp(args.variant()?, 4, 8)?;
+                let scratch = &mut Scratch::new();
+                let (ptr, len) = (scratch.put(path.as_bytes()), path.len() as i32);
+                preview1!(
+                    scratch,
+                    path_filestat_set_times,
+                    fd as i32,
+                    flags,
+                    ptr,
+                    len,
+                    atim,
+                    mtim,
+                    atim_flags | mtim_flags
+                )?;
+                Ok(None)
+            })(),
+            "link-at" => (|| {
+                let (flags, old_path) = (path_flags(&args.flags()?), args.string()?);
+                let new_fd = self.descriptor(args.handle()?)?;
+                let new_path = args.string()?;
+                let scratch = &mut Scratch::new();
+                let (old_ptr, old_len) = (scratch.put(old_path.as_bytes()), old_path.len() as i32);
+                let (new_ptr, new_len) = (scratch.put(new_path.as_bytes()), new_path.len() as i32);
+                preview1!(
+                    scratch,
+                    path_link,
+                    fd as i32,
+                    flags,
+                    old_ptr,
+                    old_len,
+                    new_fd as i32,
+                    new_ptr,
+                    new_len
+                )?;
+                Ok(None)
+            })(),
+            "open-at" => self.open_at(fd, args),
+            "readlink-at" => (|| {
+                let path = args.string()?;
+                let scratch = &mut Scratch::new();
+                let (ptr, len) = (scratch.put(path.as_bytes()), path.len() as i32);
+                let buf = scratch.alloc(READLINK_BUFFER_SIZE as u64);
+                let nread = scratch.alloc(4);
+                preview1!(
+                    scratch,
+                    path_readlink,
+                    fd as i32,
+                    ptr,
+                    len,
+                    buf,
+                    READLINK_BUFFER_SIZE as i32,
+                    nread
+                )?;
+                let target = scratch.bytes(buf, scratch.u32(nread) as u64);
+                Ok(Some(Val::String(
+                    String::from_utf8_lossy(target).into_owned(),
+                )))
+            })(),
+            "remove-directory-at" => args.string().and_then(|path| {
+                let scratch = &mut Scratch::new();
+                let (ptr, len) = (scratch.put(path.as_bytes()), path.len() as i32);
+                preview1!(scratch, path_remove_directory, fd as i32, ptr, len).map(|()| None)
+            }),
+            "rename-at" => (|| {
+                let old_path = args.string()?;
+                let new_fd = self.descriptor(args.handle()?)?;
+                let new_path = args.string()?;
+                let scratch = &mut Scratch::new();
+                let (old_ptr, old_len) = (scratch.put(old_path.as_bytes()), old_path.len() as i32);
+                let (new_ptr, new_len) = (scratch.put(new_path.as_bytes()), new_path.len() as i32);
+                preview1!(
+                    scratch,
+                    path_rename,
+                    fd as i32,
+                    old_ptr,
+                    old_len,
+                    new_fd as i32,
+                    new_ptr,
+                    new_len
+                )?;
+                Ok(None)
+            })(),
+            "symlink-at" => (|| {
+                let (old_path, new_path) = (args.string()?, args.string()?);
+                let scratch = &mut Scratch::new();
+                let (old_ptr, old_len) = (scratch.put(old_path.as_bytes()), old_path.len() as i32);
+                let (new_ptr, new_len) = (scratch.put(new_path.as_bytes()), new_path.len() as i32);
+                preview1!(
+                    scratch,
+                    path_symlink,
+                    old_ptr,
+                    old_len,
+                    fd as i32,
+                    new_ptr,
+                    new_len
+                )?;
+                Ok(None)
+            })(),
+            "unlink-file-at" => args.string().and_then(|path| {
+                let scratch = &mut Scratch::new();
+                let (ptr, len) = (scratch.put(path.as_bytes()), path.len() as i32);
+                preview1!(scratch, path_unlink_file, fd as i32, ptr, len).map(|()| None)
+            }),
+            // is-same-object returns no error code
+            "is-same-object" => {
+                return value((|| {
+                    let other = self.descriptor(args.handle()?)?;
+                    let scratch = &mut Scratch::new();
+                    let (filestat, other_filestat) = (scratch.alloc(64), scratch.alloc(64));
+                    preview1!(scratch, fd_filestat_get, fd as i32, filestat)?;
+                    preview1!(scratch, fd_filestat_get, other as i32, other_filestat)?;
+                    let same =
+                        metadata_hash(scratch, filestat) == metadata_hash(scratch, other_filestat);
+                    Ok(Some(Val::Bool(same)))
+                })())
+            }
+            "metadata-hash" => (|| {
+                let scratch = &mut Scratch::new();
+                let filestat = scratch.alloc(64);
+                preview1!(scratch, fd_filestat_get, fd as i32, filestat)?;
+                Ok(Some(metadata_hash(scratch, filestat)))
+            })(),
+            "metadata-hash-at" => (|| {
+                let (flags, path) = (path_flags(&args.flags()?), args.string()?);
+                let scratch = &mut Scratch::new();
+                let (ptr, len) = (scratch.put(path.as_bytes()), path.len() as i32);
+                let filestat = scratch.alloc(64);
+                preview1!(
+                    scratch,
+                    path_filestat_get,
+                    fd as i32,
+                    flags,
+                    ptr,
+                    len,
+                    filestat
+                )?;
+                Ok(Some(metadata_hash(scratch, filestat)))
+            })(),
+            _ => {
+                return Err(HostError::Trap(format!(
+                    "wasi:filesystem/types#[method]descriptor.{} is not supported",
+                    method
+                )))
+            }
+        };
+        fs_result(result)
+    }
+
+    /// Returns `descriptor-flags` from the rights and the flags of the file descriptor
+    fn get_flags(&mut self, fd: u32) -> Result<Option<Val>, Failure> {
+        let scratch = &mut Scratch::new();
+        let fdstat = scratch.alloc(24);
+        preview1!(scratch, fd_fdstat_get, fd as i32, fdstat)?;
+        let (filetype, fdflags, rights) = (
+            scratch.u8(fdstat),
+            scratch.u16(fdstat + 2) as i32,
+            scratch.u64(fdstat + 8),
+        );
+        let flags = [
+            ("read", rights & RIGHTS_FD_READ != 0),
+            ("write", rights & RIGHTS_FD_WRITE != 0),
+            ("file-integrity-sync", fdflags & FDFLAGS_SYNC != 0),
+            ("data-integrity-sync", fdflags & FDFLAGS_DSYNC != 0),
+            ("requested-write-sync", fdflags & FDFLAGS_RSYNC != 0),
+            (
+                "mutate-directory",
+                filetype == FILETYPE_DIRECTORY && rights & RIGHTS_PATH_CREATE_FILE != 0,
+            ),
+        ];
+        let flags = flags
+            .into_iter()
+            .filter(|(_, set)| *set)
+            .map(|(flag, _)| flag.to_string());
+        Ok(Some(Val::Flags(flags.collect())))
+    }
+
+    fn open_at(&mut self, fd: u32, args: &mut Args) -> Result<Option<Val>, Failure> {
+        let (lookup_flags, path) = (path_flags(&args.flags()?), args.string()?);
+        let open_flags = args.flags()?;
+        let flags = args.flags()?;
+        let has = |flags: &[String], flag: &str| flags.iter().any(|f| f == flag);
+
+        // open-flags are numbered like oflags of preview1
+        let oflags = ["create", "directory", "exclusive", "truncate"]
+            .iter()
+            .enumerate()
+            .filter(|(_, flag)| has(&open_flags, flag))
+            .fold(0, |oflags, (i, _)| oflags | 1 << i);
+        let mut rights = RIGHTS_ALL;
+        if !has(&flags, "read") {
+            rights &= !RIGHTS_FD_READ;
+        }
+        if !has(&flags, "write") {
+            rights &= !RIGHTS_FD_WRITE;
+        }
+        let fdflags = [
+            ("file-integrity-sync", FDFLAGS_SYNC),
+            ("data-integrity-sync", FDFLAGS_DSYNC),
+            ("requested-write-sync", FDFLAGS_RSYNC),
+        ]
+        .iter()
+        .filter(|(flag, _)| has(&flags, flag))
+        .fold(0, |fdflags, (_, bit)| fdflags | bit);
+
+        let scratch = &mut Scratch::new();
+        let (ptr, len) = (scratch.put(path.as_bytes()), path.len() as i32);
+        let opened = scratch.alloc(4);
+        preview1!(
+            scratch,
+            path_open,
+            fd as i32,
+            lookup_flags,
+            ptr,
+            len,
+            oflags,
+            rights as i64,
+            rights as i64,
+            fdflags,
+            opened
+        )?;
+        Ok(Some(self.push(Resource::Descriptor {
+            fd: scratch.u32(opened),
+            preopened: false,
+        })))
+    }
+}
+
+/// Converts the failure of a function which has no error code
+fn host_error(failure: Failure) -> HostError {
+    match failure {
+        Failure::Errno(errno) => {
+            HostError::Trap(format!("WASI function failed with errno {}", errno))
+        }
+        Failure::Host(error) => error,
+    }
+}
+
+/// Exits with the code, which is traced and denied like proc_exit
+fn exit(code: i32) -> HostError {
+    let trace =
+        trace::is_enabled().then(|| trace::Call::new("proc_exit", &[code as i64], false, &[]));
+    if !matches!(policy::decide("proc_exit"), policy::Decision::Allow) {
+        let message = "WASI function proc_exit is denied by the policy";
+        if let Some(trace) = trace {
+            trace.finish_without_return(message);
+        }
+        return HostError::Trap(message.to_string());
+    }
+    if let Some(trace) = trace {
+        trace.finish_without_return("exit");
+    }
+    HostError::Exit(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::Cli;
+    use clap::Parser;
+    use std::path::PathBuf;
+
+    /// Selects a WASI context with a fake clock on this thread, with the directory preopened as
+    /// `/dir`
+    fn select_ctx(dir: Option<PathBuf>) {
+        let args = Cli::parse_from(["lv8", "--fake-clock", "--random-seed", "1", "a.wasm"]);
+        let preopens: Vec<_> = dir
+            .into_iter()
+            .map(|dir| ("/dir".to_string(), dir))
+            .collect();
+        let ctx = wasi::create_js_wasi_ctx(
+            wasi::CtxOptions::from_args(&args),
+            &["cmd".to_string(), "arg".to_string()],
+            &[("KEY".to_string(), "value".to_string())],
+            &preopens,
+        )
+        .unwrap();
+        wasi::select_wasi_ctx(Some(ctx), false);
+    }
+
+    fn flags(flags: &[&str]) -> Val {
+        Val::Flags(flags.iter().map(|flag| flag.to_string()).collect())
+    }
+
+    fn string(s: &str) -> Val {
+        Val::String(s.to_string())
+    }
+
+    #[test]
+    fn gives_arguments_and_environment_variables() {
+        select_ctx(None);
+        let mut host = Host::default();
+        assert_eq!(
+            host.call("wasi:cli/environment@0.2.0", "get-arguments", vec![]),
+            Ok(vec![Val::List(vec![string("cmd"), string("arg")])])
+        );
+        assert_eq!(
+            host.call("wasi:cli/environment@0.2.0", "get-environment", vec![]),
+            Ok(vec![Val::List(vec![Val::Record(vec![
+                string("KEY"),
+                string("value")
+            ])])])
+        );
+        assert_eq!(
+            host.call("wasi:cli/exit@0.2.0", "exit", vec![Val::error(None)]),
+            Err(HostError::Exit(1))
+        );
+        assert_eq!(
+            host.call(
+                "wasi:sockets/tcp@0.2.0",
+                "[method]tcp-socket.listen",
+                vec![]
+            ),
+            Err(HostError::Trap(
+                "wasi:sockets/tcp#[method]tcp-socket.listen is not supported".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn reads_and_writes_files_of_preopened_directories() {
+        let dir = std::env::temp_dir().join(format!("lv8-component-host-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        select_ctx(Some(dir.clone()));
+        let mut host = Host::default();
+        let types = "wasi:filesystem/types@0.2.0";
+        let streams = "wasi:io/streams@0.2.0";
+
+        let directories = host
+            .call("wasi:filesystem/preopens@0.2.0", "get-directories", vec![])
+            .unwrap();
+        let [Val::List(directories)] = directories.as_slice() else {
+            panic!("expected a list");
+        };
+        assert_eq!(
+            directories.as_slice(),
+            [Val::Record(vec![Val::Handle(1), string("/dir")])]
+        );
+
+        let open = |host: &mut Host, path: &str, open_flags: &[&str]| {
+            host.call(
+                types,
+                "[method]descriptor.open-at",
+                vec![
+                    Val::Handle(1),
+                    flags(&[]),
+                    string(path),
+                    flags(open_flags),
+                    flags(&["read", "write"]),
+                ],
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            open(&mut host, "a.txt", &["create"]),
+            [Val::ok(Some(Val::Handle(2)))]
+        );
+        assert_eq!(
+            open(&mut host, "missing", &[]),
+            [Val::error(Some(Val::variant("no-entry", None)))]
+        );
+
+        let stream = host
+            .call(
+                types,
+                "[method]descriptor.write-via-stream",
+                vec![Val::Handle(2), Val::U64(0)],
+            )
+            .unwrap();
+        assert_eq!(stream, [Val::ok(Some(Val::Handle(3)))]);
+        assert_eq!(
+            host.call(
+                streams,
+                "[method]output-stream.blocking-write-and-flush",
+                vec![Val::Handle(3), Val::Bytes(b"hello".to_vec())],
+            ),
+            Ok(vec![Val::ok(None)])
+        );
+        assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"hello");
+
+        host.call(
+            types,
+            "[method]descriptor.read-via-stream",
+            vec![Val::Handle(2), Val::U64(1)],
+        )
+        .unwrap();
+        let read = |host: &mut Host| {
+            host.call(
+                streams,
+                "[method]input-stream.read",
+                vec![Val::Handle(4), Val::U64(16)],
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            read(&mut host),
+            [Val::ok(Some(Val::Bytes(b"ello".to_vec())))]
+        );
+        assert_eq!(
+            read(&mut host),
+            [Val::error(Some(Val::variant("closed", None)))]
+        );
+
+        let stat = host
+            .call(types, "[method]descriptor.stat", vec![Val::Handle(2)])
+            .unwrap();
+        let [Val::Variant(case, Some(stat))] = stat.as_slice() else {
+            panic!("expected a result");
+        };
+        assert_eq!(case, "ok");
+        let Val::Record(fields) = &**stat else {
+            panic!("expected a record");
+        };
+        assert_eq!(fields[0], Val::variant("regular-file", None));
+        assert_eq!(fields[2], Val::U64(5));
+
+        host.call(
+            types,
+            "[method]descriptor.read-directory",
+            vec![Val::Handle(1)],
+        )
+        .unwrap();
+        let read_entry = |host: &mut Host| {
+            host.call(
+                types,
+                "[method]directory-entry-stream.read-directory-entry",
+                vec![Val::Handle(5)],
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            read_entry(&mut host),
+            [Val::ok(Some(Val::some(Val::Record(vec![
+                Val::variant("regular-file", None),
+                string("a.txt"),
+            ]))))]
+        );
+        assert_eq!(read_entry(&mut host), [Val::ok(Some(Val::none()))]);
+
+        assert_eq!(host.drop(2), Ok(()));
+        assert!(matches!(host.drop(2), Err(HostError::Trap(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sleeps_on_the_clock_of_the_context() {
+        select_ctx(None);
+        let mut host = Host::default();
+        let clock = "wasi:clocks/monotonic-clock@0.2.0";
+        let [Val::U64(start)] = host.call(clock, "now", vec![]).unwrap()[..] else {
+            panic!("expected an instant");
+        };
+        let pollable = host
+            .call(clock, "subscribe-duration", vec![Val::U64(1_000_000_000)])
+            .unwrap();
+        let ready = |host: &mut Host| {
+            host.call(
+                "wasi:io/poll@0.2.0",
+                "[method]pollable.ready",
+                pollable.clone(),
+            )
+            .unwrap()
+        };
+        assert_eq!(ready(&mut host), [Val::Bool(false)]);
+        assert_eq!(
+            host.call(
+                "wasi:io/poll@0.2.0",
+                "poll",
+                vec![Val::List(pollable.clone())]
+            ),
+            Ok(vec![Val::List(vec![Val::U32(0)])])
+        );
+        assert_eq!(ready(&mut host), [Val::Bool(true)]);
+        let [Val::U64(end)] = host.call(clock, "now", vec![]).unwrap()[..] else {
+            panic!("expected an instant");
+        };
+        assert!(end - start >= 1_000_000_000);
+
+        let bytes = host
+            .call(
+                "wasi:random/random@0.2.0",
+                "get-random-bytes",
+                vec![Val::U64(16)],
+            )
+            .unwrap();
+        assert!(matches!(&bytes[..], [Val::Bytes(bytes)] if bytes.len() == 16));
+    }
+}
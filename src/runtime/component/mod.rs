@@ -0,0 +1,389 @@
+// WASI Preview 2 command components (e.g. built for `wasm32-wasip2`)
+//
+// V8 only understands core modules, so the core modules of the component are instantiated in the
+// order given by the component, and the functions of the wasi:cli/command world are given to them
+// as host functions which lift their arguments and lower their results by the canonical ABI.
+mod abi;
+mod host;
+mod plan;
+
+use anyhow::{anyhow, Result};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use abi::{Flat, FlatType, FuncType, Memory};
+use host::{Host, HostError};
+use plan::{CoreDef, CoreInstance, Lowering};
+
+use super::{call_export, compile, describe_exception, wasi};
+use crate::driver::Cli;
+
+thread_local! {
+    /// Host functions lowered into the component, which are found by the index given as the data
+    /// of their V8 functions
+    static LOWERED: RefCell<Vec<Rc<Lowered>>> = const { RefCell::new(vec![]) };
+    /// Resources given to the component
+    static HOST: RefCell<Host> = RefCell::new(Host::default());
+}
+
+struct Lowered {
+    interface: String,
+    name: String,
+    ty: FuncType,
+    memory: Option<v8::Global<v8::Object>>,
+    realloc: Option<v8::Global<v8::Function>>,
+}
+
+/// Instantiated component, which runs by `run` of wasi:cli/run
+pub(super) struct Command {
+    run: v8::Global<v8::Function>,
+    memory: Option<v8::Global<v8::Object>>,
+    post_return: Option<v8::Global<v8::Function>>,
+    ty: FuncType,
+}
+
+/// Returns an option of lv8 which configures the imports of core modules, and is not supported
+/// for components
+///
+/// Core modules of a component are given their imports by the component itself:
+/// - `--wasi-memory` chooses the memory of WASI functions, whereas each function lowered into a
+///   component is given its memory by `canon lower`.
+/// - `--jspi` suspends the wasm stack on the promises of WASI functions, whereas functions lowered
+///   into a component are called synchronously, since their arguments and results are lifted and
+///   lowered (and may be allocated by `realloc`) during the call.
+/// - `--preload` gives the import object of the module to scripts, whereas the core modules of a
+///   component are instantiated with the exports of each other and with the lowered functions, which
+///   are defined by the component rather than by an import object.
+/// - `--emscripten` and `--assemblyscript` add `env` functions to the import object, whereas a
+///   component only imports WASI interfaces, and its core modules import `env` from the component.
+pub(super) fn unsupported_option(args: &Cli) -> Option<&'static str> {
+    [
+        (args.wasi_memory.is_some(), "--wasi-memory"),
+        (args.jspi, "--jspi"),
+        (!args.preload.is_empty(), "--preload"),
+        (args.emscripten, "--emscripten"),
+        (args.assemblyscript, "--assemblyscript"),
+    ]
+    .into_iter()
+    .find_map(|(given, option)| given.then_some(option))
+}
+
+/// Instantiates the core modules of the component in the current context
+pub(super) fn instantiate(scope: &mut v8::HandleScope, wasm: &[u8]) -> Result<Command> {
+    let plan = plan::plan(wasm)?;
+    let modules = plan
+        .modules
+        .iter()
+        .enumerate()
+        .map(|(i, module)| {
+            compile(scope, module)
+                .map_err(|message| anyhow!("Failed to compile core module {}: {}", i, message))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let context = scope.get_current_context();
+    let global = context.global(scope);
+    let str_wasm = v8::String::new(scope, "WebAssembly").unwrap();
+    let global_wasm = global
+        .get(scope, str_wasm.into())
+        .unwrap()
+        .to_object(scope)
+        .unwrap();
+    let str_instance = v8::String::new(scope, "Instance").unwrap();
+    let instance_ctor = global_wasm.get(scope, str_instance.into()).unwrap();
+    let instance_ctor = instance_ctor.cast::<v8::Function>();
+    let str_exports = v8::String::new(scope, "exports").unwrap();
+
+    // exports of the core instances, which are given to other instances as imports
+    let mut instances: Vec<v8::Local<v8::Object>> = vec![];
+    for (index, instance) in plan.instances.iter().enumerate() {
+        let exports = match instance {
+            CoreInstance::Instantiate { module, imports } => {
+                let import_object = v8::Object::new(scope);
+                for (name, instance) in imports {
+                    let name = v8::String::new(scope, name).unwrap();
+                    import_object.set(scope, name.into(), instances[*instance].into());
+                }
+                let scope = &mut v8::TryCatch::new(scope);
+                let instance = instance_ctor
+                    .new_instance(scope, &[modules[*module].into(), import_object.into()]);
+                let Some(instance) = instance else {
+                    let exception = scope.exception().map_or_else(String::new, |exception| {
+                        exception.to_rust_string_lossy(scope)
+                    });
+                    return Err(anyhow!(
+                        "Failed to instantiate core instance {}: {}",
+                        index,
+                        exception
+                    ));
+                };
+                let exports = instance.get(scope, str_exports.into()).unwrap();
+                exports.to_object(scope).unwrap()
+            }
+            CoreInstance::Items(items) => {
+                let object = v8::Object::new(scope);
+                for (name, def) in items {
+                    let value = resolve(scope, &instances, &plan.lowerings, def)?;
+                    let name = v8::String::new(scope, name).unwrap();
+                    object.set(scope, name.into(), value);
+                }
+                object
+            }
+        };
+        instances.push(exports);
+    }
+
+    let run = resolve(scope, &instances, &plan.lowerings, &plan.run.func)?;
+    let memory = match &plan.run.memory {
+        Some(memory) => Some(resolve(scope, &instances, &plan.lowerings, memory)?),
+        None => None,
+    };
+    let post_return = match &plan.run.post_return {
+        Some(post_return) => Some(resolve(scope, &instances, &plan.lowerings, post_return)?),
+        None => None,
+    };
+    Ok(Command {
+        run: v8::Global::new(scope, run.cast::<v8::Function>()),
+        memory: memory.map(|memory| v8::Global::new(scope, memory.cast::<v8::Object>())),
+        post_return: post_return
+            .map(|post_return| v8::Global::new(scope, post_return.cast::<v8::Function>())),
+        ty: plan.run.ty,
+    })
+}
+
+/// Returns the function, memory, table or global of the core definition
+fn resolve<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    instances: &[v8::Local<'s, v8::Object>],
+    lowerings: &[Lowering],
+    def: &CoreDef,
+) -> Result<v8::Local<'s, v8::Value>> {
+    match def {
+        CoreDef::Export { instance, name } => {
+            let str_name = v8::String::new(scope, name).unwrap();
+            instances[*instance]
+                .get(scope, str_name.into())
+                .filter(|value| !value.is_undefined())
+                .ok_or_else(|| anyhow!("core instance {} does not export {}", instance, name))
+        }
+        CoreDef::Lowered(index) => {
+            let lowering = &lowerings[*index];
+            let memory = match &lowering.memory {
+                Some(memory) => Some(resolve(scope, instances, lowerings, memory)?),
+                None => None,
+            };
+            let realloc = match &lowering.realloc {
+                Some(realloc) => Some(resolve(scope, instances, lowerings, realloc)?),
+                None => None,
+            };
+            let lowered = Lowered {
+                interface: lowering.interface.clone(),
+                name: lowering.name.clone(),
+                ty: lowering.ty.clone(),
+                memory: memory.map(|memory| v8::Global::new(scope, memory.cast::<v8::Object>())),
+                realloc: realloc
+                    .map(|realloc| v8::Global::new(scope, realloc.cast::<v8::Function>())),
+            };
+            let index = LOWERED.with(|lowered_functions| {
+                let mut lowered_functions = lowered_functions.borrow_mut();
+                lowered_functions.push(Rc::new(lowered));
+                lowered_functions.len() - 1
+            });
+            let data = v8::Integer::new(scope, index as i32);
+            let function = v8::Function::builder(call_lowered)
+                .data(data.into())
+                .build(scope)
+                .unwrap();
+            Ok(function.into())
+        }
+        CoreDef::ResourceDrop => {
+            let function = v8::Function::new(scope, drop_resource).unwrap();
+            Ok(function.into())
+        }
+    }
+}
+
+/// Linear memory of the component, whose realloc is called in the scope
+struct ComponentMemory<'a, 's> {
+    scope: &'a mut v8::HandleScope<'s>,
+    memory: Option<v8::Local<'s, v8::Object>>,
+    realloc: Option<v8::Local<'s, v8::Function>>,
+    /// Backing store of the memory, which is replaced when the memory grows
+    backing_store: Option<v8::SharedRef<v8::BackingStore>>,
+}
+
+impl Memory for ComponentMemory<'_, '_> {
+    fn bytes(&mut self) -> &mut [u8] {
+        let Some(memory) = self.memory else {
+            return &mut [];
+        };
+        let str_buffer = v8::String::new(self.scope, "buffer").unwrap();
+        let buffer = memory.get(self.scope, str_buffer.into()).unwrap();
+        let backing_store = if buffer.is_shared_array_buffer() {
+            buffer.cast::<v8::SharedArrayBuffer>().get_backing_store()
+        } else {
+            buffer.cast::<v8::ArrayBuffer>().get_backing_store()
+        };
+        wasi::memory_bytes(self.backing_store.insert(backing_store))
+    }
+
+    fn alloc(&mut self, align: u32, size: u32) -> Result<u32> {
+        let realloc = self
+            .realloc
+            .ok_or_else(|| anyhow!("the function has no realloc option"))?;
+        // realloc(original_ptr, original_size, alignment, new_size)
+        let args =
+            [0, 0, align, size].map(|value| v8::Integer::new(self.scope, value as i32).into());
+        let undefined = v8::undefined(self.scope).into();
+        let scope = &mut v8::TryCatch::new(&mut *self.scope);
+        let ptr = realloc
+            .call(scope, undefined, &args)
+            .ok_or_else(|| anyhow!("realloc failed"))?;
+        Ok(ptr.int32_value(scope).unwrap_or_default() as u32)
+    }
+}
+
+/// Converts a value given by wasm into a core value
+fn to_flat(scope: &mut v8::HandleScope, value: v8::Local<v8::Value>, ty: FlatType) -> Flat {
+    match ty {
+        FlatType::I32 => Flat::I32(value.int32_value(scope).unwrap_or_default()),
+        FlatType::I64 => Flat::I64(
+            value
+                .to_big_int(scope)
+                .map_or(0, |bigint| bigint.i64_value().0),
+        ),
+        FlatType::F32 => Flat::F32(value.number_value(scope).unwrap_or_default() as f32),
+        FlatType::F64 => Flat::F64(value.number_value(scope).unwrap_or_default()),
+    }
+}
+
+/// Converts a core value into a value returned to wasm
+fn from_flat<'s>(scope: &mut v8::HandleScope<'s>, value: Flat) -> v8::Local<'s, v8::Value> {
+    match value {
+        Flat::I32(value) => v8::Integer::new(scope, value).into(),
+        Flat::I64(value) => v8::BigInt::new_from_i64(scope, value).into(),
+        Flat::F32(value) => v8::Number::new(scope, value as f64).into(),
+        Flat::F64(value) => v8::Number::new(scope, value).into(),
+    }
+}
+
+/// Calls a host function lowered into the component
+fn call_lowered(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let index = args.data().int32_value(scope).unwrap_or_default() as usize;
+    let lowered = LOWERED.with(|lowered| lowered.borrow()[index].clone());
+    let flat_args: Vec<Flat> = lowered
+        .ty
+        .lowered_params()
+        .into_iter()
+        .enumerate()
+        .map(|(i, ty)| to_flat(scope, args.get(i as i32), ty))
+        .collect();
+
+    let memory = lowered
+        .memory
+        .as_ref()
+        .map(|memory| v8::Local::new(scope, memory));
+    let realloc = lowered
+        .realloc
+        .as_ref()
+        .map(|realloc| v8::Local::new(scope, realloc));
+    let memory = &mut ComponentMemory {
+        scope,
+        memory,
+        realloc,
+        backing_store: None,
+    };
+    let results = lowered
+        .ty
+        .lift_args(memory, &flat_args)
+        .map_err(|e| HostError::Trap(e.to_string()))
+        .and_then(|vals| {
+            HOST.with(|host| {
+                host.borrow_mut()
+                    .call(&lowered.interface, &lowered.name, vals)
+            })
+        })
+        .and_then(|results| {
+            lowered
+                .ty
+                .lower_results(memory, &flat_args, &results)
+                .map_err(|e| HostError::Trap(e.to_string()))
+        });
+    match results {
+        Ok(results) => {
+            if let Some(result) = results.first() {
+                let result = from_flat(scope, *result);
+                rv.set(result);
+            }
+        }
+        Err(HostError::Exit(code)) => wasi::exit(scope, code),
+        Err(HostError::Trap(message)) => wasi::throw_error(
+            scope,
+            &format!("{}#{}: {}", lowered.interface, lowered.name, message),
+        ),
+    }
+}
+
+/// `resource.drop` of the resources given to the component
+fn drop_resource(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    let handle = args.get(0).int32_value(scope).unwrap_or_default() as u32;
+    if let Err(HostError::Trap(message)) = HOST.with(|host| host.borrow_mut().drop(handle)) {
+        wasi::throw_error(scope, &message);
+    }
+}
+
+impl Command {
+    /// Calls `run` of wasi:cli/run, and returns the exit code
+    pub fn run(&self, scope: &mut v8::HandleScope) -> Result<i32> {
+        let run = v8::Local::new(scope, &self.run);
+        let undefined = v8::undefined(scope).into();
+        let ret = match call_export(scope, run, undefined, &[]) {
+            Ok(ret) => ret,
+            Err(exception) => {
+                if let Some(code) = wasi::exit_code() {
+                    return Ok(code);
+                }
+                return Err(anyhow!(describe_exception(scope, exception, &[])));
+            }
+        };
+        let flat: Vec<Flat> = self
+            .ty
+            .lifted_results()
+            .into_iter()
+            .map(|ty| to_flat(scope, ret, ty))
+            .collect();
+        let memory = self
+            .memory
+            .as_ref()
+            .map(|memory| v8::Local::new(scope, memory));
+        let results = self.ty.lift_results(
+            &mut ComponentMemory {
+                scope,
+                memory,
+                realloc: None,
+                backing_store: None,
+            },
+            &flat,
+        )?;
+        if let Some(post_return) = &self.post_return {
+            let post_return = v8::Local::new(scope, post_return);
+            let args: Vec<_> = flat.iter().map(|value| from_flat(scope, *value)).collect();
+            if let Err(exception) = call_export(scope, post_return, undefined, &args) {
+                return Err(anyhow!(describe_exception(scope, exception, &[])));
+            }
+        }
+        // run returns result, which is ok on success
+        match results.first() {
+            Some(abi::Val::Variant(case, _)) if case == "ok" => Ok(0),
+            _ => Ok(1),
+        }
+    }
+}
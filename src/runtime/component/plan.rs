@@ -0,0 +1,850 @@
+// Flattening of a component into the core instances created by V8
+//
+// Components are not understood by V8, so the index spaces of the component (and of its nested
+// components) are interpreted here. The result is the list of core instances in the order they
+// are created, the host functions lowered into them and the core function which runs the command.
+
+use anyhow::{anyhow, Result};
+use std::rc::Rc;
+use wasmparser::component_types::ComponentEntityType;
+use wasmparser::types::Types;
+use wasmparser::{
+    CanonicalFunction, CanonicalOption, ComponentAlias, ComponentExternalKind, ComponentInstance,
+    ComponentOuterAliasKind, ComponentTypeRef, ExternalKind, Instance, Parser, Payload, Validator,
+    WasmFeatures,
+};
+
+use super::abi::FuncType;
+
+/// Core modules and instances of a component
+pub(super) struct Plan {
+    pub modules: Vec<Vec<u8>>,
+    /// Core instances in the order they are created
+    pub instances: Vec<CoreInstance>,
+    pub lowerings: Vec<Lowering>,
+    pub run: Run,
+}
+
+pub(super) enum CoreInstance {
+    /// Instance of `modules[module]`, whose imports are given by other instances by module name
+    Instantiate {
+        module: usize,
+        imports: Vec<(String, usize)>,
+    },
+    /// Instance made of the exports of other instances, which is only used as imports
+    Items(Vec<(String, CoreDef)>),
+}
+
+/// Core function, memory, table or global
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum CoreDef {
+    /// Export of an instance created by `CoreInstance::Instantiate`
+    Export { instance: usize, name: String },
+    /// Host function given by `lowerings[index]`
+    Lowered(usize),
+    /// `resource.drop` of a resource of the host
+    ResourceDrop,
+}
+
+/// Host function lowered by `canon lower`
+#[derive(Debug)]
+pub(super) struct Lowering {
+    /// Name of the interface, such as `wasi:cli/stdout@0.2.0`
+    pub interface: String,
+    pub name: String,
+    pub ty: FuncType,
+    pub memory: Option<CoreDef>,
+    pub realloc: Option<CoreDef>,
+}
+
+/// `run` of `wasi:cli/run` lifted by `canon lift`
+pub(super) struct Run {
+    pub func: CoreDef,
+    pub memory: Option<CoreDef>,
+    pub post_return: Option<CoreDef>,
+    pub ty: FuncType,
+}
+
+#[derive(Clone)]
+enum Func {
+    Host {
+        interface: String,
+        name: String,
+        ty: FuncType,
+    },
+    Lifted {
+        func: CoreDef,
+        memory: Option<CoreDef>,
+        post_return: Option<CoreDef>,
+    },
+}
+
+#[derive(Clone)]
+enum ComponentInstanceDef {
+    /// Instance imported by the top-level component, which is given by the host
+    Host(String),
+    Items(Rc<Vec<(String, Item)>>),
+}
+
+/// Nested component with the modules and components which it can alias from its enclosing
+/// components, innermost first
+struct ComponentDef {
+    bytes: Vec<u8>,
+    enclosing: Vec<Enclosing>,
+}
+
+#[derive(Clone)]
+struct Enclosing {
+    modules: Vec<usize>,
+    components: Vec<Rc<ComponentDef>>,
+}
+
+#[derive(Clone)]
+enum Item {
+    Func(Func),
+    Instance(ComponentInstanceDef),
+    Module(usize),
+    Component(Rc<ComponentDef>),
+    /// Type, which only matters to validation
+    Type,
+}
+
+/// Index spaces of a component being instantiated
+#[derive(Default)]
+struct Scope {
+    core_funcs: Vec<CoreDef>,
+    core_tables: Vec<CoreDef>,
+    core_memories: Vec<CoreDef>,
+    core_globals: Vec<CoreDef>,
+    core_tags: Vec<CoreDef>,
+    /// Indexes in `Plan::instances`
+    core_instances: Vec<usize>,
+    /// Indexes in `Plan::modules`
+    modules: Vec<usize>,
+    funcs: Vec<Func>,
+    instances: Vec<ComponentInstanceDef>,
+    components: Vec<Rc<ComponentDef>>,
+    exports: Vec<(String, Item)>,
+}
+
+impl Scope {
+    fn push(&mut self, item: Item) {
+        match item {
+            Item::Func(func) => self.funcs.push(func),
+            Item::Instance(instance) => self.instances.push(instance),
+            Item::Module(module) => self.modules.push(module),
+            Item::Component(component) => self.components.push(component),
+            Item::Type => {}
+        }
+    }
+
+    fn item(&self, kind: ComponentExternalKind, index: u32) -> Result<Item> {
+        let index = index as usize;
+        let item = match kind {
+            ComponentExternalKind::Func => self.funcs.get(index).cloned().map(Item::Func),
+            ComponentExternalKind::Instance => {
+                self.instances.get(index).cloned().map(Item::Instance)
+            }
+            ComponentExternalKind::Module => self.modules.get(index).copied().map(Item::Module),
+            ComponentExternalKind::Component => {
+                self.components.get(index).cloned().map(Item::Component)
+            }
+            ComponentExternalKind::Type => Some(Item::Type),
+            ComponentExternalKind::Value => return Err(anyhow!("values are not supported")),
+        };
+        item.ok_or_else(|| anyhow!("invalid index {} of {:?}", index, kind))
+    }
+
+    fn core_def(&self, kind: ExternalKind, index: u32) -> Result<CoreDef> {
+        let defs = match kind {
+            ExternalKind::Func => &self.core_funcs,
+            ExternalKind::Table => &self.core_tables,
+            ExternalKind::Memory => &self.core_memories,
+            ExternalKind::Global => &self.core_globals,
+            ExternalKind::Tag => &self.core_tags,
+        };
+        defs.get(index as usize)
+            .cloned()
+            .ok_or_else(|| anyhow!("invalid index {} of core {:?}", index, kind))
+    }
+
+    fn push_core_def(&mut self, kind: ExternalKind, def: CoreDef) {
+        match kind {
+            ExternalKind::Func => self.core_funcs.push(def),
+            ExternalKind::Table => self.core_tables.push(def),
+            ExternalKind::Memory => self.core_memories.push(def),
+            ExternalKind::Global => self.core_globals.push(def),
+            ExternalKind::Tag => self.core_tags.push(def),
+        }
+    }
+
+    fn enclosing(&self) -> Enclosing {
+        Enclosing {
+            modules: self.modules.clone(),
+            components: self.components.clone(),
+        }
+    }
+}
+
+/// Builds the plan of a command component, which exports `wasi:cli/run`
+pub(super) fn plan(bytes: &[u8]) -> Result<Plan> {
+    let types = Validator::new_with_features(WasmFeatures::all()).validate_all(bytes)?;
+    let mut builder = Builder {
+        types: &types,
+        modules: vec![],
+        instances: vec![],
+        lowerings: vec![],
+    };
+    let exports = builder.instantiate(bytes, &[], None)?;
+
+    let (export_name, run_instance) = exports
+        .iter()
+        .find_map(|(name, item)| match item {
+            Item::Instance(instance) if is_run_interface(name) => Some((name, instance)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            anyhow!("the component is not a command, as it does not export wasi:cli/run")
+        })?;
+    let ComponentInstanceDef::Items(run_items) = run_instance else {
+        return Err(anyhow!("{} is exported from the imports", export_name));
+    };
+    let Some((
+        _,
+        Item::Func(Func::Lifted {
+            func,
+            memory,
+            post_return,
+        }),
+    )) = run_items.iter().find(|(name, _)| name == "run")
+    else {
+        return Err(anyhow!("{} does not export the function run", export_name));
+    };
+    let ty = instance_func_type(
+        &types,
+        types.component_entity_type_of_export(export_name),
+        "run",
+    )
+    .ok_or_else(|| anyhow!("{}#run is not a function", export_name))?;
+
+    Ok(Plan {
+        modules: builder.modules,
+        instances: builder.instances,
+        lowerings: builder.lowerings,
+        run: Run {
+            func: func.clone(),
+            memory: memory.clone(),
+            post_return: post_return.clone(),
+            ty,
+        },
+    })
+}
+
+fn is_run_interface(name: &str) -> bool {
+    name == "wasi:cli/run" || name.starts_with("wasi:cli/run@")
+}
+
+/// Returns the type of a function exported by an instance of the top-level component
+fn instance_func_type(
+    types: &Types,
+    instance: Option<ComponentEntityType>,
+    name: &str,
+) -> Option<FuncType> {
+    let Some(ComponentEntityType::Instance(id)) = instance else {
+        return None;
+    };
+    match types[id].exports.get(name)? {
+        ComponentEntityType::Func(id) => Some(FuncType::from_component(types, &types[*id])),
+        _ => None,
+    }
+}
+
+struct Builder<'a> {
+    /// Types of the top-level component, which declare the functions of the host
+    types: &'a Types,
+    modules: Vec<Vec<u8>>,
+    instances: Vec<CoreInstance>,
+    lowerings: Vec<Lowering>,
+}
+
+impl Builder<'_> {
+    /// Instantiates a component with the items given to its imports, and returns its exports
+    ///
+    /// `args` is `None` for the top-level component, whose imports are given by the host.
+    fn instantiate(
+        &mut self,
+        bytes: &[u8],
+        enclosing: &[Enclosing],
+        args: Option<&[(String, Item)]>,
+    ) -> Result<Vec<(String, Item)>> {
+        let mut scope = Scope::default();
+        // nested modules and components are skipped, as they are parsed when they are used
+        let mut depth = 0;
+        for payload in Parser::new(0).parse_all(bytes) {
+            let payload = payload?;
+            if depth > 0 {
+                match payload {
+                    Payload::ModuleSection { .. } | Payload::ComponentSection { .. } => depth += 1,
+                    Payload::End(_) => depth -= 1,
+                    _ => {}
+                }
+                continue;
+            }
+            match payload {
+                Payload::ModuleSection {
+                    unchecked_range, ..
+                } => {
+                    depth += 1;
+                    self.modules.push(bytes[unchecked_range].to_vec());
+                    scope.modules.push(self.modules.len() - 1);
+                }
+                Payload::ComponentSection {
+                    unchecked_range, ..
+                } => {
+                    depth += 1;
+                    let mut outer = vec![scope.enclosing()];
+                    outer.extend(enclosing.iter().cloned());
+                    scope.components.push(Rc::new(ComponentDef {
+                        bytes: bytes[unchecked_range].to_vec(),
+                        enclosing: outer,
+                    }));
+                }
+                Payload::ComponentImportSection(reader) => {
+                    for import in reader {
+                        let import = import?;
+                        let name = import.name.0;
+                        let item = match args {
+                            Some(args) => args
+                                .iter()
+                                .find(|(arg, _)| arg == name)
+                                .map(|(_, item)| item.clone())
+                                .ok_or_else(|| anyhow!("missing argument {}", name))?,
+                            None => self.host_import(name, import.ty)?,
+                        };
+                        scope.push(item);
+                    }
+                }
+                Payload::InstanceSection(reader) => {
+                    for instance in reader {
+                        let instance = match instance? {
+                            Instance::Instantiate { module_index, args } => {
+                                let module = *scope
+                                    .modules
+                                    .get(module_index as usize)
+                                    .ok_or_else(|| anyhow!("invalid module {}", module_index))?;
+                                let imports = args
+                                    .iter()
+                                    .map(|arg| {
+                                        let instance = scope
+                                            .core_instances
+                                            .get(arg.index as usize)
+                                            .ok_or_else(|| {
+                                                anyhow!("invalid instance {}", arg.index)
+                                            })?;
+                                        Ok((arg.name.to_string(), *instance))
+                                    })
+                                    .collect::<Result<_>>()?;
+                                CoreInstance::Instantiate { module, imports }
+                            }
+                            Instance::FromExports(exports) => CoreInstance::Items(
+                                exports
+                                    .iter()
+                                    .map(|export| {
+                                        let def = scope.core_def(export.kind, export.index)?;
+                                        Ok((export.name.to_string(), def))
+                                    })
+                                    .collect::<Result<_>>()?,
+                            ),
+                        };
+                        self.instances.push(instance);
+                        scope.core_instances.push(self.instances.len() - 1);
+                    }
+                }
+                Payload::ComponentAliasSection(reader) => {
+                    for alias in reader {
+                        match alias? {
+                            ComponentAlias::CoreInstanceExport {
+                                kind,
+                                instance_index,
+                                name,
+                            } => {
+                                let def = self.core_export(&scope, instance_index, name)?;
+                                scope.push_core_def(kind, def);
+                            }
+                            ComponentAlias::InstanceExport {
+                                kind,
+                                instance_index,
+                                name,
+                            } => {
+                                let item =
+                                    self.instance_export(&scope, kind, instance_index, name)?;
+                                scope.push(item);
+                            }
+                            ComponentAlias::Outer { kind, count, index } => {
+                                let outer = enclosing
+                                    .get(count as usize - 1)
+                                    .ok_or_else(|| anyhow!("invalid outer alias"))?;
+                                match kind {
+                                    ComponentOuterAliasKind::CoreModule => scope.modules.push(
+                                        *outer
+                                            .modules
+                                            .get(index as usize)
+                                            .ok_or_else(|| anyhow!("invalid outer module"))?,
+                                    ),
+                                    ComponentOuterAliasKind::Component => scope.components.push(
+                                        outer
+                                            .components
+                                            .get(index as usize)
+                                            .cloned()
+                                            .ok_or_else(|| anyhow!("invalid outer component"))?,
+                                    ),
+                                    ComponentOuterAliasKind::CoreType
+                                    | ComponentOuterAliasKind::Type => {}
+                                }
+                            }
+                        }
+                    }
+                }
+                Payload::ComponentCanonicalSection(reader) => {
+                    for function in reader {
+                        self.canonical(&mut scope, function?)?;
+                    }
+                }
+                Payload::ComponentInstanceSection(reader) => {
+                    for instance in reader {
+                        let items = match instance? {
+                            ComponentInstance::Instantiate {
+                                component_index,
+                                args,
+                            } => {
+                                let component = scope
+                                    .components
+                                    .get(component_index as usize)
+                                    .cloned()
+                                    .ok_or_else(|| {
+                                        anyhow!("invalid component {}", component_index)
+                                    })?;
+                                let args = args
+                                    .iter()
+                                    .map(|arg| {
+                                        Ok((arg.name.to_string(), scope.item(arg.kind, arg.index)?))
+                                    })
+                                    .collect::<Result<Vec<_>>>()?;
+                                self.instantiate(
+                                    &component.bytes,
+                                    &component.enclosing,
+                                    Some(&args),
+                                )?
+                            }
+                            ComponentInstance::FromExports(exports) => exports
+                                .iter()
+                                .map(|export| {
+                                    Ok((
+                                        export.name.0.to_string(),
+                                        scope.item(export.kind, export.index)?,
+                                    ))
+                                })
+                                .collect::<Result<_>>()?,
+                        };
+                        scope
+                            .instances
+                            .push(ComponentInstanceDef::Items(Rc::new(items)));
+                    }
+                }
+                Payload::ComponentExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        let item = scope.item(export.kind, export.index)?;
+                        // exports are new items in the index spaces
+                        scope.push(item.clone());
+                        scope.exports.push((export.name.0.to_string(), item));
+                    }
+                }
+                Payload::ComponentStartSection { .. } => {
+                    return Err(anyhow!("start functions of components are not supported"))
+                }
+                _ => {}
+            }
+        }
+        Ok(scope.exports)
+    }
+
+    /// Returns the item given by the host to an import of the top-level component
+    fn host_import(&self, name: &str, ty: ComponentTypeRef) -> Result<Item> {
+        match ty {
+            ComponentTypeRef::Instance(_) => {
+                Ok(Item::Instance(ComponentInstanceDef::Host(name.to_string())))
+            }
+            ComponentTypeRef::Type(_) => Ok(Item::Type),
+            _ => Err(anyhow!("the import {} is not an instance", name)),
+        }
+    }
+
+    fn core_export(&self, scope: &Scope, instance: u32, name: &str) -> Result<CoreDef> {
+        let instance = *scope
+            .core_instances
+            .get(instance as usize)
+            .ok_or_else(|| anyhow!("invalid core instance {}", instance))?;
+        match &self.instances[instance] {
+            CoreInstance::Instantiate { .. } => Ok(CoreDef::Export {
+                instance,
+                name: name.to_string(),
+            }),
+            CoreInstance::Items(items) => items
+                .iter()
+                .find(|(item, _)| item == name)
+                .map(|(_, def)| def.clone())
+                .ok_or_else(|| anyhow!("core instance {} does not export {}", instance, name)),
+        }
+    }
+
+    fn instance_export(
+        &self,
+        scope: &Scope,
+        kind: ComponentExternalKind,
+        instance: u32,
+        name: &str,
+    ) -> Result<Item> {
+        let instance = scope
+            .instances
+            .get(instance as usize)
+            .ok_or_else(|| anyhow!("invalid instance {}", instance))?;
+        match instance {
+            ComponentInstanceDef::Host(interface) => match kind {
+                ComponentExternalKind::Func => {
+                    let ty = instance_func_type(
+                        self.types,
+                        self.types.component_entity_type_of_import(interface),
+                        name,
+                    )
+                    .ok_or_else(|| anyhow!("{}#{} is not a function", interface, name))?;
+                    Ok(Item::Func(Func::Host {
+                        interface: interface.clone(),
+                        name: name.to_string(),
+                        ty,
+                    }))
+                }
+                ComponentExternalKind::Type => Ok(Item::Type),
+                _ => Err(anyhow!("{}#{} is not supported", interface, name)),
+            },
+            ComponentInstanceDef::Items(items) => items
+                .iter()
+                .find(|(item, _)| item == name)
+                .map(|(_, item)| item.clone())
+                .ok_or_else(|| anyhow!("the instance does not export {}", name)),
+        }
+    }
+
+    fn canonical(&mut self, scope: &mut Scope, function: CanonicalFunction) -> Result<()> {
+        match function {
+            CanonicalFunction::Lift {
+                core_func_index,
+                options,
+                ..
+            } => {
+                let options = Options::new(scope, &options)?;
+                scope.funcs.push(Func::Lifted {
+                    func: scope.core_def(ExternalKind::Func, core_func_index)?,
+                    memory: options.memory,
+                    post_return: options.post_return,
+                });
+            }
+            CanonicalFunction::Lower {
+                func_index,
+                options,
+            } => {
+                let options = Options::new(scope, &options)?;
+                let func = scope
+                    .funcs
+                    .get(func_index as usize)
+                    .ok_or_else(|| anyhow!("invalid function {}", func_index))?;
+                let Func::Host {
+                    interface,
+                    name,
+                    ty,
+                } = func
+                else {
+                    return Err(anyhow!("lowering lifted functions is not supported"));
+                };
+                self.lowerings.push(Lowering {
+                    interface: interface.clone(),
+                    name: name.clone(),
+                    ty: ty.clone(),
+                    memory: options.memory,
+                    realloc: options.realloc,
+                });
+                scope
+                    .core_funcs
+                    .push(CoreDef::Lowered(self.lowerings.len() - 1));
+            }
+            CanonicalFunction::ResourceDrop { .. } => scope.core_funcs.push(CoreDef::ResourceDrop),
+            CanonicalFunction::ResourceNew { .. } | CanonicalFunction::ResourceRep { .. } => {
+                return Err(anyhow!("resources defined by components are not supported"))
+            }
+            CanonicalFunction::ThreadSpawn { .. } | CanonicalFunction::ThreadHwConcurrency => {
+                return Err(anyhow!("threads of components are not supported"))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options of `canon lift` and `canon lower`
+struct Options {
+    memory: Option<CoreDef>,
+    realloc: Option<CoreDef>,
+    post_return: Option<CoreDef>,
+}
+
+impl Options {
+    fn new(scope: &Scope, options: &[CanonicalOption]) -> Result<Self> {
+        let mut result = Options {
+            memory: None,
+            realloc: None,
+            post_return: None,
+        };
+        for option in options {
+            match option {
+                CanonicalOption::UTF8 => {}
+                CanonicalOption::UTF16 | CanonicalOption::CompactUTF16 => {
+                    return Err(anyhow!("only UTF-8 strings are supported"))
+                }
+                CanonicalOption::Memory(index) => {
+                    result.memory = Some(scope.core_def(ExternalKind::Memory, *index)?)
+                }
+                CanonicalOption::Realloc(index) => {
+                    result.realloc = Some(scope.core_def(ExternalKind::Func, *index)?)
+                }
+                CanonicalOption::PostReturn(index) => {
+                    result.post_return = Some(scope.core_def(ExternalKind::Func, *index)?)
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::component::abi::{FlatType, Type};
+
+    fn plan_wat(wat: &str) -> Result<Plan> {
+        plan(&wat::parse_str(wat).unwrap())
+    }
+
+    const COMMAND: &str = r#"
+        (component
+          (import "wasi:cli/environment@0.2.0" (instance $env
+            (export "get-arguments" (func (result (list string))))
+          ))
+          (import "wasi:cli/exit@0.2.0" (instance $exit
+            (export "exit" (func (param "status" (result))))
+          ))
+          (core module $mem
+            (memory (export "memory") 1)
+            (global $next (mut i32) (i32.const 1024))
+            (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+              (local $ptr i32)
+              (local.set $ptr (global.get $next))
+              (global.set $next (i32.add (local.get $ptr) (local.get 3)))
+              (local.get $ptr)))
+          (core instance $mem (instantiate $mem))
+          (alias core export $mem "memory" (core memory $memory))
+          (alias core export $mem "realloc" (core func $realloc))
+          (alias export $env "get-arguments" (func $get-arguments))
+          (core func $get-arguments (canon lower (func $get-arguments) (memory $memory) (realloc $realloc)))
+          (alias export $exit "exit" (func $exit))
+          (core func $exit (canon lower (func $exit)))
+          (core instance $host
+            (export "get-arguments" (func $get-arguments))
+            (export "exit" (func $exit)))
+          (core module $main
+            (import "host" "get-arguments" (func $get-arguments (param i32)))
+            (import "host" "exit" (func $exit (param i32)))
+            (import "mem" "memory" (memory 1))
+            (func (export "run") (result i32)
+              (call $get-arguments (i32.const 0))
+              (i32.const 0)))
+          (core instance $main (instantiate $main
+            (with "host" (instance $host))
+            (with "mem" (instance $mem))))
+          (func $run (result (result)) (canon lift (core func $main "run")))
+          (instance $run (export "run" (func $run)))
+          (export "wasi:cli/run@0.2.0" (instance $run))
+        )
+    "#;
+
+    #[test]
+    fn plans_core_instances_and_lowerings() {
+        let plan = plan_wat(COMMAND).unwrap();
+        assert_eq!(plan.modules.len(), 2);
+        assert!(matches!(
+            plan.instances[0],
+            CoreInstance::Instantiate { module: 0, ref imports } if imports.is_empty()
+        ));
+        let CoreInstance::Items(items) = &plan.instances[1] else {
+            panic!("expected an instance of items");
+        };
+        assert_eq!(
+            items,
+            &[
+                ("get-arguments".to_string(), CoreDef::Lowered(0)),
+                ("exit".to_string(), CoreDef::Lowered(1)),
+            ]
+        );
+        let CoreInstance::Instantiate { module, imports } = &plan.instances[2] else {
+            panic!("expected an instantiation");
+        };
+        assert_eq!(*module, 1);
+        assert_eq!(imports, &[("host".to_string(), 1), ("mem".to_string(), 0)]);
+
+        let lowering = &plan.lowerings[0];
+        assert_eq!(lowering.interface, "wasi:cli/environment@0.2.0");
+        assert_eq!(lowering.name, "get-arguments");
+        assert_eq!(lowering.ty.results, [Type::List(Box::new(Type::String))]);
+        let export = |instance, name: &str| CoreDef::Export {
+            instance,
+            name: name.to_string(),
+        };
+        assert_eq!(lowering.memory, Some(export(0, "memory")));
+        assert_eq!(lowering.realloc, Some(export(0, "realloc")));
+        assert_eq!(plan.lowerings[1].memory, None);
+
+        assert_eq!(plan.run.func, export(2, "run"));
+        assert_eq!(
+            plan.run.ty.results,
+            [Type::Variant(vec![
+                ("ok".to_string(), None),
+                ("error".to_string(), None)
+            ])]
+        );
+    }
+
+    #[test]
+    fn instantiates_nested_components() {
+        let plan = plan_wat(
+            r#"
+            (component $outer
+              (import "wasi:cli/exit@0.2.0" (instance $exit
+                (export "exit" (func (param "status" (result))))
+              ))
+              (core module $m
+                (import "host" "exit" (func (param i32)))
+                (func (export "run") (result i32) (i32.const 1)))
+              (component $inner
+                (import "exit" (instance $exit
+                  (export "exit" (func (param "status" (result))))
+                ))
+                (alias outer $outer $m (core module $m))
+                (alias export $exit "exit" (func $exit))
+                (core func $exit (canon lower (func $exit)))
+                (core instance $host (export "exit" (func $exit)))
+                (core instance $m (instantiate $m (with "host" (instance $host))))
+                (func $run (result (result)) (canon lift (core func $m "run")))
+                (instance $run (export "run" (func $run)))
+                (export "run" (instance $run))
+              )
+              (instance $inner (instantiate $inner (with "exit" (instance $exit))))
+              (alias export $inner "run" (instance $run))
+              (export "wasi:cli/run@0.2.0" (instance $run))
+            )
+            "#,
+        )
+        .unwrap();
+        assert_eq!(plan.modules.len(), 1);
+        assert_eq!(plan.instances.len(), 2);
+        assert!(matches!(
+            &plan.instances[1],
+            CoreInstance::Instantiate { module: 0, imports } if imports == &[("host".to_string(), 0)]
+        ));
+        assert_eq!(plan.lowerings[0].interface, "wasi:cli/exit@0.2.0");
+        assert_eq!(
+            plan.run.func,
+            CoreDef::Export {
+                instance: 1,
+                name: "run".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn plans_resource_handles_and_drops() {
+        let plan = plan_wat(
+            r#"
+            (component
+                (import "wasi:io/error@0.2.0" (instance $io-error
+                  (export "error" (type (sub resource)))))
+                (alias export $io-error "error" (type $error))
+                (import "wasi:io/streams@0.2.0" (instance $streams
+                  (alias outer 1 $error (type $e))
+                  (export "error" (type $err (eq $e)))
+                  (export "output-stream" (type $os (sub resource)))
+                  (type $se (variant (case "last-operation-failed" (own $err)) (case "closed")))
+                  (export "stream-error" (type $stream-error (eq $se)))
+                  (export "[method]output-stream.blocking-write-and-flush"
+                    (func (param "self" (borrow $os)) (param "contents" (list u8))
+                      (result (result (error $stream-error)))))))
+                (alias export $streams "output-stream" (type $output-stream))
+                (import "wasi:cli/stdout@0.2.0" (instance $stdout
+                  (alias outer 1 $output-stream (type $o))
+                  (export "output-stream" (type $os (eq $o)))
+                  (export "get-stdout" (func (result (own $os))))))
+                (core module $mem
+                  (memory (export "memory") 1)
+                  (data (i32.const 16) "hello\n"))
+                (core instance $mem (instantiate $mem))
+                (alias core export $mem "memory" (core memory $memory))
+                (alias export $stdout "get-stdout" (func $get-stdout))
+                (core func $get-stdout (canon lower (func $get-stdout)))
+                (alias export $streams "[method]output-stream.blocking-write-and-flush" (func $write))
+                (core func $write (canon lower (func $write) (memory $memory)))
+                (core func $drop (canon resource.drop $output-stream))
+                (core instance $host
+                  (export "get-stdout" (func $get-stdout))
+                  (export "write" (func $write))
+                  (export "drop" (func $drop)))
+                (core module $main
+                  (import "host" "get-stdout" (func $get-stdout (result i32)))
+                  (import "host" "write" (func $write (param i32 i32 i32 i32)))
+                  (import "host" "drop" (func $drop (param i32)))
+                  (import "mem" "memory" (memory 1))
+                  (func (export "run") (result i32)
+                    (local $stdout i32)
+                    (local.set $stdout (call $get-stdout))
+                    (call $write (local.get $stdout) (i32.const 16) (i32.const 6) (i32.const 32))
+                    (call $drop (local.get $stdout))
+                    ;; ok if the write succeeded
+                    (i32.load8_u (i32.const 32))))
+                (core instance $main (instantiate $main
+                  (with "host" (instance $host))
+                  (with "mem" (instance $mem))))
+                (func $run (result (result)) (canon lift (core func $main "run")))
+                (instance $run (export "run" (func $run)))
+                (export "wasi:cli/run@0.2.0" (instance $run))
+            )
+            "#,
+        )
+        .unwrap();
+        let CoreInstance::Items(items) = &plan.instances[1] else {
+            panic!("expected an instance of items");
+        };
+        assert_eq!(items[2], ("drop".to_string(), CoreDef::ResourceDrop));
+        let write = &plan.lowerings[1];
+        assert_eq!(write.interface, "wasi:io/streams@0.2.0");
+        assert_eq!(write.name, "[method]output-stream.blocking-write-and-flush");
+        // the handle, the list and the pointer to the result
+        assert_eq!(write.ty.lowered_params(), [FlatType::I32; 4]);
+        assert_eq!(plan.lowerings[0].ty.results, [Type::Handle]);
+    }
+
+    #[test]
+    fn rejects_components_which_are_not_commands() {
+        let error = plan_wat("(component)").err().unwrap();
+        assert!(error.to_string().contains("not a command"), "{}", error);
+        let error = plan_wat(r#"(component (import "run" (func)))"#)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("not an instance"), "{}", error);
+    }
+}
@@ -0,0 +1,114 @@
+// Virtual clocks and seeded randomness for reproducible runs
+// Time starts at a fixed date, and only advances when the module reads the clocks or sleeps.
+
+use cap_rand::{rngs::StdRng, SeedableRng};
+use cap_std::time::{Duration, Instant, SystemTime};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use wasi_common::{
+    sched::Poll, Error, RngCore, WasiClocks, WasiMonotonicClock, WasiSched, WasiSystemClock,
+};
+
+/// Time of the system clock when the module starts (2000-01-01T00:00:00Z)
+const START_TIME: Duration = Duration::from_secs(946_684_800);
+/// Time by which the clocks advance on every read, so that loops waiting for the clocks terminate
+const TICK: Duration = Duration::from_micros(1);
+
+/// Clock shared by the system clock and the monotonic clock
+#[derive(Clone)]
+struct FakeClock {
+    elapsed_nanos: Arc<AtomicU64>,
+    /// Instant which the monotonic clock is based on, which is invisible to the module
+    base: Instant,
+}
+
+impl FakeClock {
+    fn new() -> Self {
+        FakeClock {
+            elapsed_nanos: Arc::new(AtomicU64::new(0)),
+            base: Instant::from_std(std::time::Instant::now()),
+        }
+    }
+
+    /// Returns the time since the module started, and advances it by a tick
+    fn tick(&self) -> Duration {
+        let elapsed = self
+            .elapsed_nanos
+            .fetch_add(TICK.as_nanos() as u64, Ordering::Relaxed);
+        Duration::from_nanos(elapsed)
+    }
+
+    fn advance(&self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.elapsed_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+impl WasiSystemClock for FakeClock {
+    fn resolution(&self) -> Duration {
+        TICK
+    }
+
+    fn now(&self, _precision: Duration) -> SystemTime {
+        SystemTime::from_std(std::time::UNIX_EPOCH + START_TIME + self.tick())
+    }
+}
+
+impl WasiMonotonicClock for FakeClock {
+    fn resolution(&self) -> Duration {
+        TICK
+    }
+
+    fn now(&self, _precision: Duration) -> Instant {
+        self.base + self.tick()
+    }
+}
+
+/// Scheduler which sleeps by advancing the fake clock instead of waiting
+struct FakeSched {
+    clock: FakeClock,
+}
+
+#[wiggle::async_trait]
+impl WasiSched for FakeSched {
+    async fn poll_oneoff<'a>(&self, poll: &mut Poll<'a>) -> Result<(), Error> {
+        // jump to the earliest deadline, so that the timeout has expired when the poll returns
+        if let Some(subscription) = poll.earliest_clock_deadline() {
+            if let Some(duration) = subscription.duration_until() {
+                self.clock.advance(duration);
+            }
+        }
+        // files and sockets are still polled on the host
+        if poll.rw_subscriptions().next().is_some() {
+            wasi_common::sync::sched::poll_oneoff(poll).await
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn sched_yield(&self) -> Result<(), Error> {
+        std::thread::yield_now();
+        Ok(())
+    }
+
+    async fn sleep(&self, duration: Duration) -> Result<(), Error> {
+        self.clock.advance(duration);
+        Ok(())
+    }
+}
+
+/// Returns fake clocks and the scheduler which advances them
+pub(super) fn fake_clocks() -> (WasiClocks, Box<dyn WasiSched>) {
+    let clock = FakeClock::new();
+    let clocks = WasiClocks::new()
+        .with_system(clock.clone())
+        .with_monotonic(clock.clone());
+    (clocks, Box::new(FakeSched { clock }))
+}
+
+/// Returns a random number generator which generates the same bytes for the same seed
+pub(super) fn seeded_random(seed: u64) -> Box<dyn RngCore + Send + Sync> {
+    Box::new(StdRng::seed_from_u64(seed))
+}
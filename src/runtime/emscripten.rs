@@ -0,0 +1,269 @@
+// `env` functions imported by modules built with Emscripten in standalone mode (-sSTANDALONE_WASM)
+// Most of libc is implemented by the module on top of WASI, and these are the few functions which
+// would otherwise be given by the JS glue of Emscripten.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{get_or_create_import_module, set_function, wasi};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Maximum size of the heap, which is the default maximum of Emscripten for wasm32 (2 GiB)
+const HEAP_MAX: f64 = 2_147_483_648.0;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+pub(super) fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub(super) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Adds the functions to `imports.env`
+pub(super) fn create_emscripten_import<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    import_object: &v8::Local<'a, v8::Object>,
+) {
+    let import_env = get_or_create_import_module(scope, import_object, "env");
+    set_function(
+        scope,
+        import_env,
+        "emscripten_notify_memory_growth",
+        emscripten_notify_memory_growth,
+    );
+    // memcpy of large sizes has been renamed across versions of Emscripten
+    set_function(
+        scope,
+        import_env,
+        "emscripten_memcpy_big",
+        emscripten_memcpy_js,
+    );
+    set_function(
+        scope,
+        import_env,
+        "emscripten_memcpy_js",
+        emscripten_memcpy_js,
+    );
+    set_function(
+        scope,
+        import_env,
+        "_emscripten_memcpy_js",
+        emscripten_memcpy_js,
+    );
+    set_function(
+        scope,
+        import_env,
+        "emscripten_resize_heap",
+        emscripten_resize_heap,
+    );
+    set_function(
+        scope,
+        import_env,
+        "emscripten_get_heap_max",
+        emscripten_get_heap_max,
+    );
+    set_function(
+        scope,
+        import_env,
+        "emscripten_date_now",
+        emscripten_date_now,
+    );
+    set_function(scope, import_env, "emscripten_get_now", emscripten_get_now);
+    set_function(
+        scope,
+        import_env,
+        "_emscripten_throw_longjmp",
+        emscripten_throw_longjmp,
+    );
+}
+
+/// Returns the argument as an address or a size, which is a BigInt in memory64 modules
+fn arg_u64(scope: &mut v8::HandleScope, args: &v8::FunctionCallbackArguments, index: i32) -> u64 {
+    let arg = args.get(index);
+    if arg.is_big_int() {
+        arg.to_big_int(scope).unwrap().u64_value().0
+    } else {
+        // addresses above 2 GiB are passed as negative i32
+        arg.int32_value(scope).unwrap_or_default() as u32 as u64
+    }
+}
+
+/// emscripten_notify_memory_growth(memory_index)
+///
+/// WASI functions look up `gMemory.buffer` on every call, so there is nothing to refresh.
+fn emscripten_notify_memory_growth(
+    _scope: &mut v8::HandleScope,
+    _args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+}
+
+/// emscripten_memcpy_js(dest, src, num)
+fn emscripten_memcpy_js(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    let dest = arg_u64(scope, &args, 0);
+    let src = arg_u64(scope, &args, 1);
+    let num = arg_u64(scope, &args, 2);
+    let backing_store = match wasi::get_backing_store_from_scope(scope) {
+        Ok(backing_store) => backing_store,
+        Err(e) => {
+            wasi::throw_error(scope, &e.to_string());
+            return;
+        }
+    };
+    if !copy_within(wasi::memory_bytes(&backing_store), dest, src, num) {
+        wasi::throw_error(scope, "emscripten_memcpy_js: memory access out of bounds");
+    }
+}
+
+/// Copies `num` bytes from `src` to `dest`, or returns false if either range is out of bounds
+fn copy_within(memory: &mut [u8], dest: u64, src: u64, num: u64) -> bool {
+    let len = memory.len() as u64;
+    if dest.saturating_add(num) > len || src.saturating_add(num) > len {
+        return false;
+    }
+    let (dest, src, num) = (dest as usize, src as usize, num as usize);
+    memory.copy_within(src..src + num, dest);
+    true
+}
+
+/// emscripten_resize_heap(requested_size), which returns 1 if the memory has grown to the size
+fn emscripten_resize_heap(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let requested_size = arg_u64(scope, &args, 0);
+    let memory64 = args.get(0).is_big_int();
+    rv.set(v8::Integer::new(scope, 0).into());
+
+    let context = scope.get_current_context();
+    let global = context.global(scope);
+    let str_memory = v8::String::new(scope, "gMemory").unwrap();
+    let Some(memory) = global
+        .get(scope, str_memory.into())
+        .filter(|memory| memory.is_object())
+        .and_then(|memory| memory.to_object(scope))
+    else {
+        return;
+    };
+    let Ok(backing_store) = wasi::get_backing_store_from_scope(scope) else {
+        return;
+    };
+    let old_size = backing_store.byte_length() as u64;
+    let Some(delta) = pages_to_grow(old_size, requested_size, memory64) else {
+        return;
+    };
+    if delta == 0 {
+        rv.set(v8::Integer::new(scope, 1).into());
+        return;
+    }
+
+    // memory.grow(delta), which throws RangeError if the memory cannot grow
+    let delta: v8::Local<v8::Value> = if memory64 {
+        v8::BigInt::new_from_u64(scope, delta).into()
+    } else {
+        v8::Number::new(scope, delta as f64).into()
+    };
+    let str_grow = v8::String::new(scope, "grow").unwrap();
+    let grow = memory.get(scope, str_grow.into()).unwrap();
+    let grow = grow.cast::<v8::Function>();
+    let scope = &mut v8::TryCatch::new(scope);
+    if grow.call(scope, memory.into(), &[delta]).is_some() {
+        rv.set(v8::Integer::new(scope, 1).into());
+    }
+}
+
+/// Returns the number of pages by which the memory grows to the requested size, or None if the
+/// size is above the maximum heap of wasm32
+fn pages_to_grow(old_size: u64, requested_size: u64, memory64: bool) -> Option<u64> {
+    if requested_size <= old_size {
+        return Some(0);
+    }
+    if requested_size as f64 > HEAP_MAX && !memory64 {
+        return None;
+    }
+    Some((requested_size - old_size).div_ceil(WASM_PAGE_SIZE))
+}
+
+/// emscripten_get_heap_max()
+fn emscripten_get_heap_max(
+    scope: &mut v8::HandleScope,
+    _args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    rv.set(v8::Number::new(scope, HEAP_MAX).into());
+}
+
+/// emscripten_date_now(), which returns milliseconds since the epoch like `Date.now()`
+fn emscripten_date_now(
+    scope: &mut v8::HandleScope,
+    _args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    rv.set(v8::Number::new(scope, wasi::clock_millis(false)).into());
+}
+
+/// emscripten_get_now(), which returns milliseconds of the monotonic clock like `performance.now()`
+fn emscripten_get_now(
+    scope: &mut v8::HandleScope,
+    _args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    rv.set(v8::Number::new(scope, wasi::clock_millis(true)).into());
+}
+
+/// _emscripten_throw_longjmp(), which is imported by modules using the JS-based setjmp/longjmp
+fn emscripten_throw_longjmp(
+    scope: &mut v8::HandleScope,
+    _args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    wasi::throw_error(
+        scope,
+        "longjmp requires the JS glue of Emscripten (build with -sSUPPORT_LONGJMP=wasm instead)",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copies_overlapping_ranges() {
+        let mut memory = *b"abcdefgh";
+        assert!(copy_within(&mut memory, 2, 0, 4));
+        assert_eq!(&memory, b"ababcdgh");
+        assert!(copy_within(&mut memory, 0, 4, 4));
+        assert_eq!(&memory, b"cdghcdgh");
+        assert!(copy_within(&mut memory, 8, 0, 0));
+    }
+
+    #[test]
+    fn rejects_copies_out_of_bounds() {
+        let mut memory = *b"abcdefgh";
+        assert!(!copy_within(&mut memory, 5, 0, 4));
+        assert!(!copy_within(&mut memory, 0, 5, 4));
+        // addresses of memory64 modules may wrap around
+        assert!(!copy_within(&mut memory, u64::MAX, 0, 2));
+        assert!(!copy_within(&mut memory, 0, 1, u64::MAX));
+        assert_eq!(&memory, b"abcdefgh");
+    }
+
+    #[test]
+    fn grows_by_whole_pages_up_to_the_heap_max() {
+        assert_eq!(pages_to_grow(65536, 1000, false), Some(0));
+        assert_eq!(pages_to_grow(65536, 65536, false), Some(0));
+        assert_eq!(pages_to_grow(65536, 65537, false), Some(1));
+        assert_eq!(pages_to_grow(65536, 3 * 65536, false), Some(2));
+        assert_eq!(pages_to_grow(65536, 1 << 31, false), Some(32767));
+        assert_eq!(pages_to_grow(65536, (1 << 31) + 1, false), None);
+        // the heap of memory64 modules has no maximum of its own
+        assert_eq!(pages_to_grow(65536, (1 << 32) + 65536, true), Some(65536));
+    }
+}
@@ -0,0 +1,669 @@
+// Contents of a wasm module printed by `lv8 inspect`
+
+use anyhow::{anyhow, Result};
+use std::{fmt::Write as _, path::Path};
+use wasmparser::{
+    BinaryReader, CompositeInnerType, ExternalKind, GlobalType, KnownCustom, MemoryType, Parser,
+    Payload, TableType, TypeRef, ValType,
+};
+
+use super::trace::json_string;
+use crate::driver::Cli;
+
+/// Type of an import or an export
+enum Extern {
+    Func {
+        params: Vec<ValType>,
+        results: Vec<ValType>,
+    },
+    Table(TableType),
+    Memory(MemoryType),
+    Global(GlobalType),
+    Tag {
+        params: Vec<ValType>,
+    },
+}
+
+struct Import {
+    module: String,
+    name: String,
+    ty: Extern,
+    /// Whether lv8 provides the import when instantiating the module
+    provided: bool,
+}
+
+struct Export {
+    name: String,
+    ty: Extern,
+}
+
+/// Producer of the module (e.g. `language: Rust`), from the producers section
+struct Producer {
+    field: String,
+    name: String,
+    version: String,
+}
+
+#[derive(Default)]
+struct Inspection {
+    imports: Vec<Import>,
+    exports: Vec<Export>,
+    /// Tables defined by the module
+    tables: Vec<TableType>,
+    /// Memories defined by the module
+    memories: Vec<MemoryType>,
+    /// Globals defined by the module
+    globals: Vec<GlobalType>,
+    start: Option<u32>,
+    /// Names and sizes of custom sections
+    custom_sections: Vec<(String, usize)>,
+    producers: Vec<Producer>,
+    /// Features used (`+`), not used (`-`) or required (`=`), from the target_features section
+    target_features: Vec<(char, String)>,
+}
+
+/// Prints the imports, exports and other contents of the module
+///
+/// Imports are checked against the import object given with the options (`--emscripten` and
+/// `--assemblyscript`).
+pub fn inspect(args: &Cli, path: &Path, json: bool) -> Result<i32> {
+    let wasm_module =
+        std::fs::read(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+    if Parser::is_component(&wasm_module) {
+        return Err(anyhow!(
+            "{} is a component, but only core modules can be inspected",
+            path.display()
+        ));
+    }
+    let mut inspection = parse(&wasm_module)?;
+    check_imports(args, &mut inspection.imports);
+
+    let output = if json {
+        to_json(&inspection)
+    } else {
+        to_text(&inspection)
+    };
+    print!("{}", output);
+    Ok(0)
+}
+
+fn parse(wasm_module: &[u8]) -> Result<Inspection> {
+    let mut inspection = Inspection::default();
+    // function types, indexed by type index (other composite types are None)
+    let mut func_types: Vec<Option<(Vec<ValType>, Vec<ValType>)>> = vec![];
+    // index spaces, where imported items come first
+    let mut funcs: Vec<u32> = vec![];
+    let mut tables: Vec<TableType> = vec![];
+    let mut memories: Vec<MemoryType> = vec![];
+    let mut globals: Vec<GlobalType> = vec![];
+    let mut tags: Vec<u32> = vec![];
+    let mut exports = vec![];
+
+    let func_type = |func_types: &[Option<(Vec<ValType>, Vec<ValType>)>], index: u32| {
+        func_types
+            .get(index as usize)
+            .cloned()
+            .flatten()
+            .unwrap_or_default()
+    };
+
+    for payload in Parser::new(0).parse_all(wasm_module) {
+        match payload? {
+            Payload::TypeSection(reader) => {
+                for rec_group in reader {
+                    for sub_type in rec_group?.into_types() {
+                        func_types.push(match &sub_type.composite_type.inner {
+                            CompositeInnerType::Func(ty) => {
+                                Some((ty.params().to_vec(), ty.results().to_vec()))
+                            }
+                            _ => None,
+                        });
+                    }
+                }
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    let ty = match import.ty {
+                        TypeRef::Func(index) => {
+                            funcs.push(index);
+                            let (params, results) = func_type(&func_types, index);
+                            Extern::Func { params, results }
+                        }
+                        TypeRef::Table(ty) => {
+                            tables.push(ty);
+                            Extern::Table(ty)
+                        }
+                        TypeRef::Memory(ty) => {
+                            memories.push(ty);
+                            Extern::Memory(ty)
+                        }
+                        TypeRef::Global(ty) => {
+                            globals.push(ty);
+                            Extern::Global(ty)
+                        }
+                        TypeRef::Tag(ty) => {
+                            tags.push(ty.func_type_idx);
+                            let (params, _) = func_type(&func_types, ty.func_type_idx);
+                            Extern::Tag { params }
+                        }
+                    };
+                    inspection.imports.push(Import {
+                        module: import.module.to_string(),
+                        name: import.name.to_string(),
+                        ty,
+                        provided: false,
+                    });
+                }
+            }
+            Payload::FunctionSection(reader) => {
+                for index in reader {
+                    funcs.push(index?);
+                }
+            }
+            Payload::TableSection(reader) => {
+                for table in reader {
+                    let ty = table?.ty;
+                    tables.push(ty);
+                    inspection.tables.push(ty);
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for ty in reader {
+                    let ty = ty?;
+                    memories.push(ty);
+                    inspection.memories.push(ty);
+                }
+            }
+            Payload::GlobalSection(reader) => {
+                for global in reader {
+                    let ty = global?.ty;
+                    globals.push(ty);
+                    inspection.globals.push(ty);
+                }
+            }
+            Payload::TagSection(reader) => {
+                for ty in reader {
+                    tags.push(ty?.func_type_idx);
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    exports.push((export.name.to_string(), export.kind, export.index));
+                }
+            }
+            Payload::StartSection { func, .. } => inspection.start = Some(func),
+            Payload::CustomSection(reader) => {
+                inspection
+                    .custom_sections
+                    .push((reader.name().to_string(), reader.data().len()));
+                match reader.as_known() {
+                    KnownCustom::Producers(reader) => {
+                        for field in reader {
+                            let field = field?;
+                            for value in field.values {
+                                let value = value?;
+                                inspection.producers.push(Producer {
+                                    field: field.name.to_string(),
+                                    name: value.name.to_string(),
+                                    version: value.version.to_string(),
+                                });
+                            }
+                        }
+                    }
+                    _ if reader.name() == "target_features" => {
+                        inspection.target_features = parse_target_features(reader.data())?;
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // exports are resolved after all sections are read, since they may refer to later sections
+    for (name, kind, index) in exports {
+        let index = index as usize;
+        let ty = match kind {
+            ExternalKind::Func => {
+                let type_index = funcs.get(index).copied().unwrap_or(u32::MAX);
+                let (params, results) = func_type(&func_types, type_index);
+                Some(Extern::Func { params, results })
+            }
+            ExternalKind::Table => tables.get(index).copied().map(Extern::Table),
+            ExternalKind::Memory => memories.get(index).copied().map(Extern::Memory),
+            ExternalKind::Global => globals.get(index).copied().map(Extern::Global),
+            ExternalKind::Tag => tags.get(index).map(|&type_index| {
+                let (params, _) = func_type(&func_types, type_index);
+                Extern::Tag { params }
+            }),
+        };
+        let ty = ty.ok_or_else(|| anyhow!("Export {} refers to an undefined item", name))?;
+        inspection.exports.push(Export { name, ty });
+    }
+
+    Ok(inspection)
+}
+
+/// Decodes the target_features section written by LLVM, which is a vector of prefixed names
+fn parse_target_features(data: &[u8]) -> Result<Vec<(char, String)>> {
+    let mut reader = BinaryReader::new(data, 0);
+    let count = reader.read_var_u32()?;
+    let mut features = vec![];
+    for _ in 0..count {
+        let prefix = reader.read_u8()? as char;
+        let name = reader.read_string()?;
+        features.push((prefix, name.to_string()));
+    }
+    Ok(features)
+}
+
+/// Marks the imports which lv8 provides, by looking them up in the import object given to modules
+fn check_imports(args: &Cli, imports: &mut [Import]) {
+    super::init_v8(false);
+    let isolate = &mut v8::Isolate::new(Default::default());
+    let scope = &mut v8::HandleScope::new(isolate);
+    let context = v8::Context::new(scope, Default::default());
+    let scope = &mut v8::ContextScope::new(scope, context);
+
+    let import_object = v8::Object::new(scope);
+    super::create_wasip1_import(scope, &import_object);
+    super::create_wasi_threads_import(scope, &import_object);
+    super::create_wasi_nn_import(scope, &import_object);
+    if args.emscripten {
+        super::emscripten::create_emscripten_import(scope, &import_object);
+    }
+    if args.assemblyscript {
+        super::assemblyscript::create_assemblyscript_import(scope, &import_object);
+    }
+
+    for import in imports {
+        import.provided = match import.ty {
+            Extern::Func { .. } => {
+                let str_module = v8::String::new(scope, &import.module).unwrap();
+                let str_name = v8::String::new(scope, &import.name).unwrap();
+                import_object
+                    .get(scope, str_module.into())
+                    .filter(|module_object| module_object.is_object())
+                    .and_then(|module_object| module_object.to_object(scope))
+                    .and_then(|module_object| module_object.get(scope, str_name.into()))
+                    .is_some_and(|function| function.is_function())
+            }
+            // memories and tags are created from the import declarations
+            Extern::Memory(_) | Extern::Tag { .. } => true,
+            Extern::Table(_) | Extern::Global(_) => false,
+        };
+    }
+}
+
+fn types_to_string(types: &[ValType]) -> String {
+    let types: Vec<String> = types.iter().map(ValType::to_string).collect();
+    format!("({})", types.join(", "))
+}
+
+fn limits_to_string(initial: u64, maximum: Option<u64>, unit: &str) -> String {
+    match maximum {
+        Some(maximum) => format!("{} {}, maximum {}", initial, unit, maximum),
+        None => format!("{} {}, no maximum", initial, unit),
+    }
+}
+
+fn table_to_string(ty: &TableType) -> String {
+    let mut s = format!(
+        "{}, {}",
+        ty.element_type,
+        limits_to_string(ty.initial, ty.maximum, "elements")
+    );
+    if ty.table64 {
+        s.push_str(", table64");
+    }
+    s
+}
+
+fn memory_to_string(ty: &MemoryType) -> String {
+    let mut s = limits_to_string(ty.initial, ty.maximum, "pages");
+    if ty.memory64 {
+        s.push_str(", memory64");
+    }
+    if ty.shared {
+        s.push_str(", shared");
+    }
+    s
+}
+
+fn global_to_string(ty: &GlobalType) -> String {
+    if ty.mutable {
+        format!("mut {}", ty.content_type)
+    } else {
+        ty.content_type.to_string()
+    }
+}
+
+fn extern_to_string(ty: &Extern) -> String {
+    match ty {
+        Extern::Func { params, results } => format!(
+            "func {} -> {}",
+            types_to_string(params),
+            types_to_string(results)
+        ),
+        Extern::Table(ty) => format!("table {}", table_to_string(ty)),
+        Extern::Memory(ty) => format!("memory {}", memory_to_string(ty)),
+        Extern::Global(ty) => format!("global {}", global_to_string(ty)),
+        Extern::Tag { params } => format!("tag {}", types_to_string(params)),
+    }
+}
+
+fn to_text(inspection: &Inspection) -> String {
+    let mut s = String::new();
+    let _ = writeln!(s, "Imports:");
+    for import in &inspection.imports {
+        let _ = writeln!(
+            s,
+            "  {}.{}: {} [{}]",
+            import.module,
+            import.name,
+            extern_to_string(&import.ty),
+            if import.provided {
+                "provided"
+            } else {
+                "not provided"
+            }
+        );
+    }
+    let _ = writeln!(s, "Exports:");
+    for export in &inspection.exports {
+        let _ = writeln!(s, "  {}: {}", export.name, extern_to_string(&export.ty));
+    }
+    if !inspection.tables.is_empty() {
+        let _ = writeln!(s, "Tables:");
+        for (i, ty) in inspection.tables.iter().enumerate() {
+            let _ = writeln!(s, "  {}: {}", i, table_to_string(ty));
+        }
+    }
+    if !inspection.memories.is_empty() {
+        let _ = writeln!(s, "Memories:");
+        for (i, ty) in inspection.memories.iter().enumerate() {
+            let _ = writeln!(s, "  {}: {}", i, memory_to_string(ty));
+        }
+    }
+    if !inspection.globals.is_empty() {
+        let _ = writeln!(s, "Globals:");
+        for (i, ty) in inspection.globals.iter().enumerate() {
+            let _ = writeln!(s, "  {}: {}", i, global_to_string(ty));
+        }
+    }
+    if let Some(func) = inspection.start {
+        let _ = writeln!(s, "Start function: {}", func);
+    }
+    if !inspection.custom_sections.is_empty() {
+        let _ = writeln!(s, "Custom sections:");
+        for (name, size) in &inspection.custom_sections {
+            let _ = writeln!(s, "  {}: {} bytes", name, size);
+        }
+    }
+    if !inspection.producers.is_empty() {
+        let _ = writeln!(s, "Producers:");
+        for producer in &inspection.producers {
+            let line = format!(
+                "  {}: {} {}",
+                producer.field, producer.name, producer.version
+            );
+            let _ = writeln!(s, "{}", line.trim_end());
+        }
+    }
+    if !inspection.target_features.is_empty() {
+        let _ = writeln!(s, "Target features:");
+        for (prefix, name) in &inspection.target_features {
+            let _ = writeln!(s, "  {}{}", prefix, name);
+        }
+    }
+    s
+}
+
+fn types_to_json(types: &[ValType]) -> String {
+    let types: Vec<String> = types
+        .iter()
+        .map(|ty| json_string(&ty.to_string()))
+        .collect();
+    format!("[{}]", types.join(","))
+}
+
+fn maximum_to_json(maximum: Option<u64>) -> String {
+    maximum.map_or_else(|| "null".to_string(), |maximum| maximum.to_string())
+}
+
+fn table_to_json(ty: &TableType) -> String {
+    format!(
+        "{{\"element_type\":{},\"initial\":{},\"maximum\":{},\"table64\":{}}}",
+        json_string(&ty.element_type.to_string()),
+        ty.initial,
+        maximum_to_json(ty.maximum),
+        ty.table64
+    )
+}
+
+fn memory_to_json(ty: &MemoryType) -> String {
+    format!(
+        "{{\"initial\":{},\"maximum\":{},\"memory64\":{},\"shared\":{}}}",
+        ty.initial,
+        maximum_to_json(ty.maximum),
+        ty.memory64,
+        ty.shared
+    )
+}
+
+fn global_to_json(ty: &GlobalType) -> String {
+    format!(
+        "{{\"type\":{},\"mutable\":{}}}",
+        json_string(&ty.content_type.to_string()),
+        ty.mutable
+    )
+}
+
+/// Returns the kind and the type of the import or the export as JSON members
+fn extern_to_json(ty: &Extern) -> String {
+    let (kind, ty) = match ty {
+        Extern::Func { params, results } => (
+            "func",
+            format!(
+                "{{\"params\":{},\"results\":{}}}",
+                types_to_json(params),
+                types_to_json(results)
+            ),
+        ),
+        Extern::Table(ty) => ("table", table_to_json(ty)),
+        Extern::Memory(ty) => ("memory", memory_to_json(ty)),
+        Extern::Global(ty) => ("global", global_to_json(ty)),
+        Extern::Tag { params } => ("tag", format!("{{\"params\":{}}}", types_to_json(params))),
+    };
+    format!("\"kind\":\"{}\",\"type\":{}", kind, ty)
+}
+
+fn to_json(inspection: &Inspection) -> String {
+    let imports: Vec<String> = inspection
+        .imports
+        .iter()
+        .map(|import| {
+            format!(
+                "{{\"module\":{},\"name\":{},{},\"provided\":{}}}",
+                json_string(&import.module),
+                json_string(&import.name),
+                extern_to_json(&import.ty),
+                import.provided
+            )
+        })
+        .collect();
+    let exports: Vec<String> = inspection
+        .exports
+        .iter()
+        .map(|export| {
+            format!(
+                "{{\"name\":{},{}}}",
+                json_string(&export.name),
+                extern_to_json(&export.ty)
+            )
+        })
+        .collect();
+    let tables: Vec<String> = inspection.tables.iter().map(table_to_json).collect();
+    let memories: Vec<String> = inspection.memories.iter().map(memory_to_json).collect();
+    let globals: Vec<String> = inspection.globals.iter().map(global_to_json).collect();
+    let start = inspection
+        .start
+        .map_or_else(|| "null".to_string(), |func| func.to_string());
+    let custom_sections: Vec<String> = inspection
+        .custom_sections
+        .iter()
+        .map(|(name, size)| format!("{{\"name\":{},\"size\":{}}}", json_string(name), size))
+        .collect();
+    let producers: Vec<String> = inspection
+        .producers
+        .iter()
+        .map(|producer| {
+            format!(
+                "{{\"field\":{},\"name\":{},\"version\":{}}}",
+                json_string(&producer.field),
+                json_string(&producer.name),
+                json_string(&producer.version)
+            )
+        })
+        .collect();
+    let target_features: Vec<String> = inspection
+        .target_features
+        .iter()
+        .map(|(prefix, name)| {
+            format!(
+                "{{\"prefix\":{},\"name\":{}}}",
+                json_string(&prefix.to_string()),
+                json_string(name)
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"imports\":[{}],\"exports\":[{}],\"tables\":[{}],\"memories\":[{}],\"globals\":[{}],\"start\":{},\"custom_sections\":[{}],\"producers\":[{}],\"target_features\":[{}]}}\n",
+        imports.join(","),
+        exports.join(","),
+        tables.join(","),
+        memories.join(","),
+        globals.join(","),
+        start,
+        custom_sections.join(","),
+        producers.join(","),
+        target_features.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE: &str = r#"
+        (module
+          (@producers (language "Rust" "1.80.0") (processed-by "rustc" ""))
+          (@custom "target_features" "\02\2b\0bbulk-memory\2d\07simd128")
+          (type (func (param i32 i32 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "fd_write" (func (type 0)))
+          (import "env" "table" (table 1 funcref))
+          (import "env" "counter" (global (mut i64)))
+          (tag (param i32))
+          (memory (export "memory") 1 16)
+          (table 2 10 externref)
+          (global (mut i32) (i32.const 1024))
+          (func)
+          (func (export "add") (param f32 f32) (result f32)
+            (f32.add (local.get 0) (local.get 1)))
+          (start 1)
+          (export "sp" (global 1))
+          (export "error" (tag 0)))
+    "#;
+
+    fn inspection() -> Inspection {
+        let mut inspection = parse(&wat::parse_str(MODULE).unwrap()).unwrap();
+        // check_imports needs V8, so the first import is marked as if it were provided
+        inspection.imports[0].provided = true;
+        inspection
+    }
+
+    #[test]
+    fn prints_contents_as_text() {
+        assert_eq!(
+            to_text(&inspection()),
+            "Imports:
+  wasi_snapshot_preview1.fd_write: func (i32, i32, i32, i32) -> (i32) [provided]
+  env.table: table funcref, 1 elements, no maximum [not provided]
+  env.counter: global mut i64 [not provided]
+Exports:
+  memory: memory 1 pages, maximum 16
+  add: func (f32, f32) -> (f32)
+  sp: global mut i32
+  error: tag (i32)
+Tables:
+  0: externref, 2 elements, maximum 10
+Memories:
+  0: 1 pages, maximum 16
+Globals:
+  0: mut i32
+Start function: 1
+Custom sections:
+  producers: 44 bytes
+  target_features: 23 bytes
+Producers:
+  language: Rust 1.80.0
+  processed-by: rustc
+Target features:
+  +bulk-memory
+  -simd128
+"
+        );
+    }
+
+    #[test]
+    fn prints_contents_as_json() {
+        let json = to_json(&inspection());
+        assert!(json.starts_with(
+            "{\"imports\":[{\"module\":\"wasi_snapshot_preview1\",\"name\":\"fd_write\",\"kind\":\"func\",\"type\":{\"params\":[\"i32\",\"i32\",\"i32\",\"i32\"],\"results\":[\"i32\"]},\"provided\":true},"
+        ));
+        assert!(json.contains(
+            "{\"name\":\"memory\",\"kind\":\"memory\",\"type\":{\"initial\":1,\"maximum\":16,\"memory64\":false,\"shared\":false}}"
+        ));
+        assert!(json.contains(
+            "\"tables\":[{\"element_type\":\"externref\",\"initial\":2,\"maximum\":10,\"table64\":false}]"
+        ));
+        assert!(json.contains("\"start\":1,"));
+        assert!(json.contains("{\"field\":\"processed-by\",\"name\":\"rustc\",\"version\":\"\"}"));
+        assert!(json.ends_with(
+            "\"target_features\":[{\"prefix\":\"+\",\"name\":\"bulk-memory\"},{\"prefix\":\"-\",\"name\":\"simd128\"}]}\n"
+        ));
+    }
+
+    #[test]
+    fn prints_limits_of_64_bit_and_shared_memories() {
+        let wasm_module =
+            wat::parse_str("(module (memory i64 1) (memory 1 2 shared) (table i64 0 funcref))")
+                .unwrap();
+        let inspection = parse(&wasm_module).unwrap();
+        assert_eq!(
+            memory_to_string(&inspection.memories[0]),
+            "1 pages, no maximum, memory64"
+        );
+        assert_eq!(
+            memory_to_string(&inspection.memories[1]),
+            "1 pages, maximum 2, shared"
+        );
+        assert_eq!(
+            table_to_string(&inspection.tables[0]),
+            "funcref, 0 elements, no maximum, table64"
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_target_features() {
+        assert_eq!(
+            parse_target_features(b"\x01\x2b\x04simd").unwrap(),
+            [('+', "simd".to_string())]
+        );
+        assert!(parse_target_features(b"\x02\x2b\x04simd").is_err());
+        assert!(parse(b"\0asm\x01\0\0\0\x01").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+// Asynchronous host calls via JS Promise Integration (JSPI)
+// WASI functions return promises, and the wasm stack is suspended until the event loop resolves them.
+
+use anyhow::Result;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+use tokio::{
+    runtime::Runtime as TokioRuntime,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Runtime which runs host calls in the background
+static TOKIO_RUNTIME: OnceLock<TokioRuntime> = OnceLock::new();
+
+thread_local! {
+    /// Event loop of the isolate running on this thread
+    static EVENT_LOOP: RefCell<EventLoop> = RefCell::new(EventLoop::new());
+}
+
+/// Result of a host call, identified by the ID of its promise
+type Completion = (u64, Result<i32>);
+
+struct EventLoop {
+    sender: UnboundedSender<Completion>,
+    receiver: UnboundedReceiver<Completion>,
+    pending: HashMap<u64, v8::Global<v8::PromiseResolver>>,
+    next_id: u64,
+}
+
+impl EventLoop {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        EventLoop {
+            sender,
+            receiver,
+            pending: HashMap::new(),
+            next_id: 0,
+        }
+    }
+}
+
+/// Backing store of the memory, which is accessed by a host call in the background
+pub(super) struct SendBackingStore(pub v8::SharedRef<v8::BackingStore>);
+
+// SAFETY: the wasm stack which owns the memory is suspended until the host call completes
+unsafe impl Send for SendBackingStore {}
+
+impl SendBackingStore {
+    pub fn get(&self) -> &v8::SharedRef<v8::BackingStore> {
+        &self.0
+    }
+}
+
+pub(super) fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub(super) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Runs the host call in the background, and returns a promise resolved with its result
+pub(super) fn spawn<'s, F>(
+    scope: &mut v8::HandleScope<'s>,
+    host_call: F,
+) -> v8::Local<'s, v8::Promise>
+where
+    F: FnOnce() -> Result<i32> + Send + 'static,
+{
+    let resolver = v8::PromiseResolver::new(scope).unwrap();
+    let promise = resolver.get_promise(scope);
+    let resolver = v8::Global::new(scope, resolver);
+
+    let (id, sender) = EVENT_LOOP.with_borrow_mut(|event_loop| {
+        let id = event_loop.next_id;
+        event_loop.next_id += 1;
+        event_loop.pending.insert(id, resolver);
+        (id, event_loop.sender.clone())
+    });
+
+    let tokio_runtime = TOKIO_RUNTIME.get_or_init(|| TokioRuntime::new().unwrap());
+    tokio_runtime.spawn_blocking(move || {
+        let result = host_call();
+        // the receiver is alive as long as the thread of the isolate is alive
+        let _ = sender.send((id, result));
+    });
+
+    promise
+}
+
+/// Wraps every function of the import module by `WebAssembly.Suspending`
+pub(super) fn wrap_imports<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    import_module: &v8::Local<'a, v8::Object>,
+) {
+    let suspending_ctor = get_webassembly_function(scope, "Suspending");
+    let names = import_module
+        .get_own_property_names(scope, Default::default())
+        .unwrap();
+    for i in 0..names.length() {
+        let name = names.get_index(scope, i).unwrap();
+        let function = import_module.get(scope, name).unwrap();
+        // new WebAssembly.Suspending(function)
+        let suspending = suspending_ctor.new_instance(scope, &[function]).unwrap();
+        import_module.set(scope, name, suspending.into());
+    }
+}
+
+/// Calls the exported function wrapped by `WebAssembly.promising`, and runs the event loop
+/// until the returned promise settles
+///
+/// Returns the exception if the function throws.
+pub(super) fn call_promising<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    function: v8::Local<'s, v8::Function>,
+    recv: v8::Local<'s, v8::Value>,
+    args: &[v8::Local<'s, v8::Value>],
+) -> Result<v8::Local<'s, v8::Value>, v8::Local<'s, v8::Value>> {
+    let scope = &mut v8::TryCatch::new(scope);
+
+    // WebAssembly.promising(function)(...args)
+    let promising = get_webassembly_function(scope, "promising");
+    let undefined = v8::undefined(scope);
+    let Some(function) = promising.call(scope, undefined.into(), &[function.into()]) else {
+        return Err(scope.exception().unwrap());
+    };
+    let function = function.cast::<v8::Function>();
+    let Some(promise) = function.call(scope, recv, args) else {
+        // the execution is terminated without an exception by proc_exit
+        return Err(scope
+            .exception()
+            .unwrap_or_else(|| v8::undefined(scope).into()));
+    };
+    let promise = promise.cast::<v8::Promise>();
+
+    loop {
+        scope.perform_microtask_checkpoint();
+        // proc_exit called after the wasm stack is resumed terminates the execution, which leaves
+        // the promise pending
+        if scope.has_terminated() {
+            return Err(v8::undefined(scope).into());
+        }
+        match promise.state() {
+            v8::PromiseState::Fulfilled => return Ok(promise.result(scope)),
+            v8::PromiseState::Rejected => return Err(promise.result(scope)),
+            v8::PromiseState::Pending => {}
+        }
+
+        let completion = EVENT_LOOP.with_borrow_mut(|event_loop| {
+            if event_loop.pending.is_empty() {
+                None
+            } else {
+                event_loop.receiver.blocking_recv()
+            }
+        });
+        let Some((id, result)) = completion else {
+            let message =
+                v8::String::new(scope, "Wasm module is suspended with no pending host call")
+                    .unwrap();
+            return Err(v8::Exception::error(scope, message));
+        };
+
+        let resolver = EVENT_LOOP
+            .with_borrow_mut(|event_loop| event_loop.pending.remove(&id))
+            .unwrap();
+        let resolver = v8::Local::new(scope, &resolver);
+        match result {
+            Ok(errno) => {
+                let errno = v8::Integer::new(scope, errno);
+                resolver.resolve(scope, errno.into());
+            }
+            Err(e) => {
+                let message = v8::String::new(scope, &e.to_string()).unwrap();
+                let exception = v8::Exception::error(scope, message);
+                resolver.reject(scope, exception);
+            }
+        }
+    }
+}
+
+/// Returns `WebAssembly[name]`
+fn get_webassembly_function<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    name: &str,
+) -> v8::Local<'a, v8::Function> {
+    let context = scope.get_current_context();
+    let global = context.global(scope);
+    let str_wasm = v8::String::new(scope, "WebAssembly").unwrap();
+    let global_wasm = global
+        .get(scope, str_wasm.into())
+        .unwrap()
+        .to_object(scope)
+        .unwrap();
+    let str_name = v8::String::new(scope, name).unwrap();
+    let function = global_wasm.get(scope, str_name.into()).unwrap();
+    function.cast::<v8::Function>()
+}
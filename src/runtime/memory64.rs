@@ -0,0 +1,560 @@
+// WASI calls of memory64 modules (wasm64-wasi)
+//
+// Pointers and sizes are 64-bit, and so are the fields of iovecs and prestats. wasi-common only
+// addresses 32-bit memories with the wasm32 layouts, so the arguments are copied into a scratch
+// memory with the wasm32 layouts before the call, and the results are copied back after it.
+
+use wasi_common::WasiCtx;
+
+/// EFAULT in wasi_snapshot_preview1
+const ERRNO_FAULT: i32 = 21;
+
+/// Maximum number of bytes read or written by a call, beyond which the call transfers less bytes
+/// than requested, like a short read or write
+const MAX_IO_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Parameter of a WASI function
+#[derive(Clone, Copy)]
+enum Param {
+    /// Passed as is (e.g. fds, flags and offsets)
+    Value,
+    /// Length of a buffer or an array given by another parameter
+    Size,
+    /// Bytes read by the function, whose length is the parameter at the index (e.g. paths)
+    In(usize),
+    /// Bytes written by the function, whose length is the parameter at the index
+    Out(usize),
+    /// Array of ciovecs, whose length is the parameter at the index
+    Ciovecs(usize),
+    /// Array of iovecs, whose length is the parameter at the index
+    Iovecs(usize),
+    /// Array of structs read by the function, whose length is the parameter at the index
+    /// (the layouts are the same on wasm32 and wasm64)
+    InStructs(usize, u64),
+    /// Array of structs written by the function
+    OutStructs(usize, u64),
+    /// Value of the size written by the function, which does not depend on the pointer width
+    OutFixed(u64),
+    /// size_t written by the function
+    OutSize,
+    /// prestat written by the function
+    OutPrestat,
+    /// Array of pointers to the strings of the buffer given by the parameter at the index
+    OutPointers(usize, Strings),
+    /// Buffer of the strings
+    OutStrings(Strings),
+}
+
+#[derive(Clone, Copy)]
+enum Strings {
+    Args,
+    Env,
+}
+
+use Param::*;
+
+/// Returns the parameters of the function of wasi_snapshot_preview1
+fn params(name: &str) -> &'static [Param] {
+    match name {
+        "args_get" => &[OutPointers(1, Strings::Args), OutStrings(Strings::Args)],
+        "environ_get" => &[OutPointers(1, Strings::Env), OutStrings(Strings::Env)],
+        "args_sizes_get" | "environ_sizes_get" => &[OutSize, OutSize],
+        "clock_res_get" => &[Value, OutFixed(8)],
+        "clock_time_get" => &[Value, Value, OutFixed(8)],
+        "fd_fdstat_get" => &[Value, OutFixed(24)],
+        "fd_filestat_get" => &[Value, OutFixed(64)],
+        "fd_pread" => &[Value, Iovecs(2), Size, Value, OutSize],
+        "fd_prestat_get" => &[Value, OutPrestat],
+        "fd_prestat_dir_name" => &[Value, Out(2), Size],
+        "fd_pwrite" => &[Value, Ciovecs(2), Size, Value, OutSize],
+        "fd_read" => &[Value, Iovecs(2), Size, OutSize],
+        "fd_readdir" => &[Value, Out(2), Size, Value, OutSize],
+        "fd_seek" => &[Value, Value, Value, OutFixed(8)],
+        "fd_tell" => &[Value, OutFixed(8)],
+        "fd_write" => &[Value, Ciovecs(2), Size, OutSize],
+        "path_create_directory" | "path_remove_directory" | "path_unlink_file" => {
+            &[Value, In(2), Size]
+        }
+        "path_filestat_get" => &[Value, Value, In(3), Size, OutFixed(64)],
+        "path_filestat_set_times" => &[Value, Value, In(3), Size, Value, Value, Value],
+        "path_link" => &[Value, Value, In(3), Size, Value, In(6), Size],
+        "path_open" => &[
+            Value,
+            Value,
+            In(3),
+            Size,
+            Value,
+            Value,
+            Value,
+            Value,
+            OutFixed(4),
+        ],
+        "path_readlink" => &[Value, In(2), Size, Out(4), Size, OutSize],
+        "path_rename" => &[Value, In(2), Size, Value, In(5), Size],
+        "path_symlink" => &[In(1), Size, Value, In(4), Size],
+        "poll_oneoff" => &[InStructs(2, 48), OutStructs(2, 32), Size, OutSize],
+        "random_get" => &[Out(1), Size],
+        "sock_accept" => &[Value, Value, OutFixed(4)],
+        "sock_recv" => &[Value, Iovecs(2), Size, Value, OutSize, OutFixed(2)],
+        "sock_send" => &[Value, Ciovecs(2), Size, Value, OutSize],
+        // e.g. fd_close, which takes no pointers
+        _ => &[],
+    }
+}
+
+/// Returns whether the call is made with 64-bit pointers, which are passed as BigInt
+///
+/// `bigints` tells whether each argument is a BigInt. Arguments which are not pointers or sizes
+/// (e.g. offsets) are i64 on wasm32 as well, so only pointers and sizes are looked at.
+pub(super) fn is_memory64_call(name: &str, bigints: &[bool]) -> bool {
+    params(name)
+        .iter()
+        .zip(bigints)
+        .any(|(param, bigint)| !matches!(param, Value) && *bigint)
+}
+
+/// Bytes copied from the scratch memory to the memory of the module after the call
+enum CopyBack {
+    Bytes {
+        scratch: u32,
+        guest: u64,
+        len: u32,
+    },
+    /// size_t, which is widened from u32 to u64
+    Size {
+        scratch: u32,
+        guest: u64,
+    },
+    /// prestat of 8 bytes, which is widened to 16 bytes
+    Prestat {
+        scratch: u32,
+        guest: u64,
+    },
+    /// Pointers into the buffer, which are rebased onto the buffer in the memory of the module
+    Pointers {
+        scratch: u32,
+        guest: u64,
+        count: u32,
+        scratch_buf: u32,
+        guest_buf: u64,
+    },
+}
+
+/// Arguments of a call translated for the scratch memory
+pub(super) struct Translation {
+    /// Arguments of the call, where pointers are offsets into `scratch`
+    pub args: Vec<i64>,
+    pub scratch: Vec<u8>,
+    copy_backs: Vec<CopyBack>,
+}
+
+impl Translation {
+    /// Copies the arguments of the call into a scratch memory
+    ///
+    /// Returns the errno if a pointer is out of bounds of the memory.
+    pub fn new(name: &str, args: &[i64], memory: &[u8], wasi_ctx: &WasiCtx) -> Result<Self, i32> {
+        let params = params(name);
+        let mut translation = Translation {
+            args: args.to_vec(),
+            // offset 0 is left unused, so that null pointers are not valid
+            scratch: vec![0; 8],
+            copy_backs: vec![],
+        };
+        // offsets in the scratch memory of the parameters which are pointers
+        let mut scratch_offsets = vec![0u32; args.len()];
+        let arg = |index: usize| args.get(index).map_or(0, |arg| *arg as u64);
+
+        for (index, param) in params.iter().enumerate() {
+            let ptr = arg(index);
+            let offset = match *param {
+                Value | Size => continue,
+                In(len) => translation.copy_in(memory, ptr, arg(len))?,
+                Out(len) => {
+                    let offset = translation.copy_in(memory, ptr, arg(len))?;
+                    translation.copy_back_bytes(offset, ptr, arg(len));
+                    offset
+                }
+                Ciovecs(len) | Iovecs(len) => {
+                    let write = matches!(param, Iovecs(_));
+                    translation.copy_iovecs(memory, ptr, arg(len), write)?
+                }
+                InStructs(len, size) => {
+                    translation.copy_in(memory, ptr, arg(len).saturating_mul(size))?
+                }
+                OutStructs(len, size) => {
+                    let size = arg(len).saturating_mul(size);
+                    let offset = translation.copy_in(memory, ptr, size)?;
+                    translation.copy_back_bytes(offset, ptr, size);
+                    offset
+                }
+                OutFixed(size) => {
+                    let offset = translation.copy_in(memory, ptr, size)?;
+                    translation.copy_back_bytes(offset, ptr, size);
+                    offset
+                }
+                OutSize => {
+                    check_bounds(memory, ptr, 8)?;
+                    let offset = translation.alloc(4)?;
+                    translation.copy_backs.push(CopyBack::Size {
+                        scratch: offset,
+                        guest: ptr,
+                    });
+                    offset
+                }
+                OutPrestat => {
+                    check_bounds(memory, ptr, 16)?;
+                    let offset = translation.alloc(8)?;
+                    translation.copy_backs.push(CopyBack::Prestat {
+                        scratch: offset,
+                        guest: ptr,
+                    });
+                    offset
+                }
+                OutPointers(_, strings) => {
+                    let count = string_array(wasi_ctx, strings).number_elements();
+                    check_bounds(memory, ptr, count as u64 * 8)?;
+                    translation.alloc(count as u64 * 4)?
+                }
+                OutStrings(strings) => {
+                    let size = string_array(wasi_ctx, strings).cumulative_size() as u64;
+                    let offset = translation.copy_in(memory, ptr, size)?;
+                    translation.copy_back_bytes(offset, ptr, size);
+                    offset
+                }
+            };
+            scratch_offsets[index] = offset;
+            translation.args[index] = offset as i64;
+        }
+
+        // pointers of args_get and environ_get refer to the buffer, which is translated after them
+        for (index, param) in params.iter().enumerate() {
+            if let OutPointers(buf, strings) = *param {
+                translation.copy_backs.push(CopyBack::Pointers {
+                    scratch: scratch_offsets[index],
+                    guest: arg(index),
+                    count: string_array(wasi_ctx, strings).number_elements(),
+                    scratch_buf: scratch_offsets[buf],
+                    guest_buf: arg(buf),
+                });
+            }
+        }
+
+        // sizes are narrowed to u32 for wasi-common, which checks them against the scratch memory
+        for (index, param) in params.iter().enumerate() {
+            if let Size = param {
+                if arg(index) > u32::MAX as u64 {
+                    return Err(ERRNO_FAULT);
+                }
+            }
+        }
+        Ok(translation)
+    }
+
+    /// Copies the results in the scratch memory to the memory of the module
+    ///
+    /// This must be called only if the call succeeds, since the outputs are not written otherwise.
+    pub fn copy_back(&self, memory: &mut [u8]) {
+        let scratch = &self.scratch;
+        for copy_back in &self.copy_backs {
+            match *copy_back {
+                CopyBack::Bytes {
+                    scratch: offset,
+                    guest,
+                    len,
+                } => {
+                    let source = &scratch[offset as usize..][..len as usize];
+                    memory[guest as usize..][..len as usize].copy_from_slice(source);
+                }
+                CopyBack::Size {
+                    scratch: offset,
+                    guest,
+                } => {
+                    let size = read_u32(scratch, offset as u64) as u64;
+                    memory[guest as usize..][..8].copy_from_slice(&size.to_le_bytes());
+                }
+                CopyBack::Prestat {
+                    scratch: offset,
+                    guest,
+                } => {
+                    // prestat { tag: u8, pr_name_len: size }
+                    let tag = scratch[offset as usize];
+                    let name_len = read_u32(scratch, offset as u64 + 4) as u64;
+                    let prestat = &mut memory[guest as usize..][..16];
+                    prestat.fill(0);
+                    prestat[0] = tag;
+                    prestat[8..].copy_from_slice(&name_len.to_le_bytes());
+                }
+                CopyBack::Pointers {
+                    scratch: offset,
+                    guest,
+                    count,
+                    scratch_buf,
+                    guest_buf,
+                } => {
+                    for i in 0..count as u64 {
+                        let pointer = read_u32(scratch, offset as u64 + i * 4);
+                        let pointer = guest_buf + (pointer.wrapping_sub(scratch_buf)) as u64;
+                        memory[(guest + i * 8) as usize..][..8]
+                            .copy_from_slice(&pointer.to_le_bytes());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Allocates zeroed bytes aligned to 8 bytes in the scratch memory
+    fn alloc(&mut self, len: u64) -> Result<u32, i32> {
+        let offset = self.scratch.len() as u64;
+        let end = (offset + len).next_multiple_of(8);
+        if end > u32::MAX as u64 {
+            return Err(ERRNO_FAULT);
+        }
+        self.scratch.resize(end as usize, 0);
+        Ok(offset as u32)
+    }
+
+    /// Copies the bytes of the memory into the scratch memory
+    ///
+    /// Output buffers are copied as well, so that the bytes which are not written by the call are
+    /// left as they were when they are copied back.
+    fn copy_in(&mut self, memory: &[u8], ptr: u64, len: u64) -> Result<u32, i32> {
+        let bytes = read_bytes(memory, ptr, len)?;
+        let offset = self.alloc(len)?;
+        self.scratch[offset as usize..][..len as usize].copy_from_slice(bytes);
+        Ok(offset)
+    }
+
+    fn copy_back_bytes(&mut self, scratch: u32, guest: u64, len: u64) {
+        self.copy_backs.push(CopyBack::Bytes {
+            scratch,
+            guest,
+            len: len as u32,
+        });
+    }
+
+    /// Copies an array of iovecs of 16 bytes ({ buf: u64, buf_len: u64 }) and their buffers, and
+    /// returns the offset of the array of iovecs of 8 bytes
+    ///
+    /// Buffers beyond MAX_IO_SIZE in total are shortened, and are copied back if `write` is set.
+    fn copy_iovecs(&mut self, memory: &[u8], ptr: u64, len: u64, write: bool) -> Result<u32, i32> {
+        let iovecs = read_bytes(memory, ptr, len.saturating_mul(16))?;
+        let offset = self.alloc(len * 8)?;
+        let mut remaining = MAX_IO_SIZE;
+        for (i, iovec) in iovecs.chunks_exact(16).enumerate() {
+            let buf = u64::from_le_bytes(iovec[..8].try_into().unwrap());
+            let buf_len = u64::from_le_bytes(iovec[8..].try_into().unwrap());
+            let buf_len = buf_len.min(remaining);
+            remaining -= buf_len;
+            let buf_offset = self.copy_in(memory, buf, buf_len)?;
+            if write {
+                self.copy_back_bytes(buf_offset, buf, buf_len);
+            }
+            let iovec_offset = offset as usize + i * 8;
+            self.scratch[iovec_offset..][..4].copy_from_slice(&buf_offset.to_le_bytes());
+            self.scratch[iovec_offset + 4..][..4].copy_from_slice(&(buf_len as u32).to_le_bytes());
+        }
+        Ok(offset)
+    }
+}
+
+fn string_array(wasi_ctx: &WasiCtx, strings: Strings) -> &wasi_common::StringArray {
+    match strings {
+        Strings::Args => &wasi_ctx.args,
+        Strings::Env => &wasi_ctx.env,
+    }
+}
+
+fn read_bytes(memory: &[u8], ptr: u64, len: u64) -> Result<&[u8], i32> {
+    let end = ptr.checked_add(len).ok_or(ERRNO_FAULT)?;
+    if end > memory.len() as u64 {
+        return Err(ERRNO_FAULT);
+    }
+    Ok(&memory[ptr as usize..end as usize])
+}
+
+fn check_bounds(memory: &[u8], ptr: u64, len: u64) -> Result<(), i32> {
+    read_bytes(memory, ptr, len).map(|_| ())
+}
+
+fn read_u32(memory: &[u8], offset: u64) -> u32 {
+    u32::from_le_bytes(memory[offset as usize..][..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, RwLock};
+    use wasi_common::pipe::{ReadPipe, WritePipe};
+    use wasi_common::snapshots::preview_1::wasi_snapshot_preview1 as preview1;
+    use wasi_common::sync::{clocks_ctx, random_ctx, sched_ctx};
+    use wasi_common::Table;
+    use wiggle::GuestMemory;
+
+    const GIB: u64 = 1 << 30;
+
+    fn wasi_ctx() -> WasiCtx {
+        WasiCtx::new(random_ctx(), clocks_ctx(), sched_ctx(), Table::new())
+    }
+
+    fn write_u64(memory: &mut [u8], offset: u64, value: u64) {
+        memory[offset as usize..][..8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn read_u64(memory: &[u8], offset: u64) -> u64 {
+        u64::from_le_bytes(memory[offset as usize..][..8].try_into().unwrap())
+    }
+
+    /// Translates the arguments, calls the function with the scratch memory and copies back
+    fn call<F>(name: &str, args: &[i64], memory: &mut [u8], ctx: &mut WasiCtx, f: F) -> i32
+    where
+        F: FnOnce(&mut WasiCtx, &mut GuestMemory, &[i32]) -> i32,
+    {
+        let mut translation = Translation::new(name, args, memory, ctx).unwrap();
+        let args: Vec<i32> = translation.args.iter().map(|arg| *arg as i32).collect();
+        let errno = f(
+            ctx,
+            &mut GuestMemory::Unshared(&mut translation.scratch),
+            &args,
+        );
+        if errno == 0 {
+            translation.copy_back(memory);
+        }
+        errno
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn detects_pointers_passed_as_bigint() {
+        assert!(is_memory64_call("fd_write", &[false, true, true, true]));
+        assert!(!is_memory64_call("fd_write", &[false, false, false, false]));
+        // offsets are i64 on wasm32 as well
+        assert!(!is_memory64_call("fd_seek", &[false, true, false, false]));
+        assert!(!is_memory64_call("fd_close", &[false]));
+    }
+
+    #[test]
+    #[ignore = "allocates more than 4 GiB of memory"]
+    fn fd_write_above_4gib() {
+        // the pages of the memory are not touched except for those written below
+        let mut memory = vec![0u8; (4 * GIB + 65536) as usize];
+        let data = 4 * GIB + 0x100;
+        let iovs = 4 * GIB + 0x200;
+        let nwritten = 4 * GIB + 0x300;
+        memory[data as usize..][..6].copy_from_slice(b"hello\n");
+        write_u64(&mut memory, iovs, data);
+        write_u64(&mut memory, iovs + 8, 6);
+        write_u64(&mut memory, nwritten, u64::MAX);
+
+        let stdout: Arc<RwLock<Vec<u8>>> = Default::default();
+        let mut ctx = wasi_ctx();
+        ctx.set_stdout(Box::new(WritePipe::from_shared(stdout.clone())));
+        let args = [1, iovs as i64, 1, nwritten as i64];
+        let errno = call(
+            "fd_write",
+            &args,
+            &mut memory,
+            &mut ctx,
+            |ctx, memory, args| {
+                block_on(preview1::fd_write(
+                    ctx, memory, args[0], args[1], args[2], args[3],
+                ))
+                .unwrap()
+            },
+        );
+
+        assert_eq!(errno, 0);
+        assert_eq!(stdout.read().unwrap().as_slice(), b"hello\n");
+        // nwritten is a 64-bit size
+        assert_eq!(read_u64(&memory, nwritten), 6);
+    }
+
+    #[test]
+    fn fd_read_copies_back_iovecs() {
+        let mut memory = vec![0u8; 4096];
+        memory[256..264].fill(0xaa);
+        write_u64(&mut memory, 16, 256);
+        write_u64(&mut memory, 24, 8);
+
+        let mut ctx = wasi_ctx();
+        ctx.set_stdin(Box::new(ReadPipe::from("abc")));
+        let errno = call(
+            "fd_read",
+            &[0, 16, 1, 64],
+            &mut memory,
+            &mut ctx,
+            |ctx, memory, args| {
+                block_on(preview1::fd_read(
+                    ctx, memory, args[0], args[1], args[2], args[3],
+                ))
+                .unwrap()
+            },
+        );
+
+        assert_eq!(errno, 0);
+        assert_eq!(read_u64(&memory, 64), 3);
+        assert_eq!(&memory[256..259], b"abc");
+        // bytes which are not read are left as they were
+        assert_eq!(&memory[259..264], [0xaa; 5]);
+    }
+
+    #[test]
+    fn args_get_rebases_pointers() {
+        let mut memory = vec![0u8; 4096];
+        let mut ctx = wasi_ctx();
+        ctx.push_arg("this.wasm").unwrap();
+        ctx.push_arg("-v").unwrap();
+
+        let errno = call(
+            "args_sizes_get",
+            &[8, 16],
+            &mut memory,
+            &mut ctx,
+            |ctx, memory, args| {
+                block_on(preview1::args_sizes_get(ctx, memory, args[0], args[1])).unwrap()
+            },
+        );
+        assert_eq!(errno, 0);
+        assert_eq!(read_u64(&memory, 8), 2);
+        assert_eq!(read_u64(&memory, 16), 13);
+
+        let errno = call(
+            "args_get",
+            &[1024, 2048],
+            &mut memory,
+            &mut ctx,
+            |ctx, memory, args| {
+                block_on(preview1::args_get(ctx, memory, args[0], args[1])).unwrap()
+            },
+        );
+        assert_eq!(errno, 0);
+        assert_eq!(read_u64(&memory, 1024), 2048);
+        assert_eq!(read_u64(&memory, 1032), 2058);
+        assert_eq!(&memory[2048..2061], b"this.wasm\0-v\0");
+    }
+
+    #[test]
+    fn widens_prestat() {
+        let mut memory = vec![0xffu8; 64];
+        let mut translation =
+            Translation::new("fd_prestat_get", &[3, 16], &memory, &wasi_ctx()).unwrap();
+        let offset = translation.args[1] as usize;
+        // prestat of the dir "/data" on wasm32
+        translation.scratch[offset..offset + 8].copy_from_slice(&[0, 0, 0, 0, 5, 0, 0, 0]);
+        translation.copy_back(&mut memory);
+        assert_eq!(&memory[16..24], [0; 8]);
+        assert_eq!(read_u64(&memory, 24), 5);
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_pointers() {
+        let memory = vec![0u8; 4096];
+        let ctx = wasi_ctx();
+        let result = Translation::new("fd_write", &[1, 4090, 1, 0], &memory, &ctx);
+        assert_eq!(result.err(), Some(ERRNO_FAULT));
+        let result = Translation::new("path_open", &[3, 0, -1, 4, 0, 0, 0, 0, 0], &memory, &ctx);
+        assert_eq!(result.err(), Some(ERRNO_FAULT));
+    }
+}
@@ -1,9 +1,39 @@
+mod assemblyscript;
+mod component;
+mod deterministic;
+mod emscripten;
+mod inspect;
+mod jspi;
+mod memory64;
+mod module;
+mod nn;
+mod policy;
+mod preload;
+mod repl;
+mod run_js;
+mod threads;
+mod trace;
+mod validate;
+mod vfs;
 mod wasi;
 
 use anyhow::{anyhow, Result};
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use module::{MemoryImport, ModuleInfo};
+use wasmparser::ValType;
 
 use crate::driver::{self, Cli};
 
+pub use inspect::inspect;
+pub use repl::repl;
+pub use run_js::run_js;
+pub use validate::validate;
+pub use vfs::{Change, MemFs};
+
 macro_rules! import_wasi_function {
     ($scope:expr, $import_wasi_p1:expr, $import_name:expr, $fn_name:ident) => {
         let $fn_name = v8::FunctionTemplate::new($scope, wasi::$fn_name);
@@ -13,24 +43,147 @@ macro_rules! import_wasi_function {
     };
 }
 
+macro_rules! import_nn_function {
+    ($scope:expr, $import_nn:expr, $import_name:expr, $fn_name:ident) => {
+        let $fn_name = v8::FunctionTemplate::new($scope, nn::$fn_name);
+        let $fn_name = $fn_name.get_function($scope).unwrap();
+        let value_name = v8::String::new($scope, $import_name).unwrap().into();
+        $import_nn.set($scope, value_name, $fn_name.into());
+    };
+}
+
+/// Buffer which captures the output of the module
+pub type OutputBuffer = Arc<RwLock<Vec<u8>>>;
+
+/// Options given by the embedder in addition to the command line options
+#[derive(Default)]
+pub struct Embedding {
+    /// In-memory directories preopened at the guest paths
+    pub mem_mounts: Vec<(String, MemFs)>,
+    /// Bytes read by the module from stdin
+    pub stdin: Option<Vec<u8>>,
+    /// Buffer to which the module writes stdout
+    pub stdout: Option<OutputBuffer>,
+    /// Buffer to which the module writes stderr
+    pub stderr: Option<OutputBuffer>,
+}
+
 pub fn run(args: &Cli) -> Result<i32> {
-    init_v8();
-    let mut runtime = create_runtime(args)?;
-    runtime.run()
+    run_embedded(args, Embedding::default())
+}
+
+pub fn run_embedded(args: &Cli, embedding: Embedding) -> Result<i32> {
+    let wasmfile_path = args
+        .wasmfile_path
+        .as_deref()
+        .ok_or_else(|| anyhow!("No wasm file is given"))?;
+    init_runtime(args)?;
+    let mem_mounts = wasi::init_wasi_ctx(args, embedding)?;
+    let mut runtime = create_runtime(args, wasmfile_path)?;
+    let result = runtime.run();
+
+    // export in-memory directories even if the module traps, which helps to see what went wrong
+    if let Some(path) = &args.mem_export {
+        for (guest_path, fs) in &mem_mounts.mem {
+            fs.export(&path.join(guest_path.trim_start_matches('/')))?;
+        }
+    }
+    for overlay in &mem_mounts.overlays {
+        report_overlay_changes(overlay, args.overlay_export.as_deref())?;
+    }
+    result
+}
+
+/// Initializes V8 and the features enabled by the command line options, which must be done
+/// before the module is instantiated
+fn init_runtime(args: &Cli) -> Result<()> {
+    init_v8(args.jspi);
+    if args.jspi {
+        jspi::enable();
+    }
+    if args.emscripten {
+        emscripten::enable();
+    }
+    if args.assemblyscript {
+        assemblyscript::enable();
+    }
+    trace::init(args)?;
+    policy::init(args)?;
+    preload::init(args)?;
+    Ok(())
+}
+
+/// Prints the files and directories changed in the overlay, and writes added or modified ones to
+/// `export_dir`
+fn report_overlay_changes(overlay: &vfs::Overlay, export_dir: Option<&Path>) -> Result<()> {
+    let changes = overlay.diff();
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!(
+        "Changes in {} (overlay of {}):",
+        overlay.guest_path,
+        overlay.host_dir.display()
+    );
+    for (path, change) in &changes {
+        eprintln!("{} {}", change, path);
+    }
+
+    if let Some(export_dir) = export_dir {
+        let export_dir = export_dir.join(overlay.guest_path.trim_start_matches('/'));
+        for (path, change) in &changes {
+            if *change == Change::Deleted {
+                continue;
+            }
+            let host_path = export_dir.join(path.trim_start_matches('/'));
+            if path.ends_with('/') {
+                std::fs::create_dir_all(&host_path)?;
+                continue;
+            }
+            let data = overlay.fs.read_file(path).unwrap_or_default();
+            if let Some(parent) = host_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&host_path, data)?;
+        }
+    }
+    Ok(())
 }
 
 struct Runtime {
     isolate: v8::OwnedIsolate,
-    wasm_instance: v8::Global<v8::Object>,
+    context: v8::Global<v8::Context>,
+    instance: Instance,
+}
+
+enum Instance {
+    /// Instance of a core module
+    Module {
+        instance: v8::Global<v8::Object>,
+        tags: Vec<TagInfo>,
+    },
+    /// Core instances of a WASI Preview 2 command component
+    Component(component::Command),
+}
+
+/// Tag which is imported or exported by the module, and is set to `gTags[name]`
+struct TagInfo {
+    name: String,
+    arity: usize,
 }
 
 impl Runtime {
     fn run(&mut self) -> Result<i32> {
         let scope = &mut v8::HandleScope::new(&mut self.isolate);
-        let context = v8::Context::new(scope, Default::default());
+        // enter the context where the module was instantiated, so that gTags is visible
+        let context = v8::Local::new(scope, &self.context);
         let scope = &mut v8::ContextScope::new(scope, context);
 
-        let wasm_instance = self.wasm_instance.open(scope);
+        let (wasm_instance, tags) = match &self.instance {
+            Instance::Module { instance, tags } => (instance.open(scope), tags),
+            Instance::Component(command) => return command.run(scope),
+        };
 
         let str_exports = v8::String::new(scope, "exports").unwrap();
         let exports = wasm_instance.get(scope, str_exports.into()).unwrap();
@@ -43,7 +196,19 @@ impl Runtime {
         let start = start.cast::<v8::Function>();
 
         // call instance.exports._start()
-        let ret = start.call(scope, exports.into(), &[]).unwrap();
+        let ret = match call_export(scope, start, exports.into(), &[]) {
+            Ok(ret) => ret,
+            Err(exception) => {
+                // proc_exit in any thread terminates the execution of the main thread
+                if let Some(code) = wasi::exit_code() {
+                    return Ok(code);
+                }
+                if let Some(error) = threads::thread_error() {
+                    return Err(anyhow!(error));
+                }
+                return Err(anyhow!(describe_exception(scope, exception, tags)));
+            }
+        };
         if ret.type_repr() == "undefined" {
             Ok(0)
         } else if ret.type_repr() == "number" {
@@ -61,55 +226,475 @@ impl Runtime {
     }
 }
 
-fn init_v8() {
+/// Calls a function exported by the module, and returns the exception if it throws
+///
+/// With JSPI, the function is called through `WebAssembly.promising` and the event loop runs
+/// until it returns.
+fn call_export<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    function: v8::Local<'s, v8::Function>,
+    recv: v8::Local<'s, v8::Value>,
+    args: &[v8::Local<'s, v8::Value>],
+) -> Result<v8::Local<'s, v8::Value>, v8::Local<'s, v8::Value>> {
+    if jspi::is_enabled() {
+        return jspi::call_promising(scope, function, recv, args);
+    }
+
+    let scope = &mut v8::TryCatch::new(scope);
+    match function.call(scope, recv, args) {
+        Some(ret) => Ok(ret),
+        // the execution is terminated without an exception by proc_exit
+        None => Err(scope
+            .exception()
+            .unwrap_or_else(|| v8::undefined(scope).into())),
+    }
+}
+
+/// Describes an exception thrown out of the wasm module
+///
+/// Exceptions thrown by wasm `throw` are described with their tag and payload.
+fn describe_exception<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    exception: v8::Local<'s, v8::Value>,
+    tags: &[TagInfo],
+) -> String {
+    let context = scope.get_current_context();
+    let global = context.global(scope);
+    let str_wasm = v8::String::new(scope, "WebAssembly").unwrap();
+    let global_wasm = global
+        .get(scope, str_wasm.into())
+        .unwrap()
+        .to_object(scope)
+        .unwrap();
+    let str_exception = v8::String::new(scope, "Exception").unwrap();
+    let exception_ctor = global_wasm
+        .get(scope, str_exception.into())
+        .unwrap()
+        .to_object(scope)
+        .unwrap();
+    if exception.instance_of(scope, exception_ctor) != Some(true) {
+        // e.g. RuntimeError on traps
+        return exception.to_rust_string_lossy(scope);
+    }
+
+    let exception = exception.to_object(scope).unwrap();
+    let str_is = v8::String::new(scope, "is").unwrap();
+    let is = exception.get(scope, str_is.into()).unwrap();
+    let is = is.cast::<v8::Function>();
+    let str_get_arg = v8::String::new(scope, "getArg").unwrap();
+    let get_arg = exception.get(scope, str_get_arg.into()).unwrap();
+    let get_arg = get_arg.cast::<v8::Function>();
+
+    let str_gtags = v8::String::new(scope, "gTags").unwrap();
+    let gtags = global
+        .get(scope, str_gtags.into())
+        .unwrap()
+        .to_object(scope)
+        .unwrap();
+    for tag in tags {
+        let str_name = v8::String::new(scope, &tag.name).unwrap();
+        let tag_object = gtags.get(scope, str_name.into()).unwrap();
+
+        // exception.is(tag)
+        let is_tag = is.call(scope, exception.into(), &[tag_object]);
+        if !is_tag.is_some_and(|is_tag| is_tag.is_true()) {
+            continue;
+        }
+
+        // exception.getArg(tag, index)
+        let mut payload = vec![];
+        for index in 0..tag.arity {
+            let index = v8::Integer::new(scope, index as i32);
+            let arg = get_arg.call(scope, exception.into(), &[tag_object, index.into()]);
+            payload
+                .push(arg.map_or_else(|| "?".to_string(), |arg| arg.to_rust_string_lossy(scope)));
+        }
+        return format!(
+            "Uncaught wasm exception with tag {} and payload [{}]",
+            tag.name,
+            payload.join(", ")
+        );
+    }
+
+    // tags which are neither imported nor exported are not visible outside of the module
+    "Uncaught wasm exception with an unknown tag".to_string()
+}
+
+fn init_v8(jspi: bool) {
+    // enable exnref variant of the exception handling proposal, and memory64
+    v8::V8::set_flags_from_string("--experimental-wasm-exnref --experimental-wasm-memory64");
+    // JSPI is still experimental, so it is enabled only when it is used
+    if jspi {
+        v8::V8::set_flags_from_string("--experimental-wasm-jspi");
+    }
+
     let platform = v8::new_default_platform(0, false).make_shared();
     v8::V8::initialize_platform(platform);
     v8::V8::initialize();
 }
 
-fn create_runtime(args: &driver::Cli) -> Result<Runtime> {
+fn create_runtime(args: &driver::Cli, wasmfile_path: &Path) -> Result<Runtime> {
     let mut isolate = v8::Isolate::new(Default::default());
-    let instance = {
+    let (context, instance) = {
         let scope = &mut v8::HandleScope::new(&mut isolate);
         let context = v8::Context::new(scope, Default::default());
         let scope = &mut v8::ContextScope::new(scope, context);
 
-        let wasm_module = std::fs::read(&args.wasmfile_path).expect("Failed to read file");
-
-        let module = v8::WasmModuleObject::compile(scope, &wasm_module).unwrap();
-
-        let import_object = v8::Object::new(scope);
-        let global = context.global(scope);
-        let str_wasm = v8::String::new(scope, "WebAssembly").unwrap();
-        let global_wasm = global
-            .get(scope, str_wasm.into())
-            .unwrap()
-            .to_object(scope)
-            .unwrap();
+        let wasm_module = std::fs::read(wasmfile_path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", wasmfile_path.display(), e))?;
+        if wasmparser::Parser::is_component(&wasm_module) {
+            if let Some(option) = component::unsupported_option(args) {
+                return Err(anyhow!("{} is not supported for components", option));
+            }
+            let command = component::instantiate(scope, &wasm_module)
+                .map_err(|e| anyhow!("Failed to instantiate {}: {}", wasmfile_path.display(), e))?;
+            (
+                v8::Global::new(scope, context),
+                Instance::Component(command),
+            )
+        } else {
+            let module = compile(scope, &wasm_module).map_err(|message| {
+                anyhow!("Failed to compile {}: {}", wasmfile_path.display(), message)
+            })?;
+            let module_info = ModuleInfo::parse(&wasm_module)?;
 
-        // prepare imports.wasi_snapshot_preview1
-        create_wasip1_import(scope, &import_object);
+            let wasi_memory = args.wasi_memory.as_deref();
+            let instance = instantiate(scope, module, &module_info, None, wasi_memory)?;
 
-        let str2 = v8::String::new(scope, "Instance").unwrap();
-        let instance_ctor = global_wasm.get(scope, str2.into()).unwrap();
-        let instance_ctor = instance_ctor.cast::<v8::Function>();
-        let instance = instance_ctor
-            .new_instance(scope, &[module.into(), import_object.into()])
-            .unwrap();
+            let imported_tags = module_info.tag_imports.iter().map(|tag| TagInfo {
+                name: format!("{}.{}", tag.module, tag.name),
+                arity: tag.params.len(),
+            });
+            let exported_tags = module_info.tag_exports.iter().map(|tag| TagInfo {
+                name: tag.name.clone(),
+                arity: tag.params.len(),
+            });
+            let tags = imported_tags.chain(exported_tags).collect();
 
-        // set instance to global
-        let str_ginstance = v8::String::new(scope, "gInstance").unwrap();
-        global.set(scope, str_ginstance.into(), instance.into());
+            // share the module and its memory with threads spawned by wasi.thread-spawn
+            if module_info.imports_function("wasi", "thread-spawn") {
+                threads::init(scope, module, module_info, args.wasi_memory.clone())?;
+            }
 
-        v8::Global::new(scope, instance)
+            (
+                v8::Global::new(scope, context),
+                Instance::Module {
+                    instance: v8::Global::new(scope, instance),
+                    tags,
+                },
+            )
+        }
     };
 
     Ok(Runtime {
         isolate,
-        wasm_instance: instance,
+        context,
+        instance,
     })
 }
 
+/// Compiles the module, or returns the message of the CompileError thrown by V8 if it is invalid
+fn compile<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    wasm_module: &[u8],
+) -> Result<v8::Local<'s, v8::WasmModuleObject>, String> {
+    let scope = &mut v8::TryCatch::new(scope);
+    if let Some(module) = v8::WasmModuleObject::compile(scope, wasm_module) {
+        return Ok(module);
+    }
+    let message = scope
+        .exception()
+        .and_then(|exception| exception.to_string(scope))
+        .map(|message| message.to_rust_string_lossy(scope))
+        .unwrap_or_else(|| "CompileError".to_string());
+    Err(message)
+}
+
+/// Instantiates the module in the current context, and sets the instance and its memory to global
+///
+/// If `shared_memory` is given, it is passed to the module instead of creating a new memory.
+/// If `wasi_memory` is given, the exported or imported memory of that name is used by WASI functions.
+fn instantiate<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    module: v8::Local<'a, v8::WasmModuleObject>,
+    module_info: &ModuleInfo,
+    shared_memory: Option<v8::Local<'a, v8::Object>>,
+    wasi_memory: Option<&str>,
+) -> Result<v8::Local<'a, v8::Object>> {
+    let context = scope.get_current_context();
+    let import_object = v8::Object::new(scope);
+    let global = context.global(scope);
+    let str_wasm = v8::String::new(scope, "WebAssembly").unwrap();
+    let global_wasm = global
+        .get(scope, str_wasm.into())
+        .unwrap()
+        .to_object(scope)
+        .unwrap();
+
+    // prepare imports.wasi_snapshot_preview1
+    create_wasip1_import(scope, &import_object);
+
+    // prepare imports.wasi (wasi-threads), which is only given to modules importing it, since
+    // thread-spawn needs a shared memory
+    if module_info.imports_function("wasi", "thread-spawn") {
+        create_wasi_threads_import(scope, &import_object);
+    }
+
+    // prepare imports.wasi_ephemeral_nn
+    create_wasi_nn_import(scope, &import_object);
+
+    // prepare imports.env of modules built with Emscripten
+    if emscripten::is_enabled() {
+        emscripten::create_emscripten_import(scope, &import_object);
+    }
+
+    // prepare imports.env of modules built with AssemblyScript
+    if assemblyscript::is_enabled() {
+        assemblyscript::create_assemblyscript_import(scope, &import_object);
+    }
+
+    // let --preload scripts add imports or wrap the ones above
+    preload::run(scope, &import_object)?;
+
+    // prepare tags imported by the module (e.g. env.__cpp_exception of C++ modules)
+    // gTags holds every tag known to the runtime, so that uncaught exceptions can be described
+    let tags = v8::Object::new(scope);
+    for tag_import in &module_info.tag_imports {
+        let tag = create_tag(scope, &global_wasm, &tag_import.params);
+        let module_object = get_or_create_import_module(scope, &import_object, &tag_import.module);
+        let str_name = v8::String::new(scope, &tag_import.name).unwrap();
+        module_object.set(scope, str_name.into(), tag.into());
+        let str_tag_name =
+            v8::String::new(scope, &format!("{}.{}", tag_import.module, tag_import.name)).unwrap();
+        tags.set(scope, str_tag_name.into(), tag.into());
+    }
+
+    // prepare memories imported by the module (e.g. env.memory of modules built with -pthread)
+    let mut imported_memories = vec![];
+    for memory_import in &module_info.memory_imports {
+        let memory = match shared_memory {
+            Some(shared_memory) if imported_memories.is_empty() => shared_memory,
+            _ => create_memory(scope, &global_wasm, memory_import),
+        };
+        let module_object =
+            get_or_create_import_module(scope, &import_object, &memory_import.module);
+        let str_name = v8::String::new(scope, &memory_import.name).unwrap();
+        module_object.set(scope, str_name.into(), memory.into());
+        imported_memories.push((memory_import.name.as_str(), memory));
+    }
+
+    let str2 = v8::String::new(scope, "Instance").unwrap();
+    let instance_ctor = global_wasm.get(scope, str2.into()).unwrap();
+    let instance_ctor = instance_ctor.cast::<v8::Function>();
+    // instantiation fails with LinkError if an import is missing or has a wrong type
+    let instance = {
+        let scope = &mut v8::TryCatch::new(scope);
+        match instance_ctor.new_instance(scope, &[module.into(), import_object.into()]) {
+            Some(instance) => instance,
+            None => {
+                let exception = scope.exception().map_or_else(String::new, |exception| {
+                    exception.to_rust_string_lossy(scope)
+                });
+                return Err(anyhow!("Failed to instantiate the module: {}", exception));
+            }
+        }
+    };
+
+    // set instance to global
+    let str_ginstance = v8::String::new(scope, "gInstance").unwrap();
+    global.set(scope, str_ginstance.into(), instance.into());
+
+    // set memory used by WASI functions to global
+    // use the memory named by --wasi-memory if given, otherwise prefer instance.exports.memory
+    // and fall back to the first imported memory
+    let str_exports = v8::String::new(scope, "exports").unwrap();
+    let exports = instance.get(scope, str_exports.into()).unwrap();
+    let exports = exports.to_object(scope).unwrap();
+    let memory = match wasi_memory {
+        Some(name) => {
+            let imported_memory = imported_memories
+                .iter()
+                .find(|(import_name, _)| *import_name == name)
+                .map(|(_, memory)| *memory);
+            let memory =
+                get_exported_memory(scope, &global_wasm, &exports, name).or(imported_memory);
+            if memory.is_none() {
+                return Err(anyhow!("Wasm module has no memory named {}", name));
+            }
+            memory
+        }
+        None => {
+            let imported_memory = imported_memories.first().map(|(_, memory)| *memory);
+            get_exported_memory(scope, &global_wasm, &exports, "memory").or(imported_memory)
+        }
+    };
+    if let Some(memory) = memory {
+        let str_gmemory = v8::String::new(scope, "gMemory").unwrap();
+        global.set(scope, str_gmemory.into(), memory.into());
+    }
+
+    // set tags to global
+    for tag_export in &module_info.tag_exports {
+        let str_name = v8::String::new(scope, &tag_export.name).unwrap();
+        let tag = exports.get(scope, str_name.into()).unwrap();
+        tags.set(scope, str_name.into(), tag);
+    }
+    let str_gtags = v8::String::new(scope, "gTags").unwrap();
+    global.set(scope, str_gtags.into(), tags.into());
+
+    Ok(instance)
+}
+
+/// Returns `exports[name]` if it is a WebAssembly.Memory
+fn get_exported_memory<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    global_wasm: &v8::Local<'a, v8::Object>,
+    exports: &v8::Local<'a, v8::Object>,
+    name: &str,
+) -> Option<v8::Local<'a, v8::Object>> {
+    let str_name = v8::String::new(scope, name).unwrap();
+    let memory = exports.get(scope, str_name.into())?;
+    let str_memory = v8::String::new(scope, "Memory").unwrap();
+    let memory_ctor = global_wasm
+        .get(scope, str_memory.into())?
+        .to_object(scope)?;
+    if memory.instance_of(scope, memory_ctor) != Some(true) {
+        return None;
+    }
+    memory.to_object(scope)
+}
+
+/// Creates a WebAssembly.Memory sized from the import declaration
+fn create_memory<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    global_wasm: &v8::Local<'a, v8::Object>,
+    memory_import: &MemoryImport,
+) -> v8::Local<'a, v8::Object> {
+    // new WebAssembly.Memory({ initial, maximum, index, shared })
+    let descriptor = v8::Object::new(scope);
+    let str_initial = v8::String::new(scope, "initial").unwrap();
+    let initial = v8::Number::new(scope, memory_import.ty.initial as f64);
+    descriptor.set(scope, str_initial.into(), initial.into());
+    if let Some(maximum) = memory_import.ty.maximum {
+        let str_maximum = v8::String::new(scope, "maximum").unwrap();
+        let maximum = v8::Number::new(scope, maximum as f64);
+        descriptor.set(scope, str_maximum.into(), maximum.into());
+    }
+    if memory_import.ty.memory64 {
+        let str_index = v8::String::new(scope, "index").unwrap();
+        let index = v8::String::new(scope, "i64").unwrap();
+        descriptor.set(scope, str_index.into(), index.into());
+    }
+    if memory_import.ty.shared {
+        let str_shared = v8::String::new(scope, "shared").unwrap();
+        let shared = v8::Boolean::new(scope, true);
+        descriptor.set(scope, str_shared.into(), shared.into());
+    }
+
+    let str_memory = v8::String::new(scope, "Memory").unwrap();
+    let memory_ctor = global_wasm.get(scope, str_memory.into()).unwrap();
+    let memory_ctor = memory_ctor.cast::<v8::Function>();
+    memory_ctor
+        .new_instance(scope, &[descriptor.into()])
+        .unwrap()
+}
+
+/// Creates a WebAssembly.Tag with the given parameter types
+fn create_tag<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    global_wasm: &v8::Local<'a, v8::Object>,
+    params: &[ValType],
+) -> v8::Local<'a, v8::Object> {
+    // new WebAssembly.Tag({ parameters })
+    let parameters = params
+        .iter()
+        .map(|ty| {
+            v8::String::new(scope, module::js_type_name(ty))
+                .unwrap()
+                .into()
+        })
+        .collect::<Vec<v8::Local<v8::Value>>>();
+    let parameters = v8::Array::new_with_elements(scope, &parameters);
+    let descriptor = v8::Object::new(scope);
+    let str_parameters = v8::String::new(scope, "parameters").unwrap();
+    descriptor.set(scope, str_parameters.into(), parameters.into());
+
+    let str_tag = v8::String::new(scope, "Tag").unwrap();
+    let tag_ctor = global_wasm.get(scope, str_tag.into()).unwrap();
+    let tag_ctor = tag_ctor.cast::<v8::Function>();
+    tag_ctor.new_instance(scope, &[descriptor.into()]).unwrap()
+}
+
+/// Returns `imports[module]`, creating it if it does not exist yet
+fn get_or_create_import_module<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    import_object: &v8::Local<'a, v8::Object>,
+    module: &str,
+) -> v8::Local<'a, v8::Object> {
+    let str_module = v8::String::new(scope, module).unwrap();
+    if let Some(module_object) = import_object.get(scope, str_module.into()) {
+        if module_object.is_object() {
+            return module_object.to_object(scope).unwrap();
+        }
+    }
+    let module_object = v8::Object::new(scope);
+    import_object.set(scope, str_module.into(), module_object.into());
+    module_object
+}
+
+/// Sets `object[name]` (e.g. a function of an import module) to a function which calls the callback
+fn set_function(
+    scope: &mut v8::HandleScope,
+    object: v8::Local<v8::Object>,
+    name: &str,
+    callback: impl v8::MapFnTo<v8::FunctionCallback>,
+) {
+    let function = v8::FunctionTemplate::new(scope, callback);
+    let function = function.get_function(scope).unwrap();
+    let name = v8::String::new(scope, name).unwrap();
+    object.set(scope, name.into(), function.into());
+}
+
+fn create_wasi_threads_import<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    import_object: &v8::Local<'a, v8::Object>,
+) {
+    let import_wasi = v8::Object::new(scope);
+    let thread_spawn = v8::FunctionTemplate::new(scope, threads::wasi_thread_spawn);
+    let thread_spawn = thread_spawn.get_function(scope).unwrap();
+    let str_thread_spawn = v8::String::new(scope, "thread-spawn").unwrap();
+    import_wasi.set(scope, str_thread_spawn.into(), thread_spawn.into());
+
+    let str_wasi = v8::String::new(scope, "wasi").unwrap();
+    import_object.set(scope, str_wasi.into(), import_wasi.into());
+}
+
+fn create_wasi_nn_import<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    import_object: &v8::Local<'a, v8::Object>,
+) {
+    let import_nn = v8::Object::new(scope);
+    import_nn_function!(scope, import_nn, "load", wasi_ephemeral_nn_load);
+    import_nn_function!(
+        scope,
+        import_nn,
+        "load_by_name",
+        wasi_ephemeral_nn_load_by_name
+    );
+    import_nn_function!(
+        scope,
+        import_nn,
+        "init_execution_context",
+        wasi_ephemeral_nn_init_execution_context
+    );
+    import_nn_function!(scope, import_nn, "set_input", wasi_ephemeral_nn_set_input);
+    import_nn_function!(scope, import_nn, "compute", wasi_ephemeral_nn_compute);
+    import_nn_function!(scope, import_nn, "get_output", wasi_ephemeral_nn_get_output);
+
+    let str_nn = v8::String::new(scope, "wasi_ephemeral_nn").unwrap();
+    import_object.set(scope, str_nn.into(), import_nn.into());
+}
+
 fn create_wasip1_import<'a>(
     scope: &'a mut v8::HandleScope,
     import_object: &v8::Local<'a, v8::Object>,
@@ -452,32 +1037,291 @@ fn create_wasip1_import<'a>(
         wasi_snapshot_preview1_sock_shutdown
     );
 
+    // suspend the wasm stack while WASI functions run in the background
+    if jspi::is_enabled() {
+        jspi::wrap_imports(scope, &import_wasi_p1);
+    }
+
     let str_wasip1 = v8::String::new(scope, "wasi_snapshot_preview1").unwrap();
     import_object.set(scope, str_wasip1.into(), import_wasi_p1.into());
-}
 
-/*
-fn to_pretty_string(mut try_catch: v8::TryCatch<v8::HandleScope>) -> String {
-    // TODO (enhancement): better error handling needed! wanna remove uncareful unwrap().
-    let exception_string = try_catch
-        .exception()
-        .unwrap()
-        .to_string(&mut try_catch)
-        .unwrap()
-        .to_rust_string_lossy(&mut try_catch);
-    let message = try_catch.message().unwrap();
-
-    let filename = message
-        .get_script_resource_name(&mut try_catch)
-        .map_or_else(
-            || "(unknown)".into(),
-            |s| {
-                s.to_string(&mut try_catch)
-                    .unwrap()
-                    .to_rust_string_lossy(&mut try_catch)
-            },
-        );
-    let line_number = message.get_line_number(&mut try_catch).unwrap_or_default();
-    format!("{}:{}: {}", filename, line_number, exception_string)
+    // older binaries import the snapshot 0 of WASI
+    let import_wasi_unstable = v8::Object::new(scope);
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "args_get",
+        wasi_unstable_args_get
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "args_sizes_get",
+        wasi_unstable_args_sizes_get
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "clock_res_get",
+        wasi_unstable_clock_res_get
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "clock_time_get",
+        wasi_unstable_clock_time_get
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "environ_get",
+        wasi_unstable_environ_get
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "environ_sizes_get",
+        wasi_unstable_environ_sizes_get
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_advise",
+        wasi_unstable_fd_advise
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_allocate",
+        wasi_unstable_fd_allocate
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_close",
+        wasi_unstable_fd_close
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_datasync",
+        wasi_unstable_fd_datasync
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_fdstat_get",
+        wasi_unstable_fd_fdstat_get
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_fdstat_set_flags",
+        wasi_unstable_fd_fdstat_set_flags
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_fdstat_set_rights",
+        wasi_unstable_fd_fdstat_set_rights
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_filestat_get",
+        wasi_unstable_fd_filestat_get
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_filestat_set_size",
+        wasi_unstable_fd_filestat_set_size
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_filestat_set_times",
+        wasi_unstable_fd_filestat_set_times
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_pread",
+        wasi_unstable_fd_pread
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_prestat_dir_name",
+        wasi_unstable_fd_prestat_dir_name
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_prestat_get",
+        wasi_unstable_fd_prestat_get
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_pwrite",
+        wasi_unstable_fd_pwrite
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_read",
+        wasi_unstable_fd_read
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_readdir",
+        wasi_unstable_fd_readdir
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_renumber",
+        wasi_unstable_fd_renumber
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_seek",
+        wasi_unstable_fd_seek
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_sync",
+        wasi_unstable_fd_sync
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_tell",
+        wasi_unstable_fd_tell
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "fd_write",
+        wasi_unstable_fd_write
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "path_create_directory",
+        wasi_unstable_path_create_directory
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "path_filestat_get",
+        wasi_unstable_path_filestat_get
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "path_filestat_set_times",
+        wasi_unstable_path_filestat_set_times
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "path_link",
+        wasi_unstable_path_link
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "path_open",
+        wasi_unstable_path_open
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "path_readlink",
+        wasi_unstable_path_readlink
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "path_remove_directory",
+        wasi_unstable_path_remove_directory
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "path_rename",
+        wasi_unstable_path_rename
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "path_symlink",
+        wasi_unstable_path_symlink
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "path_unlink_file",
+        wasi_unstable_path_unlink_file
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "poll_oneoff",
+        wasi_unstable_poll_oneoff
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "proc_exit",
+        wasi_snapshot_preview1_proc_exit
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "proc_raise",
+        wasi_unstable_proc_raise
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "random_get",
+        wasi_unstable_random_get
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "sched_yield",
+        wasi_unstable_sched_yield
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "sock_recv",
+        wasi_unstable_sock_recv
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "sock_send",
+        wasi_unstable_sock_send
+    );
+    import_wasi_function!(
+        scope,
+        import_wasi_unstable,
+        "sock_shutdown",
+        wasi_unstable_sock_shutdown
+    );
+
+    if jspi::is_enabled() {
+        jspi::wrap_imports(scope, &import_wasi_unstable);
+    }
+
+    let str_wasi_unstable = v8::String::new(scope, "wasi_unstable").unwrap();
+    import_object.set(scope, str_wasi_unstable.into(), import_wasi_unstable.into());
 }
-*/
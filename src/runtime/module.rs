@@ -0,0 +1,145 @@
+use anyhow::Result;
+use wasmparser::{
+    CompositeInnerType, ExternalKind, MemoryType, Parser, Payload, RefType, TypeRef, ValType,
+};
+
+/// Information about a wasm module which V8 does not expose through its API
+pub(super) struct ModuleInfo {
+    pub function_imports: Vec<FunctionImport>,
+    pub memory_imports: Vec<MemoryImport>,
+    pub tag_imports: Vec<TagImport>,
+    pub tag_exports: Vec<TagExport>,
+}
+
+pub(super) struct FunctionImport {
+    pub module: String,
+    pub name: String,
+}
+
+pub(super) struct MemoryImport {
+    pub module: String,
+    pub name: String,
+    pub ty: MemoryType,
+}
+
+pub(super) struct TagImport {
+    pub module: String,
+    pub name: String,
+    pub params: Vec<ValType>,
+}
+
+pub(super) struct TagExport {
+    pub name: String,
+    pub params: Vec<ValType>,
+}
+
+impl ModuleInfo {
+    pub fn parse(wasm_module: &[u8]) -> Result<Self> {
+        let mut function_imports = vec![];
+        let mut memory_imports = vec![];
+        let mut tag_imports = vec![];
+        // parameters of function types, indexed by type index
+        let mut func_types: Vec<Vec<ValType>> = vec![];
+        // parameters of tags, indexed by tag index (imported tags come first)
+        let mut tags: Vec<Vec<ValType>> = vec![];
+        let mut tag_exports = vec![];
+
+        for payload in Parser::new(0).parse_all(wasm_module) {
+            match payload? {
+                Payload::TypeSection(reader) => {
+                    for rec_group in reader {
+                        for sub_type in rec_group?.into_types() {
+                            let params = match &sub_type.composite_type.inner {
+                                CompositeInnerType::Func(func_type) => func_type.params().to_vec(),
+                                _ => vec![],
+                            };
+                            func_types.push(params);
+                        }
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import?;
+                        match import.ty {
+                            TypeRef::Func(_) => function_imports.push(FunctionImport {
+                                module: import.module.to_string(),
+                                name: import.name.to_string(),
+                            }),
+                            TypeRef::Memory(ty) => memory_imports.push(MemoryImport {
+                                module: import.module.to_string(),
+                                name: import.name.to_string(),
+                                ty,
+                            }),
+                            TypeRef::Tag(ty) => {
+                                let params = func_type_params(&func_types, ty.func_type_idx);
+                                tags.push(params.clone());
+                                tag_imports.push(TagImport {
+                                    module: import.module.to_string(),
+                                    name: import.name.to_string(),
+                                    params,
+                                });
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::TagSection(reader) => {
+                    for ty in reader {
+                        tags.push(func_type_params(&func_types, ty?.func_type_idx));
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        if export.kind == ExternalKind::Tag {
+                            tag_exports.push(TagExport {
+                                name: export.name.to_string(),
+                                params: tags
+                                    .get(export.index as usize)
+                                    .cloned()
+                                    .unwrap_or_default(),
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(ModuleInfo {
+            function_imports,
+            memory_imports,
+            tag_imports,
+            tag_exports,
+        })
+    }
+
+    pub fn imports_function(&self, module: &str, name: &str) -> bool {
+        self.function_imports
+            .iter()
+            .any(|import| import.module == module && import.name == name)
+    }
+}
+
+/// Returns the parameters of the function type, or nothing if the index is out of range
+///
+/// Invalid modules are reported by V8 when compiling them, so they are not handled here.
+fn func_type_params(func_types: &[Vec<ValType>], type_index: u32) -> Vec<ValType> {
+    func_types
+        .get(type_index as usize)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Returns the name of the value type used by the JS API (e.g. `new WebAssembly.Tag({ parameters })`)
+pub(super) fn js_type_name(ty: &ValType) -> &'static str {
+    match ty {
+        ValType::I32 => "i32",
+        ValType::I64 => "i64",
+        ValType::F32 => "f32",
+        ValType::F64 => "f64",
+        ValType::V128 => "v128",
+        ValType::Ref(ref_type) if *ref_type == RefType::FUNCREF => "anyfunc",
+        ValType::Ref(_) => "externref",
+    }
+}
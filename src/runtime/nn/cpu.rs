@@ -0,0 +1,327 @@
+// Reference backend running fully-connected networks on CPU
+//
+// The graph is a single builder in the following format (all numbers are little-endian):
+//   magic "LV8NN\0\0\0", number of layers: u32,
+//   and for each layer: inputs: u32, outputs: u32, activation: u32 (0: none, 1: relu),
+//   weights: [f32; outputs * inputs] (row-major), bias: [f32; outputs]
+use std::sync::Arc;
+
+use super::{
+    Backend, ExecutionContext, ExecutionTarget, Graph, GraphEncoding, NnErrno, Tensor, TensorType,
+};
+
+const MAGIC: &[u8; 8] = b"LV8NN\0\0\0";
+
+pub(super) struct CpuBackend;
+
+impl Backend for CpuBackend {
+    fn supports(&self, encoding: GraphEncoding, target: ExecutionTarget) -> bool {
+        encoding == GraphEncoding::Autodetect && target == ExecutionTarget::Cpu
+    }
+
+    fn load(&self, builders: &[&[u8]]) -> Result<Box<dyn Graph>, NnErrno> {
+        let [builder] = builders else {
+            return Err(NnErrno::InvalidArgument);
+        };
+        let network = Network::parse(builder)?;
+        Ok(Box::new(network))
+    }
+}
+
+enum Activation {
+    None,
+    Relu,
+}
+
+struct Layer {
+    inputs: usize,
+    outputs: usize,
+    activation: Activation,
+    weights: Vec<f32>,
+    bias: Vec<f32>,
+}
+
+impl Layer {
+    fn forward(&self, input: &[f32]) -> Vec<f32> {
+        let mut output = self.bias.clone();
+        for (o, value) in output.iter_mut().enumerate() {
+            let row = &self.weights[o * self.inputs..(o + 1) * self.inputs];
+            *value += row.iter().zip(input).map(|(w, x)| w * x).sum::<f32>();
+            if let Activation::Relu = self.activation {
+                *value = value.max(0.0);
+            }
+        }
+        output
+    }
+}
+
+struct Network {
+    layers: Arc<Vec<Layer>>,
+}
+
+impl Network {
+    fn parse(bytes: &[u8]) -> Result<Self, NnErrno> {
+        let mut reader = Reader { bytes };
+        if reader.read_bytes(MAGIC.len())? != MAGIC {
+            return Err(NnErrno::InvalidEncoding);
+        }
+
+        let mut layers = vec![];
+        let num_layers = reader.read_u32()?;
+        for _ in 0..num_layers {
+            let inputs = reader.read_u32()? as usize;
+            let outputs = reader.read_u32()? as usize;
+            let activation = match reader.read_u32()? {
+                0 => Activation::None,
+                1 => Activation::Relu,
+                _ => return Err(NnErrno::InvalidArgument),
+            };
+            if layers
+                .last()
+                .is_some_and(|last: &Layer| last.outputs != inputs)
+            {
+                return Err(NnErrno::InvalidArgument);
+            }
+            let num_weights = inputs
+                .checked_mul(outputs)
+                .ok_or(NnErrno::InvalidArgument)?;
+            let weights = reader.read_f32s(num_weights)?;
+            let bias = reader.read_f32s(outputs)?;
+            layers.push(Layer {
+                inputs,
+                outputs,
+                activation,
+                weights,
+                bias,
+            });
+        }
+        if layers.is_empty() {
+            return Err(NnErrno::InvalidArgument);
+        }
+
+        Ok(Network {
+            layers: Arc::new(layers),
+        })
+    }
+}
+
+impl Graph for Network {
+    fn init_execution_context(&self) -> Result<Box<dyn ExecutionContext>, NnErrno> {
+        Ok(Box::new(CpuExecutionContext {
+            layers: self.layers.clone(),
+            input: None,
+            output: None,
+        }))
+    }
+}
+
+struct CpuExecutionContext {
+    layers: Arc<Vec<Layer>>,
+    input: Option<Vec<f32>>,
+    output: Option<Vec<f32>>,
+}
+
+impl ExecutionContext for CpuExecutionContext {
+    fn set_input(&mut self, index: u32, tensor: Tensor) -> Result<(), NnErrno> {
+        if index != 0 {
+            return Err(NnErrno::InvalidArgument);
+        }
+        if tensor.ty != TensorType::F32 {
+            return Err(NnErrno::UnsupportedOperation);
+        }
+        let num_elements = tensor
+            .dimensions
+            .iter()
+            .try_fold(1usize, |product, d| product.checked_mul(*d as usize))
+            .ok_or(NnErrno::InvalidArgument)?;
+        if num_elements != self.layers[0].inputs
+            || num_elements.checked_mul(4) != Some(tensor.data.len())
+        {
+            return Err(NnErrno::InvalidArgument);
+        }
+        self.input = Some(
+            tensor
+                .data
+                .chunks_exact(4)
+                .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+                .collect(),
+        );
+        Ok(())
+    }
+
+    fn compute(&mut self) -> Result<(), NnErrno> {
+        let Some(input) = &self.input else {
+            return Err(NnErrno::RuntimeError);
+        };
+        let mut values = input.clone();
+        for layer in self.layers.iter() {
+            values = layer.forward(&values);
+        }
+        self.output = Some(values);
+        Ok(())
+    }
+
+    fn get_output(&self, index: u32) -> Result<Tensor, NnErrno> {
+        if index != 0 {
+            return Err(NnErrno::InvalidArgument);
+        }
+        let Some(output) = &self.output else {
+            return Err(NnErrno::RuntimeError);
+        };
+        Ok(Tensor {
+            dimensions: vec![1, output.len() as u32],
+            ty: TensorType::F32,
+            data: output
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+        })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], NnErrno> {
+        if self.bytes.len() < len {
+            return Err(NnErrno::InvalidArgument);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, NnErrno> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_f32s(&mut self, len: usize) -> Result<Vec<f32>, NnErrno> {
+        let bytes = self.read_bytes(len.checked_mul(4).ok_or(NnErrno::InvalidArgument)?)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XOR: &[u8] = include_bytes!("../../../examples/nn/xor.lv8nn");
+
+    fn f32s(values: &[f32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    fn run(network: &Network, input: &[f32]) -> Result<Vec<f32>, NnErrno> {
+        let mut context = network.init_execution_context()?;
+        context.set_input(
+            0,
+            Tensor {
+                dimensions: vec![1, input.len() as u32],
+                ty: TensorType::F32,
+                data: f32s(input),
+            },
+        )?;
+        context.compute()?;
+        let output = context.get_output(0)?;
+        assert_eq!(output.dimensions, vec![1, output.data.len() as u32 / 4]);
+        Ok(output
+            .data
+            .chunks_exact(4)
+            .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+            .collect())
+    }
+
+    #[test]
+    fn parses_xor() {
+        let network = Network::parse(XOR).unwrap();
+        assert_eq!(network.layers.len(), 2);
+        assert_eq!(
+            (network.layers[0].inputs, network.layers[0].outputs),
+            (2, 2)
+        );
+        assert_eq!(
+            (network.layers[1].inputs, network.layers[1].outputs),
+            (2, 1)
+        );
+    }
+
+    #[test]
+    fn computes_xor() {
+        let network = Network::parse(XOR).unwrap();
+        assert_eq!(run(&network, &[0.0, 0.0]), Ok(vec![0.0]));
+        assert_eq!(run(&network, &[0.0, 1.0]), Ok(vec![1.0]));
+        assert_eq!(run(&network, &[1.0, 0.0]), Ok(vec![1.0]));
+        assert_eq!(run(&network, &[1.0, 1.0]), Ok(vec![0.0]));
+    }
+
+    #[test]
+    fn rejects_invalid_graphs() {
+        assert!(matches!(
+            Network::parse(b"ONNX\0\0\0\0"),
+            Err(NnErrno::InvalidEncoding)
+        ));
+        // truncated weights
+        assert!(matches!(
+            Network::parse(&XOR[..XOR.len() - 4]),
+            Err(NnErrno::InvalidArgument)
+        ));
+        // no layers
+        let mut empty = MAGIC.to_vec();
+        empty.extend(0u32.to_le_bytes());
+        assert!(matches!(
+            Network::parse(&empty),
+            Err(NnErrno::InvalidArgument)
+        ));
+        // the second layer takes 3 inputs while the first has 2 outputs
+        let mut mismatched = MAGIC.to_vec();
+        for value in [2, 2, 2, 0] {
+            mismatched.extend(u32::to_le_bytes(value));
+        }
+        mismatched.extend(f32s(&[0.0; 2 * 2 + 2]));
+        for value in [3, 1, 0] {
+            mismatched.extend(u32::to_le_bytes(value));
+        }
+        mismatched.extend(f32s(&[0.0; 3 + 1]));
+        assert!(matches!(
+            Network::parse(&mismatched),
+            Err(NnErrno::InvalidArgument)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_inputs() {
+        let network = Network::parse(XOR).unwrap();
+        assert_eq!(run(&network, &[1.0]), Err(NnErrno::InvalidArgument));
+        let mut context = network.init_execution_context().unwrap();
+        // compute before set_input
+        assert_eq!(context.compute(), Err(NnErrno::RuntimeError));
+        let result = context.set_input(
+            0,
+            Tensor {
+                dimensions: vec![1, 2],
+                ty: TensorType::U8,
+                data: vec![1, 0],
+            },
+        );
+        assert_eq!(result, Err(NnErrno::UnsupportedOperation));
+        // the number of elements overflows
+        let result = context.set_input(
+            0,
+            Tensor {
+                dimensions: vec![u32::MAX; 4],
+                ty: TensorType::F32,
+                data: vec![],
+            },
+        );
+        assert_eq!(result, Err(NnErrno::InvalidArgument));
+    }
+}
@@ -0,0 +1,479 @@
+// wasi-nn (wasi_ephemeral_nn) with pluggable backends
+mod cpu;
+
+use std::sync::{Mutex, OnceLock};
+
+use super::wasi;
+
+/// Errors of wasi_ephemeral_nn, numbered like `nn_errno` of wasi-nn.witx (0 is success),
+/// which is the version of the witx implemented by Wasmtime and WasmEdge
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum NnErrno {
+    InvalidArgument = 1,
+    InvalidEncoding = 2,
+    RuntimeError = 4,
+    UnsupportedOperation = 5,
+    TooLarge = 6,
+    NotFound = 7,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum GraphEncoding {
+    Openvino,
+    Onnx,
+    Tensorflow,
+    Pytorch,
+    TensorflowLite,
+    Ggml,
+    Autodetect,
+}
+
+impl GraphEncoding {
+    fn from_u32(value: u32) -> Option<Self> {
+        Some(match value {
+            0 => GraphEncoding::Openvino,
+            1 => GraphEncoding::Onnx,
+            2 => GraphEncoding::Tensorflow,
+            3 => GraphEncoding::Pytorch,
+            4 => GraphEncoding::TensorflowLite,
+            5 => GraphEncoding::Ggml,
+            6 => GraphEncoding::Autodetect,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum ExecutionTarget {
+    Cpu,
+    Gpu,
+    Tpu,
+}
+
+impl ExecutionTarget {
+    fn from_u32(value: u32) -> Option<Self> {
+        Some(match value {
+            0 => ExecutionTarget::Cpu,
+            1 => ExecutionTarget::Gpu,
+            2 => ExecutionTarget::Tpu,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum TensorType {
+    F16,
+    F32,
+    F64,
+    U8,
+    I32,
+    I64,
+}
+
+impl TensorType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => TensorType::F16,
+            1 => TensorType::F32,
+            2 => TensorType::F64,
+            3 => TensorType::U8,
+            4 => TensorType::I32,
+            5 => TensorType::I64,
+            _ => return None,
+        })
+    }
+}
+
+pub(super) struct Tensor {
+    pub dimensions: Vec<u32>,
+    pub ty: TensorType,
+    pub data: Vec<u8>,
+}
+
+/// Backend which loads graphs of some encodings
+pub(super) trait Backend: Send {
+    fn supports(&self, encoding: GraphEncoding, target: ExecutionTarget) -> bool;
+
+    /// Loads a graph from its builders
+    ///
+    /// Returns `InvalidEncoding` if the builders are not in a format the backend understands.
+    fn load(&self, builders: &[&[u8]]) -> Result<Box<dyn Graph>, NnErrno>;
+}
+
+pub(super) trait Graph: Send {
+    fn init_execution_context(&self) -> Result<Box<dyn ExecutionContext>, NnErrno>;
+}
+
+pub(super) trait ExecutionContext: Send {
+    fn set_input(&mut self, index: u32, tensor: Tensor) -> Result<(), NnErrno>;
+    fn compute(&mut self) -> Result<(), NnErrno>;
+    fn get_output(&self, index: u32) -> Result<Tensor, NnErrno>;
+}
+
+/// Global wasi-nn context
+static NN_CTX: OnceLock<Mutex<NnCtx>> = OnceLock::new();
+
+fn get_nn_ctx_mut() -> &'static Mutex<NnCtx> {
+    NN_CTX.get_or_init(|| Mutex::new(NnCtx::new(vec![Box::new(cpu::CpuBackend)])))
+}
+
+struct NnCtx {
+    backends: Vec<Box<dyn Backend>>,
+    graphs: Vec<Box<dyn Graph>>,
+    contexts: Vec<Box<dyn ExecutionContext>>,
+}
+
+impl NnCtx {
+    fn new(backends: Vec<Box<dyn Backend>>) -> Self {
+        NnCtx {
+            backends,
+            graphs: vec![],
+            contexts: vec![],
+        }
+    }
+
+    fn load(
+        &mut self,
+        memory: &mut [u8],
+        builder_ptr: u32,
+        builder_len: u32,
+        encoding: u32,
+        target: u32,
+        graph_out: u32,
+    ) -> Result<(), NnErrno> {
+        let encoding = GraphEncoding::from_u32(encoding).ok_or(NnErrno::InvalidEncoding)?;
+        let target = ExecutionTarget::from_u32(target).ok_or(NnErrno::InvalidArgument)?;
+
+        // graph_builder_array is a list of (ptr, len)
+        let mut builders = vec![];
+        for i in 0..builder_len {
+            let builder = i
+                .checked_mul(8)
+                .and_then(|offset| builder_ptr.checked_add(offset))
+                .ok_or(NnErrno::InvalidArgument)?;
+            let ptr = read_u32(memory, builder)?;
+            let len = read_u32(memory, offset(builder, 4)?)?;
+            builders.push(read_bytes(memory, ptr, len)?);
+        }
+
+        // try backends in order, so that autodetect picks the first one which understands the graph
+        let mut result = Err(NnErrno::InvalidEncoding);
+        for backend in &self.backends {
+            if !backend.supports(encoding, target) {
+                continue;
+            }
+            result = backend.load(&builders);
+            if !matches!(result, Err(NnErrno::InvalidEncoding)) {
+                break;
+            }
+        }
+        self.graphs.push(result?);
+        write_u32(memory, graph_out, self.graphs.len() as u32 - 1)
+    }
+
+    fn load_by_name(
+        &mut self,
+        _memory: &mut [u8],
+        _name_ptr: u32,
+        _name_len: u32,
+        _graph_out: u32,
+    ) -> Result<(), NnErrno> {
+        // no graph is registered by name
+        Err(NnErrno::NotFound)
+    }
+
+    fn init_execution_context(
+        &mut self,
+        memory: &mut [u8],
+        graph: u32,
+        context_out: u32,
+    ) -> Result<(), NnErrno> {
+        let graph = self
+            .graphs
+            .get(graph as usize)
+            .ok_or(NnErrno::InvalidArgument)?;
+        self.contexts.push(graph.init_execution_context()?);
+        write_u32(memory, context_out, self.contexts.len() as u32 - 1)
+    }
+
+    fn set_input(
+        &mut self,
+        memory: &mut [u8],
+        context: u32,
+        index: u32,
+        tensor_ptr: u32,
+    ) -> Result<(), NnErrno> {
+        let context = self
+            .contexts
+            .get_mut(context as usize)
+            .ok_or(NnErrno::InvalidArgument)?;
+
+        // tensor is { dimensions: (ptr, len), type: u8, data: (ptr, len) }
+        let dimensions_ptr = read_u32(memory, tensor_ptr)?;
+        let dimensions_len = read_u32(memory, offset(tensor_ptr, 4)?)?;
+        let dimensions_size = dimensions_len
+            .checked_mul(4)
+            .ok_or(NnErrno::InvalidArgument)?;
+        let dimensions = read_bytes(memory, dimensions_ptr, dimensions_size)?
+            .chunks_exact(4)
+            .map(|dimension| u32::from_le_bytes(dimension.try_into().unwrap()))
+            .collect();
+        let ty = read_bytes(memory, offset(tensor_ptr, 8)?, 1)?[0];
+        let ty = TensorType::from_u8(ty).ok_or(NnErrno::InvalidArgument)?;
+        let data_ptr = read_u32(memory, offset(tensor_ptr, 12)?)?;
+        let data_len = read_u32(memory, offset(tensor_ptr, 16)?)?;
+        let data = read_bytes(memory, data_ptr, data_len)?.to_vec();
+
+        context.set_input(
+            index,
+            Tensor {
+                dimensions,
+                ty,
+                data,
+            },
+        )
+    }
+
+    fn compute(&mut self, _memory: &mut [u8], context: u32) -> Result<(), NnErrno> {
+        let context = self
+            .contexts
+            .get_mut(context as usize)
+            .ok_or(NnErrno::InvalidArgument)?;
+        context.compute()
+    }
+
+    fn get_output(
+        &mut self,
+        memory: &mut [u8],
+        context: u32,
+        index: u32,
+        out_buffer: u32,
+        out_buffer_max_size: u32,
+        bytes_written_out: u32,
+    ) -> Result<(), NnErrno> {
+        let context = self
+            .contexts
+            .get(context as usize)
+            .ok_or(NnErrno::InvalidArgument)?;
+        let tensor = context.get_output(index)?;
+        if tensor.data.len() > out_buffer_max_size as usize {
+            return Err(NnErrno::TooLarge);
+        }
+        let len = tensor.data.len() as u32;
+        read_bytes_mut(memory, out_buffer, len)?.copy_from_slice(&tensor.data);
+        write_u32(memory, bytes_written_out, len)
+    }
+}
+
+/// Returns the address at the offset from the pointer, which overflows for invalid pointers
+fn offset(ptr: u32, offset: u32) -> Result<u32, NnErrno> {
+    ptr.checked_add(offset).ok_or(NnErrno::InvalidArgument)
+}
+
+fn read_bytes(memory: &[u8], ptr: u32, len: u32) -> Result<&[u8], NnErrno> {
+    let start = ptr as usize;
+    let end = start
+        .checked_add(len as usize)
+        .ok_or(NnErrno::InvalidArgument)?;
+    memory.get(start..end).ok_or(NnErrno::InvalidArgument)
+}
+
+fn read_bytes_mut(memory: &mut [u8], ptr: u32, len: u32) -> Result<&mut [u8], NnErrno> {
+    let start = ptr as usize;
+    let end = start
+        .checked_add(len as usize)
+        .ok_or(NnErrno::InvalidArgument)?;
+    memory.get_mut(start..end).ok_or(NnErrno::InvalidArgument)
+}
+
+fn read_u32(memory: &[u8], ptr: u32) -> Result<u32, NnErrno> {
+    let bytes = read_bytes(memory, ptr, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn write_u32(memory: &mut [u8], ptr: u32, value: u32) -> Result<(), NnErrno> {
+    read_bytes_mut(memory, ptr, 4)?.copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+macro_rules! nn_function {
+    ($export:ident, $name:ident, $( $arg_name: ident ),*) => {
+        pub(super) fn $export(
+            scope: &mut v8::HandleScope,
+            _args: v8::FunctionCallbackArguments,
+            mut rv: v8::ReturnValue,
+        ) {
+            let mut _argcnt = 0;
+            $(
+                let $arg_name = _args.get(_argcnt);
+                let $arg_name = $arg_name.integer_value(scope).unwrap() as u32;
+                _argcnt += 1;
+            )*
+
+            let backing_store = match wasi::get_backing_store_from_scope(scope) {
+                Ok(backing_store) => backing_store,
+                Err(e) => {
+                    wasi::throw_error(scope, &e.to_string());
+                    return;
+                }
+            };
+            let memory = wasi::memory_bytes(&backing_store);
+            let mut nn_ctx = get_nn_ctx_mut().lock().unwrap();
+            let errno = match nn_ctx.$name(memory, $( $arg_name ),*) {
+                Ok(()) => 0,
+                Err(errno) => errno as i32,
+            };
+
+            rv.set(v8::Integer::new(scope, errno).into());
+        }
+    }
+}
+
+nn_function!(wasi_ephemeral_nn_load, load, arg0, arg1, arg2, arg3, arg4);
+nn_function!(
+    wasi_ephemeral_nn_load_by_name,
+    load_by_name,
+    arg0,
+    arg1,
+    arg2
+);
+nn_function!(
+    wasi_ephemeral_nn_init_execution_context,
+    init_execution_context,
+    arg0,
+    arg1
+);
+nn_function!(wasi_ephemeral_nn_set_input, set_input, arg0, arg1, arg2);
+nn_function!(wasi_ephemeral_nn_compute, compute, arg0);
+nn_function!(
+    wasi_ephemeral_nn_get_output,
+    get_output,
+    arg0,
+    arg1,
+    arg2,
+    arg3,
+    arg4
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XOR: &[u8] = include_bytes!("../../../examples/nn/xor.lv8nn");
+
+    fn write(memory: &mut [u8], ptr: u32, bytes: &[u8]) {
+        memory[ptr as usize..][..bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Loads xor.lv8nn at 1024 and returns the graph
+    fn load_xor(nn_ctx: &mut NnCtx, memory: &mut [u8]) -> u32 {
+        write(memory, 1024, XOR);
+        write(memory, 64, &1024u32.to_le_bytes());
+        write(memory, 68, &(XOR.len() as u32).to_le_bytes());
+        nn_ctx.load(memory, 64, 1, 6, 0, 140).unwrap();
+        read_u32(memory, 140).unwrap()
+    }
+
+    /// Computes the output of the context for the input, which is copied to 200
+    fn compute(nn_ctx: &mut NnCtx, memory: &mut [u8], context: u32, input: [f32; 2]) -> f32 {
+        // tensor { dimensions: (80, 2), type: f32, data: (96, 8) }
+        write(memory, 80, &[1, 0, 0, 0, 2, 0, 0, 0]);
+        write(memory, 96, &input[0].to_le_bytes());
+        write(memory, 100, &input[1].to_le_bytes());
+        write(
+            memory,
+            112,
+            &[80, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 96, 0, 0, 0, 8, 0, 0, 0],
+        );
+        nn_ctx.set_input(memory, context, 0, 112).unwrap();
+        nn_ctx.compute(memory, context).unwrap();
+        nn_ctx.get_output(memory, context, 0, 200, 4, 204).unwrap();
+        assert_eq!(read_u32(memory, 204).unwrap(), 4);
+        f32::from_le_bytes(read_bytes(memory, 200, 4).unwrap().try_into().unwrap())
+    }
+
+    #[test]
+    fn computes_xor() {
+        let mut nn_ctx = NnCtx::new(vec![Box::new(cpu::CpuBackend)]);
+        let mut memory = vec![0u8; 4096];
+        let graph = load_xor(&mut nn_ctx, &mut memory);
+        nn_ctx
+            .init_execution_context(&mut memory, graph, 144)
+            .unwrap();
+        let context = read_u32(&memory, 144).unwrap();
+
+        for (input, expected) in [
+            ([0.0, 0.0], 0.0),
+            ([0.0, 1.0], 1.0),
+            ([1.0, 0.0], 1.0),
+            ([1.0, 1.0], 0.0),
+        ] {
+            assert_eq!(compute(&mut nn_ctx, &mut memory, context, input), expected);
+        }
+    }
+
+    #[test]
+    fn get_output_into_small_buffer_is_too_large() {
+        let mut nn_ctx = NnCtx::new(vec![Box::new(cpu::CpuBackend)]);
+        let mut memory = vec![0u8; 4096];
+        let graph = load_xor(&mut nn_ctx, &mut memory);
+        nn_ctx
+            .init_execution_context(&mut memory, graph, 144)
+            .unwrap();
+        compute(&mut nn_ctx, &mut memory, 0, [1.0, 0.0]);
+
+        let result = nn_ctx.get_output(&mut memory, 0, 0, 200, 3, 204);
+        assert_eq!(result, Err(NnErrno::TooLarge));
+    }
+
+    #[test]
+    fn load_fails_without_a_backend_for_the_graph() {
+        let mut nn_ctx = NnCtx::new(vec![Box::new(cpu::CpuBackend)]);
+        let mut memory = vec![0u8; 4096];
+        write(&mut memory, 1024, XOR);
+        write(&mut memory, 64, &1024u32.to_le_bytes());
+        write(&mut memory, 68, &(XOR.len() as u32).to_le_bytes());
+
+        // ONNX is not supported by the CPU backend
+        let result = nn_ctx.load(&mut memory, 64, 1, 1, 0, 140);
+        assert_eq!(result, Err(NnErrno::InvalidEncoding));
+        // neither is GPU
+        let result = nn_ctx.load(&mut memory, 64, 1, 6, 1, 140);
+        assert_eq!(result, Err(NnErrno::InvalidEncoding));
+        let result = nn_ctx.load(&mut memory, 64, 1, 7, 0, 140);
+        assert_eq!(result, Err(NnErrno::InvalidEncoding));
+        // builder out of bounds of the memory
+        write(&mut memory, 64, &4090u32.to_le_bytes());
+        let result = nn_ctx.load(&mut memory, 64, 1, 6, 0, 140);
+        assert_eq!(result, Err(NnErrno::InvalidArgument));
+        assert!(nn_ctx.graphs.is_empty());
+
+        let result = nn_ctx.load_by_name(&mut memory, 0, 0, 140);
+        assert_eq!(result, Err(NnErrno::NotFound));
+    }
+
+    #[test]
+    fn pointers_which_overflow_are_invalid_arguments() {
+        let mut nn_ctx = NnCtx::new(vec![Box::new(cpu::CpuBackend)]);
+        let mut memory = vec![0u8; 4096];
+
+        // the builders at the end of the address space
+        let result = nn_ctx.load(&mut memory, u32::MAX - 4, 1, 6, 0, 140);
+        assert_eq!(result, Err(NnErrno::InvalidArgument));
+        let result = nn_ctx.load(&mut memory, 64, u32::MAX, 6, 0, 140);
+        assert_eq!(result, Err(NnErrno::InvalidArgument));
+
+        let graph = load_xor(&mut nn_ctx, &mut memory);
+        nn_ctx
+            .init_execution_context(&mut memory, graph, 144)
+            .unwrap();
+        // the tensor at the end of the address space
+        let result = nn_ctx.set_input(&mut memory, 0, 0, u32::MAX - 2);
+        assert_eq!(result, Err(NnErrno::InvalidArgument));
+        // dimensions whose size does not fit in u32
+        write(&mut memory, 112, &[80, 0, 0, 0, 0, 0, 0, 0x40]);
+        let result = nn_ctx.set_input(&mut memory, 0, 0, 112);
+        assert_eq!(result, Err(NnErrno::InvalidArgument));
+    }
+}
@@ -0,0 +1,281 @@
+// Allow and deny lists of WASI functions (--allow-wasi, --deny-wasi and --wasi-policy)
+//
+// The policy file has one rule per line, and `#` starts a comment:
+//
+//     action eperm
+//     deny path_unlink_file
+//     deny path_rename
+//     deny sock_*
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use std::sync::OnceLock;
+
+use crate::driver::{Cli, DenyAction};
+
+/// ENOSYS in wasi_snapshot_preview1
+const ERRNO_NOSYS: i32 = 52;
+/// EPERM in wasi_snapshot_preview1
+const ERRNO_PERM: i32 = 63;
+
+/// Functions of wasi_snapshot_preview1
+const WASI_FUNCTIONS: &[&str] = &[
+    "args_get",
+    "args_sizes_get",
+    "clock_res_get",
+    "clock_time_get",
+    "environ_get",
+    "environ_sizes_get",
+    "fd_advise",
+    "fd_allocate",
+    "fd_close",
+    "fd_datasync",
+    "fd_fdstat_get",
+    "fd_fdstat_set_flags",
+    "fd_fdstat_set_rights",
+    "fd_filestat_get",
+    "fd_filestat_set_size",
+    "fd_filestat_set_times",
+    "fd_pread",
+    "fd_prestat_dir_name",
+    "fd_prestat_get",
+    "fd_pwrite",
+    "fd_read",
+    "fd_readdir",
+    "fd_renumber",
+    "fd_seek",
+    "fd_sync",
+    "fd_tell",
+    "fd_write",
+    "path_create_directory",
+    "path_filestat_get",
+    "path_filestat_set_times",
+    "path_link",
+    "path_open",
+    "path_readlink",
+    "path_remove_directory",
+    "path_rename",
+    "path_symlink",
+    "path_unlink_file",
+    "poll_oneoff",
+    "proc_exit",
+    "proc_raise",
+    "random_get",
+    "sched_yield",
+    "sock_accept",
+    "sock_recv",
+    "sock_send",
+    "sock_shutdown",
+];
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+struct Policy {
+    /// Patterns of allowed functions, or empty if all functions are allowed
+    allow: Vec<String>,
+    /// Patterns of denied functions, which take precedence over `allow`
+    deny: Vec<String>,
+    action: DenyAction,
+}
+
+/// What to do with a call of a WASI function
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Decision {
+    Allow,
+    /// Return the errno without calling the function
+    Return(i32),
+    /// Throw an error, which traps the module
+    Trap,
+}
+
+pub(super) fn init(args: &Cli) -> Result<()> {
+    let Some(policy) = Policy::from_args(args)? else {
+        return Ok(());
+    };
+    if POLICY.set(policy).is_err() {
+        return Err(anyhow!("WASI policy is already initialized"));
+    }
+    Ok(())
+}
+
+impl Policy {
+    /// Reads the policy from the command line options and the policy file,
+    /// or returns None if every function is allowed
+    fn from_args(args: &Cli) -> Result<Option<Self>> {
+        let mut allow = args.allow_wasi.clone();
+        let mut deny = args.deny_wasi.clone();
+        let mut action = None;
+
+        if let Some(path) = &args.wasi_policy {
+            let policy = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+            for (i, line) in policy.lines().enumerate() {
+                let line = line.split('#').next().unwrap().trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let error = || anyhow!("{}:{}: invalid rule: {}", path.display(), i + 1, line);
+                let Some((rule, value)) = line.split_once(char::is_whitespace) else {
+                    return Err(error());
+                };
+                let value = value.trim();
+                match rule {
+                    "allow" => allow.push(value.to_string()),
+                    "deny" => deny.push(value.to_string()),
+                    "action" => {
+                        action = Some(DenyAction::from_str(value, true).map_err(|_| error())?)
+                    }
+                    _ => return Err(error()),
+                }
+            }
+        }
+
+        if allow.is_empty() && deny.is_empty() {
+            return Ok(None);
+        }
+        // a misspelled function would be silently allowed
+        for pattern in allow.iter().chain(&deny) {
+            if !WASI_FUNCTIONS.iter().any(|name| matches(pattern, name)) {
+                return Err(anyhow!("Unknown WASI function in the policy: {}", pattern));
+            }
+        }
+
+        Ok(Some(Policy {
+            allow,
+            deny,
+            // the command line option takes precedence over the policy file
+            action: args.deny_action.or(action).unwrap_or(DenyAction::Enosys),
+        }))
+    }
+
+    fn decide(&self, name: &str) -> Decision {
+        let allowed = (self.allow.is_empty()
+            || self.allow.iter().any(|pattern| matches(pattern, name)))
+            && !self.deny.iter().any(|pattern| matches(pattern, name));
+        if allowed {
+            return Decision::Allow;
+        }
+
+        match self.action {
+            DenyAction::Enosys => Decision::Return(ERRNO_NOSYS),
+            DenyAction::Eperm => Decision::Return(ERRNO_PERM),
+            DenyAction::Trap => Decision::Trap,
+            DenyAction::Log => {
+                eprintln!("Warning: WASI function {} is denied by the policy", name);
+                Decision::Allow
+            }
+        }
+    }
+}
+
+/// Returns whether the pattern matches the function name
+///
+/// A pattern ending with `*` matches names starting with the rest of the pattern.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+pub(super) fn decide(name: &str) -> Decision {
+    match POLICY.get() {
+        Some(policy) => policy.decide(name),
+        None => Decision::Allow,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn parse(args: &[&str]) -> Result<Option<Policy>> {
+        let args = ["lv8"].iter().chain(args).chain(&["a.wasm"]);
+        Policy::from_args(&Cli::parse_from(args))
+    }
+
+    /// Writes the policy file, and returns its path
+    fn policy_file(test: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("lv8-policy-{}-{}", std::process::id(), test));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn matches_names_and_prefixes() {
+        assert!(matches("fd_write", "fd_write"));
+        assert!(!matches("fd_write", "fd_writ"));
+        assert!(matches("sock_*", "sock_accept"));
+        assert!(!matches("sock_*", "fd_write"));
+        assert!(matches("*", "fd_write"));
+    }
+
+    #[test]
+    fn allows_everything_without_rules() {
+        assert!(parse(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn denied_functions_take_precedence_over_allowed_ones() {
+        let policy = parse(&["--allow-wasi", "fd_*,proc_exit", "--deny-wasi", "fd_close"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(policy.decide("fd_write"), Decision::Allow);
+        assert_eq!(policy.decide("proc_exit"), Decision::Allow);
+        assert_eq!(policy.decide("fd_close"), Decision::Return(ERRNO_NOSYS));
+        assert_eq!(policy.decide("path_open"), Decision::Return(ERRNO_NOSYS));
+    }
+
+    #[test]
+    fn deny_actions() {
+        let policy = parse(&["--deny-wasi", "sock_*", "--deny-action", "eperm"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(policy.decide("sock_accept"), Decision::Return(ERRNO_PERM));
+        assert_eq!(policy.decide("fd_write"), Decision::Allow);
+        let policy = parse(&["--deny-wasi", "sock_*", "--deny-action", "trap"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(policy.decide("sock_send"), Decision::Trap);
+        let policy = parse(&["--deny-wasi", "sock_*", "--deny-action", "log"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(policy.decide("sock_send"), Decision::Allow);
+    }
+
+    #[test]
+    fn reads_rules_from_the_policy_file() {
+        let path = policy_file(
+            "reads_rules",
+            "# no deleting\naction trap\ndeny path_unlink_file  # files\n\nallow path_*\n",
+        );
+        let policy = parse(&["--wasi-policy", &path]).unwrap().unwrap();
+        assert_eq!(policy.decide("path_open"), Decision::Allow);
+        assert_eq!(policy.decide("path_unlink_file"), Decision::Trap);
+        assert_eq!(policy.decide("fd_write"), Decision::Trap);
+
+        // the command line option takes precedence over the policy file
+        let policy = parse(&["--wasi-policy", &path, "--deny-action", "eperm"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(policy.decide("fd_write"), Decision::Return(ERRNO_PERM));
+    }
+
+    #[test]
+    fn rejects_invalid_rules_and_unknown_functions() {
+        let path = policy_file("rejects_invalid_rules", "deny fd_write\nforbid fd_read\n");
+        let error = parse(&["--wasi-policy", &path]).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!("{}:2: invalid rule: forbid fd_read", path)
+        );
+
+        let error = parse(&["--deny-wasi", "fd_wrte"]).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Unknown WASI function in the policy: fd_wrte"
+        );
+        assert!(parse(&["--deny-wasi", "nothing_*"]).is_err());
+    }
+}
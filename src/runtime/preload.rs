@@ -0,0 +1,58 @@
+// Scripts given by --preload, which are evaluated before the module is instantiated
+//
+// The import object is visible to the scripts as `imports`, so that they can add imports
+// (e.g. `imports.env = { ... }`) or wrap WASI functions. The scripts are evaluated again in every
+// thread spawned by wasi-threads, since each thread instantiates the module in its own isolate.
+
+use anyhow::{anyhow, Result};
+use std::sync::OnceLock;
+
+use crate::driver::Cli;
+
+/// Paths and sources of the scripts
+static SCRIPTS: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+pub(super) fn init(args: &Cli) -> Result<()> {
+    let mut scripts = vec![];
+    for path in &args.preload {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        scripts.push((path.display().to_string(), source));
+    }
+    if SCRIPTS.set(scripts).is_err() {
+        return Err(anyhow!("Preloaded scripts are already initialized"));
+    }
+    Ok(())
+}
+
+/// Sets the import object to `imports`, and evaluates the scripts in the current context
+pub(super) fn run<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    import_object: &v8::Local<'a, v8::Object>,
+) -> Result<()> {
+    let Some(scripts) = SCRIPTS.get().filter(|scripts| !scripts.is_empty()) else {
+        return Ok(());
+    };
+    let context = scope.get_current_context();
+    let global = context.global(scope);
+    let str_imports = v8::String::new(scope, "imports").unwrap();
+    global.set(scope, str_imports.into(), (*import_object).into());
+
+    for (path, source) in scripts {
+        let scope = &mut v8::TryCatch::new(scope);
+        let source = v8::String::new(scope, source).unwrap();
+        let result = v8::Script::compile(scope, source, None).and_then(|script| script.run(scope));
+        if result.is_some() {
+            continue;
+        }
+        let line = scope
+            .message()
+            .and_then(|message| message.get_line_number(scope))
+            .unwrap_or_default();
+        let exception = scope.exception().map_or_else(String::new, |exception| {
+            exception.to_rust_string_lossy(scope)
+        });
+        return Err(anyhow!("{}:{}: {}", path, line, exception));
+    }
+    Ok(())
+}
@@ -0,0 +1,195 @@
+// Interactive shell of `lv8 repl`, which evaluates JS in the context where the module is instantiated
+
+use anyhow::{anyhow, Result};
+use rustyline::{
+    completion::Completer, error::ReadlineError, history::DefaultHistory, Context, Editor, Helper,
+    Highlighter, Hinter, Validator,
+};
+use std::path::Path;
+
+use super::{create_runtime, describe_exception, init_runtime, wasi, Embedding, Instance};
+use crate::driver::Cli;
+
+/// Globals and helpers defined before the first line is evaluated
+const PRELUDE: &str = r#"
+var exports = gInstance.exports;
+// undefined if the module has no memory
+var memory = globalThis.gMemory;
+// returns the bytes of the memory as hex
+function peek(address, length = 16) {
+  return Array.from(new Uint8Array(gMemory.buffer, address, length),
+    (byte) => byte.toString(16).padStart(2, "0")).join(" ");
+}
+// writes the bytes to the memory
+function poke(address, ...bytes) {
+  new Uint8Array(gMemory.buffer).set(bytes, address);
+}
+"#;
+
+/// Lists the exports with their kinds, and the values of globals
+const DESCRIBE_EXPORTS: &str = r#"
+Object.entries(gInstance.exports).map(([name, value]) => {
+  if (value instanceof WebAssembly.Global) return `${name}: global = ${value.value}`;
+  if (value instanceof WebAssembly.Memory) return `${name}: memory (${value.buffer.byteLength} bytes)`;
+  if (value instanceof WebAssembly.Table) return `${name}: table (${value.length} elements)`;
+  if (typeof value === "function") return `${name}: function (${value.length} parameters)`;
+  return `${name}: ${typeof value}`;
+}).join("\n")
+"#;
+
+const HELP: &str = "\
+Lines are evaluated as JS, where the instance is gInstance.
+  exports               exports of the instance (e.g. exports.add(1, 2), exports.g.value = 1)
+  memory                memory used by WASI functions (e.g. new Uint8Array(memory.buffer))
+  peek(address, len)    bytes of the memory as hex
+  poke(address, ...)    writes bytes to the memory
+  .exports              lists the exports
+  .help                 prints this help
+  .exit                 exits the shell";
+
+/// Names completed at the start of a word
+const GLOBAL_NAMES: &[&str] = &["exports", "memory", "peek", "poke", "gInstance", "gMemory"];
+const COMMANDS: &[&str] = &["exports", "help", "exit"];
+
+/// Completes export names after `exports.`, and global names and commands elsewhere
+#[derive(Helper, Highlighter, Hinter, Validator)]
+struct ReplHelper {
+    export_names: Vec<String>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !(c.is_alphanumeric() || *c == '_' || *c == '$'))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let word = &line[start..];
+        let names: Vec<&str> = if &line[..start] == "." {
+            COMMANDS.to_vec()
+        } else if line[..start].ends_with("exports.") {
+            self.export_names.iter().map(String::as_str).collect()
+        } else {
+            GLOBAL_NAMES.to_vec()
+        };
+        let candidates = names
+            .into_iter()
+            .filter(|name| name.starts_with(word))
+            .map(str::to_string)
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+/// Instantiates the module without running it, and evaluates lines read from the terminal
+pub fn repl(args: &Cli, wasmfile_path: &Path) -> Result<i32> {
+    // exports are called by lines synchronously, while JSPI needs them to be called through
+    // WebAssembly.promising and the event loop
+    if args.jspi {
+        return Err(anyhow!("--jspi is not supported by lv8 repl"));
+    }
+    init_runtime(args)?;
+    wasi::init_wasi_ctx(args, Embedding::default())?;
+    let mut runtime = create_runtime(args, wasmfile_path)?;
+
+    let Instance::Module { instance, tags } = &runtime.instance else {
+        return Err(anyhow!("components are not supported by lv8 repl"));
+    };
+    let scope = &mut v8::HandleScope::new(&mut runtime.isolate);
+    let context = v8::Local::new(scope, &runtime.context);
+    let scope = &mut v8::ContextScope::new(scope, context);
+    if eval(scope, PRELUDE).is_err() {
+        return Err(anyhow!("Failed to define the helpers of the shell"));
+    }
+
+    let wasm_instance = instance.open(scope);
+    let str_exports = v8::String::new(scope, "exports").unwrap();
+    let exports = wasm_instance.get(scope, str_exports.into()).unwrap();
+    let exports = exports.to_object(scope).unwrap();
+    let names = exports
+        .get_own_property_names(scope, Default::default())
+        .unwrap();
+    let export_names = (0..names.length())
+        .filter_map(|i| {
+            let name = names.get_index(scope, i)?;
+            Some(name.to_rust_string_lossy(scope))
+        })
+        .collect();
+
+    let mut editor: Editor<ReplHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(ReplHelper { export_names }));
+    println!("{} (type .help for help)", wasmfile_path.display());
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            // Ctrl-C clears the line, and Ctrl-D exits
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+        let source = match line {
+            ".exit" => break,
+            ".help" => {
+                println!("{}", HELP);
+                continue;
+            }
+            ".exports" => DESCRIBE_EXPORTS,
+            _ => line,
+        };
+
+        // handles are released after each line
+        let scope = &mut v8::HandleScope::new(scope);
+        match eval(scope, source) {
+            Ok(value) => println!("{}", display(scope, value)),
+            Err(exception) => {
+                // proc_exit terminates the execution, which cannot be resumed
+                if let Some(code) = wasi::exit_code() {
+                    return Ok(code);
+                }
+                eprintln!("{}", describe_exception(scope, exception, tags));
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// Evaluates the script, and returns the exception if it throws
+fn eval<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    source: &str,
+) -> Result<v8::Local<'s, v8::Value>, v8::Local<'s, v8::Value>> {
+    let scope = &mut v8::TryCatch::new(scope);
+    let source = v8::String::new(scope, source).unwrap();
+    let result = v8::Script::compile(scope, source, None).and_then(|script| script.run(scope));
+    match result {
+        Some(value) => Ok(value),
+        None => Err(scope
+            .exception()
+            .unwrap_or_else(|| v8::undefined(scope).into())),
+    }
+}
+
+fn display(scope: &mut v8::HandleScope, value: v8::Local<v8::Value>) -> String {
+    if value.is_big_int() {
+        format!("{}n", value.to_rust_string_lossy(scope))
+    } else if value.is_string() {
+        value.to_rust_string_lossy(scope)
+    } else {
+        value
+            .to_detail_string(scope)
+            .map_or_else(String::new, |s| s.to_rust_string_lossy(scope))
+    }
+}
@@ -0,0 +1,381 @@
+// JS programs run by `lv8 run-js`, with a WASI helper compatible with Node's `wasi` module
+//
+// Each WASI instance has its own WASI context, configured by the options given to `new WASI()`
+// (`args`, `env`, `preopens` and `returnOnExit`). Its WASI functions select the context and the
+// memory of the instance before each call, since WASI functions use the global `gMemory`.
+
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use wasi_common::WasiCtx;
+
+use super::{create_wasip1_import, init_v8, policy, set_function, trace, wasi};
+use crate::driver::Cli;
+
+/// Clocks and random source of the WASI contexts, given by the command line options
+static CTX_OPTIONS: OnceLock<wasi::CtxOptions> = OnceLock::new();
+
+/// Defines `WASI`, `require`, `process` and `console`, given the native helpers as `lv8`
+const PRELUDE: &str = r#"
+(function (lv8) {
+  class WASI {
+    #context;
+    #returnOnExit;
+    #memory;
+    #started = false;
+
+    constructor(options = {}) {
+      const env = Object.entries(options.env ?? {}).flat().map(String);
+      const preopens = Object.entries(options.preopens ?? {}).flat().map(String);
+      this.#context = lv8.createContext((options.args ?? []).map(String), env, preopens);
+      this.#returnOnExit = options.returnOnExit ?? true;
+      this.wasiImport = {};
+      for (const [name, func] of Object.entries(lv8.wasiImport())) {
+        this.wasiImport[name] = (...args) => {
+          lv8.selectContext(this.#context, this.#returnOnExit);
+          globalThis.gMemory = this.#memory;
+          return func(...args);
+        };
+      }
+    }
+
+    getImportObject() {
+      return { wasi_snapshot_preview1: this.wasiImport };
+    }
+
+    #bind(instance) {
+      if (this.#started) {
+        throw new Error("WASI instance has already started");
+      }
+      if (!(instance.exports.memory instanceof WebAssembly.Memory)) {
+        throw new TypeError("instance.exports.memory must be a WebAssembly.Memory");
+      }
+      this.#started = true;
+      this.#memory = instance.exports.memory;
+    }
+
+    // returns the exit code given by proc_exit, or 0 if _start returns
+    start(instance) {
+      if (typeof instance.exports._start !== "function") {
+        throw new TypeError("instance.exports._start is not a function");
+      }
+      this.#bind(instance);
+      lv8.takeExitCode();
+      try {
+        instance.exports._start();
+      } catch (e) {
+        const code = lv8.takeExitCode();
+        if (code === undefined) {
+          throw e;
+        }
+        return code;
+      }
+      return 0;
+    }
+
+    initialize(instance) {
+      this.#bind(instance);
+      if (instance.exports._initialize) {
+        instance.exports._initialize();
+      }
+    }
+  }
+
+  const format = (args) => args.map((arg) => {
+    if (typeof arg === "string") return arg;
+    if (typeof arg === "bigint") return `${arg}n`;
+    if (arg instanceof Error) return arg.stack;
+    if (typeof arg === "object" && arg !== null) {
+      try {
+        return JSON.stringify(arg);
+      } catch {
+        return String(arg);
+      }
+    }
+    return String(arg);
+  }).join(" ");
+
+  const modules = {
+    wasi: { WASI },
+    fs: { readFileSync: lv8.readFile },
+  };
+  globalThis.WASI = WASI;
+  globalThis.require = (name) => {
+    const module = modules[name.replace(/^node:/, "")];
+    if (!module) {
+      throw new Error(`Cannot find module '${name}'`);
+    }
+    return module;
+  };
+  globalThis.process = { argv: lv8.argv, env: lv8.env };
+  globalThis.console = {
+    log: (...args) => lv8.print(format(args), false),
+    info: (...args) => lv8.print(format(args), false),
+    debug: (...args) => lv8.print(format(args), false),
+    warn: (...args) => lv8.print(format(args), true),
+    error: (...args) => lv8.print(format(args), true),
+  };
+})
+"#;
+
+/// Returns an option of lv8 which configures the WASI context of `lv8 run` or the instantiation
+/// of the module, and is not supported by `lv8 run-js`
+fn unsupported_option(args: &Cli) -> Option<&'static str> {
+    [
+        (args.wasi_memory.is_some(), "--wasi-memory"),
+        (args.jspi, "--jspi"),
+        (args.emscripten, "--emscripten"),
+        (args.assemblyscript, "--assemblyscript"),
+        (!args.tcplisten.is_empty(), "--tcplisten"),
+        (!args.preload.is_empty(), "--preload"),
+        (!args.inherit_fd.is_empty(), "--inherit-fd"),
+        (!args.mount_mem.is_empty(), "--mount-mem"),
+        (args.mem_export.is_some(), "--mem-export"),
+        (!args.mount_archive.is_empty(), "--mount-archive"),
+        (!args.overlay.is_empty(), "--overlay"),
+        (args.overlay_export.is_some(), "--overlay-export"),
+        (args.stdin.is_some(), "--stdin"),
+        (args.stdout.is_some(), "--stdout"),
+        (args.stderr.is_some(), "--stderr"),
+    ]
+    .into_iter()
+    .find_map(|(given, option)| given.then_some(option))
+}
+
+/// Runs the script, and returns the exit code given by proc_exit or 0
+pub fn run_js(args: &Cli, script_path: &Path) -> Result<i32> {
+    if let Some(option) = unsupported_option(args) {
+        return Err(anyhow!(
+            "{} is not supported by lv8 run-js (the WASI context is configured by `new WASI()`)",
+            option
+        ));
+    }
+    let source = std::fs::read_to_string(script_path)
+        .map_err(|e| anyhow!("Failed to read {}: {}", script_path.display(), e))?;
+
+    // WebAssembly.compile and WebAssembly.instantiate complete in microtasks, since there is no
+    // event loop which waits for background compilation
+    v8::V8::set_flags_from_string("--no-wasm-async-compilation");
+    init_v8(false);
+    trace::init(args)?;
+    policy::init(args)?;
+    let _ = CTX_OPTIONS.set(wasi::CtxOptions::from_args(args));
+
+    let isolate = &mut v8::Isolate::new(Default::default());
+    let scope = &mut v8::HandleScope::new(isolate);
+    let context = v8::Context::new(scope, Default::default());
+    let scope = &mut v8::ContextScope::new(scope, context);
+    let scope = &mut v8::TryCatch::new(scope);
+
+    let native = create_native_helpers(scope, script_path);
+    let result = eval(scope, PRELUDE).and_then(|prelude| {
+        let prelude = prelude.cast::<v8::Function>();
+        let undefined = v8::undefined(scope);
+        prelude.call(scope, undefined.into(), &[native.into()])
+    });
+    if result.is_none() {
+        return Err(anyhow!("Failed to define the WASI helper"));
+    }
+
+    let result = eval(scope, &source);
+    scope.perform_microtask_checkpoint();
+    if let Some(code) = wasi::exit_code() {
+        return Ok(code);
+    }
+    match result {
+        // a promise returned by the script (e.g. `main()`) is awaited
+        Some(value) if value.is_promise() => {
+            let promise = value.cast::<v8::Promise>();
+            if promise.state() == v8::PromiseState::Rejected {
+                let exception = promise.result(scope);
+                return Err(anyhow!(
+                    "{}: Uncaught (in promise) {}",
+                    script_path.display(),
+                    exception.to_rust_string_lossy(scope)
+                ));
+            }
+        }
+        Some(_) => {}
+        None => {
+            let line = scope
+                .message()
+                .and_then(|message| message.get_line_number(scope))
+                .unwrap_or_default();
+            let exception = scope.exception().map_or_else(String::new, |exception| {
+                exception.to_rust_string_lossy(scope)
+            });
+            return Err(anyhow!(
+                "{}:{}: Uncaught {}",
+                script_path.display(),
+                line,
+                exception
+            ));
+        }
+    }
+    Ok(0)
+}
+
+fn eval<'s>(scope: &mut v8::HandleScope<'s>, source: &str) -> Option<v8::Local<'s, v8::Value>> {
+    let source = v8::String::new(scope, source).unwrap();
+    let script = v8::Script::compile(scope, source, None)?;
+    script.run(scope)
+}
+
+/// Returns the object passed to the prelude, which holds native functions and process information
+fn create_native_helpers<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    script_path: &Path,
+) -> v8::Local<'s, v8::Object> {
+    let native = v8::Object::new(scope);
+
+    set_function(scope, native, "wasiImport", wasi_import);
+    set_function(scope, native, "readFile", read_file);
+    set_function(scope, native, "createContext", create_context);
+    set_function(scope, native, "selectContext", select_context);
+    set_function(scope, native, "takeExitCode", take_exit_code);
+    set_function(scope, native, "print", print);
+
+    // argv is [lv8, script, ...arguments after "--"] like [node, script, ...] in Node
+    let mut argv = vec!["lv8".to_string(), script_path.display().to_string()];
+    argv.extend(std::env::args().skip_while(|arg| arg != "--").skip(1));
+    let argv = argv
+        .iter()
+        .map(|arg| v8::String::new(scope, arg).unwrap().into())
+        .collect::<Vec<v8::Local<v8::Value>>>();
+    let argv = v8::Array::new_with_elements(scope, &argv);
+    let str_argv = v8::String::new(scope, "argv").unwrap();
+    native.set(scope, str_argv.into(), argv.into());
+
+    let env = v8::Object::new(scope);
+    for (key, value) in std::env::vars() {
+        let key = v8::String::new(scope, &key).unwrap();
+        let value = v8::String::new(scope, &value).unwrap();
+        env.set(scope, key.into(), value.into());
+    }
+    let str_env = v8::String::new(scope, "env").unwrap();
+    native.set(scope, str_env.into(), env.into());
+
+    native
+}
+
+/// Returns a new object of WASI functions, which is the same as `imports.wasi_snapshot_preview1`
+fn wasi_import(
+    scope: &mut v8::HandleScope,
+    _args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let import_object = v8::Object::new(scope);
+    create_wasip1_import(scope, &import_object);
+    let str_wasip1 = v8::String::new(scope, "wasi_snapshot_preview1").unwrap();
+    if let Some(wasi_import) = import_object.get(scope, str_wasip1.into()) {
+        rv.set(wasi_import);
+    }
+}
+
+/// readFileSync(path[, encoding]), which returns a string if the encoding is given
+fn read_file(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let path = args.get(0).to_rust_string_lossy(scope);
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => {
+            wasi::throw_error(scope, &format!("Failed to read {}: {}", path, e));
+            return;
+        }
+    };
+    if !args.get(1).is_null_or_undefined() {
+        let Some(s) = v8::String::new(scope, &String::from_utf8_lossy(&data)) else {
+            wasi::throw_error(scope, &format!("{} is too large for a string", path));
+            return;
+        };
+        rv.set(s.into());
+        return;
+    }
+    let length = data.len();
+    let backing_store = v8::ArrayBuffer::new_backing_store_from_vec(data).make_shared();
+    let buffer = v8::ArrayBuffer::with_backing_store(scope, &backing_store);
+    if let Some(array) = v8::Uint8Array::new(scope, buffer, 0, length) {
+        rv.set(array.into());
+    }
+}
+
+/// Returns the strings of a JS array, which are converted by the prelude
+fn strings(scope: &mut v8::HandleScope, value: v8::Local<v8::Value>) -> Vec<String> {
+    if !value.is_array() {
+        return vec![];
+    }
+    let array = value.cast::<v8::Array>();
+    (0..array.length())
+        .filter_map(|i| {
+            let value = array.get_index(scope, i)?;
+            Some(value.to_rust_string_lossy(scope))
+        })
+        .collect()
+}
+
+/// createContext(args, [key, value, ...], [guest path, host dir, ...]), which creates the WASI
+/// context of a WASI instance
+fn create_context(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let wasm_args = strings(scope, args.get(0));
+    let env: Vec<(String, String)> = strings(scope, args.get(1))
+        .chunks_exact(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    let preopens: Vec<(String, PathBuf)> = strings(scope, args.get(2))
+        .chunks_exact(2)
+        .map(|pair| (pair[0].clone(), PathBuf::from(&pair[1])))
+        .collect();
+    let options = *CTX_OPTIONS.get().unwrap();
+    match wasi::create_js_wasi_ctx(options, &wasm_args, &env, &preopens) {
+        Ok(ctx) => {
+            let ctx = ctx as *const Mutex<WasiCtx> as *mut std::ffi::c_void;
+            rv.set(v8::External::new(scope, ctx).into());
+        }
+        Err(e) => wasi::throw_error(scope, &e.to_string()),
+    }
+}
+
+/// selectContext(context, returnOnExit), which is called before each WASI function of a WASI
+/// instance
+fn select_context(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    let context = args.get(0);
+    if !context.is_external() {
+        wasi::throw_error(scope, "Invalid WASI context");
+        return;
+    }
+    // SAFETY: contexts are created by createContext, and are never freed
+    let ctx = unsafe { &*(context.cast::<v8::External>().value() as *const Mutex<WasiCtx>) };
+    wasi::select_wasi_ctx(Some(ctx), args.get(1).boolean_value(scope));
+}
+
+/// takeExitCode(), which returns the code given by proc_exit with returnOnExit, or undefined
+fn take_exit_code(
+    scope: &mut v8::HandleScope,
+    _args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    if let Some(code) = wasi::take_returned_exit_code() {
+        rv.set(v8::Integer::new(scope, code).into());
+    }
+}
+
+/// print(message, stderr), which prints a line of console.log or console.error
+fn print(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments, _rv: v8::ReturnValue) {
+    let message = args.get(0).to_rust_string_lossy(scope);
+    if args.get(1).boolean_value(scope) {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
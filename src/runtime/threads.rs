@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Result};
+use std::sync::{
+    atomic::{AtomicI32, Ordering},
+    OnceLock,
+};
+use v8::{ValueDeserializerHelper, ValueSerializerHelper};
+
+use super::module::ModuleInfo;
+
+/// Largest thread ID allowed by wasi-threads
+const MAX_THREAD_ID: i32 = 0x1FFFFFFF;
+/// EAGAIN in wasi_snapshot_preview1
+const ERRNO_AGAIN: i32 = 6;
+
+/// Module and memory shared by all threads
+struct SharedInstance {
+    module: v8::CompiledWasmModule,
+    module_info: ModuleInfo,
+    /// Name of the memory used by WASI functions given by --wasi-memory
+    wasi_memory: Option<String>,
+    memory: v8::SharedRef<v8::BackingStore>,
+    /// WebAssembly.Memory serialized by ValueSerializer, which refers to `memory`
+    serialized_memory: Vec<u8>,
+}
+
+// SAFETY: CompiledWasmModule and the backing store of a shared memory are designed to be
+// shared across isolates, and they are never mutated through this struct
+unsafe impl Send for SharedInstance {}
+unsafe impl Sync for SharedInstance {}
+
+static SHARED_INSTANCE: OnceLock<SharedInstance> = OnceLock::new();
+static NEXT_THREAD_ID: AtomicI32 = AtomicI32::new(1);
+/// Isolate of the main thread, which is terminated when a spawned thread ends the process
+static MAIN_ISOLATE: OnceLock<v8::IsolateHandle> = OnceLock::new();
+/// Error of the spawned thread which ended the process
+static THREAD_ERROR: OnceLock<String> = OnceLock::new();
+
+/// Makes the module and its shared memory available to wasi.thread-spawn
+pub(super) fn init(
+    scope: &mut v8::HandleScope,
+    module: v8::Local<v8::WasmModuleObject>,
+    module_info: ModuleInfo,
+    wasi_memory: Option<String>,
+) -> Result<()> {
+    let context = scope.get_current_context();
+    let global = context.global(scope);
+    let str_memory = v8::String::new(scope, "gMemory").unwrap();
+    let memory = global.get(scope, str_memory.into()).unwrap();
+    if !memory.is_object() {
+        return Err(anyhow!("wasi-threads requires the module to have a memory"));
+    }
+
+    let str_buffer = v8::String::new(scope, "buffer").unwrap();
+    let buffer = memory
+        .to_object(scope)
+        .unwrap()
+        .get(scope, str_buffer.into())
+        .unwrap();
+    if !buffer.is_shared_array_buffer() {
+        return Err(anyhow!("wasi-threads requires the memory to be shared"));
+    }
+    let backing_store = buffer.cast::<v8::SharedArrayBuffer>().get_backing_store();
+
+    // serialize WebAssembly.Memory so that other isolates can create it over the same backing store
+    let serializer = v8::ValueSerializer::new(scope, Box::new(MemorySerializer));
+    serializer.write_header();
+    if serializer.write_value(context, memory) != Some(true) {
+        return Err(anyhow!("Failed to serialize shared memory"));
+    }
+    let serialized_memory = serializer.release();
+
+    let shared_instance = SharedInstance {
+        module: module.get_compiled_module(),
+        module_info,
+        wasi_memory,
+        memory: backing_store,
+        serialized_memory,
+    };
+    if SHARED_INSTANCE.set(shared_instance).is_err() {
+        return Err(anyhow!("Shared instance is already initialized"));
+    }
+    let _ = MAIN_ISOLATE.set(scope.thread_safe_handle());
+    Ok(())
+}
+
+/// Returns the error of the spawned thread which ended the process
+pub(super) fn thread_error() -> Option<String> {
+    THREAD_ERROR.get().cloned()
+}
+
+/// Ends the process from a spawned thread
+///
+/// The process is not exited here, so that the main thread can finish its work before exiting.
+/// Instead, the execution of the main thread is terminated, and it exits with the code given by
+/// proc_exit or with the error.
+fn end_process(error: Option<String>) {
+    if let Some(error) = error {
+        let _ = THREAD_ERROR.set(error);
+    }
+    if let Some(main_isolate) = MAIN_ISOLATE.get() {
+        main_isolate.terminate_execution();
+    }
+}
+
+pub(super) fn wasi_thread_spawn(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    // the exception is thrown to the caller if the argument cannot be converted to a number
+    let Some(start_arg) = args.get(0).int32_value(scope) else {
+        return;
+    };
+
+    let Some(shared_instance) = SHARED_INSTANCE.get() else {
+        rv.set(v8::Integer::new(scope, -ERRNO_AGAIN).into());
+        return;
+    };
+    let tid = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+    if tid > MAX_THREAD_ID {
+        rv.set(v8::Integer::new(scope, -ERRNO_AGAIN).into());
+        return;
+    }
+
+    let result = std::thread::Builder::new()
+        .name(format!("wasi-thread-{}", tid))
+        .spawn(move || run_thread(shared_instance, tid, start_arg));
+    if result.is_ok() {
+        rv.set(v8::Integer::new(scope, tid).into());
+    } else {
+        rv.set(v8::Integer::new(scope, -ERRNO_AGAIN).into());
+    }
+}
+
+/// Instantiates the module in a new isolate and calls `wasi_thread_start(tid, start_arg)`
+fn run_thread(shared_instance: &'static SharedInstance, tid: i32, start_arg: i32) {
+    let mut isolate = v8::Isolate::new(Default::default());
+    let scope = &mut v8::HandleScope::new(&mut isolate);
+    let context = v8::Context::new(scope, Default::default());
+    let scope = &mut v8::ContextScope::new(scope, context);
+
+    let module =
+        v8::WasmModuleObject::from_compiled_module(scope, &shared_instance.module).unwrap();
+
+    // deserialize WebAssembly.Memory backed by the memory of the main thread
+    let memory = {
+        let deserializer = v8::ValueDeserializer::new(
+            scope,
+            Box::new(MemoryDeserializer {
+                backing_store: shared_instance.memory.clone(),
+            }),
+            &shared_instance.serialized_memory,
+        );
+        deserializer.read_header(context);
+        deserializer.read_value(context).unwrap()
+    };
+    let memory = memory.to_object(scope).unwrap();
+
+    let instance = match super::instantiate(
+        scope,
+        module,
+        &shared_instance.module_info,
+        Some(memory),
+        shared_instance.wasi_memory.as_deref(),
+    ) {
+        Ok(instance) => instance,
+        Err(e) => {
+            end_process(Some(e.to_string()));
+            return;
+        }
+    };
+
+    let str_exports = v8::String::new(scope, "exports").unwrap();
+    let exports = instance.get(scope, str_exports.into()).unwrap();
+    let exports = exports.to_object(scope).unwrap();
+
+    let str_thread_start = v8::String::new(scope, "wasi_thread_start").unwrap();
+    let thread_start = exports.get(scope, str_thread_start.into()).unwrap();
+    if !thread_start.is_function() {
+        end_process(Some(
+            "Wasm module does not export wasi_thread_start function".to_string(),
+        ));
+        return;
+    }
+    let thread_start = thread_start.cast::<v8::Function>();
+
+    // call instance.exports.wasi_thread_start(tid, start_arg)
+    let tid_value = v8::Integer::new(scope, tid);
+    let start_arg = v8::Integer::new(scope, start_arg);
+    if super::call_export(
+        scope,
+        thread_start,
+        exports.into(),
+        &[tid_value.into(), start_arg.into()],
+    )
+    .is_err()
+    {
+        // proc_exit or a trap in any thread ends the whole process
+        if super::wasi::exit_code().is_some() {
+            end_process(None);
+        } else {
+            end_process(Some(format!("thread {} exited with an exception", tid)));
+        }
+    }
+}
+
+struct MemorySerializer;
+
+impl v8::ValueSerializerImpl for MemorySerializer {
+    fn throw_data_clone_error<'s>(
+        &self,
+        scope: &mut v8::HandleScope<'s>,
+        message: v8::Local<'s, v8::String>,
+    ) {
+        let error = v8::Exception::error(scope, message);
+        scope.throw_exception(error);
+    }
+
+    fn get_shared_array_buffer_id<'s>(
+        &self,
+        _scope: &mut v8::HandleScope<'s>,
+        _shared_array_buffer: v8::Local<'s, v8::SharedArrayBuffer>,
+    ) -> Option<u32> {
+        // the only SharedArrayBuffer is the buffer of the memory
+        Some(0)
+    }
+}
+
+struct MemoryDeserializer {
+    backing_store: v8::SharedRef<v8::BackingStore>,
+}
+
+impl v8::ValueDeserializerImpl for MemoryDeserializer {
+    fn get_shared_array_buffer_from_id<'s>(
+        &self,
+        scope: &mut v8::HandleScope<'s>,
+        _transfer_id: u32,
+    ) -> Option<v8::Local<'s, v8::SharedArrayBuffer>> {
+        Some(v8::SharedArrayBuffer::with_backing_store(
+            scope,
+            &self.backing_store,
+        ))
+    }
+}
@@ -0,0 +1,467 @@
+// strace-like tracing of WASI calls (--trace-wasi)
+// Each call is written as a line of text or JSON after it returns.
+
+use anyhow::{anyhow, Result};
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{LineWriter, Write},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use wasi_common::snapshots::preview_1::types::Errno;
+
+use crate::driver::Cli;
+
+/// Maximum number of iovecs decoded for a call
+const MAX_IOVS: u64 = 64;
+
+static TRACER: OnceLock<Tracer> = OnceLock::new();
+
+struct Tracer {
+    output: Mutex<Box<dyn Write + Send>>,
+    json: bool,
+}
+
+pub(super) fn init(args: &Cli) -> Result<()> {
+    if !args.trace_wasi && args.trace_file.is_none() {
+        return Ok(());
+    }
+    let output: Box<dyn Write + Send> = match &args.trace_file {
+        Some(path) => {
+            let file = File::create(path)
+                .map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+            // lines are flushed one by one, since proc_exit does not return
+            Box::new(LineWriter::new(file))
+        }
+        None => Box::new(std::io::stderr()),
+    };
+    let tracer = Tracer {
+        output: Mutex::new(output),
+        json: args.trace_json,
+    };
+    if TRACER.set(tracer).is_err() {
+        return Err(anyhow!("Tracer is already initialized"));
+    }
+    Ok(())
+}
+
+pub(super) fn is_enabled() -> bool {
+    TRACER.get().is_some()
+}
+
+/// Parameter of a WASI function
+#[derive(Clone, Copy)]
+enum Param {
+    Int(&'static str),
+    /// Pointer and length of a string, which take two arguments
+    Path(&'static str),
+    /// Pointer and length of an array of iovecs, which take two arguments
+    Iovs(&'static str),
+}
+
+use Param::*;
+
+/// Returns the parameters of the function, in the order of wasi_snapshot_preview1
+fn params(name: &str) -> &'static [Param] {
+    match name {
+        "args_get" => &[Int("argv"), Int("argv_buf")],
+        "args_sizes_get" => &[Int("argc_ptr"), Int("argv_buf_size_ptr")],
+        "clock_res_get" => &[Int("id"), Int("resolution_ptr")],
+        "clock_time_get" => &[Int("id"), Int("precision"), Int("time_ptr")],
+        "environ_get" => &[Int("environ"), Int("environ_buf")],
+        "environ_sizes_get" => &[Int("count_ptr"), Int("size_ptr")],
+        "fd_advise" => &[Int("fd"), Int("offset"), Int("len"), Int("advice")],
+        "fd_allocate" => &[Int("fd"), Int("offset"), Int("len")],
+        "fd_close" | "fd_datasync" | "fd_sync" => &[Int("fd")],
+        "fd_fdstat_get" => &[Int("fd"), Int("stat_ptr")],
+        "fd_fdstat_set_flags" => &[Int("fd"), Int("flags")],
+        "fd_fdstat_set_rights" => &[
+            Int("fd"),
+            Int("fs_rights_base"),
+            Int("fs_rights_inheriting"),
+        ],
+        "fd_filestat_get" => &[Int("fd"), Int("buf")],
+        "fd_filestat_set_size" => &[Int("fd"), Int("size")],
+        "fd_filestat_set_times" => &[Int("fd"), Int("atim"), Int("mtim"), Int("fst_flags")],
+        "fd_pread" => &[Int("fd"), Iovs("iovs"), Int("offset"), Int("nread_ptr")],
+        "fd_prestat_dir_name" => &[Int("fd"), Int("path"), Int("path_len")],
+        "fd_prestat_get" => &[Int("fd"), Int("buf")],
+        "fd_pwrite" => &[Int("fd"), Iovs("iovs"), Int("offset"), Int("nwritten_ptr")],
+        "fd_read" => &[Int("fd"), Iovs("iovs"), Int("nread_ptr")],
+        "fd_readdir" => &[
+            Int("fd"),
+            Int("buf"),
+            Int("buf_len"),
+            Int("cookie"),
+            Int("bufused_ptr"),
+        ],
+        "fd_renumber" => &[Int("fd"), Int("to")],
+        "fd_seek" => &[
+            Int("fd"),
+            Int("offset"),
+            Int("whence"),
+            Int("newoffset_ptr"),
+        ],
+        "fd_tell" => &[Int("fd"), Int("offset_ptr")],
+        "fd_write" => &[Int("fd"), Iovs("iovs"), Int("nwritten_ptr")],
+        "path_create_directory" | "path_remove_directory" | "path_unlink_file" => {
+            &[Int("fd"), Path("path")]
+        }
+        "path_filestat_get" => &[Int("fd"), Int("flags"), Path("path"), Int("buf")],
+        "path_filestat_set_times" => &[
+            Int("fd"),
+            Int("flags"),
+            Path("path"),
+            Int("atim"),
+            Int("mtim"),
+            Int("fst_flags"),
+        ],
+        "path_link" => &[
+            Int("old_fd"),
+            Int("old_flags"),
+            Path("old_path"),
+            Int("new_fd"),
+            Path("new_path"),
+        ],
+        "path_open" => &[
+            Int("fd"),
+            Int("dirflags"),
+            Path("path"),
+            Int("oflags"),
+            Int("fs_rights_base"),
+            Int("fs_rights_inheriting"),
+            Int("fdflags"),
+            Int("fd_ptr"),
+        ],
+        "path_readlink" => &[
+            Int("fd"),
+            Path("path"),
+            Int("buf"),
+            Int("buf_len"),
+            Int("bufused_ptr"),
+        ],
+        "path_rename" => &[Int("fd"), Path("old_path"), Int("new_fd"), Path("new_path")],
+        "path_symlink" => &[Path("old_path"), Int("fd"), Path("new_path")],
+        "poll_oneoff" => &[
+            Int("in"),
+            Int("out"),
+            Int("nsubscriptions"),
+            Int("nevents_ptr"),
+        ],
+        "proc_exit" => &[Int("rval")],
+        "proc_raise" => &[Int("sig")],
+        "random_get" => &[Int("buf"), Int("buf_len")],
+        "sock_accept" => &[Int("fd"), Int("flags"), Int("fd_ptr")],
+        "sock_recv" => &[
+            Int("fd"),
+            Iovs("ri_data"),
+            Int("ri_flags"),
+            Int("ro_datalen_ptr"),
+            Int("ro_flags_ptr"),
+        ],
+        "sock_send" => &[
+            Int("fd"),
+            Iovs("si_data"),
+            Int("si_flags"),
+            Int("so_datalen_ptr"),
+        ],
+        "sock_shutdown" => &[Int("fd"), Int("how")],
+        _ => &[],
+    }
+}
+
+/// Argument decoded from the arguments and the memory
+enum Arg {
+    Int(i64),
+    Str(String),
+    /// Lengths of the buffers of iovecs
+    Lengths(Vec<u64>),
+    /// Pointer which is out of bounds of the memory
+    Invalid,
+}
+
+/// WASI call being traced
+pub(super) struct Call {
+    name: &'static str,
+    args: Vec<(&'static str, Arg)>,
+    start: Instant,
+}
+
+impl Call {
+    /// Decodes the arguments before the call, since the memory may be modified by the call
+    ///
+    /// Pointers and iovecs of memory64 modules are 64-bit.
+    pub fn new(name: &'static str, values: &[i64], memory64: bool, memory: &[u8]) -> Self {
+        let pointer = |value: i64| {
+            if memory64 {
+                value as u64
+            } else {
+                value as u32 as u64
+            }
+        };
+        let mut values = values.iter().copied();
+        let mut args = vec![];
+        for param in params(name) {
+            match *param {
+                Int(name) => args.push((name, Arg::Int(values.next().unwrap_or_default()))),
+                Path(name) => {
+                    let ptr = pointer(values.next().unwrap_or_default());
+                    let len = pointer(values.next().unwrap_or_default());
+                    args.push((name, read_string(memory, ptr, len)));
+                }
+                Iovs(name) => {
+                    let ptr = pointer(values.next().unwrap_or_default());
+                    let len = pointer(values.next().unwrap_or_default());
+                    args.push((name, read_iov_lengths(memory, ptr, len, memory64)));
+                }
+            }
+        }
+        // arguments of unknown functions are shown without names
+        args.extend(values.map(|value| ("arg", Arg::Int(value))));
+
+        Call {
+            name,
+            args,
+            start: Instant::now(),
+        }
+    }
+
+    /// Writes the call with its result
+    pub fn finish(self, errno: i32) {
+        self.write(Ok(errno));
+    }
+
+    /// Writes the call which does not return an errno, with the reason
+    /// (e.g. a trap, or proc_exit)
+    pub fn finish_without_return(self, reason: &str) {
+        self.write(Err(reason));
+    }
+
+    fn write(self, result: Result<i32, &str>) {
+        let Some(tracer) = TRACER.get() else {
+            return;
+        };
+        let line = self.format(result, self.start.elapsed(), tracer.json);
+        let mut output = tracer.output.lock().unwrap();
+        let _ = writeln!(output, "{}", line);
+    }
+
+    fn format(&self, result: Result<i32, &str>, elapsed: Duration, json: bool) -> String {
+        let errno_name = |errno: i32| match Errno::try_from(errno) {
+            Ok(errno) => format!("{:?}", errno).to_lowercase(),
+            Err(_) => "unknown".to_string(),
+        };
+
+        let mut line = String::new();
+        if json {
+            let _ = write!(line, "{{\"function\":{}", json_string(self.name));
+            line.push_str(",\"args\":{");
+            for (i, (name, arg)) in self.args.iter().enumerate() {
+                if i > 0 {
+                    line.push(',');
+                }
+                let _ = write!(line, "{}:", json_string(name));
+                match arg {
+                    Arg::Int(value) => {
+                        let _ = write!(line, "{}", value);
+                    }
+                    Arg::Str(s) => line.push_str(&json_string(s)),
+                    Arg::Lengths(lengths) => {
+                        let lengths: Vec<String> = lengths.iter().map(u64::to_string).collect();
+                        let _ = write!(line, "[{}]", lengths.join(","));
+                    }
+                    Arg::Invalid => line.push_str("null"),
+                }
+            }
+            match result {
+                Ok(errno) => {
+                    let _ = write!(
+                        line,
+                        "}},\"errno\":{},\"errno_name\":{}",
+                        errno,
+                        json_string(&errno_name(errno))
+                    );
+                }
+                Err(reason) => {
+                    let _ = write!(line, "}},\"errno\":null,\"reason\":{}", json_string(reason));
+                }
+            }
+            let _ = write!(line, ",\"duration_ns\":{}}}", elapsed.as_nanos());
+        } else {
+            let args: Vec<String> = self
+                .args
+                .iter()
+                .map(|(name, arg)| match arg {
+                    Arg::Int(value) => format!("{}={}", name, value),
+                    Arg::Str(s) => format!("{}={:?}", name, s),
+                    Arg::Lengths(lengths) => format!("{}={:?}", name, lengths),
+                    Arg::Invalid => format!("{}=<out of bounds>", name),
+                })
+                .collect();
+            let result = match result {
+                Ok(errno) => format!("{} ({})", errno, errno_name(errno)),
+                Err(reason) => format!("? ({})", reason),
+            };
+            let _ = write!(
+                line,
+                "{}({}) = {} <{:.6}s>",
+                self.name,
+                args.join(", "),
+                result,
+                elapsed.as_secs_f64()
+            );
+        }
+        line
+    }
+}
+
+fn read_bytes(memory: &[u8], ptr: u64, len: u64) -> Option<&[u8]> {
+    let start = usize::try_from(ptr).ok()?;
+    memory.get(start..start.checked_add(usize::try_from(len).ok()?)?)
+}
+
+fn read_string(memory: &[u8], ptr: u64, len: u64) -> Arg {
+    match read_bytes(memory, ptr, len) {
+        Some(bytes) => Arg::Str(String::from_utf8_lossy(bytes).into_owned()),
+        None => Arg::Invalid,
+    }
+}
+
+/// Reads the lengths of iovecs, each of which is a pair of a pointer and a length
+/// (u32 on wasm32 and u64 on wasm64)
+fn read_iov_lengths(memory: &[u8], ptr: u64, len: u64, memory64: bool) -> Arg {
+    let size = if memory64 { 16 } else { 8 };
+    let Some(iovs) = read_bytes(memory, ptr, len.min(MAX_IOVS) * size) else {
+        return Arg::Invalid;
+    };
+    let lengths = iovs
+        .chunks_exact(size as usize)
+        .map(|iov| {
+            if memory64 {
+                u64::from_le_bytes(iov[8..16].try_into().unwrap())
+            } else {
+                u32::from_le_bytes(iov[4..8].try_into().unwrap()) as u64
+            }
+        })
+        .collect();
+    Arg::Lengths(lengths)
+}
+
+pub(super) fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELAPSED: Duration = Duration::from_micros(21);
+
+    /// Memory with the path "model.bin" at 16 and iovecs at 32
+    fn memory(memory64: bool) -> Vec<u8> {
+        let mut memory = vec![0u8; 128];
+        memory[16..25].copy_from_slice(b"model.bin");
+        if memory64 {
+            memory[40..48].copy_from_slice(&5u64.to_le_bytes());
+            memory[56..64].copy_from_slice(&7u64.to_le_bytes());
+        } else {
+            memory[36..40].copy_from_slice(&5u32.to_le_bytes());
+            memory[44..48].copy_from_slice(&7u32.to_le_bytes());
+        }
+        memory
+    }
+
+    #[test]
+    fn formats_calls_as_text() {
+        let args = [3, 1, 16, 9, 0, 2, 0, 0, 100];
+        let call = Call::new("path_open", &args, false, &memory(false));
+        assert_eq!(
+            call.format(Ok(44), ELAPSED, false),
+            "path_open(fd=3, dirflags=1, path=\"model.bin\", oflags=0, fs_rights_base=2, \
+             fs_rights_inheriting=0, fdflags=0, fd_ptr=100) = 44 (noent) <0.000021s>"
+        );
+
+        let call = Call::new("fd_write", &[1, 32, 2, 64], false, &memory(false));
+        assert_eq!(
+            call.format(Ok(0), ELAPSED, false),
+            "fd_write(fd=1, iovs=[5, 7], nwritten_ptr=64) = 0 (success) <0.000021s>"
+        );
+    }
+
+    #[test]
+    fn formats_calls_as_json() {
+        let call = Call::new("path_unlink_file", &[3, 16, 9], false, &memory(false));
+        assert_eq!(
+            call.format(Ok(0), ELAPSED, true),
+            "{\"function\":\"path_unlink_file\",\"args\":{\"fd\":3,\"path\":\"model.bin\"},\
+             \"errno\":0,\"errno_name\":\"success\",\"duration_ns\":21000}"
+        );
+
+        let call = Call::new("fd_write", &[1, 120, 2, 64], false, &memory(false));
+        assert_eq!(
+            call.format(Ok(21), ELAPSED, true),
+            "{\"function\":\"fd_write\",\"args\":{\"fd\":1,\"iovs\":null,\"nwritten_ptr\":64},\
+             \"errno\":21,\"errno_name\":\"fault\",\"duration_ns\":21000}"
+        );
+    }
+
+    #[test]
+    fn formats_calls_which_do_not_return() {
+        let call = Call::new("path_unlink_file", &[3, 16, 9], false, &memory(false));
+        assert_eq!(
+            call.format(Err("unexpected IoError"), ELAPSED, false),
+            "path_unlink_file(fd=3, path=\"model.bin\") = ? (unexpected IoError) <0.000021s>"
+        );
+        let call = Call::new("proc_exit", &[1], false, &[]);
+        assert_eq!(
+            call.format(Err("exit"), ELAPSED, true),
+            "{\"function\":\"proc_exit\",\"args\":{\"rval\":1},\
+             \"errno\":null,\"reason\":\"exit\",\"duration_ns\":21000}"
+        );
+    }
+
+    #[test]
+    fn decodes_wasm64_iovecs() {
+        let call = Call::new("fd_write", &[1, 32, 2, 64], true, &memory(true));
+        assert_eq!(
+            call.format(Ok(0), ELAPSED, false),
+            "fd_write(fd=1, iovs=[5, 7], nwritten_ptr=64) = 0 (success) <0.000021s>"
+        );
+    }
+
+    #[test]
+    fn shows_invalid_pointers_and_unknown_functions() {
+        let call = Call::new("path_create_directory", &[3, 120, 9], false, &memory(false));
+        assert_eq!(
+            call.format(Ok(21), ELAPSED, false),
+            "path_create_directory(fd=3, path=<out of bounds>) = 21 (fault) <0.000021s>"
+        );
+        let call = Call::new("unknown", &[1, 2], false, &[]);
+        assert_eq!(
+            call.format(Ok(52), ELAPSED, false),
+            "unknown(arg=1, arg=2) = 52 (nosys) <0.000021s>"
+        );
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+}
@@ -0,0 +1,125 @@
+// Validation of wasm modules by `lv8 validate`
+// Modules are compiled by V8 with the same features as `lv8 run`, so the errors are those of V8.
+// Components are not compiled by V8, and are validated by wasmparser instead.
+
+use std::path::{Path, PathBuf};
+
+/// Result of compiling a module
+enum Validation {
+    Valid,
+    Invalid {
+        message: String,
+        /// Byte offset of the error in the module
+        offset: Option<u64>,
+        /// Index of the function which failed to compile
+        function: Option<u32>,
+    },
+}
+
+/// Validates the modules, and prints the errors of the invalid ones
+///
+/// Returns 1 if any of the modules is invalid or cannot be read, and 0 otherwise.
+pub fn validate(paths: &[PathBuf]) -> anyhow::Result<i32> {
+    super::init_v8(false);
+    let isolate = &mut v8::Isolate::new(Default::default());
+    let scope = &mut v8::HandleScope::new(isolate);
+    let context = v8::Context::new(scope, Default::default());
+    let scope = &mut v8::ContextScope::new(scope, context);
+
+    let mut exit_code = 0;
+    for path in paths {
+        match validate_file(scope, path) {
+            Ok(Validation::Valid) => println!("{}: valid", path.display()),
+            Ok(Validation::Invalid {
+                message,
+                offset,
+                function,
+            }) => {
+                exit_code = 1;
+                println!("{}: invalid", path.display());
+                println!("  error: {}", message);
+                if let Some(offset) = offset {
+                    println!("  offset: {:#x} ({})", offset, offset);
+                }
+                if let Some(function) = function {
+                    println!("  function: {}", function);
+                }
+            }
+            Err(message) => {
+                exit_code = 1;
+                println!("{}: error", path.display());
+                println!("  error: {}", message);
+            }
+        }
+    }
+    Ok(exit_code)
+}
+
+fn validate_file(scope: &mut v8::HandleScope, path: &Path) -> Result<Validation, String> {
+    let wasm_module = std::fs::read(path).map_err(|e| format!("Failed to read file: {}", e))?;
+    if wasmparser::Parser::is_component(&wasm_module) {
+        let mut validator =
+            wasmparser::Validator::new_with_features(wasmparser::WasmFeatures::all());
+        return Ok(match validator.validate_all(&wasm_module) {
+            Ok(_) => Validation::Valid,
+            Err(e) => Validation::Invalid {
+                message: e.message().to_string(),
+                offset: Some(e.offset() as u64),
+                function: None,
+            },
+        });
+    }
+    // handles are released after each module, so that many modules can be validated
+    let scope = &mut v8::HandleScope::new(scope);
+    match super::compile(scope, &wasm_module) {
+        Ok(_) => Ok(Validation::Valid),
+        Err(message) => {
+            let (offset, function) = parse_location(&message);
+            Ok(Validation::Invalid {
+                message,
+                offset,
+                function,
+            })
+        }
+    }
+}
+
+/// Extracts the byte offset (`@+123`) and the function index (`function #4`) from the message of V8
+fn parse_location(message: &str) -> (Option<u64>, Option<u32>) {
+    fn number_after<T: std::str::FromStr>(message: &str, prefix: &str) -> Option<T> {
+        let start = message.rfind(prefix)? + prefix.len();
+        let digits = message[start..]
+            .split(|c: char| !c.is_ascii_digit())
+            .next()?;
+        digits.parse().ok()
+    }
+    (
+        number_after(message, "@+"),
+        number_after(message, "function #"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_offsets_and_functions_from_messages() {
+        assert_eq!(
+            parse_location(
+                "WebAssembly.Module(): Compiling function #3 failed: type error in fallthru[0] (expected i32, got f32) @+71"
+            ),
+            (Some(71), Some(3))
+        );
+        assert_eq!(
+            parse_location(
+                "WebAssembly.Module(): expected magic word 00 61 73 6d, found 7f 45 4c 46 @+0"
+            ),
+            (Some(0), None)
+        );
+        assert_eq!(
+            parse_location("WebAssembly.Module(): BufferSource argument is empty"),
+            (None, None)
+        );
+    }
+}
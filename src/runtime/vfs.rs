@@ -0,0 +1,1286 @@
+// In-memory filesystem which can be preopened by the wasm module in place of a host directory
+// Symbolic links and hard links are not supported.
+// Trees loaded from tar or zip archives are read-only.
+// Overlays are trees of a host directory whose files are read from the host until they are written
+// (copy-on-write), and whose changes are reported after the run.
+
+use anyhow::{anyhow, Result};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{IoSlice, IoSliceMut, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::SystemTime,
+};
+use wasi_common::{
+    dir::{OpenResult, ReaddirCursor, ReaddirEntity},
+    file::{FdFlags, FileType, Filestat, OFlags},
+    snapshots::preview_1::types::Errno,
+    Error, ErrorExt, SystemTimeSpec, WasiDir, WasiFile,
+};
+
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+type DirRef = Arc<RwLock<DirNode>>;
+type FileRef = Arc<RwLock<FileNode>>;
+
+#[derive(Clone)]
+enum Node {
+    File(FileRef),
+    Dir(DirRef),
+}
+
+struct FileNode {
+    inode: u64,
+    /// Contents, unless they are still those of `host_file`
+    data: Vec<u8>,
+    /// Host file which is read until the file is written, when its contents are copied up to `data`
+    host_file: Option<PathBuf>,
+    /// Whether the file has been written since it was created or copied from the host
+    dirty: bool,
+    atim: SystemTime,
+    mtim: SystemTime,
+}
+
+struct DirNode {
+    inode: u64,
+    entries: BTreeMap<String, Node>,
+    atim: SystemTime,
+    mtim: SystemTime,
+}
+
+impl FileNode {
+    fn new(data: Vec<u8>) -> FileRef {
+        let now = SystemTime::now();
+        Arc::new(RwLock::new(FileNode {
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            data,
+            host_file: None,
+            dirty: false,
+            atim: now,
+            mtim: now,
+        }))
+    }
+
+    /// Creates a file whose contents are read from the host file until it is written
+    fn from_host(path: PathBuf, metadata: &std::fs::Metadata) -> FileRef {
+        let now = SystemTime::now();
+        Arc::new(RwLock::new(FileNode {
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            data: vec![],
+            host_file: Some(path),
+            dirty: false,
+            atim: metadata.accessed().unwrap_or(now),
+            mtim: metadata.modified().unwrap_or(now),
+        }))
+    }
+
+    fn len(&self) -> u64 {
+        match &self.host_file {
+            Some(path) => std::fs::metadata(path).map_or(0, |metadata| metadata.len()),
+            None => self.data.len() as u64,
+        }
+    }
+
+    fn read_at(&self, bufs: &mut [IoSliceMut], offset: u64) -> Result<u64, Error> {
+        let Some(path) = &self.host_file else {
+            return Ok(read_at(&self.data, bufs, offset));
+        };
+        let len = bufs.iter().map(|buf| buf.len() as u64).sum::<u64>();
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![];
+        file.take(len).read_to_end(&mut data)?;
+        Ok(read_at(&data, bufs, 0))
+    }
+
+    /// Returns the contents
+    fn contents(&self) -> std::io::Result<Vec<u8>> {
+        match &self.host_file {
+            Some(path) => std::fs::read(path),
+            None => Ok(self.data.clone()),
+        }
+    }
+
+    /// Returns the contents to be written, copying them up from the host file if needed
+    fn data_mut(&mut self) -> Result<&mut Vec<u8>, Error> {
+        if let Some(path) = &self.host_file {
+            self.data = std::fs::read(path)?;
+            self.host_file = None;
+        }
+        self.dirty = true;
+        self.mtim = SystemTime::now();
+        Ok(&mut self.data)
+    }
+
+    /// Empties the file, which does not need to copy up the contents
+    fn truncate(&mut self) {
+        self.host_file = None;
+        self.data.clear();
+        self.dirty = true;
+        self.mtim = SystemTime::now();
+    }
+
+    fn stat(&self) -> Filestat {
+        Filestat {
+            device_id: 0,
+            inode: self.inode,
+            filetype: FileType::RegularFile,
+            nlink: 1,
+            size: self.len(),
+            atim: Some(self.atim),
+            mtim: Some(self.mtim),
+            ctim: Some(self.mtim),
+        }
+    }
+}
+
+impl DirNode {
+    fn new() -> DirRef {
+        let now = SystemTime::now();
+        Arc::new(RwLock::new(DirNode {
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            entries: BTreeMap::new(),
+            atim: now,
+            mtim: now,
+        }))
+    }
+
+    fn stat(&self) -> Filestat {
+        Filestat {
+            device_id: 0,
+            inode: self.inode,
+            filetype: FileType::Directory,
+            nlink: 1,
+            size: 0,
+            atim: Some(self.atim),
+            mtim: Some(self.mtim),
+            ctim: Some(self.mtim),
+        }
+    }
+}
+
+impl Node {
+    fn stat(&self) -> Filestat {
+        match self {
+            Node::File(file) => file.read().unwrap().stat(),
+            Node::Dir(dir) => dir.read().unwrap().stat(),
+        }
+    }
+}
+
+/// Directory tree kept in memory
+///
+/// Clones share the same tree, so the embedder can inspect what the wasm module wrote after the run.
+#[derive(Clone)]
+pub struct MemFs {
+    root: DirRef,
+    read_only: bool,
+}
+
+impl Default for MemFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemFs {
+    /// Creates an empty tree
+    pub fn new() -> Self {
+        MemFs {
+            root: DirNode::new(),
+            read_only: false,
+        }
+    }
+
+    /// Creates a tree with a copy of the host directory
+    ///
+    /// Symbolic links to regular files are followed, while those to directories are skipped since
+    /// they may form cycles. Files which are neither regular files nor directories, and files whose
+    /// names are not UTF-8 (which cannot be named by WASI paths) are skipped as well.
+    pub fn from_host_dir(path: &Path) -> Result<Self> {
+        let fs = Self::new();
+        copy_from_host(&fs.root, path, "", false, &mut BTreeMap::new())
+            .map_err(|e| anyhow!("Failed to copy {}: {}", path.display(), e))?;
+        Ok(fs)
+    }
+
+    /// Creates a read-only tree with the contents of a tar (optionally gzipped) or zip archive
+    ///
+    /// The format is chosen by the extension, and entries which are neither regular files nor
+    /// directories are skipped.
+    pub fn from_archive(path: &Path) -> Result<Self> {
+        let mut fs = Self::new();
+        let file =
+            File::open(path).map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+        let file_name = path.to_string_lossy();
+        let result = if file_name.ends_with(".zip") {
+            fs.load_zip(file)
+        } else if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
+            fs.load_tar(flate2::read::GzDecoder::new(file))
+        } else {
+            fs.load_tar(file)
+        };
+        result.map_err(|e| anyhow!("Failed to load {}: {}", path.display(), e))?;
+        fs.read_only = true;
+        Ok(fs)
+    }
+
+    fn load_tar(&self, reader: impl Read) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            match entry.header().entry_type() {
+                tar::EntryType::Directory => {
+                    self.create_dir_all(&path)?;
+                }
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let mut data = vec![];
+                    entry.read_to_end(&mut data)?;
+                    self.write_file(&path, data)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn load_zip(&self, file: File) -> Result<()> {
+        let mut archive = zip::ZipArchive::new(file)?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            // names which escape the archive are rejected
+            let Some(path) = entry.enclosed_name() else {
+                return Err(anyhow!("Invalid file name: {}", entry.name()));
+            };
+            let path = path.to_string_lossy().into_owned();
+            if entry.is_dir() {
+                self.create_dir_all(&path)?;
+            } else {
+                let mut data = vec![];
+                entry.read_to_end(&mut data)?;
+                self.write_file(&path, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the directory at the path and its parents
+    fn create_dir_all(&self, path: &str) -> Result<DirRef> {
+        // leading slashes of archive entries are ignored
+        let path = path.trim_start_matches('/');
+        let names = split_path(path).map_err(|_| anyhow!("Invalid path: {}", path))?;
+        let mut dir = self.root.clone();
+        for name in names {
+            let child = dir
+                .write()
+                .unwrap()
+                .entries
+                .entry(name.to_string())
+                .or_insert_with(|| Node::Dir(DirNode::new()))
+                .clone();
+            dir = match child {
+                Node::Dir(child) => child,
+                Node::File(_) => return Err(anyhow!("Not a directory: {}", name)),
+            };
+        }
+        Ok(dir)
+    }
+
+    /// Creates or overwrites the file at the path, creating its parent directories
+    pub fn write_file(&self, path: &str, data: impl Into<Vec<u8>>) -> Result<()> {
+        let (parent, name) = match path.trim_start_matches('/').rsplit_once('/') {
+            Some((parent, name)) => (self.create_dir_all(parent)?, name),
+            None => (self.root.clone(), path.trim_start_matches('/')),
+        };
+        if matches!(name, "" | "." | "..") {
+            return Err(anyhow!("Invalid path: {}", path));
+        }
+
+        let mut dir = parent.write().unwrap();
+        if let Some(Node::Dir(_)) = dir.entries.get(name) {
+            return Err(anyhow!("Is a directory: {}", path));
+        }
+        dir.entries
+            .insert(name.to_string(), Node::File(FileNode::new(data.into())));
+        Ok(())
+    }
+
+    /// Returns the contents of the file at the path
+    pub fn read_file(&self, path: &str) -> Option<Vec<u8>> {
+        match self.root_dir().lookup(path.trim_start_matches('/')) {
+            Ok(Node::File(file)) => file.read().unwrap().contents().ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the path and the contents of every file, sorted by path
+    pub fn files(&self) -> Vec<(String, Vec<u8>)> {
+        let mut files = vec![];
+        collect_files(&self.root, "", &mut files);
+        files
+    }
+
+    /// Writes the tree to the host directory, which is created if it does not exist
+    pub fn export(&self, path: &Path) -> Result<()> {
+        export_to_host(&self.root, path)
+            .map_err(|e| anyhow!("Failed to export to {}: {}", path.display(), e))
+    }
+
+    /// Returns the root directory to be preopened
+    pub(super) fn root_dir(&self) -> MemDir {
+        MemDir {
+            dir: self.root.clone(),
+            read_only: self.read_only,
+        }
+    }
+}
+
+/// Copies the host directory into the tree, and records the paths of the copied files and
+/// directories (with a trailing slash) with their inodes
+///
+/// If `lazy` is set, the contents of files are left in the host until they are written.
+fn copy_from_host(
+    dir: &DirRef,
+    path: &Path,
+    prefix: &str,
+    lazy: bool,
+    copied: &mut BTreeMap<String, u64>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let guest_path = format!("{}/{}", prefix, name);
+        // symbolic links are not followed to directories, which may contain the link itself
+        let mut metadata = std::fs::symlink_metadata(entry.path())?;
+        if metadata.is_symlink() {
+            match std::fs::metadata(entry.path()) {
+                Ok(target) if target.is_file() => metadata = target,
+                _ => continue,
+            }
+        }
+        let node = if metadata.is_dir() {
+            let child = DirNode::new();
+            copy_from_host(&child, &entry.path(), &guest_path, lazy, copied)?;
+            copied.insert(format!("{}/", guest_path), child.read().unwrap().inode);
+            Node::Dir(child)
+        } else if metadata.is_file() {
+            let file = if lazy {
+                FileNode::from_host(entry.path(), &metadata)
+            } else {
+                FileNode::new(std::fs::read(entry.path())?)
+            };
+            copied.insert(guest_path, file.read().unwrap().inode);
+            Node::File(file)
+        } else {
+            continue;
+        };
+        dir.write().unwrap().entries.insert(name, node);
+    }
+    Ok(())
+}
+
+fn collect_files(dir: &DirRef, prefix: &str, files: &mut Vec<(String, Vec<u8>)>) {
+    for (name, node) in &dir.read().unwrap().entries {
+        let path = format!("{}/{}", prefix, name);
+        match node {
+            Node::File(file) => {
+                // host files of overlays may have been removed since they were mounted
+                if let Ok(data) = file.read().unwrap().contents() {
+                    files.push((path, data));
+                }
+            }
+            Node::Dir(child) => collect_files(child, &path, files),
+        }
+    }
+}
+
+/// Collects the paths of files and directories (with a trailing slash) with their inodes,
+/// and whether the files have been written
+fn collect_nodes(dir: &DirRef, prefix: &str, nodes: &mut BTreeMap<String, (u64, bool)>) {
+    for (name, node) in &dir.read().unwrap().entries {
+        let path = format!("{}/{}", prefix, name);
+        match node {
+            Node::File(file) => {
+                let file = file.read().unwrap();
+                nodes.insert(path, (file.inode, file.dirty));
+            }
+            Node::Dir(child) => {
+                collect_nodes(child, &path, nodes);
+                nodes.insert(format!("{}/", path), (child.read().unwrap().inode, false));
+            }
+        }
+    }
+}
+
+fn export_to_host(dir: &DirRef, path: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(path)?;
+    for (name, node) in &dir.read().unwrap().entries {
+        match node {
+            Node::File(file) => std::fs::write(path.join(name), file.read().unwrap().contents()?)?,
+            Node::Dir(child) => export_to_host(child, &path.join(name))?,
+        }
+    }
+    Ok(())
+}
+
+/// Change made to a file or a directory of an overlay
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Change {
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // same letters as `git status --short`
+        match self {
+            Change::Added => write!(f, "A"),
+            Change::Modified => write!(f, "M"),
+            Change::Deleted => write!(f, "D"),
+        }
+    }
+}
+
+/// Host directory mounted at the guest path, whose changes are kept in memory
+pub(super) struct Overlay {
+    pub host_dir: PathBuf,
+    pub guest_path: String,
+    pub fs: MemFs,
+    /// Files and directories (with a trailing slash) of the host directory with their inodes
+    mounted: BTreeMap<String, u64>,
+}
+
+impl Overlay {
+    /// Mounts the host directory
+    ///
+    /// The tree of the directory is listed in the same way as `MemFs::from_host_dir`, but the
+    /// contents of files are read from the host until they are written.
+    pub fn new(host_dir: &Path, guest_path: &str) -> Result<Self> {
+        let fs = MemFs::new();
+        let mut mounted = BTreeMap::new();
+        copy_from_host(&fs.root, host_dir, "", true, &mut mounted)
+            .map_err(|e| anyhow!("Failed to read {}: {}", host_dir.display(), e))?;
+        Ok(Overlay {
+            host_dir: host_dir.into(),
+            guest_path: guest_path.to_string(),
+            fs,
+            mounted,
+        })
+    }
+
+    /// Returns the changed files and directories (with a trailing slash) sorted by path
+    ///
+    /// Files are modified if they have been written, or replaced by other files (e.g. by
+    /// renaming), which is told by their inodes. The host directory is not read again.
+    pub fn diff(&self) -> Vec<(String, Change)> {
+        let mut nodes = BTreeMap::new();
+        collect_nodes(&self.fs.root, "", &mut nodes);
+
+        let mut changes = vec![];
+        for (path, (inode, dirty)) in &nodes {
+            match self.mounted.get(path) {
+                None => changes.push((path.clone(), Change::Added)),
+                Some(_) if path.ends_with('/') => {}
+                Some(mounted_inode) => {
+                    if *dirty || mounted_inode != inode {
+                        changes.push((path.clone(), Change::Modified));
+                    }
+                }
+            }
+        }
+        for path in self.mounted.keys() {
+            if !nodes.contains_key(path) {
+                changes.push((path.clone(), Change::Deleted));
+            }
+        }
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        changes
+    }
+}
+
+/// Splits a path relative to a directory into names, resolving `.` and `..`
+///
+/// Like cap-std, paths which are absolute or escape the directory are not permitted.
+fn split_path(path: &str) -> Result<Vec<&str>, Error> {
+    if path.starts_with('/') {
+        return Err(Error::perm());
+    }
+    let mut names = vec![];
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => {
+                if names.pop().is_none() {
+                    return Err(Error::perm());
+                }
+            }
+            _ => names.push(name),
+        }
+    }
+    Ok(names)
+}
+
+pub(super) struct MemDir {
+    dir: DirRef,
+    read_only: bool,
+}
+
+impl MemDir {
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            Err(Errno::Rofs.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the directory reached by following the names
+    fn walk(&self, names: &[&str]) -> Result<DirRef, Error> {
+        let mut dir = self.dir.clone();
+        for name in names {
+            let child = dir.read().unwrap().entries.get(*name).cloned();
+            dir = match child {
+                Some(Node::Dir(child)) => child,
+                Some(Node::File(_)) => return Err(Error::not_dir()),
+                None => return Err(Error::not_found()),
+            };
+        }
+        Ok(dir)
+    }
+
+    fn lookup(&self, path: &str) -> Result<Node, Error> {
+        let names = split_path(path)?;
+        let Some((name, parents)) = names.split_last() else {
+            return Ok(Node::Dir(self.dir.clone()));
+        };
+        let parent = self.walk(parents)?;
+        let node = parent.read().unwrap().entries.get(*name).cloned();
+        node.ok_or_else(Error::not_found)
+    }
+
+    /// Returns the parent directory of the path and the last name
+    ///
+    /// The path must not refer to this directory itself.
+    fn lookup_parent<'p>(&self, path: &'p str) -> Result<(DirRef, &'p str), Error> {
+        let names = split_path(path)?;
+        let Some((name, parents)) = names.split_last() else {
+            return Err(Error::invalid_argument());
+        };
+        Ok((self.walk(parents)?, name))
+    }
+}
+
+#[wiggle::async_trait]
+impl WasiDir for MemDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn open_file(
+        &self,
+        _symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        _read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<OpenResult, Error> {
+        if write || oflags.intersects(OFlags::CREATE | OFlags::TRUNCATE) {
+            self.check_writable()?;
+        }
+        let exclusive = oflags.contains(OFlags::CREATE | OFlags::EXCLUSIVE);
+        let names = split_path(path)?;
+        let Some((name, parents)) = names.split_last() else {
+            if exclusive {
+                return Err(Error::exist());
+            }
+            let dir = MemDir {
+                dir: self.dir.clone(),
+                read_only: self.read_only,
+            };
+            return Ok(OpenResult::Dir(Box::new(dir)));
+        };
+
+        let parent = self.walk(parents)?;
+        let mut parent = parent.write().unwrap();
+        let file = match parent.entries.get(*name) {
+            Some(_) if exclusive => return Err(Error::exist()),
+            Some(Node::Dir(dir)) => {
+                if write {
+                    return Err(Errno::Isdir.into());
+                }
+                let dir = MemDir {
+                    dir: dir.clone(),
+                    read_only: self.read_only,
+                };
+                return Ok(OpenResult::Dir(Box::new(dir)));
+            }
+            Some(Node::File(file)) => {
+                if oflags.contains(OFlags::DIRECTORY) {
+                    return Err(Error::not_dir());
+                }
+                if oflags.contains(OFlags::TRUNCATE) {
+                    file.write().unwrap().truncate();
+                }
+                file.clone()
+            }
+            None => {
+                if !oflags.contains(OFlags::CREATE) {
+                    return Err(Error::not_found());
+                }
+                if oflags.contains(OFlags::DIRECTORY) {
+                    return Err(Error::invalid_argument());
+                }
+                let file = FileNode::new(vec![]);
+                parent
+                    .entries
+                    .insert(name.to_string(), Node::File(file.clone()));
+                parent.mtim = SystemTime::now();
+                file
+            }
+        };
+
+        Ok(OpenResult::File(Box::new(MemFile {
+            file,
+            position: Mutex::new(0),
+            append: fdflags.contains(FdFlags::APPEND),
+            read_only: self.read_only,
+        })))
+    }
+
+    async fn create_dir(&self, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        let (parent, name) = self.lookup_parent(path)?;
+        let mut parent = parent.write().unwrap();
+        if parent.entries.contains_key(name) {
+            return Err(Error::exist());
+        }
+        parent
+            .entries
+            .insert(name.to_string(), Node::Dir(DirNode::new()));
+        parent.mtim = SystemTime::now();
+        Ok(())
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        let dir = self.dir.read().unwrap();
+        // the parent of a preopened directory is not visible, so `..` refers to the directory itself
+        let mut entries = vec![
+            (".".to_string(), FileType::Directory, dir.inode),
+            ("..".to_string(), FileType::Directory, dir.inode),
+        ];
+        for (name, node) in &dir.entries {
+            let stat = node.stat();
+            entries.push((name.clone(), stat.filetype, stat.inode));
+        }
+
+        let entries = entries
+            .into_iter()
+            .enumerate()
+            .skip(u64::from(cursor) as usize)
+            .map(|(i, (name, filetype, inode))| {
+                Ok(ReaddirEntity {
+                    next: ReaddirCursor::from(i as u64 + 1),
+                    inode,
+                    name,
+                    filetype,
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    async fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        let (parent, name) = self.lookup_parent(path)?;
+        let mut parent = parent.write().unwrap();
+        match parent.entries.get(name) {
+            Some(Node::Dir(dir)) => {
+                if !dir.read().unwrap().entries.is_empty() {
+                    return Err(Errno::Notempty.into());
+                }
+            }
+            Some(Node::File(_)) => return Err(Error::not_dir()),
+            None => return Err(Error::not_found()),
+        }
+        parent.entries.remove(name);
+        parent.mtim = SystemTime::now();
+        Ok(())
+    }
+
+    async fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        let (parent, name) = self.lookup_parent(path)?;
+        let mut parent = parent.write().unwrap();
+        match parent.entries.get(name) {
+            Some(Node::File(_)) => {}
+            Some(Node::Dir(_)) => return Err(Errno::Isdir.into()),
+            None => return Err(Error::not_found()),
+        }
+        parent.entries.remove(name);
+        parent.mtim = SystemTime::now();
+        Ok(())
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(self.dir.read().unwrap().stat())
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        _follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        Ok(self.lookup(path)?.stat())
+    }
+
+    async fn rename(
+        &self,
+        path: &str,
+        dest_dir: &dyn WasiDir,
+        dest_path: &str,
+    ) -> Result<(), Error> {
+        // files cannot be moved between an in-memory tree and the host
+        let Some(dest_dir) = dest_dir.as_any().downcast_ref::<MemDir>() else {
+            return Err(Errno::Xdev.into());
+        };
+        self.check_writable()?;
+        dest_dir.check_writable()?;
+        let (src_parent, src_name) = self.lookup_parent(path)?;
+        let (dest_parent, dest_name) = dest_dir.lookup_parent(dest_path)?;
+
+        let node = src_parent.write().unwrap().entries.remove(src_name);
+        let Some(node) = node else {
+            return Err(Error::not_found());
+        };
+
+        let mut dest = dest_parent.write().unwrap();
+        let result = match (dest.entries.get(dest_name), &node) {
+            (Some(Node::Dir(dir)), Node::Dir(_)) if !dir.read().unwrap().entries.is_empty() => {
+                Err(Errno::Notempty.into())
+            }
+            (Some(Node::Dir(_)), Node::File(_)) => Err(Errno::Isdir.into()),
+            (Some(Node::File(_)), Node::Dir(_)) => Err(Error::not_dir()),
+            _ => Ok(()),
+        };
+        if result.is_ok() {
+            dest.entries.insert(dest_name.to_string(), node);
+            dest.mtim = SystemTime::now();
+        } else {
+            // put the node back
+            drop(dest);
+            src_parent
+                .write()
+                .unwrap()
+                .entries
+                .insert(src_name.to_string(), node);
+        }
+        result
+    }
+
+    async fn set_times(
+        &self,
+        path: &str,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+        _follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        match self.lookup(path)? {
+            Node::File(file) => {
+                let file = &mut *file.write().unwrap();
+                update_times(&mut file.atim, &mut file.mtim, atime, mtime);
+            }
+            Node::Dir(dir) => {
+                let dir = &mut *dir.write().unwrap();
+                update_times(&mut dir.atim, &mut dir.mtim, atime, mtime);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn update_times(
+    atim: &mut SystemTime,
+    mtim: &mut SystemTime,
+    atime: Option<SystemTimeSpec>,
+    mtime: Option<SystemTimeSpec>,
+) {
+    let to_system_time = |spec| match spec {
+        SystemTimeSpec::SymbolicNow => SystemTime::now(),
+        SystemTimeSpec::Absolute(time) => cap_std::time::SystemTime::into_std(time),
+    };
+    if let Some(atime) = atime {
+        *atim = to_system_time(atime);
+    }
+    if let Some(mtime) = mtime {
+        *mtim = to_system_time(mtime);
+    }
+}
+
+/// Open file of an in-memory tree
+struct MemFile {
+    file: FileRef,
+    position: Mutex<u64>,
+    append: bool,
+    read_only: bool,
+}
+
+fn read_at(data: &[u8], bufs: &mut [IoSliceMut], offset: u64) -> u64 {
+    let start = offset.min(data.len() as u64) as usize;
+    let mut offset = start;
+    for buf in bufs {
+        let n = buf.len().min(data.len() - offset);
+        buf[..n].copy_from_slice(&data[offset..offset + n]);
+        offset += n;
+    }
+    (offset - start) as u64
+}
+
+fn write_at(data: &mut Vec<u8>, bufs: &[IoSlice], offset: u64) -> Result<u64, Error> {
+    let start = usize::try_from(offset).map_err(|_| Error::too_big())?;
+    let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+    let end = start.checked_add(len).ok_or_else(Error::too_big)?;
+    if data.len() < end {
+        data.resize(end, 0);
+    }
+    let mut offset = start;
+    for buf in bufs {
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        offset += buf.len();
+    }
+    Ok(len as u64)
+}
+
+#[wiggle::async_trait]
+impl WasiFile for MemFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn get_filetype(&self) -> Result<FileType, Error> {
+        Ok(FileType::RegularFile)
+    }
+
+    async fn datasync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn get_fdflags(&self) -> Result<FdFlags, Error> {
+        if self.append {
+            Ok(FdFlags::APPEND)
+        } else {
+            Ok(FdFlags::empty())
+        }
+    }
+
+    async fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        self.append = flags.contains(FdFlags::APPEND);
+        Ok(())
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(self.file.read().unwrap().stat())
+    }
+
+    async fn set_filestat_size(&self, size: u64) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Errno::Rofs.into());
+        }
+        let size = usize::try_from(size).map_err(|_| Error::too_big())?;
+        let mut file = self.file.write().unwrap();
+        file.data_mut()?.resize(size, 0);
+        Ok(())
+    }
+
+    async fn advise(
+        &self,
+        _offset: u64,
+        _len: u64,
+        _advice: wasi_common::file::Advice,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn set_times(
+        &self,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Errno::Rofs.into());
+        }
+        let file = &mut *self.file.write().unwrap();
+        update_times(&mut file.atim, &mut file.mtim, atime, mtime);
+        Ok(())
+    }
+
+    async fn read_vectored<'a>(&self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        let file = self.file.read().unwrap();
+        let mut position = self.position.lock().unwrap();
+        let n = file.read_at(bufs, *position)?;
+        *position += n;
+        Ok(n)
+    }
+
+    async fn read_vectored_at<'a>(
+        &self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        let file = self.file.read().unwrap();
+        file.read_at(bufs, offset)
+    }
+
+    async fn write_vectored<'a>(&self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        let mut file = self.file.write().unwrap();
+        let mut position = self.position.lock().unwrap();
+        let data = file.data_mut()?;
+        if self.append {
+            *position = data.len() as u64;
+        }
+        let n = write_at(data, bufs, *position)?;
+        *position += n;
+        Ok(n)
+    }
+
+    async fn write_vectored_at<'a>(&self, bufs: &[IoSlice<'a>], offset: u64) -> Result<u64, Error> {
+        let mut file = self.file.write().unwrap();
+        write_at(file.data_mut()?, bufs, offset)
+    }
+
+    async fn seek(&self, pos: SeekFrom) -> Result<u64, Error> {
+        let len = self.file.read().unwrap().len();
+        let mut position = self.position.lock().unwrap();
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+            SeekFrom::End(offset) => len.checked_add_signed(offset),
+        };
+        *position = new_position.ok_or_else(Error::invalid_argument)?;
+        Ok(*position)
+    }
+
+    async fn peek(&self, buf: &mut [u8]) -> Result<u64, Error> {
+        let file = self.file.read().unwrap();
+        let position = self.position.lock().unwrap();
+        file.read_at(&mut [IoSliceMut::new(buf)], *position)
+    }
+
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        let len = self.file.read().unwrap().len();
+        Ok(len.saturating_sub(*self.position.lock().unwrap()))
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Returns a path in a directory which is empty, for the files of a test
+    fn temp_path(test: &str, name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lv8-vfs-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    fn tar_archive() -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        builder
+            .append_data(&mut header, "assets/empty", std::io::empty())
+            .unwrap();
+        for (path, data) in [("assets/a.txt", "a\n"), ("b/c.txt", "c\n")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, path, data.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn assert_archive_contents(fs: &MemFs) {
+        let files = fs.files();
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(path, data)| (path.as_str(), data.as_slice()))
+            .collect();
+        assert_eq!(
+            files,
+            [("/assets/a.txt", &b"a\n"[..]), ("/b/c.txt", &b"c\n"[..])]
+        );
+        assert!(matches!(
+            fs.root_dir().lookup("assets/empty"),
+            Ok(Node::Dir(_))
+        ));
+    }
+
+    #[test]
+    fn mounts_tar() {
+        let path = temp_path("mounts_tar", "assets.tar");
+        std::fs::write(&path, tar_archive()).unwrap();
+        assert_archive_contents(&MemFs::from_archive(&path).unwrap());
+    }
+
+    #[test]
+    fn mounts_gzipped_tar() {
+        let path = temp_path("mounts_gzipped_tar", "assets.tgz");
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&tar_archive()).unwrap();
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+        assert_archive_contents(&MemFs::from_archive(&path).unwrap());
+    }
+
+    #[test]
+    fn mounts_zip() {
+        let path = temp_path("mounts_zip", "assets.zip");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        writer.add_directory("assets/empty/", options).unwrap();
+        writer.start_file("assets/a.txt", options).unwrap();
+        writer.write_all(b"a\n").unwrap();
+        writer.start_file("b/c.txt", options).unwrap();
+        writer.write_all(b"c\n").unwrap();
+        writer.finish().unwrap();
+        assert_archive_contents(&MemFs::from_archive(&path).unwrap());
+    }
+
+    #[test]
+    fn rejects_zip_entries_escaping_the_archive() {
+        let path = temp_path("rejects_zip_entries", "evil.zip");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        writer.start_file("../evil.txt", options).unwrap();
+        writer.write_all(b"evil").unwrap();
+        writer.finish().unwrap();
+        let error = MemFs::from_archive(&path).err().unwrap();
+        assert!(error.to_string().contains("Invalid file name"));
+    }
+
+    #[test]
+    fn archives_are_read_only() {
+        let path = temp_path("archives_are_read_only", "assets.tar");
+        std::fs::write(&path, tar_archive()).unwrap();
+        let dir = MemFs::from_archive(&path).unwrap().root_dir();
+
+        // reading is allowed
+        let result = block_on(dir.open_file(
+            false,
+            "assets/a.txt",
+            OFlags::empty(),
+            true,
+            false,
+            FdFlags::empty(),
+        ));
+        assert!(matches!(result, Ok(OpenResult::File(_))));
+
+        let errno = |result: Result<(), Error>| result.unwrap_err().downcast().unwrap();
+        let result = block_on(dir.open_file(
+            false,
+            "assets/a.txt",
+            OFlags::empty(),
+            true,
+            true,
+            FdFlags::empty(),
+        ));
+        assert_eq!(errno(result.map(|_| ())), Errno::Rofs);
+        let result = block_on(dir.open_file(
+            false,
+            "new.txt",
+            OFlags::CREATE,
+            false,
+            true,
+            FdFlags::empty(),
+        ));
+        assert_eq!(errno(result.map(|_| ())), Errno::Rofs);
+        assert_eq!(errno(block_on(dir.create_dir("new"))), Errno::Rofs);
+        assert_eq!(errno(block_on(dir.unlink_file("b/c.txt"))), Errno::Rofs);
+        assert_eq!(errno(block_on(dir.remove_dir("assets/empty"))), Errno::Rofs);
+    }
+
+    /// Creates a host directory with `a.txt`, `b.txt` and `dir/c.txt`
+    fn host_dir(test: &str) -> PathBuf {
+        let dir = temp_path(test, "host");
+        std::fs::create_dir_all(dir.join("dir")).unwrap();
+        std::fs::write(dir.join("a.txt"), "a\n").unwrap();
+        std::fs::write(dir.join("b.txt"), "b\n").unwrap();
+        std::fs::write(dir.join("dir/c.txt"), "c\n").unwrap();
+        dir
+    }
+
+    fn open(dir: &MemDir, path: &str, oflags: OFlags, write: bool) -> Box<dyn WasiFile> {
+        let result = block_on(dir.open_file(false, path, oflags, true, write, FdFlags::empty()));
+        match result {
+            Ok(OpenResult::File(file)) => file,
+            _ => panic!("failed to open {}", path),
+        }
+    }
+
+    fn read(file: &dyn WasiFile) -> Vec<u8> {
+        let mut buf = [0; 64];
+        let n = block_on(file.read_vectored_at(&mut [IoSliceMut::new(&mut buf)], 0)).unwrap();
+        buf[..n as usize].to_vec()
+    }
+
+    fn write(file: &dyn WasiFile, data: &[u8]) {
+        block_on(file.write_vectored(&[IoSlice::new(data)])).unwrap();
+    }
+
+    #[test]
+    fn mem_fs_keeps_files_in_memory() {
+        let fs = MemFs::new();
+        fs.write_file("/data/in.txt", "in").unwrap();
+        let dir = fs.root_dir();
+        let file = open(&dir, "data/out.txt", OFlags::CREATE, true);
+        write(&*file, b"out");
+        block_on(dir.rename("data/in.txt", &dir, "in.txt")).unwrap();
+
+        assert_eq!(fs.read_file("/data/out.txt").unwrap(), b"out");
+        assert_eq!(
+            fs.files(),
+            [
+                ("/data/out.txt".to_string(), b"out".to_vec()),
+                ("/in.txt".to_string(), b"in".to_vec())
+            ]
+        );
+        // paths escaping the directory are not permitted
+        assert!(block_on(dir.create_dir("../outside")).is_err());
+
+        let export_dir = temp_path("mem_fs_keeps_files_in_memory", "export");
+        fs.export(&export_dir).unwrap();
+        assert_eq!(
+            std::fs::read(export_dir.join("data/out.txt")).unwrap(),
+            b"out"
+        );
+        assert_eq!(std::fs::read(export_dir.join("in.txt")).unwrap(), b"in");
+    }
+
+    #[test]
+    fn overlay_reads_fall_through_to_the_host() {
+        let host = host_dir("overlay_reads_fall_through");
+        let overlay = Overlay::new(&host, "/src").unwrap();
+        let dir = overlay.fs.root_dir();
+        let file = open(&dir, "a.txt", OFlags::empty(), false);
+        assert_eq!(read(&*file), b"a\n");
+
+        // the contents are not copied, so changes of the host are visible until the file is written
+        std::fs::write(host.join("a.txt"), "changed\n").unwrap();
+        assert_eq!(read(&*file), b"changed\n");
+        assert_eq!(
+            block_on(file.get_filestat()).unwrap().size,
+            b"changed\n".len() as u64
+        );
+        assert!(overlay.diff().is_empty());
+    }
+
+    #[test]
+    fn overlay_copies_up_written_files() {
+        let host = host_dir("overlay_copies_up");
+        let overlay = Overlay::new(&host, "/src").unwrap();
+        let dir = overlay.fs.root_dir();
+        let file = open(&dir, "a.txt", OFlags::empty(), true);
+        block_on(file.seek(SeekFrom::End(0))).unwrap();
+        write(&*file, b"more\n");
+
+        assert_eq!(read(&*file), b"a\nmore\n");
+        assert_eq!(std::fs::read(host.join("a.txt")).unwrap(), b"a\n");
+        // written files are no longer read from the host
+        std::fs::write(host.join("a.txt"), "changed\n").unwrap();
+        assert_eq!(read(&*file), b"a\nmore\n");
+        assert_eq!(overlay.diff(), [("/a.txt".to_string(), Change::Modified)]);
+    }
+
+    #[test]
+    fn overlay_reports_changed_files_and_directories() {
+        let host = host_dir("overlay_reports_changes");
+        let overlay = Overlay::new(&host, "/src").unwrap();
+        let dir = overlay.fs.root_dir();
+        open(&dir, "new.txt", OFlags::CREATE, true);
+        open(&dir, "b.txt", OFlags::TRUNCATE, true);
+        block_on(dir.unlink_file("dir/c.txt")).unwrap();
+        block_on(dir.remove_dir("dir")).unwrap();
+        block_on(dir.create_dir("out")).unwrap();
+        block_on(dir.rename("a.txt", &dir, "out/a.txt")).unwrap();
+
+        let changes = overlay.diff();
+        let changes: Vec<(&str, Change)> = changes
+            .iter()
+            .map(|(path, change)| (path.as_str(), *change))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("/a.txt", Change::Deleted),
+                ("/b.txt", Change::Modified),
+                ("/dir/", Change::Deleted),
+                ("/dir/c.txt", Change::Deleted),
+                ("/new.txt", Change::Added),
+                ("/out/", Change::Added),
+                ("/out/a.txt", Change::Added),
+            ]
+        );
+        // the host is never modified
+        assert_eq!(std::fs::read(host.join("b.txt")).unwrap(), b"b\n");
+        assert!(host.join("dir/c.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn overlay_skips_links_to_directories_and_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let host = host_dir("overlay_skips");
+        std::os::unix::fs::symlink(".", host.join("loop")).unwrap();
+        std::os::unix::fs::symlink("a.txt", host.join("link.txt")).unwrap();
+        let name = std::ffi::OsStr::from_bytes(b"\xff.txt");
+        std::fs::write(host.join(name), "not UTF-8").unwrap();
+
+        let overlay = Overlay::new(&host, "/src").unwrap();
+        let files: Vec<String> = overlay
+            .fs
+            .files()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(files, ["/a.txt", "/b.txt", "/dir/c.txt", "/link.txt"]);
+        assert_eq!(overlay.fs.read_file("/link.txt").unwrap(), b"a\n");
+        assert!(overlay.diff().is_empty());
+    }
+}
@@ -1,108 +1,673 @@
+use anyhow::{anyhow, Result};
+use cap_std::time::{Duration, SystemTime};
 use std::{
-    cell::UnsafeCell,
+    cell::{Cell, UnsafeCell},
+    path::{Path, PathBuf},
     sync::{Mutex, OnceLock},
 };
 use tokio::runtime::Runtime as TokioRuntime;
+use wasi_common::file::FileAccessMode;
+use wasi_common::pipe::{ReadPipe, WritePipe};
+use wasi_common::snapshots::preview_0::wasi_unstable as preview0;
 use wasi_common::snapshots::preview_1::wasi_snapshot_preview1 as preview1;
-use wasi_common::sync::WasiCtxBuilder;
-use wasi_common::WasiCtx;
+use wasi_common::sync::{
+    clocks_ctx, dir::Dir, file::File, net::Socket, random_ctx, sched_ctx, stdio,
+};
+use wasi_common::{Table, WasiCtx, WasiFile};
 use wiggle::GuestMemory;
 
+use super::vfs::Overlay;
+use super::{deterministic, jspi, memory64, policy, trace, Embedding, MemFs};
+use crate::driver::Cli;
+
+/// EFAULT in wasi_snapshot_preview1
+const ERRNO_FAULT: i32 = 21;
+
 /// Global WASI context
 static WASI_CTX: OnceLock<Mutex<WasiCtx>> = OnceLock::new();
 
-fn get_wasi_ctx_mut() -> &'static Mutex<WasiCtx> {
-    WASI_CTX.get_or_init(|| {
-        let mut builder = WasiCtxBuilder::new();
-        let mut builder = builder.inherit_stdin().inherit_stdout().inherit_stderr();
-
-        // use command line arguments after "--" as wasm arguments
-        // the first argument is the module name
-        builder.arg("this.wasm").unwrap();
-        let mut saw_dhiphen = false;
-        for arg in std::env::args() {
-            if saw_dhiphen {
-                builder = builder.arg(&arg).unwrap();
-            } else if arg == "--" {
-                saw_dhiphen = true;
-                continue;
+/// Exit code given by proc_exit
+static EXIT_CODE: OnceLock<i32> = OnceLock::new();
+
+thread_local! {
+    /// Context used instead of the global one by the calls of a `WASI` instance of `lv8 run-js`
+    static SELECTED_CTX: Cell<Option<SelectedCtx>> = const { Cell::new(None) };
+    /// Exit code given by proc_exit, which is returned by `WASI.start()` of `lv8 run-js`
+    static RETURNED_EXIT_CODE: Cell<Option<i32>> = const { Cell::new(None) };
+}
+
+#[derive(Clone, Copy)]
+struct SelectedCtx {
+    ctx: &'static Mutex<WasiCtx>,
+    /// Whether proc_exit throws to the caller instead of stopping the isolate
+    return_on_exit: bool,
+}
+
+/// Clocks and random source of WASI contexts, given by the command line options
+#[derive(Clone, Copy)]
+pub(super) struct CtxOptions {
+    fake_clock: bool,
+    random_seed: Option<u64>,
+}
+
+impl CtxOptions {
+    pub(super) fn from_args(args: &Cli) -> Self {
+        CtxOptions {
+            fake_clock: args.fake_clock || args.deterministic,
+            random_seed: args.random_seed.or(args.deterministic.then_some(0)),
+        }
+    }
+}
+
+/// In-memory trees preopened by the WASI context, which are inspected after the run
+pub(super) struct MemMounts {
+    pub mem: Vec<(String, MemFs)>,
+    pub overlays: Vec<Overlay>,
+}
+
+/// Initializes the global WASI context from the command line options and the embedding
+pub(super) fn init_wasi_ctx(args: &Cli, embedding: Embedding) -> Result<MemMounts> {
+    // stdio is inherited unless it is redirected by the embedding or the command line options
+    let stdin: Box<dyn WasiFile> = match (&embedding.stdin, &args.stdin) {
+        (Some(input), _) => Box::new(ReadPipe::from(input.clone())),
+        (None, Some(path)) => Box::new(open_stdio_file(path, false)?),
+        (None, None) => Box::new(stdio::stdin()),
+    };
+    let stdout: Box<dyn WasiFile> = match (&embedding.stdout, &args.stdout) {
+        (Some(buffer), _) => Box::new(WritePipe::from_shared(buffer.clone())),
+        (None, Some(path)) => Box::new(open_stdio_file(path, true)?),
+        (None, None) => Box::new(stdio::stdout()),
+    };
+    let stderr: Box<dyn WasiFile> = match (&embedding.stderr, &args.stderr) {
+        (Some(buffer), _) => Box::new(WritePipe::from_shared(buffer.clone())),
+        (None, Some(path)) => Box::new(open_stdio_file(path, true)?),
+        (None, None) => Box::new(stdio::stderr()),
+    };
+
+    let mut ctx = new_wasi_ctx(CtxOptions::from_args(args));
+    ctx.set_stdin(stdin);
+    ctx.set_stdout(stdout);
+    ctx.set_stderr(stderr);
+
+    // use command line arguments after "--" as wasm arguments
+    // the first argument is the module name
+    ctx.push_arg("this.wasm")?;
+    let mut saw_dhiphen = false;
+    for arg in std::env::args() {
+        if saw_dhiphen {
+            ctx.push_arg(&arg)
+                .map_err(|e| anyhow!("Invalid argument {:?}: {}", arg, e))?;
+        } else if arg == "--" {
+            saw_dhiphen = true;
+            continue;
+        }
+    }
+
+    for (key, value) in std::env::vars() {
+        ctx.push_env(&key, &value)
+            .map_err(|e| anyhow!("Invalid environment variable {:?}: {}", key, e))?;
+    }
+    let dir = std::fs::File::open(".")
+        .map_err(|e| anyhow!("Failed to open the current directory: {}", e))?;
+    let dir = cap_std::fs::Dir::from_std_file(dir);
+    ctx.push_preopened_dir(Box::new(Dir::from_cap_std(dir)), "/")?;
+
+    // in-memory directories, overlays and archives are preopened after the current directory
+    let mut mem_mounts = embedding.mem_mounts;
+    for mount in &args.mount_mem {
+        let (fs, guest_path) = match mount.split_once(':') {
+            Some((host_dir, guest_path)) => {
+                (MemFs::from_host_dir(Path::new(host_dir))?, guest_path)
+            }
+            None => (MemFs::new(), mount.as_str()),
+        };
+        mem_mounts.push((guest_path.to_string(), fs));
+    }
+    for (guest_path, fs) in &mem_mounts {
+        ctx.push_preopened_dir(Box::new(fs.root_dir()), guest_path)?;
+    }
+    let mut overlays = vec![];
+    for mount in &args.overlay {
+        let Some((host_dir, guest_path)) = mount.split_once(':') else {
+            return Err(anyhow!(
+                "Invalid overlay {} (expected HOST_DIR:GUEST_DIR)",
+                mount
+            ));
+        };
+        // files are copied up to memory when they are written, so the host is never modified
+        let overlay = Overlay::new(Path::new(host_dir), guest_path)?;
+        ctx.push_preopened_dir(Box::new(overlay.fs.root_dir()), guest_path)?;
+        overlays.push(overlay);
+    }
+    for mount in &args.mount_archive {
+        let Some((archive, guest_path)) = mount.split_once(':') else {
+            return Err(anyhow!(
+                "Invalid archive mount {} (expected ARCHIVE:GUEST_DIR)",
+                mount
+            ));
+        };
+        let fs = MemFs::from_archive(Path::new(archive))?;
+        ctx.push_preopened_dir(Box::new(fs.root_dir()), guest_path)?;
+    }
+
+    // sockets are pushed after preopened directories,
+    // since wasi-libc stops looking for preopened directories at the first fd which is not one
+    for addr in &args.tcplisten {
+        let listener = std::net::TcpListener::bind(addr)
+            .map_err(|e| anyhow!("Failed to listen on {}: {}", addr, e))?;
+        let listener = cap_std::net::TcpListener::from_std(listener);
+        let socket: Box<dyn WasiFile> = Socket::from(listener).into();
+        ctx.push_file(socket, FileAccessMode::READ | FileAccessMode::WRITE)?;
+    }
+
+    // inherited fds are given the next free fd unless the guest fd is specified
+    for spec in &args.inherit_fd {
+        let (host_fd, guest_fd) = match spec.split_once(':') {
+            Some((host_fd, guest_fd)) => (host_fd, Some(guest_fd)),
+            None => (spec.as_str(), None),
+        };
+        let invalid = || anyhow!("Invalid fd {} (expected HOSTFD[:GUESTFD])", spec);
+        let host_fd: i32 = host_fd.parse().map_err(|_| invalid())?;
+        let guest_fd: Option<u32> = guest_fd
+            .map(|fd| fd.parse().map_err(|_| invalid()))
+            .transpose()?;
+        let (file, access_mode) = inherit_fd(host_fd)?;
+        match guest_fd {
+            // stdio can be replaced, but other fds (e.g. preopened directories) cannot
+            Some(fd) if fd > 2 && ctx.table().contains_key(fd) => {
+                return Err(anyhow!("Guest fd {} is already in use", fd));
+            }
+            Some(fd) => ctx.insert_file(fd, file, access_mode),
+            None => {
+                ctx.push_file(file, access_mode)?;
             }
         }
+    }
 
-        builder = builder.inherit_env().unwrap();
-        let dir = cap_std::fs::Dir::from_std_file(std::fs::File::open(".").unwrap());
-        builder = builder.preopened_dir(dir, "/").unwrap();
-        Mutex::new(builder.build())
+    if WASI_CTX.set(Mutex::new(ctx)).is_err() {
+        return Err(anyhow!("WASI context is already initialized"));
+    }
+    Ok(MemMounts {
+        mem: mem_mounts,
+        overlays,
     })
 }
 
-fn get_memory_from_scope<'a>(scope: &'a mut v8::HandleScope) -> GuestMemory<'a> {
+/// Creates a WASI context without stdio, arguments, environment variables or preopened files
+fn new_wasi_ctx(options: CtxOptions) -> WasiCtx {
+    // the context is created without WasiCtxBuilder, which cannot replace the clocks and the RNG
+    let (clocks, sched) = if options.fake_clock {
+        deterministic::fake_clocks()
+    } else {
+        (clocks_ctx(), sched_ctx())
+    };
+    let random = match options.random_seed {
+        Some(seed) => deterministic::seeded_random(seed),
+        None => random_ctx(),
+    };
+    WasiCtx::new(random, clocks, sched, Table::new())
+}
+
+/// Creates the WASI context of `new WASI(options)` of `lv8 run-js`, which has the inherited stdio
+/// and the arguments, environment variables and preopened directories given by the options
+///
+/// The context lives until the process exits, like the global one.
+pub(super) fn create_js_wasi_ctx(
+    options: CtxOptions,
+    wasm_args: &[String],
+    env: &[(String, String)],
+    preopens: &[(String, PathBuf)],
+) -> Result<&'static Mutex<WasiCtx>> {
+    let mut ctx = new_wasi_ctx(options);
+    ctx.set_stdin(Box::new(stdio::stdin()));
+    ctx.set_stdout(Box::new(stdio::stdout()));
+    ctx.set_stderr(Box::new(stdio::stderr()));
+    for arg in wasm_args {
+        ctx.push_arg(arg)
+            .map_err(|e| anyhow!("Invalid argument {:?}: {}", arg, e))?;
+    }
+    for (key, value) in env {
+        ctx.push_env(key, value)
+            .map_err(|e| anyhow!("Invalid environment variable {:?}: {}", key, e))?;
+    }
+    for (guest_path, host_dir) in preopens {
+        let dir = std::fs::File::open(host_dir)
+            .map_err(|e| anyhow!("Failed to open {}: {}", host_dir.display(), e))?;
+        let dir = cap_std::fs::Dir::from_std_file(dir);
+        ctx.push_preopened_dir(Box::new(Dir::from_cap_std(dir)), guest_path)?;
+    }
+    Ok(Box::leak(Box::new(Mutex::new(ctx))))
+}
+
+/// Makes WASI functions called on this thread use the context, or the global one if None
+///
+/// With `return_on_exit`, proc_exit throws an exception to the caller, and the code is returned
+/// by `take_returned_exit_code`.
+pub(super) fn select_wasi_ctx(ctx: Option<&'static Mutex<WasiCtx>>, return_on_exit: bool) {
+    let selected = ctx.map(|ctx| SelectedCtx {
+        ctx,
+        return_on_exit,
+    });
+    SELECTED_CTX.with(|cell| cell.set(selected));
+}
+
+/// Returns the exit code given by proc_exit of a context selected with `return_on_exit`, and
+/// clears it
+pub(super) fn take_returned_exit_code() -> Option<i32> {
+    RETURNED_EXIT_CODE.with(Cell::take)
+}
+
+/// Opens the host file for reading, or creates it for writing
+fn open_stdio_file(path: &Path, write: bool) -> Result<File> {
+    let file = if write {
+        std::fs::File::create(path)
+    } else {
+        std::fs::File::open(path)
+    };
+    let file = file.map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+    Ok(File::from_cap_std(cap_std::fs::File::from_std(file)))
+}
+
+/// Wraps a duplicate of the fd inherited from the parent process, which may be a file, a pipe or a
+/// stream socket (TCP or Unix), and returns it with the access mode it was opened with
+#[cfg(unix)]
+fn inherit_fd(host_fd: i32) -> Result<(Box<dyn WasiFile>, FileAccessMode)> {
+    use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+    use std::os::unix::fs::FileTypeExt;
+
+    let error = |e: std::io::Error| anyhow!("Failed to inherit fd {}: {}", host_fd, e);
+    if host_fd < 0 {
+        return Err(error(std::io::Error::from_raw_os_error(libc::EBADF)));
+    }
+    // duplicating the fd fails with EBADF if it is not open
+    let fd = unsafe { BorrowedFd::borrow_raw(host_fd) }
+        .try_clone_to_owned()
+        .map_err(error)?;
+    let file = std::fs::File::from(fd);
+    let metadata = file.metadata().map_err(error)?;
+    if !metadata.file_type().is_socket() {
+        // the duplicate shares the status flags, which have the access mode given to open(2)
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 {
+            return Err(error(std::io::Error::last_os_error()));
+        }
+        let access_mode = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => FileAccessMode::READ,
+            libc::O_WRONLY => FileAccessMode::WRITE,
+            _ => FileAccessMode::READ | FileAccessMode::WRITE,
+        };
+        let file = File::from_cap_std(cap_std::fs::File::from_std(file));
+        return Ok((Box::new(file), access_mode));
+    }
+
+    // wasi-common only has stream sockets, so datagram (e.g. UDP), seqpacket and raw sockets are rejected
+    let socket_type = socket_option(&file, libc::SO_TYPE).map_err(error)?;
+    if socket_type != libc::SOCK_STREAM {
+        let socket_type = match socket_type {
+            libc::SOCK_DGRAM => "datagram (e.g. UDP)",
+            libc::SOCK_SEQPACKET => "seqpacket",
+            libc::SOCK_RAW => "raw",
+            _ => "unknown",
+        };
+        return Err(anyhow!(
+            "Failed to inherit fd {}: {} sockets are not supported (only TCP and Unix stream sockets are)",
+            host_fd,
+            socket_type
+        ));
+    }
+    let listening = socket_option(&file, libc::SO_ACCEPTCONN).map_err(error)? != 0;
+    let family = socket_family(&file).map_err(error)?;
+
+    let fd = OwnedFd::from(file);
+    let socket = match (family, listening) {
+        (libc::AF_INET | libc::AF_INET6, false) => {
+            Socket::from(cap_std::net::TcpStream::from_std(fd.into()))
+        }
+        (libc::AF_INET | libc::AF_INET6, true) => {
+            Socket::from(cap_std::net::TcpListener::from_std(fd.into()))
+        }
+        (libc::AF_UNIX, false) => {
+            Socket::from(cap_std::os::unix::net::UnixStream::from_std(fd.into()))
+        }
+        (libc::AF_UNIX, true) => {
+            Socket::from(cap_std::os::unix::net::UnixListener::from_std(fd.into()))
+        }
+        _ => {
+            return Err(anyhow!(
+                "Failed to inherit fd {}: sockets of the address family {} are not supported",
+                host_fd,
+                family
+            ));
+        }
+    };
+    Ok((socket.into(), FileAccessMode::READ | FileAccessMode::WRITE))
+}
+
+/// Returns the socket option of SOL_SOCKET whose value is an int
+#[cfg(unix)]
+fn socket_option(socket: &std::fs::File, option: libc::c_int) -> std::io::Result<libc::c_int> {
+    use std::os::fd::AsRawFd;
+
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// Returns the address family of the socket (e.g. AF_INET)
+#[cfg(unix)]
+fn socket_family(socket: &std::fs::File) -> std::io::Result<libc::c_int> {
+    use std::os::fd::AsRawFd;
+
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockname(
+            socket.as_raw_fd(),
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(addr.ss_family as libc::c_int)
+}
+
+#[cfg(not(unix))]
+fn inherit_fd(host_fd: i32) -> Result<(Box<dyn WasiFile>, FileAccessMode)> {
+    Err(anyhow!(
+        "Failed to inherit fd {}: not supported on this platform",
+        host_fd
+    ))
+}
+
+pub(super) fn get_wasi_ctx_mut() -> &'static Mutex<WasiCtx> {
+    match SELECTED_CTX.with(Cell::get) {
+        Some(selected) => selected.ctx,
+        None => WASI_CTX.get().expect("WASI context is not initialized"),
+    }
+}
+
+pub(super) fn get_backing_store_from_scope(
+    scope: &mut v8::HandleScope,
+) -> Result<v8::SharedRef<v8::BackingStore>> {
     // get global object
     let context = scope.get_current_context();
     let global = context.global(scope);
-    // access to global memory
-    let str_instance = v8::String::new(scope, "gInstance").unwrap();
-    let instance = global.get(scope, str_instance.into()).unwrap();
-    let instance = instance.to_object(scope).unwrap();
-
-    // access to instance.exports.memory.buffer
-    let str_exports = v8::String::new(scope, "exports").unwrap();
-    let exports = instance.get(scope, str_exports.into()).unwrap();
-    let exports = exports.to_object(scope).unwrap();
-    let str_memory = v8::String::new(scope, "memory").unwrap();
-    let memory = exports.get(scope, str_memory.into()).unwrap();
+    // access to global memory (either exported or imported by the instance)
+    let str_memory = v8::String::new(scope, "gMemory").unwrap();
+    let memory = global.get(scope, str_memory.into()).unwrap();
+    if !memory.is_object() {
+        return Err(anyhow!(
+            "Wasm module has no memory to use for WASI functions (use --wasi-memory to choose one)"
+        ));
+    }
     let memory = memory.to_object(scope).unwrap();
 
-    // cast memory.buffer to ArrayBuffer
+    // memory.buffer is a SharedArrayBuffer if the memory is shared, otherwise an ArrayBuffer
     let str_buffer = v8::String::new(scope, "buffer").unwrap();
-    let array_buffer = memory.get(scope, str_buffer.into()).unwrap();
-    let array_buffer = array_buffer.cast::<v8::ArrayBuffer>();
-    let backing_store = array_buffer.get_backing_store();
-    let memory: &mut [u8] = unsafe {
-        std::slice::from_raw_parts_mut(
-            backing_store.data().unwrap().as_ptr() as *mut u8,
-            backing_store.byte_length(),
-        )
-    };
+    let buffer = memory.get(scope, str_buffer.into()).unwrap();
+    if buffer.is_shared_array_buffer() {
+        Ok(buffer.cast::<v8::SharedArrayBuffer>().get_backing_store())
+    } else {
+        Ok(buffer.cast::<v8::ArrayBuffer>().get_backing_store())
+    }
+}
+
+/// Returns the memory as bytes
+///
+/// The memory is shared with the wasm module, so it may be modified by other threads.
+#[allow(clippy::mut_from_ref)]
+pub(super) fn memory_bytes(backing_store: &v8::SharedRef<v8::BackingStore>) -> &mut [u8] {
+    match backing_store.data() {
+        Some(data) => unsafe {
+            std::slice::from_raw_parts_mut(data.as_ptr() as *mut u8, backing_store.byte_length())
+        },
+        // memory of zero pages
+        None => &mut [],
+    }
+}
+
+fn guest_memory(backing_store: &v8::SharedRef<v8::BackingStore>) -> GuestMemory<'_> {
+    let memory = memory_bytes(backing_store);
     let memory = unsafe { &*(memory as *mut [u8] as *mut [UnsafeCell<u8>]) };
     wiggle::GuestMemory::Shared(memory)
 }
 
+/// Returns the time of the system clock (since the epoch) or the monotonic clock (since the start)
+/// of the WASI context in milliseconds, which is fake with --fake-clock
+pub(super) fn clock_millis(monotonic: bool) -> f64 {
+    let wasi_ctx = get_wasi_ctx_mut().lock().unwrap();
+    let clocks = &wasi_ctx.clocks;
+    let elapsed = if monotonic {
+        clocks.monotonic.as_ref().map(|clock| {
+            clock
+                .abs_clock
+                .now(Duration::ZERO)
+                .duration_since(clock.creation_time)
+        })
+    } else {
+        clocks.system.as_ref().map(|clock| {
+            clock
+                .now(Duration::ZERO)
+                .duration_since(SystemTime::from_std(std::time::UNIX_EPOCH))
+                .unwrap_or_default()
+        })
+    };
+    elapsed.map_or(0.0, |elapsed| elapsed.as_secs_f64() * 1000.0)
+}
+
+/// Returns a random number from the random source of the WASI context, which is seeded with --random-seed
+pub(super) fn random_u64() -> u64 {
+    let wasi_ctx = get_wasi_ctx_mut().lock().unwrap();
+    let mut random = wasi_ctx.random.lock().unwrap();
+    random.next_u64()
+}
+
+/// Throws a JS error, which is reported when it escapes the wasm module
+pub(super) fn throw_error(scope: &mut v8::HandleScope, message: &str) {
+    let message = v8::String::new(scope, message).unwrap();
+    let exception = v8::Exception::error(scope, message);
+    scope.throw_exception(exception);
+}
+
+/// Conversion from a wasm argument to the type expected by wasi-common
+trait FromWasmArg: Sized {
+    fn from_wasm_arg(value: i64) -> Option<Self>;
+}
+
+impl FromWasmArg for i32 {
+    fn from_wasm_arg(value: i64) -> Option<Self> {
+        // pointers of memory64 modules are translated into the scratch memory beforehand,
+        // so they always fit in 32 bits here
+        if (i32::MIN as i64..=u32::MAX as i64).contains(&value) {
+            Some(value as i32)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromWasmArg for i64 {
+    fn from_wasm_arg(value: i64) -> Option<Self> {
+        Some(value)
+    }
+}
+
+/// Returns the argument as an integer, and whether it is a BigInt (i.e. an i64 of wasm)
+fn wasm_arg(
+    scope: &mut v8::HandleScope,
+    args: &v8::FunctionCallbackArguments,
+    index: usize,
+) -> (i64, bool) {
+    let arg = args.get(index as i32);
+    if arg.is_big_int() {
+        let bigint = arg.to_big_int(scope).unwrap();
+        (bigint.i64_value().0, true)
+    } else {
+        (arg.integer_value(scope).unwrap(), false)
+    }
+}
+
 macro_rules! wasi_function {
-    ($export:ident, $name:ident,  $( $arg_name: ident : $arg_ty: ty ),*) => {
+    // functions of wasi_unstable are implemented by preview0, which converts the older layouts
+    // (e.g. filestat and whence) to those of preview1
+    ($snapshot:ident => $export:ident, $name:ident,  $( $arg_name: ident : $arg_ty: ty ),*) => {
         pub(super) fn $export(
             scope: &mut v8::HandleScope,
             _args: v8::FunctionCallbackArguments,
             mut rv: v8::ReturnValue,
         ) {
+            let arg_count = <[&str]>::len(&[$( stringify!($arg_name) ),*]);
+            let (values, bigints): (Vec<i64>, Vec<bool>) =
+                (0..arg_count).map(|index| wasm_arg(scope, &_args, index)).unzip();
+
+            let backing_store = match get_backing_store_from_scope(scope) {
+                Ok(backing_store) => backing_store,
+                Err(e) => {
+                    throw_error(scope, &e.to_string());
+                    return;
+                }
+            };
+
+            // memory64 modules pass pointers and sizes as i64 (BigInt), and use the wasm64 layouts
+            let memory64 = stringify!($snapshot) == "preview1"
+                && memory64::is_memory64_call(stringify!($name), &bigints);
+
+            let trace = trace::is_enabled().then(|| {
+                trace::Call::new(stringify!($name), &values, memory64, memory_bytes(&backing_store))
+            });
+
+            match policy::decide(stringify!($name)) {
+                policy::Decision::Allow => {}
+                policy::Decision::Return(errno) => {
+                    if let Some(trace) = trace {
+                        trace.finish(errno);
+                    }
+                    rv.set(v8::Integer::new(scope, errno).into());
+                    return;
+                }
+                policy::Decision::Trap => {
+                    let message = format!("WASI function {} is denied by the policy", stringify!($name));
+                    if let Some(trace) = trace {
+                        trace.finish_without_return(&message);
+                    }
+                    throw_error(scope, &message);
+                    return;
+                }
+            }
+
+            // arguments of memory64 modules point into a scratch memory with the wasm32 layouts
+            let translation = if memory64 {
+                let wasi_ctx = get_wasi_ctx_mut().lock().unwrap();
+                let memory = memory_bytes(&backing_store);
+                match memory64::Translation::new(stringify!($name), &values, memory, &wasi_ctx) {
+                    Ok(translation) => Some(translation),
+                    Err(errno) => {
+                        if let Some(trace) = trace {
+                            trace.finish(errno);
+                        }
+                        rv.set(v8::Integer::new(scope, errno).into());
+                        return;
+                    }
+                }
+            } else {
+                None
+            };
+            let _call_values = translation.as_ref().map_or(&values, |translation| &translation.args);
             let mut _argcnt = 0;
             $(
-                let $arg_name = _args.get(_argcnt);
-                let $arg_name = if $arg_name.is_big_int() {
-                    let bigint = $arg_name.to_big_int(scope).unwrap();
-                    bigint.i64_value().0
-                } else {
-                    $arg_name.integer_value(scope).unwrap()
+                let Some($arg_name) = <$arg_ty as FromWasmArg>::from_wasm_arg(_call_values[_argcnt]) else {
+                    if let Some(trace) = trace {
+                        trace.finish(ERRNO_FAULT);
+                    }
+                    rv.set(v8::Integer::new(scope, ERRNO_FAULT).into());
+                    return;
                 };
-                //let $arg_name = $arg_name.integer_value(scope).unwrap_or_default() as $arg_ty;
                 _argcnt += 1;
             )*
+            let mut translation = translation;
 
+            // with JSPI, the call runs in the background while the wasm stack is suspended
+            if jspi::is_enabled() {
+                let backing_store = jspi::SendBackingStore(backing_store);
+                // the context is looked up on this thread, which may have selected one
+                let wasi_ctx = get_wasi_ctx_mut();
+                let promise = jspi::spawn(scope, move || {
+                    let mut memory = match &mut translation {
+                        Some(translation) => GuestMemory::Unshared(&mut translation.scratch),
+                        None => guest_memory(backing_store.get()),
+                    };
+                    let mut wasi_ctx = wasi_ctx.lock().unwrap();
+                    let result = tokio::runtime::Handle::current().block_on($snapshot::$name(
+                        &mut *wasi_ctx,
+                        &mut memory,
+                        $( $arg_name ),*
+                    ));
+                    if let (Some(translation), Ok(0)) = (&translation, &result) {
+                        translation.copy_back(memory_bytes(backing_store.get()));
+                    }
+                    match (trace, &result) {
+                        (Some(trace), Ok(errno)) => trace.finish(*errno),
+                        // the promise is rejected, which throws the error in the wasm module
+                        (Some(trace), Err(e)) => trace.finish_without_return(&e.to_string()),
+                        (None, _) => {}
+                    }
+                    result
+                });
+                rv.set(promise.into());
+                return;
+            }
 
-            let mut memory = get_memory_from_scope(scope);
+            let mut memory = match &mut translation {
+                Some(translation) => GuestMemory::Unshared(&mut translation.scratch),
+                None => guest_memory(&backing_store),
+            };
             let mut wasi_ctx = get_wasi_ctx_mut().lock().unwrap();
             let result = TokioRuntime::new()
                 .unwrap()
-                .block_on(preview1::$name(
+                .block_on($snapshot::$name(
                     &mut *wasi_ctx,
                     &mut memory,
-                    $( $arg_name as $arg_ty ),*
-                ))
-                .unwrap();
+                    $( $arg_name ),*
+                ));
+            drop(wasi_ctx);
+            // errors other than errnos are traps, like the rejected promises of JSPI
+            let result = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    if let Some(trace) = trace {
+                        trace.finish_without_return(&e.to_string());
+                    }
+                    throw_error(scope, &e.to_string());
+                    return;
+                }
+            };
+            if let (Some(translation), 0) = (&translation, result) {
+                translation.copy_back(memory_bytes(&backing_store));
+            }
+            if let Some(trace) = trace {
+                trace.finish(result);
+            }
 
             rv.set(v8::Integer::new(scope, result).into());
         }
-    }
+    };
+    ($export:ident, $name:ident,  $( $arg_name: ident : $arg_ty: ty ),*) => {
+        wasi_function!(preview1 => $export, $name, $( $arg_name: $arg_ty ),*);
+    };
 }
 
 wasi_function!(wasi_snapshot_preview1_args_get, args_get, arg0: i32, arg1: i32);
@@ -152,12 +717,160 @@ wasi_function!(wasi_snapshot_preview1_sock_recv,sock_recv, arg0: i32, arg1: i32,
 wasi_function!(wasi_snapshot_preview1_sock_send,sock_send, arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i32);
 wasi_function!(wasi_snapshot_preview1_sock_shutdown,sock_shutdown, arg0: i32, arg1: i32);
 
+// wasi_unstable has no sock_accept, and proc_exit is shared with wasi_snapshot_preview1
+wasi_function!(preview0 => wasi_unstable_args_get, args_get, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_args_sizes_get, args_sizes_get, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_clock_res_get, clock_res_get, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_clock_time_get, clock_time_get, arg0: i32, arg1: i64, arg2: i32);
+wasi_function!(preview0 => wasi_unstable_environ_get, environ_get, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_environ_sizes_get, environ_sizes_get, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_fd_advise, fd_advise, arg0: i32, arg1: i64, arg2: i64, arg3: i32);
+wasi_function!(preview0 => wasi_unstable_fd_allocate, fd_allocate, arg0: i32, arg1: i64, arg2: i64);
+wasi_function!(preview0 => wasi_unstable_fd_close, fd_close, arg0: i32);
+wasi_function!(preview0 => wasi_unstable_fd_datasync, fd_datasync, arg0: i32);
+wasi_function!(preview0 => wasi_unstable_fd_fdstat_get, fd_fdstat_get, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_fd_fdstat_set_flags, fd_fdstat_set_flags, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_fd_fdstat_set_rights, fd_fdstat_set_rights, arg0: i32, arg1: i64, arg2: i64);
+wasi_function!(preview0 => wasi_unstable_fd_filestat_get, fd_filestat_get, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_fd_filestat_set_size, fd_filestat_set_size, arg0: i32, arg1: i64);
+wasi_function!(preview0 => wasi_unstable_fd_filestat_set_times, fd_filestat_set_times, arg0: i32, arg1: i64, arg2: i64, arg3: i32);
+wasi_function!(preview0 => wasi_unstable_fd_pread, fd_pread, arg0: i32, arg1: i32, arg2: i32, arg3: i64, arg4: i32);
+wasi_function!(preview0 => wasi_unstable_fd_prestat_dir_name, fd_prestat_dir_name, arg0: i32, arg1: i32, arg2: i32);
+wasi_function!(preview0 => wasi_unstable_fd_prestat_get, fd_prestat_get, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_fd_pwrite, fd_pwrite, arg0: i32, arg1: i32, arg2: i32, arg3: i64, arg4: i32);
+wasi_function!(preview0 => wasi_unstable_fd_read, fd_read, arg0: i32, arg1: i32, arg2: i32, arg3: i32);
+wasi_function!(preview0 => wasi_unstable_fd_readdir, fd_readdir, arg0: i32, arg1: i32, arg2: i32, arg3: i64, arg4: i32);
+wasi_function!(preview0 => wasi_unstable_fd_renumber, fd_renumber, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_fd_seek, fd_seek, arg0: i32, arg1: i64, arg2: i32, arg3: i32);
+wasi_function!(preview0 => wasi_unstable_fd_sync, fd_sync, arg0: i32);
+wasi_function!(preview0 => wasi_unstable_fd_tell, fd_tell, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_fd_write, fd_write, arg0: i32, arg1: i32, arg2: i32, arg3: i32);
+wasi_function!(preview0 => wasi_unstable_path_create_directory, path_create_directory, arg0: i32, arg1: i32, arg2: i32);
+wasi_function!(preview0 => wasi_unstable_path_filestat_get, path_filestat_get, arg0: i32, arg1: i32, arg2: i32, arg3 :i32, arg4: i32);
+wasi_function!(preview0 => wasi_unstable_path_filestat_set_times, path_filestat_set_times, arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i64, arg5: i64, arg6: i32);
+wasi_function!(preview0 => wasi_unstable_path_link, path_link, arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i32, arg5: i32, arg6: i32);
+wasi_function!(preview0 => wasi_unstable_path_open, path_open, arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i32, arg5: i64, arg6: i64, arg7: i32, arg8:i32);
+wasi_function!(preview0 => wasi_unstable_path_readlink, path_readlink, arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i32, arg5: i32);
+wasi_function!(preview0 => wasi_unstable_path_remove_directory, path_remove_directory, arg0: i32, arg1: i32, arg2: i32);
+wasi_function!(preview0 => wasi_unstable_path_rename, path_rename, arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i32, arg5: i32);
+wasi_function!(preview0 => wasi_unstable_path_symlink, path_symlink, arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i32);
+wasi_function!(preview0 => wasi_unstable_path_unlink_file, path_unlink_file, arg0: i32, arg1: i32, arg2: i32);
+wasi_function!(preview0 => wasi_unstable_poll_oneoff, poll_oneoff, arg0: i32, arg1: i32, arg2: i32, arg3: i32);
+wasi_function!(preview0 => wasi_unstable_proc_raise, proc_raise, arg0: i32);
+wasi_function!(preview0 => wasi_unstable_random_get, random_get, arg0: i32, arg1: i32);
+wasi_function!(preview0 => wasi_unstable_sched_yield, sched_yield,);
+wasi_function!(preview0 => wasi_unstable_sock_recv, sock_recv, arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i32, arg5: i32);
+wasi_function!(preview0 => wasi_unstable_sock_send, sock_send, arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i32);
+wasi_function!(preview0 => wasi_unstable_sock_shutdown, sock_shutdown, arg0: i32, arg1: i32);
+
+/// proc_exit(code), which stops the module with the exit code
+///
+/// The process is not exited here, since the runtime has work left after the module stops
+/// (exporting in-memory directories, reporting overlay changes, returning the code to embedders).
+/// Instead, the code is recorded and the execution of the isolate is terminated, so that
+/// `Runtime::run` returns the code. A thread spawned by thread-spawn has no caller to return to,
+/// so it exits the process with the code (see `threads::run_thread`). A `WASI` instance of
+/// `lv8 run-js` created with `returnOnExit` instead throws to `WASI.start()`, which returns the code
+/// and lets the script continue.
 pub(super) fn wasi_snapshot_preview1_proc_exit(
     scope: &mut v8::HandleScope,
     args: v8::FunctionCallbackArguments,
     mut _rv: v8::ReturnValue,
 ) {
-    let arg0 = args.get(0);
-    let arg0 = arg0.integer_value(scope).unwrap() as i32;
-    std::process::exit(arg0);
+    let Some(arg0) = args.get(0).int32_value(scope) else {
+        return;
+    };
+    let trace =
+        trace::is_enabled().then(|| trace::Call::new("proc_exit", &[arg0 as i64], false, &[]));
+    // proc_exit has no errno to return, so a denied call always traps
+    if !matches!(policy::decide("proc_exit"), policy::Decision::Allow) {
+        let message = "WASI function proc_exit is denied by the policy";
+        if let Some(trace) = trace {
+            trace.finish_without_return(message);
+        }
+        throw_error(scope, message);
+        return;
+    }
+    if let Some(trace) = trace {
+        trace.finish_without_return("exit");
+    }
+    if SELECTED_CTX
+        .with(Cell::get)
+        .is_some_and(|selected| selected.return_on_exit)
+    {
+        RETURNED_EXIT_CODE.with(|code| code.set(Some(arg0)));
+        throw_error(scope, &format!("proc_exit({})", arg0));
+        return;
+    }
+    exit(scope, arg0);
+}
+
+/// Records the exit code and stops the module, which is also done by `exit` of wasi:cli/exit
+pub(super) fn exit(scope: &mut v8::HandleScope, code: i32) {
+    let _ = EXIT_CODE.set(code);
+    // stop the module without unwinding through wasm code, which cannot catch the termination,
+    // so that the runtime can finish its work (e.g. exporting in-memory directories) before exiting
+    scope.terminate_execution();
+}
+
+/// Returns the exit code if the module has called proc_exit
+pub(super) fn exit_code() -> Option<i32> {
+    EXIT_CODE.get().copied()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn inherits_files_with_their_access_mode() {
+        let path = std::env::temp_dir().join(format!("lv8-inherit-fd-{}", std::process::id()));
+        std::fs::write(&path, "data").unwrap();
+
+        let file = std::fs::File::open(&path).unwrap();
+        let (_, access_mode) = inherit_fd(file.as_raw_fd()).unwrap();
+        assert_eq!(access_mode, FileAccessMode::READ);
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let (_, access_mode) = inherit_fd(file.as_raw_fd()).unwrap();
+        assert_eq!(access_mode, FileAccessMode::WRITE);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let (_, access_mode) = inherit_fd(file.as_raw_fd()).unwrap();
+        assert_eq!(access_mode, FileAccessMode::READ | FileAccessMode::WRITE);
+    }
+
+    #[test]
+    fn inherits_stream_sockets() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (unix_stream, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        for fd in [
+            listener.as_raw_fd(),
+            stream.as_raw_fd(),
+            unix_stream.as_raw_fd(),
+        ] {
+            let (_, access_mode) = inherit_fd(fd).unwrap();
+            assert_eq!(access_mode, FileAccessMode::READ | FileAccessMode::WRITE);
+        }
+    }
+
+    #[test]
+    fn rejects_datagram_sockets_and_closed_fds() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let error = inherit_fd(socket.as_raw_fd()).err().unwrap();
+        assert!(error
+            .to_string()
+            .contains("datagram (e.g. UDP) sockets are not supported"));
+
+        let (socket, _) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        assert!(inherit_fd(socket.as_raw_fd()).is_err());
+        assert!(inherit_fd(-1).is_err());
+    }
 }
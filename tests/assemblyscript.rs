@@ -0,0 +1,39 @@
+mod common;
+
+use common::{lv8, stderr, stdout, wat_file};
+
+/// Module which traces and aborts with strings of AssemblyScript, which are preceded by their
+/// length in bytes
+const ABORT: &str = r#"(module
+    (import "env" "abort" (func $abort (param i32 i32 i32 i32)))
+    (import "env" "trace" (func $trace (param i32 i32 f64 f64 f64 f64 f64)))
+    (memory (export "memory") 1)
+    ;; "oops" at 20 and "a.ts" at 40
+    (data (i32.const 16) "\08\00\00\00o\00o\00p\00s\00")
+    (data (i32.const 36) "\08\00\00\00a\00.\00t\00s\00")
+    (func (export "_start")
+      (call $trace (i32.const 20) (i32.const 2)
+        (f64.const 1) (f64.const 2.5) (f64.const 0) (f64.const 0) (f64.const 0))
+      (call $abort (i32.const 20) (i32.const 40) (i32.const 12) (i32.const 5))))"#;
+
+#[test]
+fn abort_traps_with_the_message_of_the_loader() {
+    let path = wat_file("assemblyscript_abort", ABORT);
+    let output = lv8().arg("--assemblyscript").arg(&path).output().unwrap();
+    assert_eq!(
+        stderr(&output),
+        "trace: oops 1, 2.5\nError: RuntimeError: abort: oops at a.ts:12:5\n"
+    );
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn inspect_marks_assemblyscript_imports_with_the_option() {
+    let path = wat_file("assemblyscript_inspect", ABORT);
+    let output = lv8()
+        .args(["inspect", "--assemblyscript"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(stdout(&output).contains("env.abort: func (i32, i32, i32, i32) -> () [provided]"));
+}
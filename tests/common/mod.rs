@@ -0,0 +1,41 @@
+// Helpers of the tests which run the lv8 binary
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Returns a command which runs lv8 in the root of the repository
+pub fn lv8() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_lv8"));
+    command.current_dir(env!("CARGO_MANIFEST_DIR"));
+    command
+}
+
+/// Returns the path of a file in the repository (e.g. `examples/hello.wasm`)
+pub fn repo_path(path: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(path)
+}
+
+/// Compiles the module in the text format, and returns the path of the binary
+pub fn wat_file(name: &str, wat: &str) -> PathBuf {
+    let wasm_module = wat::parse_str(wat).unwrap();
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.wasm", name));
+    std::fs::write(&path, wasm_module).unwrap();
+    path
+}
+
+/// Returns a directory which is empty, for the files of a test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
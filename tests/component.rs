@@ -0,0 +1,451 @@
+mod common;
+
+use common::{lv8, stderr, stdout, temp_dir, wat_file};
+
+/// Writes "hello\n" to stdout by wasi:cli/stdout and wasi:io/streams
+const WRITE_HELLO: &str = r#"(component
+    (import "wasi:io/error@0.2.0" (instance $io-error
+      (export "error" (type (sub resource)))))
+    (alias export $io-error "error" (type $error))
+    (import "wasi:io/streams@0.2.0" (instance $streams
+      (alias outer 1 $error (type $e))
+      (export "error" (type $err (eq $e)))
+      (export "output-stream" (type $os (sub resource)))
+      (type $se (variant (case "last-operation-failed" (own $err)) (case "closed")))
+      (export "stream-error" (type $stream-error (eq $se)))
+      (export "[method]output-stream.blocking-write-and-flush"
+        (func (param "self" (borrow $os)) (param "contents" (list u8))
+          (result (result (error $stream-error)))))))
+    (alias export $streams "output-stream" (type $output-stream))
+    (import "wasi:cli/stdout@0.2.0" (instance $stdout
+      (alias outer 1 $output-stream (type $o))
+      (export "output-stream" (type $os (eq $o)))
+      (export "get-stdout" (func (result (own $os))))))
+    (core module $mem
+      (memory (export "memory") 1)
+      (data (i32.const 16) "hello\n"))
+    (core instance $mem (instantiate $mem))
+    (alias core export $mem "memory" (core memory $memory))
+    (alias export $stdout "get-stdout" (func $get-stdout))
+    (core func $get-stdout (canon lower (func $get-stdout)))
+    (alias export $streams "[method]output-stream.blocking-write-and-flush" (func $write))
+    (core func $write (canon lower (func $write) (memory $memory)))
+    (core func $drop (canon resource.drop $output-stream))
+    (core instance $host
+      (export "get-stdout" (func $get-stdout))
+      (export "write" (func $write))
+      (export "drop" (func $drop)))
+    (core module $main
+      (import "host" "get-stdout" (func $get-stdout (result i32)))
+      (import "host" "write" (func $write (param i32 i32 i32 i32)))
+      (import "host" "drop" (func $drop (param i32)))
+      (import "mem" "memory" (memory 1))
+      (func (export "run") (result i32)
+        (local $stdout i32)
+        (local.set $stdout (call $get-stdout))
+        (call $write (local.get $stdout) (i32.const 16) (i32.const 6) (i32.const 32))
+        (call $drop (local.get $stdout))
+        ;; ok if the write succeeded
+        (i32.load8_u (i32.const 32))))
+    (core instance $main (instantiate $main
+      (with "host" (instance $host))
+      (with "mem" (instance $mem))))
+    (func $run (result (result)) (canon lift (core func $main "run")))
+    (instance $run (export "run" (func $run)))
+    (export "wasi:cli/run@0.2.0" (instance $run))
+)"#;
+
+/// Exits with an error by wasi:cli/exit
+const EXIT_WITH_ERROR: &str = r#"(component
+    (import "wasi:cli/exit@0.2.0" (instance $exit
+      (export "exit" (func (param "status" (result))))))
+    (alias export $exit "exit" (func $exit))
+    (core func $exit (canon lower (func $exit)))
+    (core instance $host (export "exit" (func $exit)))
+    (core module $main
+      (import "host" "exit" (func $exit (param i32)))
+      (func (export "run") (result i32)
+        (call $exit (i32.const 1))
+        ;; exit does not return
+        (unreachable)))
+    (core instance $main (instantiate $main (with "host" (instance $host))))
+    (func $run (result (result)) (canon lift (core func $main "run")))
+    (instance $run (export "run" (func $run)))
+    (export "wasi:cli/run@0.2.0" (instance $run))
+)"#;
+
+/// Writes the argument to a file of the preopened directory, and writes back its contents to stdout
+///
+/// The component is laid out like `wasm32-wasip2` binaries linked by wasm-component-ld: the main
+/// module imports the interfaces directly, the functions which need its memory and cabi_realloc are
+/// called through the table of a shim module (filled by a fixup module once the main module is
+/// instantiated), and wasi:cli/run is exported through a nested component.
+const WASIP2_COMMAND: &str = r#"(component
+    (import "wasi:io/error@0.2.0" (instance $io-error
+      (export "error" (type (sub resource)))))
+    (alias export $io-error "error" (type $error))
+    (import "wasi:io/streams@0.2.0" (instance $streams
+      (alias outer 1 $error (type $e))
+      (export "error" (type $err (eq $e)))
+      (export "output-stream" (type $os (sub resource)))
+      (type $se (variant (case "last-operation-failed" (own $err)) (case "closed")))
+      (export "stream-error" (type $stream-error (eq $se)))
+      (export "[method]output-stream.blocking-write-and-flush"
+        (func (param "self" (borrow $os)) (param "contents" (list u8))
+          (result (result (error $stream-error)))))))
+    (alias export $streams "output-stream" (type $output-stream))
+    (import "wasi:cli/environment@0.2.0" (instance $environment
+      (export "get-arguments" (func (result (list string))))))
+    (import "wasi:cli/stdout@0.2.0" (instance $stdout
+      (alias outer 1 $output-stream (type $o))
+      (export "output-stream" (type $os (eq $o)))
+      (export "get-stdout" (func (result (own $os))))))
+    (import "wasi:clocks/monotonic-clock@0.2.0" (instance $monotonic-clock
+      (type $i u64)
+      (export "instant" (type $instant (eq $i)))
+      (export "now" (func (result $instant)))))
+    (import "wasi:clocks/wall-clock@0.2.0" (instance $wall-clock
+      (type $dt (record (field "seconds" u64) (field "nanoseconds" u32)))
+      (export "datetime" (type $datetime (eq $dt)))
+      (export "now" (func (result $datetime)))))
+    (import "wasi:filesystem/types@0.2.0" (instance $types
+      (export "descriptor" (type $descriptor (sub resource)))
+      (type $fs u64)
+      (export "filesize" (type $filesize (eq $fs)))
+      (type $pf (flags "symlink-follow"))
+      (export "path-flags" (type $path-flags (eq $pf)))
+      (type $of (flags "create" "directory" "exclusive" "truncate"))
+      (export "open-flags" (type $open-flags (eq $of)))
+      (type $df (flags "read" "write" "file-integrity-sync" "data-integrity-sync"
+        "requested-write-sync" "mutate-directory"))
+      (export "descriptor-flags" (type $descriptor-flags (eq $df)))
+      (type $ec (enum "access" "would-block" "already" "bad-descriptor" "busy" "deadlock"
+        "quota" "exist" "file-too-large" "illegal-byte-sequence" "in-progress" "interrupted"
+        "invalid" "io" "is-directory" "loop" "too-many-links" "message-size" "name-too-long"
+        "no-device" "no-entry" "no-lock" "insufficient-memory" "insufficient-space"
+        "not-directory" "not-empty" "not-recoverable" "unsupported" "no-tty" "no-such-device"
+        "overflow" "not-permitted" "pipe" "read-only" "invalid-seek" "text-file-busy"
+        "cross-device"))
+      (export "error-code" (type $error-code (eq $ec)))
+      (export "[method]descriptor.open-at"
+        (func (param "self" (borrow $descriptor)) (param "path-flags" $path-flags)
+          (param "path" string) (param "open-flags" $open-flags)
+          (param "flags" $descriptor-flags)
+          (result (result (own $descriptor) (error $error-code)))))
+      (export "[method]descriptor.write"
+        (func (param "self" (borrow $descriptor)) (param "buffer" (list u8))
+          (param "offset" $filesize) (result (result $filesize (error $error-code)))))
+      (export "[method]descriptor.read"
+        (func (param "self" (borrow $descriptor)) (param "length" $filesize)
+          (param "offset" $filesize)
+          (result (result (tuple (list u8) bool) (error $error-code)))))))
+    (alias export $types "descriptor" (type $descriptor))
+    (import "wasi:filesystem/preopens@0.2.0" (instance $preopens
+      (alias outer 1 $descriptor (type $d))
+      (export "descriptor" (type $desc (eq $d)))
+      (export "get-directories" (func (result (list (tuple (own $desc) string)))))))
+
+    ;; the program, which calls the imports lowered by the canonical ABI
+    (core module $main
+      (import "wasi:cli/environment@0.2.0" "get-arguments" (func $get-arguments (param i32)))
+      (import "wasi:clocks/monotonic-clock@0.2.0" "now" (func $monotonic-now (result i64)))
+      (import "wasi:clocks/wall-clock@0.2.0" "now" (func $wall-now (param i32)))
+      (import "wasi:filesystem/preopens@0.2.0" "get-directories"
+        (func $get-directories (param i32)))
+      (import "wasi:filesystem/types@0.2.0" "[method]descriptor.open-at"
+        (func $open-at (param i32 i32 i32 i32 i32 i32 i32)))
+      (import "wasi:filesystem/types@0.2.0" "[method]descriptor.write"
+        (func $write (param i32 i32 i32 i64 i32)))
+      (import "wasi:filesystem/types@0.2.0" "[method]descriptor.read"
+        (func $read (param i32 i64 i64 i32)))
+      (import "wasi:filesystem/types@0.2.0" "[resource-drop]descriptor"
+        (func $drop-descriptor (param i32)))
+      (import "wasi:cli/stdout@0.2.0" "get-stdout" (func $get-stdout (result i32)))
+      (import "wasi:io/streams@0.2.0" "[method]output-stream.blocking-write-and-flush"
+        (func $blocking-write-and-flush (param i32 i32 i32 i32)))
+      (import "wasi:io/streams@0.2.0" "[resource-drop]output-stream"
+        (func $drop-output-stream (param i32)))
+      (memory (export "memory") 1)
+      (global $heap (mut i32) (i32.const 1024))
+      (data (i32.const 200) "out.txt")
+      ;; bump allocator for the lists and strings returned by the host
+      (func (export "cabi_realloc") (param i32 i32 i32 i32) (result i32)
+        (local $ptr i32)
+        (local.set $ptr
+          (i32.and
+            (i32.add (global.get $heap) (i32.sub (local.get 2) (i32.const 1)))
+            (i32.sub (i32.const 0) (local.get 2))))
+        (global.set $heap (i32.add (local.get $ptr) (local.get 3)))
+        (local.get $ptr))
+      ;; results are returned through pointers: arguments at 0, datetime at 16, preopens at 32,
+      ;; and the results of open-at, write, read and blocking-write-and-flush at 48, 64, 80 and 96
+      (func (export "wasi:cli/run@0.2.0#run") (result i32)
+        (local $start i64) (local $args i32) (local $fd i32) (local $stdout i32)
+        (local.set $start (call $monotonic-now))
+        ;; arguments are the module name and the contents of the file
+        (call $get-arguments (i32.const 0))
+        (if (i32.ne (i32.load (i32.const 4)) (i32.const 2))
+          (then (return (i32.const 1))))
+        (local.set $args (i32.load (i32.const 0)))
+        ;; the wall clock is after 2020
+        (call $wall-now (i32.const 16))
+        (if (i64.lt_u (i64.load (i32.const 16)) (i64.const 1600000000))
+          (then (return (i32.const 1))))
+        ;; open-at(the first preopen, {}, "out.txt", {create, truncate}, {read, write})
+        (call $get-directories (i32.const 32))
+        (if (i32.eqz (i32.load (i32.const 36)))
+          (then (return (i32.const 1))))
+        (call $open-at (i32.load (i32.load (i32.const 32))) (i32.const 0) (i32.const 200)
+          (i32.const 7) (i32.const 9) (i32.const 3) (i32.const 48))
+        (if (i32.load8_u (i32.const 48))
+          (then (return (i32.const 1))))
+        (local.set $fd (i32.load (i32.const 52)))
+        (call $write (local.get $fd) (i32.load offset=8 (local.get $args))
+          (i32.load offset=12 (local.get $args)) (i64.const 0) (i32.const 64))
+        (if (i32.load8_u (i32.const 64))
+          (then (return (i32.const 1))))
+        (call $read (local.get $fd) (i64.const 256) (i64.const 0) (i32.const 80))
+        (if (i32.load8_u (i32.const 80))
+          (then (return (i32.const 1))))
+        (call $drop-descriptor (local.get $fd))
+        ;; write what was read back to stdout
+        (local.set $stdout (call $get-stdout))
+        (call $blocking-write-and-flush (local.get $stdout) (i32.load (i32.const 84))
+          (i32.load (i32.const 88)) (i32.const 96))
+        (if (i32.load8_u (i32.const 96))
+          (then (return (i32.const 1))))
+        (call $drop-output-stream (local.get $stdout))
+        ;; the monotonic clock does not go backwards
+        (if (i64.lt_u (call $monotonic-now) (local.get $start))
+          (then (return (i32.const 1))))
+        (i32.const 0)))
+
+    ;; functions which need the memory and cabi_realloc of $main are called through a table,
+    ;; which is filled by $fixup once $main is instantiated
+    (core module $shim
+      (type $ptr (func (param i32)))
+      (type $open-at (func (param i32 i32 i32 i32 i32 i32 i32)))
+      (type $write (func (param i32 i32 i32 i64 i32)))
+      (type $read (func (param i32 i64 i64 i32)))
+      (type $write-and-flush (func (param i32 i32 i32 i32)))
+      (table (export "$imports") 7 7 funcref)
+      (func (export "0") (type $ptr)
+        (call_indirect (type $ptr) (local.get 0) (i32.const 0)))
+      (func (export "1") (type $ptr)
+        (call_indirect (type $ptr) (local.get 0) (i32.const 1)))
+      (func (export "2") (type $ptr)
+        (call_indirect (type $ptr) (local.get 0) (i32.const 2)))
+      (func (export "3") (type $open-at)
+        (call_indirect (type $open-at) (local.get 0) (local.get 1) (local.get 2) (local.get 3)
+          (local.get 4) (local.get 5) (local.get 6) (i32.const 3)))
+      (func (export "4") (type $write)
+        (call_indirect (type $write) (local.get 0) (local.get 1) (local.get 2) (local.get 3)
+          (local.get 4) (i32.const 4)))
+      (func (export "5") (type $read)
+        (call_indirect (type $read) (local.get 0) (local.get 1) (local.get 2) (local.get 3)
+          (i32.const 5)))
+      (func (export "6") (type $write-and-flush)
+        (call_indirect (type $write-and-flush) (local.get 0) (local.get 1) (local.get 2)
+          (local.get 3) (i32.const 6))))
+    (core module $fixup
+      (type $ptr (func (param i32)))
+      (type $open-at (func (param i32 i32 i32 i32 i32 i32 i32)))
+      (type $write (func (param i32 i32 i32 i64 i32)))
+      (type $read (func (param i32 i64 i64 i32)))
+      (type $write-and-flush (func (param i32 i32 i32 i32)))
+      (import "" "0" (func $get-arguments (type $ptr)))
+      (import "" "1" (func $wall-now (type $ptr)))
+      (import "" "2" (func $get-directories (type $ptr)))
+      (import "" "3" (func $open-at (type $open-at)))
+      (import "" "4" (func $write (type $write)))
+      (import "" "5" (func $read (type $read)))
+      (import "" "6" (func $blocking-write-and-flush (type $write-and-flush)))
+      (import "" "$imports" (table 7 7 funcref))
+      (elem (i32.const 0) func $get-arguments $wall-now $get-directories $open-at $write $read
+        $blocking-write-and-flush))
+
+    (core instance $shim (instantiate $shim))
+    (alias export $monotonic-clock "now" (func $monotonic-now))
+    (core func $monotonic-now (canon lower (func $monotonic-now)))
+    (alias export $stdout "get-stdout" (func $get-stdout))
+    (core func $get-stdout (canon lower (func $get-stdout)))
+    (core func $drop-descriptor (canon resource.drop $descriptor))
+    (core func $drop-output-stream (canon resource.drop $output-stream))
+    (core instance $environment-imports
+      (export "get-arguments" (func $shim "0")))
+    (core instance $monotonic-clock-imports
+      (export "now" (func $monotonic-now)))
+    (core instance $wall-clock-imports
+      (export "now" (func $shim "1")))
+    (core instance $preopens-imports
+      (export "get-directories" (func $shim "2")))
+    (core instance $types-imports
+      (export "[method]descriptor.open-at" (func $shim "3"))
+      (export "[method]descriptor.write" (func $shim "4"))
+      (export "[method]descriptor.read" (func $shim "5"))
+      (export "[resource-drop]descriptor" (func $drop-descriptor)))
+    (core instance $stdout-imports
+      (export "get-stdout" (func $get-stdout)))
+    (core instance $streams-imports
+      (export "[method]output-stream.blocking-write-and-flush" (func $shim "6"))
+      (export "[resource-drop]output-stream" (func $drop-output-stream)))
+    (core instance $main (instantiate $main
+      (with "wasi:cli/environment@0.2.0" (instance $environment-imports))
+      (with "wasi:clocks/monotonic-clock@0.2.0" (instance $monotonic-clock-imports))
+      (with "wasi:clocks/wall-clock@0.2.0" (instance $wall-clock-imports))
+      (with "wasi:filesystem/preopens@0.2.0" (instance $preopens-imports))
+      (with "wasi:filesystem/types@0.2.0" (instance $types-imports))
+      (with "wasi:cli/stdout@0.2.0" (instance $stdout-imports))
+      (with "wasi:io/streams@0.2.0" (instance $streams-imports))))
+
+    (alias core export $main "memory" (core memory $memory))
+    (alias core export $main "cabi_realloc" (core func $realloc))
+    (alias export $environment "get-arguments" (func $get-arguments))
+    (core func $get-arguments (canon lower (func $get-arguments)
+      (memory $memory) (realloc $realloc) string-encoding=utf8))
+    (alias export $wall-clock "now" (func $wall-now))
+    (core func $wall-now (canon lower (func $wall-now) (memory $memory)))
+    (alias export $preopens "get-directories" (func $get-directories))
+    (core func $get-directories (canon lower (func $get-directories)
+      (memory $memory) (realloc $realloc) string-encoding=utf8))
+    (alias export $types "[method]descriptor.open-at" (func $open-at))
+    (core func $open-at (canon lower (func $open-at) (memory $memory) string-encoding=utf8))
+    (alias export $types "[method]descriptor.write" (func $write))
+    (core func $write (canon lower (func $write) (memory $memory)))
+    (alias export $types "[method]descriptor.read" (func $read))
+    (core func $read (canon lower (func $read) (memory $memory) (realloc $realloc)))
+    (alias export $streams "[method]output-stream.blocking-write-and-flush"
+      (func $blocking-write-and-flush))
+    (core func $blocking-write-and-flush (canon lower (func $blocking-write-and-flush)
+      (memory $memory)))
+    (core instance $fixup-imports
+      (export "$imports" (table $shim "$imports"))
+      (export "0" (func $get-arguments))
+      (export "1" (func $wall-now))
+      (export "2" (func $get-directories))
+      (export "3" (func $open-at))
+      (export "4" (func $write))
+      (export "5" (func $read))
+      (export "6" (func $blocking-write-and-flush)))
+    (core instance (instantiate $fixup (with "" (instance $fixup-imports))))
+
+    ;; wasi:cli/run is exported through a nested component, like the exports of wit-component
+    (type $run-type (func (result (result))))
+    (alias core export $main "wasi:cli/run@0.2.0#run" (core func $run-core))
+    (func $run (type $run-type) (canon lift (core func $run-core)))
+    (component $run-component
+      (type $run-type (func (result (result))))
+      (import "import-func-run" (func $run (type $run-type)))
+      (export "run" (func $run) (func (type $run-type))))
+    (instance $run-instance (instantiate $run-component
+      (with "import-func-run" (func $run))))
+    (export "wasi:cli/run@0.2.0" (instance $run-instance))
+)"#;
+
+#[test]
+fn runs_command_components() {
+    let path = wat_file("component_hello", WRITE_HELLO);
+    let output = lv8().arg(&path).output().unwrap();
+    assert_eq!(stdout(&output), "hello\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn exit_code_of_components() {
+    let path = wat_file("component_exit", EXIT_WITH_ERROR);
+    let output = lv8().arg(&path).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn traces_and_denies_preview1_functions_of_components() {
+    let path = wat_file("component_trace", WRITE_HELLO);
+    let output = lv8().arg("--trace-wasi").arg(&path).output().unwrap();
+    assert!(stderr(&output).contains("fd_write("), "{}", stderr(&output));
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = lv8()
+        .args(["--deny-wasi", "proc_exit", "--deny-action", "trap"])
+        .arg(wat_file("component_deny", EXIT_WITH_ERROR))
+        .output()
+        .unwrap();
+    assert!(
+        stderr(&output).contains("WASI function proc_exit is denied by the policy"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn rejects_options_of_core_modules() {
+    let path = wat_file("component_options", WRITE_HELLO);
+    for option in ["--jspi", "--emscripten", "--assemblyscript"] {
+        let output = lv8().arg(option).arg(&path).output().unwrap();
+        let message = format!("Error: {} is not supported for components", option);
+        assert!(stderr(&output).starts_with(&message), "{}", stderr(&output));
+        assert_eq!(output.status.code(), Some(1));
+    }
+
+    let script = temp_dir("component_preload").join("preload.js");
+    std::fs::write(&script, "imports.env = {};").unwrap();
+    let output = lv8()
+        .arg("--preload")
+        .arg(&script)
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(stderr(&output).starts_with("Error: --preload is not supported for components"));
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn runs_command_with_the_layout_of_wasip2_binaries() {
+    let path = wat_file("component_wasip2", WASIP2_COMMAND);
+    // the current directory is preopened as /
+    let dir = temp_dir("component_wasip2");
+    let output = lv8()
+        .current_dir(&dir)
+        .arg(&path)
+        .args(["--", "written by a component"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "written by a component");
+    assert_eq!(output.status.code(), Some(0));
+    let contents = std::fs::read_to_string(dir.join("out.txt")).unwrap();
+    assert_eq!(contents, "written by a component");
+}
+
+#[test]
+fn validates_components() {
+    let path = wat_file("component_validate", WRITE_HELLO);
+    let output = lv8().arg("validate").arg(&path).output().unwrap();
+    assert_eq!(stdout(&output), format!("{}: valid\n", path.display()));
+    assert_eq!(output.status.code(), Some(0));
+
+    // the core module of the component does not return its result
+    let path = wat_file(
+        "component_validate_invalid",
+        "(component (core module (func (result i32))))",
+    );
+    let output = lv8().arg("validate").arg(&path).output().unwrap();
+    assert_eq!(
+        stdout(&output),
+        format!(
+            "{}: invalid\n  error: type mismatch: expected i32 but nothing on stack\n  offset: 0x22 (34)\n",
+            path.display()
+        )
+    );
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn repl_rejects_components() {
+    let path = wat_file("component_repl", WRITE_HELLO);
+    let output = lv8().arg("repl").arg(&path).output().unwrap();
+    assert_eq!(
+        stderr(&output),
+        "Error: components are not supported by lv8 repl\n"
+    );
+    assert_eq!(output.status.code(), Some(1));
+}
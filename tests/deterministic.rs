@@ -0,0 +1,110 @@
+mod common;
+
+use std::process::Output;
+
+use common::{lv8, wat_file};
+
+/// Module which writes to stdout what it observes of the clocks and the random bytes:
+/// the realtime clock, the monotonic clock before and after sleeping 1s with poll_oneoff,
+/// 16 random bytes and the event of poll_oneoff
+const OBSERVE: &str = r#"(module
+    (import "wasi_snapshot_preview1" "clock_time_get"
+      (func $clock_time_get (param i32 i64 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "poll_oneoff"
+      (func $poll_oneoff (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "random_get"
+      (func $random_get (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_write"
+      (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory (export "memory") 1)
+    ;; subscription { userdata: 7, tag: clock, id: monotonic, timeout: 1s, precision: 0, flags: 0 }
+    (data (i32.const 128) "\07\00\00\00\00\00\00\00")
+    (data (i32.const 144) "\01\00\00\00\00\00\00\00\00\ca\9a\3b\00\00\00\00")
+    ;; iovec { buf: 0, buf_len: 76 } of the observations
+    (data (i32.const 200) "\00\00\00\00\4c\00\00\00")
+    (func (export "_start") (result i32)
+      ;; realtime at 0, and monotonic at 8
+      (if (call $clock_time_get (i32.const 0) (i64.const 1) (i32.const 0))
+        (then (return (i32.const 1))))
+      (if (call $clock_time_get (i32.const 1) (i64.const 1) (i32.const 8))
+        (then (return (i32.const 2))))
+      ;; the event at 40, and the number of events at 72
+      (if (call $poll_oneoff (i32.const 128) (i32.const 40) (i32.const 1) (i32.const 72))
+        (then (return (i32.const 3))))
+      ;; monotonic after sleeping at 16
+      (if (call $clock_time_get (i32.const 1) (i64.const 1) (i32.const 16))
+        (then (return (i32.const 4))))
+      ;; random bytes at 24
+      (if (call $random_get (i32.const 24) (i32.const 16))
+        (then (return (i32.const 5))))
+      (call $fd_write (i32.const 1) (i32.const 200) (i32.const 1) (i32.const 208))))"#;
+
+/// Observations of the module, in the order they are written
+struct Observations {
+    realtime: u64,
+    monotonic: u64,
+    monotonic_after_sleep: u64,
+    random: Vec<u8>,
+    event: Vec<u8>,
+}
+
+impl Observations {
+    fn from_output(output: &Output) -> Self {
+        assert_eq!(output.status.code(), Some(0));
+        let bytes = &output.stdout;
+        assert_eq!(bytes.len(), 76);
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        // one event is returned
+        assert_eq!(&bytes[72..76], &[1, 0, 0, 0]);
+        Observations {
+            realtime: u64_at(0),
+            monotonic: u64_at(8),
+            monotonic_after_sleep: u64_at(16),
+            random: bytes[24..40].to_vec(),
+            event: bytes[40..72].to_vec(),
+        }
+    }
+}
+
+fn observe(name: &str, options: &[&str]) -> Output {
+    let path = wat_file(name, OBSERVE);
+    lv8().args(options).arg(&path).output().unwrap()
+}
+
+#[test]
+fn runs_with_the_same_seed_are_identical() {
+    let options = ["--deterministic", "--random-seed", "42"];
+    let first = observe("deterministic_first", &options);
+    let second = observe("deterministic_second", &options);
+    assert_eq!(first.stdout, second.stdout);
+
+    let observations = Observations::from_output(&first);
+    // the fake clock starts at 2000-01-01T00:00:00Z, and advances by a tick on every read
+    let start = 946_684_800_000_000_000;
+    assert!((start..start + 1_000_000).contains(&observations.realtime));
+    // sleeping advances the fake clock instead of waiting
+    assert!(observations.monotonic_after_sleep - observations.monotonic >= 1_000_000_000);
+    // the event is of the clock subscription, whose userdata is 7
+    assert_eq!(&observations.event[..8], &[7, 0, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn runs_with_different_seeds_have_different_random_bytes() {
+    let first = observe(
+        "deterministic_seed_42",
+        &["--deterministic", "--random-seed", "42"],
+    );
+    let second = observe(
+        "deterministic_seed_43",
+        &["--deterministic", "--random-seed", "43"],
+    );
+    let first = Observations::from_output(&first);
+    let second = Observations::from_output(&second);
+    assert_ne!(first.random, second.random);
+    // the clocks do not depend on the seed
+    assert_eq!(first.realtime, second.realtime);
+    assert_eq!(first.monotonic, second.monotonic);
+    assert_eq!(first.monotonic_after_sleep, second.monotonic_after_sleep);
+    assert_eq!(first.event, second.event);
+}
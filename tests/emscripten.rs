@@ -0,0 +1,49 @@
+mod common;
+
+use common::{lv8, stderr, stdout, wat_file};
+
+#[test]
+fn memcpy_and_resize_heap() {
+    let path = wat_file(
+        "emscripten_heap",
+        r#"(module
+            (import "env" "emscripten_memcpy_js" (func $memcpy (param i32 i32 i32)))
+            (import "env" "emscripten_resize_heap" (func $resize_heap (param i32) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "abcd")
+            (func (export "_start")
+              (call $memcpy (i32.const 32) (i32.const 16) (i32.const 4))
+              (if (i32.ne (i32.load (i32.const 32)) (i32.load (i32.const 16)))
+                (then (call $proc_exit (i32.const 2))))
+              ;; 3 pages are requested and the memory grows by 2
+              (if (i32.eqz (call $resize_heap (i32.const 131073)))
+                (then (call $proc_exit (i32.const 3))))
+              (if (i32.ne (memory.size) (i32.const 3))
+                (then (call $proc_exit (i32.const 4))))
+              ;; above the maximum heap of 2 GiB
+              (if (call $resize_heap (i32.const -1))
+                (then (call $proc_exit (i32.const 5))))
+              ;; the source ends beyond the memory
+              (call $memcpy (i32.const 0) (i32.const 196600) (i32.const 16))))"#,
+    );
+    let output = lv8().arg("--emscripten").arg(&path).output().unwrap();
+    assert!(stderr(&output).contains("emscripten_memcpy_js: memory access out of bounds"));
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn inspect_marks_emscripten_imports_with_the_option() {
+    let path = wat_file(
+        "emscripten_inspect",
+        r#"(module (import "env" "emscripten_get_now" (func (result f64))))"#,
+    );
+    let output = lv8().arg("inspect").arg(&path).output().unwrap();
+    assert!(stdout(&output).contains("env.emscripten_get_now: func () -> (f64) [not provided]"));
+    let output = lv8()
+        .args(["inspect", "--emscripten"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(stdout(&output).contains("env.emscripten_get_now: func () -> (f64) [provided]"));
+}
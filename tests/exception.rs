@@ -0,0 +1,61 @@
+mod common;
+
+use common::{lv8, repo_path, stderr, stdout, wat_file};
+
+#[test]
+fn reports_tag_and_payload_of_uncaught_exception() {
+    // the first exception is thrown after fd_write, and is caught by _start
+    let output = lv8()
+        .arg(repo_path("examples/exception.wasm"))
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "hello from try\ncaught\n");
+    assert!(stderr(&output).contains("Uncaught wasm exception with tag error and payload [42]"));
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn reports_tag_which_is_not_exported_as_unknown() {
+    let path = wat_file(
+        "internal_tag",
+        r#"(module
+            (import "wasi_snapshot_preview1" "fd_write"
+              (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (tag $oops (param i32 i64))
+            (data (i32.const 16) "ok\n")
+            (func (export "_start")
+              ;; catch an exception thrown around a WASI call, and throw it again with another payload
+              (block $caught (result i32 i64)
+                (try_table (catch $oops $caught)
+                  (i32.store (i32.const 0) (i32.const 16))
+                  (i32.store (i32.const 4) (i32.const 3))
+                  (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+                  (throw $oops (i32.const 1) (i64.const 2)))
+                (unreachable))
+              (drop)
+              (i32.add (i32.const 6))
+              (i64.const -1)
+              (throw $oops)))"#,
+    );
+    let output = lv8().arg(path).output().unwrap();
+    assert_eq!(stdout(&output), "ok\n");
+    assert!(
+        stderr(&output).contains("Uncaught wasm exception with an unknown tag"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn reports_traps_as_errors() {
+    let path = wat_file(
+        "trap",
+        r#"(module
+            (memory (export "memory") 1)
+            (func (export "_start") (unreachable)))"#,
+    );
+    let output = lv8().arg(path).output().unwrap();
+    assert!(stderr(&output).contains("RuntimeError: unreachable"));
+    assert_eq!(output.status.code(), Some(1));
+}
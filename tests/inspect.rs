@@ -0,0 +1,46 @@
+mod common;
+
+use common::{lv8, stderr, stdout, wat_file};
+
+#[test]
+fn marks_provided_imports() {
+    let path = wat_file(
+        "inspect_imports",
+        r#"(module
+            (import "wasi_snapshot_preview1" "fd_write"
+              (func (param i32 i32 i32 i32) (result i32)))
+            (import "wasi" "thread-spawn" (func (param i32) (result i32)))
+            (import "wasi_ephemeral_nn" "load" (func (param i32 i32 i32 i32 i32) (result i32)))
+            (import "env" "now" (func (result f64)))
+            (import "env" "memory" (memory 1)))"#,
+    );
+    let output = lv8().arg("inspect").arg(&path).output().unwrap();
+    assert_eq!(
+        stdout(&output),
+        "Imports:
+  wasi_snapshot_preview1.fd_write: func (i32, i32, i32, i32) -> (i32) [provided]
+  wasi.thread-spawn: func (i32) -> (i32) [provided]
+  wasi_ephemeral_nn.load: func (i32, i32, i32, i32, i32) -> (i32) [provided]
+  env.now: func () -> (f64) [not provided]
+  env.memory: memory 1 pages, no maximum [provided]
+Exports:
+"
+    );
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = lv8()
+        .args(["inspect", "--json"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(stdout(&output)
+        .contains("{\"module\":\"env\",\"name\":\"now\",\"kind\":\"func\",\"type\":{\"params\":[],\"results\":[\"f64\"]},\"provided\":false}"));
+}
+
+#[test]
+fn rejects_components() {
+    let path = wat_file("inspect_component", "(component)");
+    let output = lv8().arg("inspect").arg(&path).output().unwrap();
+    assert!(stderr(&output).contains("is a component, but only core modules can be inspected"));
+    assert_eq!(output.status.code(), Some(1));
+}
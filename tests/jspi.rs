@@ -0,0 +1,110 @@
+mod common;
+
+use std::io::Write;
+use std::process::Stdio;
+
+use common::{lv8, stdout, wat_file};
+
+#[test]
+fn fd_read_on_stdin_is_suspended() {
+    // fd_read returns a promise, and _start is resumed with its errno once stdin is read
+    let path = wat_file(
+        "jspi_fd_read",
+        r#"(module
+            (import "wasi_snapshot_preview1" "fd_read"
+              (func $fd_read (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+              (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "_start") (result i32)
+              ;; iovec { buf: 16, buf_len: 64 }, and the number of bytes read at 8
+              (i32.store (i32.const 0) (i32.const 16))
+              (i32.store (i32.const 4) (i32.const 64))
+              (if (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8))
+                (then (return (i32.const 1))))
+              ;; write back the bytes read
+              (i32.store (i32.const 4) (i32.load (i32.const 8)))
+              (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))))"#,
+    );
+    let mut child = lv8()
+        .arg("--jspi")
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"hello jspi\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(stdout(&output), "hello jspi\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn exit_code_after_suspension() {
+    // fd_write is suspended on a promise before proc_exit is called
+    let path = wat_file(
+        "jspi_proc_exit",
+        r#"(module
+            (import "wasi_snapshot_preview1" "fd_write"
+              (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "bye\n")
+            (func (export "_start")
+              (i32.store (i32.const 0) (i32.const 16))
+              (i32.store (i32.const 4) (i32.const 4))
+              (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+              (call $proc_exit (i32.const 7))
+              ;; proc_exit does not return
+              (unreachable)))"#,
+    );
+    let output = lv8().arg("--jspi").arg(&path).output().unwrap();
+    assert_eq!(stdout(&output), "bye\n");
+    assert_eq!(output.status.code(), Some(7));
+}
+
+#[test]
+fn instances_of_threads_are_suspended_independently() {
+    // the instance of the spawned thread writes before the instance of the main thread, and each
+    // runs its own event loop
+    let path = wat_file(
+        "jspi_threads",
+        r#"(module
+            (import "wasi" "thread-spawn" (func $thread_spawn (param i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+              (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "env" "memory" (memory 1 1 shared))
+            (data (i32.const 32) "thread\n")
+            (data (i32.const 48) "main\n")
+            ;; writes the string with the iovec and nwritten at $scratch
+            (func $write (param $scratch i32) (param $ptr i32) (param $len i32) (result i32)
+              (i32.store (local.get $scratch) (local.get $ptr))
+              (i32.store offset=4 (local.get $scratch) (local.get $len))
+              (call $fd_write (i32.const 1) (local.get $scratch) (i32.const 1)
+                (i32.add (local.get $scratch) (i32.const 8))))
+            (func (export "wasi_thread_start") (param $tid i32) (param $start_arg i32)
+              (i32.atomic.store (i32.const 0)
+                (i32.add (call $write (i32.const 64) (i32.const 32) (i32.const 7)) (i32.const 1)))
+              (drop (memory.atomic.notify (i32.const 0) (i32.const 1))))
+            (func (export "_start") (result i32)
+              (if (i32.lt_s (call $thread_spawn (i32.const 0)) (i32.const 0))
+                (then (return (i32.const 1))))
+              ;; wait until the thread has written, and fail if its fd_write failed
+              (block $done
+                (loop $wait
+                  (br_if $done (i32.atomic.load (i32.const 0)))
+                  (drop (memory.atomic.wait32 (i32.const 0) (i32.const 0) (i64.const -1)))
+                  (br $wait)))
+              (if (i32.ne (i32.atomic.load (i32.const 0)) (i32.const 1))
+                (then (return (i32.const 2))))
+              (call $write (i32.const 80) (i32.const 48) (i32.const 5))))"#,
+    );
+    let output = lv8().arg("--jspi").arg(&path).output().unwrap();
+    assert_eq!(stdout(&output), "thread\nmain\n");
+    assert_eq!(output.status.code(), Some(0));
+}
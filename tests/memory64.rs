@@ -0,0 +1,47 @@
+mod common;
+
+use common::{lv8, stdout, wat_file};
+
+#[test]
+fn fd_write_above_4gib() {
+    // 65537 pages are 4 GiB and 64 KiB, which are reserved but not touched except for the last page
+    let path = wat_file(
+        "memory64_fd_write",
+        r#"(module
+            (import "wasi_snapshot_preview1" "fd_write"
+              (func $fd_write (param i32 i64 i64 i64) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") i64 65537)
+            (data (i64.const 0x100000100) "above 4 GiB\n")
+            (func (export "_start")
+              ;; iovec { buf: u64, buf_len: u64 } and nwritten: u64
+              (i64.store (i64.const 0x100000200) (i64.const 0x100000100))
+              (i64.store (i64.const 0x100000208) (i64.const 12))
+              (i64.store (i64.const 0x100000300) (i64.const -1))
+              (if (call $fd_write (i32.const 1) (i64.const 0x100000200) (i64.const 1) (i64.const 0x100000300))
+                (then (call $proc_exit (i32.const 2))))
+              (if (i64.ne (i64.load (i64.const 0x100000300)) (i64.const 12))
+                (then (call $proc_exit (i32.const 3))))))"#,
+    );
+    let output = lv8().arg(&path).output().unwrap();
+    assert_eq!(stdout(&output), "above 4 GiB\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn fd_write_out_of_bounds_fails_with_efault() {
+    let path = wat_file(
+        "memory64_efault",
+        r#"(module
+            (import "wasi_snapshot_preview1" "fd_write"
+              (func $fd_write (param i32 i64 i64 i64) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") i64 1)
+            (func (export "_start")
+              (call $proc_exit
+                (call $fd_write (i32.const 1) (i64.const 0x100000000) (i64.const 1) (i64.const 0)))))"#,
+    );
+    let output = lv8().arg(&path).output().unwrap();
+    // EFAULT
+    assert_eq!(output.status.code(), Some(21));
+}
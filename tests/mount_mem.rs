@@ -0,0 +1,61 @@
+mod common;
+
+use common::{lv8, temp_dir, wat_file};
+
+/// Writes "written before exit\n" to out.txt of the in-memory directory, and exits with 3
+const WRITE_AND_EXIT: &str = r#"(module
+    (import "wasi_snapshot_preview1" "path_open"
+      (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_write"
+      (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "out.txt")
+    (data (i32.const 32) "written before exit\n")
+    (func (export "_start")
+      ;; fd 4 is the in-memory directory, which is preopened after the current directory
+      (if (call $path_open (i32.const 4) (i32.const 0) (i32.const 16) (i32.const 7)
+            (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 64))
+        (then (call $proc_exit (i32.const 1))))
+      (i32.store (i32.const 0) (i32.const 32))
+      (i32.store (i32.const 4) (i32.const 20))
+      (drop (call $fd_write (i32.load (i32.const 64)) (i32.const 0) (i32.const 1) (i32.const 8)))
+      (call $proc_exit (i32.const 3))))"#;
+
+#[test]
+fn in_memory_directories_are_exported_after_proc_exit() {
+    // proc_exit stops the module instead of the process, so the runtime still exports the files
+    let path = wat_file("mount_mem_proc_exit", WRITE_AND_EXIT);
+    let export_dir = temp_dir("mount_mem_proc_exit");
+    let output = lv8()
+        .args(["--mount-mem", "/out", "--mem-export"])
+        .arg(&export_dir)
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    let contents = std::fs::read_to_string(export_dir.join("out/out.txt")).unwrap();
+    assert_eq!(contents, "written before exit\n");
+}
+
+#[test]
+fn copies_of_host_directories_leave_them_untouched() {
+    let path = wat_file("mount_mem_host_dir", WRITE_AND_EXIT);
+    let host_dir = temp_dir("mount_mem_host_dir");
+    std::fs::write(host_dir.join("in.txt"), "from the host\n").unwrap();
+    let export_dir = temp_dir("mount_mem_host_dir_export");
+    let output = lv8()
+        .arg("--mount-mem")
+        .arg(format!("{}:/out", host_dir.display()))
+        .arg("--mem-export")
+        .arg(&export_dir)
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(!host_dir.join("out.txt").exists());
+    let copied = std::fs::read_to_string(export_dir.join("out/in.txt")).unwrap();
+    assert_eq!(copied, "from the host\n");
+    let written = std::fs::read_to_string(export_dir.join("out/out.txt")).unwrap();
+    assert_eq!(written, "written before exit\n");
+}
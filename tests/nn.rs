@@ -0,0 +1,22 @@
+mod common;
+
+use common::{lv8, repo_path, stderr};
+
+#[test]
+fn computes_xor_with_the_example_model() {
+    // xor.wasm loads xor.lv8nn from the current directory, and exits with XOR of 1 and 0
+    let output = lv8()
+        .current_dir(repo_path("examples/nn"))
+        .arg("xor.wasm")
+        .output()
+        .unwrap();
+    assert_eq!(stderr(&output), "");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn example_is_built_from_its_text_format() {
+    let wat = std::fs::read_to_string(repo_path("examples/nn/xor.wat")).unwrap();
+    let wasm = std::fs::read(repo_path("examples/nn/xor.wasm")).unwrap();
+    assert_eq!(wat::parse_str(wat).unwrap(), wasm);
+}
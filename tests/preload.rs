@@ -0,0 +1,94 @@
+mod common;
+
+use std::path::PathBuf;
+
+use common::{lv8, stderr, stdout, temp_dir, wat_file};
+
+/// Writes the script to a file of its own directory, and returns the path
+fn script_file(name: &str, source: &str) -> PathBuf {
+    let path = temp_dir(name).join(format!("{}.js", name));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+#[test]
+fn adds_imports_to_the_import_object() {
+    let path = wat_file(
+        "preload_env",
+        r#"(module
+            (import "env" "answer" (func $answer (result i32)))
+            (memory (export "memory") 1)
+            (func (export "_start") (result i32)
+              (call $answer)))"#,
+    );
+    let script = script_file("preload_env", "imports.env = { answer: () => 42 };");
+    let output = lv8()
+        .arg("--preload")
+        .arg(&script)
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(42), "{}", stderr(&output));
+}
+
+#[test]
+fn wraps_wasi_functions() {
+    // fd_write is counted by the wrapper, and the count is returned by env.writes
+    let path = wat_file(
+        "preload_wrap",
+        r#"(module
+            (import "wasi_snapshot_preview1" "fd_write"
+              (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "env" "writes" (func $writes (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "\10\00\00\00\03\00\00\00")
+            (data (i32.const 16) "hi\0a")
+            (func (export "_start") (result i32)
+              (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+              (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+              (call $writes)))"#,
+    );
+    let script = script_file(
+        "preload_wrap",
+        r#"const fd_write = imports.wasi_snapshot_preview1.fd_write;
+let writes = 0;
+imports.wasi_snapshot_preview1.fd_write = (...args) => {
+  writes++;
+  return fd_write(...args);
+};
+imports.env = { writes: () => writes };"#,
+    );
+    let output = lv8()
+        .arg("--preload")
+        .arg(&script)
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "hi\nhi\n");
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+}
+
+#[test]
+fn reports_exceptions_of_scripts() {
+    let path = wat_file(
+        "preload_exception",
+        r#"(module
+            (memory (export "memory") 1)
+            (func (export "_start")))"#,
+    );
+    let script = script_file("preload_exception", "\nundefined_function();");
+    let output = lv8()
+        .arg("--preload")
+        .arg(&script)
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(
+        stderr(&output),
+        format!(
+            "Error: {}:2: ReferenceError: undefined_function is not defined\n",
+            script.display()
+        )
+    );
+    assert_eq!(output.status.code(), Some(1));
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use common::{lv8, stderr, stdout, temp_dir, wat_file};
+use std::path::PathBuf;
+
+/// Module which exits with `argc * 100 + envc * 10 + 1 if fd 3 is preopened`
+const COUNTS: &str = r#"(module
+    (import "wasi_snapshot_preview1" "args_sizes_get"
+      (func $args_sizes_get (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "environ_sizes_get"
+      (func $environ_sizes_get (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_prestat_get"
+      (func $fd_prestat_get (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+    (memory (export "memory") 1)
+    (func (export "_start")
+      (drop (call $args_sizes_get (i32.const 0) (i32.const 4)))
+      (drop (call $environ_sizes_get (i32.const 8) (i32.const 12)))
+      (call $proc_exit
+        (i32.add
+          (i32.add
+            (i32.mul (i32.load (i32.const 0)) (i32.const 100))
+            (i32.mul (i32.load (i32.const 8)) (i32.const 10)))
+          (i32.eqz (call $fd_prestat_get (i32.const 3) (i32.const 16)))))))"#;
+
+/// Writes the script, which is given the path of the module as process.argv[2]
+fn script_file(name: &str, script: &str) -> PathBuf {
+    let path = temp_dir(name).join("main.js");
+    std::fs::write(&path, script).unwrap();
+    path
+}
+
+#[test]
+fn wasi_instances_take_their_options() {
+    let module = wat_file("run_js_options", COUNTS);
+    let script = script_file(
+        "run_js_options",
+        r#"
+        const { WASI } = require("wasi");
+        const module = new WebAssembly.Module(require("fs").readFileSync(process.argv[2]));
+        for (const options of [
+          { args: ["a", "b", "c"], env: { X: "1", Y: "2" }, preopens: { "/sandbox": "." } },
+          {},
+        ]) {
+          const wasi = new WASI(options);
+          const instance = new WebAssembly.Instance(module, wasi.getImportObject());
+          console.log(wasi.start(instance));
+        }
+        console.error("done");
+        "#,
+    );
+    let output = lv8()
+        .arg("run-js")
+        .arg(&script)
+        .arg("--")
+        .arg(&module)
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "321\n0\n");
+    assert_eq!(stderr(&output), "done\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn proc_exit_ends_the_script_without_return_on_exit() {
+    let module = wat_file("run_js_return_on_exit", COUNTS);
+    let script = script_file(
+        "run_js_return_on_exit",
+        r#"
+        const { WASI } = require("wasi");
+        const module = new WebAssembly.Module(require("fs").readFileSync(process.argv[2]));
+        const wasi = new WASI({ args: ["a"], returnOnExit: false });
+        const instance = new WebAssembly.Instance(module, wasi.getImportObject());
+        wasi.start(instance);
+        console.log("not reached");
+        "#,
+    );
+    let output = lv8()
+        .arg("run-js")
+        .arg(&script)
+        .arg("--")
+        .arg(&module)
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "");
+    assert_eq!(output.status.code(), Some(100));
+}
+
+#[test]
+fn rejects_options_of_the_global_context() {
+    let script = script_file("run_js_unsupported", "console.log(1);");
+    let output = lv8()
+        .args(["run-js", "--mount-mem", "/tmp"])
+        .arg(&script)
+        .output()
+        .unwrap();
+    assert!(stderr(&output).contains("--mount-mem is not supported by lv8 run-js"));
+    assert_eq!(output.status.code(), Some(1));
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use std::sync::{Arc, RwLock};
+
+use clap::Parser;
+use lv8::driver::Cli;
+use lv8::runtime::{self, Embedding};
+
+use common::{lv8, stderr, stdout, temp_dir, wat_file};
+
+/// Module which copies stdin to stdout, and writes "done\n" to stderr
+const COPY_STDIN: &str = r#"(module
+    (import "wasi_snapshot_preview1" "fd_read"
+      (func $fd_read (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_write"
+      (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory (export "memory") 1)
+    ;; iovec { buf: 64, buf_len: 64 } to read
+    (data (i32.const 0) "\40\00\00\00\40\00\00\00")
+    ;; iovec { buf: 48, buf_len: 5 } of "done\n"
+    (data (i32.const 16) "\30\00\00\00\05\00\00\00")
+    (data (i32.const 48) "done\n")
+    (func (export "_start") (result i32)
+      (if (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8))
+        (then (return (i32.const 1))))
+      ;; write back the bytes read
+      (i32.store (i32.const 4) (i32.load (i32.const 8)))
+      (if (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))
+        (then (return (i32.const 2))))
+      (call $fd_write (i32.const 2) (i32.const 16) (i32.const 1) (i32.const 24))))"#;
+
+#[test]
+fn redirects_stdio_to_files() {
+    let path = wat_file("stdio_files", COPY_STDIN);
+    let dir = temp_dir("stdio_files");
+    std::fs::write(dir.join("in.txt"), "from a file\n").unwrap();
+    let output = lv8()
+        .arg("--stdin")
+        .arg(dir.join("in.txt"))
+        .arg("--stdout")
+        .arg(dir.join("out.txt"))
+        .arg("--stderr")
+        .arg(dir.join("err.txt"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), "");
+    let out = std::fs::read_to_string(dir.join("out.txt")).unwrap();
+    assert_eq!(out, "from a file\n");
+    let err = std::fs::read_to_string(dir.join("err.txt")).unwrap();
+    assert_eq!(err, "done\n");
+}
+
+#[test]
+fn fails_if_stdin_file_does_not_exist() {
+    let path = wat_file("stdio_missing", COPY_STDIN);
+    let missing = temp_dir("stdio_missing").join("in.txt");
+    let output = lv8()
+        .arg("--stdin")
+        .arg(&missing)
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(stderr(&output).starts_with(&format!("Error: Failed to open {}: ", missing.display())));
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn redirects_stdio_of_embedding_to_buffers() {
+    // the WASI context is global, so this is the only test which runs a module in this process
+    let path = wat_file("stdio_embedding", COPY_STDIN);
+    let args = Cli::parse_from([std::ffi::OsStr::new("lv8"), path.as_os_str()]);
+    let stdout = Arc::new(RwLock::new(vec![]));
+    let stderr = Arc::new(RwLock::new(vec![]));
+    let embedding = Embedding {
+        stdin: Some(b"from the embedder\n".to_vec()),
+        stdout: Some(stdout.clone()),
+        stderr: Some(stderr.clone()),
+        ..Default::default()
+    };
+    assert_eq!(runtime::run_embedded(&args, embedding).unwrap(), 0);
+    assert_eq!(*stdout.read().unwrap(), b"from the embedder\n");
+    assert_eq!(*stderr.read().unwrap(), b"done\n");
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::Stdio;
+use std::thread;
+use std::time::Duration;
+
+use common::{lv8, stderr, wat_file};
+
+/// Module which accepts a connection on fd 4, and sends back the bytes it receives
+const ECHO: &str = r#"(module
+    (import "wasi_snapshot_preview1" "sock_accept"
+      (func $sock_accept (param i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "sock_recv"
+      (func $sock_recv (param i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "sock_send"
+      (func $sock_send (param i32 i32 i32 i32 i32) (result i32)))
+    (memory (export "memory") 1)
+    ;; iovec { buf: 64, buf_len: 64 } to receive
+    (data (i32.const 16) "\40\00\00\00\40\00\00\00")
+    (func (export "_start") (result i32)
+      (local $errno i32)
+      ;; the accepted fd is at 0
+      (local.set $errno (call $sock_accept (i32.const 4) (i32.const 0) (i32.const 0)))
+      (if (local.get $errno) (then (return (i32.add (i32.const 100) (local.get $errno)))))
+      ;; the number of bytes received is at 24, and ro_flags at 28
+      (local.set $errno (call $sock_recv (i32.load (i32.const 0))
+        (i32.const 16) (i32.const 1) (i32.const 0) (i32.const 24) (i32.const 28)))
+      (if (local.get $errno) (then (return (i32.add (i32.const 100) (local.get $errno)))))
+      ;; iovec { buf: 64, buf_len: received } to send, and the number of bytes sent at 40
+      (i32.store (i32.const 32) (i32.const 64))
+      (i32.store (i32.const 36) (i32.load (i32.const 24)))
+      (call $sock_send (i32.load (i32.const 0))
+        (i32.const 32) (i32.const 1) (i32.const 0) (i32.const 40))))"#;
+
+/// Returns an address with a port which is free at the moment
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+#[test]
+fn accepts_on_preopened_socket() {
+    let path = wat_file("tcplisten_echo", ECHO);
+    let addr = free_addr();
+    let child = lv8()
+        .args(["--tcplisten", &addr])
+        .arg(&path)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // retry until lv8 listens on the address
+    let mut stream = loop {
+        match TcpStream::connect(&addr) {
+            Ok(stream) => break stream,
+            Err(_) => thread::sleep(Duration::from_millis(50)),
+        }
+    };
+    stream.write_all(b"hello socket").unwrap();
+    let mut echo = [0; 12];
+    stream.read_exact(&mut echo).unwrap();
+    assert_eq!(&echo, b"hello socket");
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(stderr(&output), "");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn fails_if_address_is_in_use() {
+    let path = wat_file("tcplisten_in_use", ECHO);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let output = lv8()
+        .args(["--tcplisten", &addr])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(stderr(&output).starts_with(&format!("Error: Failed to listen on {}: ", addr)));
+    assert_eq!(output.status.code(), Some(1));
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::{lv8, stderr, wat_file};
+
+/// Spawns two threads which increment a counter in the shared memory, and exits with the counter
+/// divided by 1000 after both threads finish
+const COUNT_IN_THREADS: &str = r#"(module
+    (import "wasi" "thread-spawn" (func $thread_spawn (param i32) (result i32)))
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+    (import "env" "memory" (memory 1 1 shared))
+    ;; the counter is at 0, and the number of finished threads is at 4
+    (func (export "wasi_thread_start") (param $tid i32) (param $count i32)
+      (local $i i32)
+      (loop $increment
+        (drop (i32.atomic.rmw.add (i32.const 0) (i32.const 1)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br_if $increment (i32.lt_u (local.get $i) (local.get $count))))
+      (drop (i32.atomic.rmw.add (i32.const 4) (i32.const 1)))
+      (drop (memory.atomic.notify (i32.const 4) (i32.const 1))))
+    (func (export "_start")
+      (local $finished i32)
+      (if (i32.lt_s (call $thread_spawn (i32.const 10000)) (i32.const 0))
+        (then (call $proc_exit (i32.const 1))))
+      (if (i32.lt_s (call $thread_spawn (i32.const 10000)) (i32.const 0))
+        (then (call $proc_exit (i32.const 1))))
+      (block $done
+        (loop $wait
+          (local.set $finished (i32.atomic.load (i32.const 4)))
+          (br_if $done (i32.eq (local.get $finished) (i32.const 2)))
+          (drop (memory.atomic.wait32 (i32.const 4) (local.get $finished) (i64.const -1)))
+          (br $wait)))
+      (call $proc_exit (i32.div_u (i32.atomic.load (i32.const 0)) (i32.const 1000)))))"#;
+
+/// Spawns a thread which calls `wasi_thread_start` of the body, and waits forever
+fn spawn_and_wait(body: &str) -> String {
+    format!(
+        r#"(module
+            (import "wasi" "thread-spawn" (func $thread_spawn (param i32) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (import "env" "memory" (memory 1 1 shared))
+            (func (export "wasi_thread_start") (param $tid i32) (param $start_arg i32)
+              {})
+            (func (export "_start")
+              (if (i32.lt_s (call $thread_spawn (i32.const 5)) (i32.const 0))
+                (then (call $proc_exit (i32.const 1))))
+              ;; wait until the thread ends the process
+              (loop $wait
+                (drop (memory.atomic.wait32 (i32.const 0) (i32.const 0) (i64.const -1)))
+                (br $wait))))"#,
+        body
+    )
+}
+
+#[test]
+fn threads_share_the_memory() {
+    let path = wat_file("threads_count", COUNT_IN_THREADS);
+    let output = lv8().arg(&path).output().unwrap();
+    assert_eq!(output.status.code(), Some(20), "{}", stderr(&output));
+}
+
+#[test]
+fn exit_code_of_spawned_thread() {
+    // the main thread is stopped and exits with the code given by the spawned thread
+    let path = wat_file(
+        "threads_proc_exit",
+        &spawn_and_wait("(call $proc_exit (local.get $start_arg))"),
+    );
+    let output = lv8().arg(&path).output().unwrap();
+    assert_eq!(output.status.code(), Some(5));
+}
+
+#[test]
+fn trap_in_spawned_thread() {
+    let path = wat_file("threads_trap", &spawn_and_wait("(unreachable)"));
+    let output = lv8().arg(&path).output().unwrap();
+    assert_eq!(
+        stderr(&output),
+        "Error: thread 1 exited with an exception\n"
+    );
+    assert_eq!(output.status.code(), Some(1));
+}
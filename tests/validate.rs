@@ -0,0 +1,38 @@
+mod common;
+
+use common::{lv8, stderr, stdout, wat_file};
+
+#[test]
+fn reports_invalid_modules() {
+    let valid = wat_file(
+        "validate_valid",
+        "(module (func (result i32) (i32.const 1)))",
+    );
+    let invalid = wat_file(
+        "validate_invalid",
+        "(module (func) (func (result i32) (f32.const 1)))",
+    );
+    let output = lv8()
+        .arg("validate")
+        .arg(&valid)
+        .arg(&invalid)
+        .output()
+        .unwrap();
+    let stdout = stdout(&output);
+    assert!(stdout.contains(&format!("{}: valid\n", valid.display())));
+    assert!(stdout.contains(&format!("{}: invalid\n", invalid.display())));
+    assert!(stdout.contains("  function: 1\n"));
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn reports_unreadable_modules() {
+    let output = lv8().args(["validate", "missing.wasm"]).output().unwrap();
+    assert!(stdout(&output).starts_with("missing.wasm: error\n  error: Failed to read file: "));
+    assert_eq!(output.status.code(), Some(1));
+
+    // running a missing module is an error instead of a panic
+    let output = lv8().arg("missing.wasm").output().unwrap();
+    assert!(stderr(&output).starts_with("Error: Failed to read missing.wasm: "));
+    assert_eq!(output.status.code(), Some(1));
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::{lv8, stderr, stdout, wat_file};
+
+/// Module with two memories, which writes the string at offset 16 of the memory used by WASI
+/// functions (the iovec is at offset 0, and nwritten at offset 8 of that memory)
+const TWO_MEMORIES: &str = r#"(module
+    (import "wasi_snapshot_preview1" "fd_write"
+      (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory $memory (export "memory") 1)
+    (memory $io (export "io") 1)
+    (data (memory $memory) (i32.const 0) "\10\00\00\00\07\00\00\00")
+    (data (memory $memory) (i32.const 16) "memory\0a")
+    (data (memory $io) (i32.const 0) "\10\00\00\00\03\00\00\00")
+    (data (memory $io) (i32.const 16) "io\0a")
+    (func (export "_start") (result i32)
+      (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))))"#;
+
+#[test]
+fn uses_exported_memory_by_default() {
+    let path = wat_file("wasi_memory_default", TWO_MEMORIES);
+    let output = lv8().arg(&path).output().unwrap();
+    assert_eq!(stdout(&output), "memory\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn uses_memory_given_by_wasi_memory() {
+    let path = wat_file("wasi_memory_io", TWO_MEMORIES);
+    let output = lv8()
+        .args(["--wasi-memory", "io"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output), "io\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn fails_if_wasi_memory_does_not_exist() {
+    let path = wat_file("wasi_memory_missing", TWO_MEMORIES);
+    let output = lv8()
+        .args(["--wasi-memory", "heap"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(
+        stderr(&output),
+        "Error: Wasm module has no memory named heap\n"
+    );
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn fails_if_no_memory_is_usable() {
+    // the memory is neither exported as memory nor imported
+    let path = wat_file(
+        "wasi_memory_none",
+        r#"(module
+            (import "wasi_snapshot_preview1" "fd_write"
+              (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "heap") 1)
+            (func (export "_start") (result i32)
+              (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))))"#,
+    );
+    let output = lv8().arg(&path).output().unwrap();
+    assert!(stderr(&output).contains(
+        "Wasm module has no memory to use for WASI functions (use --wasi-memory to choose one)"
+    ));
+    assert_eq!(output.status.code(), Some(1));
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::{lv8, stderr, stdout, temp_dir, wat_file};
+
+/// Module which imports only wasi_unstable, and reads the last 3 bytes of data.txt
+///
+/// It exits with 1 unless the offset returned by fd_seek is 7 (whence END is 1 in snapshot 0,
+/// while it is 2 in preview1), and with 2 unless the size of the file is at offset 24 of the
+/// filestat (nlink is a u32 in snapshot 0, so size is at 32 in preview1).
+const READ_TAIL: &str = r#"(module
+    (import "wasi_unstable" "path_open"
+      (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+    (import "wasi_unstable" "fd_seek"
+      (func $fd_seek (param i32 i64 i32 i32) (result i32)))
+    (import "wasi_unstable" "fd_filestat_get"
+      (func $fd_filestat_get (param i32 i32) (result i32)))
+    (import "wasi_unstable" "fd_read"
+      (func $fd_read (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_unstable" "fd_write"
+      (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory (export "memory") 1)
+    (data (i32.const 16) "data.txt")
+    ;; iovec { buf: 64, buf_len: 16 }
+    (data (i32.const 32) "\40\00\00\00\10\00\00\00")
+    (func (export "_start") (result i32)
+      (local $fd i32)
+      ;; open with the rights fd_read, fd_seek and fd_filestat_get, and the fd at 0
+      (if (call $path_open (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 8)
+            (i32.const 0) (i64.const 0x200006) (i64.const 0) (i32.const 0) (i32.const 0))
+        (then (return (i32.const 10))))
+      (local.set $fd (i32.load (i32.const 0)))
+      ;; seek to 3 bytes before the end, and the new offset at 8
+      (if (call $fd_seek (local.get $fd) (i64.const -3) (i32.const 1) (i32.const 8))
+        (then (return (i32.const 11))))
+      (if (i64.ne (i64.load (i32.const 8)) (i64.const 7))
+        (then (return (i32.const 1))))
+      ;; the filestat at 128
+      (if (call $fd_filestat_get (local.get $fd) (i32.const 128))
+        (then (return (i32.const 12))))
+      (if (i64.ne (i64.load (i32.const 152)) (i64.const 10))
+        (then (return (i32.const 2))))
+      ;; write back the bytes read, whose number is at 40
+      (if (call $fd_read (local.get $fd) (i32.const 32) (i32.const 1) (i32.const 40))
+        (then (return (i32.const 13))))
+      (i32.store (i32.const 36) (i32.load (i32.const 40)))
+      (call $fd_write (i32.const 1) (i32.const 32) (i32.const 1) (i32.const 40))))"#;
+
+#[test]
+fn converts_layouts_of_snapshot_0() {
+    let path = wat_file("wasi_unstable_read_tail", READ_TAIL);
+    // the current directory is preopened as fd 3
+    let dir = temp_dir("wasi_unstable_read_tail");
+    std::fs::write(dir.join("data.txt"), "0123456789").unwrap();
+    let output = lv8().current_dir(&dir).arg(&path).output().unwrap();
+    assert_eq!(stderr(&output), "");
+    assert_eq!(stdout(&output), "789");
+    assert_eq!(output.status.code(), Some(0));
+}